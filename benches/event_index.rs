@@ -0,0 +1,97 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use chrono::prelude::*;
+use chrono::Duration;
+use moneypenny::mp_calendar::{MpEvent, cal_io, cal_ops, cal_tz::MpTimeZone};
+use moneypenny::mp_calendar::cal_index::MpEventIndex;
+
+// A team calendar: recurring meetings that expand to tens of thousands of occurrences over two years
+fn team_calendar_ics(meetings: usize) -> String {
+    let mut ics = String::from("BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//moneypenny//bench//EN\r\n");
+    for n in 0..meetings {
+        let rule = if n % 2 == 0 { "FREQ=DAILY" } else { "FREQ=WEEKLY;BYDAY=MO,TU,WE,TH,FR" };
+        let hour = 8 + n % 9;
+        let minute = (n * 15) % 60;
+        let length = 15 + (n % 4) * 15;
+        ics.push_str(&format!("BEGIN:VEVENT\r\nUID:bench-{}@example.com\r\nSUMMARY:Meeting {}\r\n\
+                               DTSTART:20240101T{:02}{:02}00Z\r\nDURATION:PT{}M\r\nRRULE:{}\r\nEND:VEVENT\r\n",
+                              n, n, hour, minute, length, rule));
+    }
+    ics.push_str("END:VCALENDAR\r\n");
+    ics
+}
+
+fn expanded_occurrences(meetings: usize) -> Vec<MpEvent> {
+    let path = std::env::temp_dir().join(format!("moneypenny_bench_{}_{}.ics", meetings, std::process::id()));
+    std::fs::write(&path, team_calendar_ics(meetings)).unwrap();
+    let events = cal_io::read_events_from_ics_file(path.to_string_lossy().into_owned(), &MpTimeZone::utc()).unwrap();
+    let _ = std::fs::remove_file(&path);
+    let start = FixedOffset::east(0).ymd(2024, 1, 1).and_hms(0, 0, 0);
+    cal_ops::expand_mpevents_in_window(events, start, start + Duration::days(730))
+}
+
+// The scan the Vec approach does once the events are already sorted
+fn vec_intersecting(sorted: &[MpEvent], window_start: DateTime<FixedOffset>, window_end: DateTime<FixedOffset>) -> Vec<&MpEvent> {
+    sorted.iter()
+        .filter(|event| event.start_time().is_some_and(|start| start < window_end)
+                        && event.end_time().is_some_and(|end| end > window_start))
+        .collect()
+}
+
+fn bench_queries(c: &mut Criterion) {
+    let mut group = c.benchmark_group("queries");
+    for meetings in [10, 40] {
+        let occurrences = expanded_occurrences(meetings);
+        let index = MpEventIndex::from_events(occurrences.clone());
+        let size = occurrences.len();
+        let window_start = FixedOffset::east(0).ymd(2025, 3, 10).and_hms(0, 0, 0);
+        let window_end = window_start + Duration::days(1);
+        let probe = window_start + Duration::hours(10);
+
+        group.bench_with_input(BenchmarkId::new("intersecting/index", size), &index, |b, index| {
+            b.iter(|| index.intersecting(window_start, window_end).len())
+        });
+        group.bench_with_input(BenchmarkId::new("intersecting/vec_scan", size), &occurrences, |b, sorted| {
+            b.iter(|| vec_intersecting(sorted, window_start, window_end).len())
+        });
+        group.bench_with_input(BenchmarkId::new("intersecting/vec_expand_and_sort", size), &occurrences, |b, events| {
+            b.iter_batched(|| events.clone(),
+                           |events| cal_ops::expand_mpevents_in_window(events, window_start, window_end).len(),
+                           BatchSize::LargeInput)
+        });
+        group.bench_with_input(BenchmarkId::new("next_after/index", size), &index, |b, index| {
+            b.iter(|| index.next_after(probe).map(|event| event.start_time()))
+        });
+        group.bench_with_input(BenchmarkId::new("next_after/vec_scan", size), &occurrences, |b, sorted| {
+            b.iter(|| sorted.iter().find(|event| event.start_time().is_some_and(|start| start >= probe)).map(|event| event.start_time()))
+        });
+        group.bench_with_input(BenchmarkId::new("containing/index", size), &index, |b, index| {
+            b.iter(|| index.containing(probe).len())
+        });
+        group.bench_with_input(BenchmarkId::new("containing/vec_scan", size), &occurrences, |b, sorted| {
+            b.iter(|| vec_intersecting(sorted, probe, probe + Duration::nanoseconds(1)).len())
+        });
+    }
+    group.finish();
+}
+
+fn bench_incremental_insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("insert_one");
+    let occurrences = expanded_occurrences(40);
+    let size = occurrences.len();
+    let extra = occurrences[size / 2].clone();
+
+    group.bench_function(BenchmarkId::new("index", size), |b| {
+        b.iter_batched(|| MpEventIndex::from_events(occurrences.clone()),
+                       |mut index| { let _ = index.insert(extra.clone()); index },
+                       BatchSize::LargeInput)
+    });
+    group.bench_function(BenchmarkId::new("vec_push_and_sort", size), |b| {
+        b.iter_batched(|| occurrences.clone(),
+                       |mut events| { events.push(extra.clone()); cal_ops::sort_mpevents_chronologically_by_start(events) },
+                       BatchSize::LargeInput)
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_queries, bench_incremental_insert);
+criterion_main!(benches);
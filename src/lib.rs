@@ -0,0 +1,2 @@
+pub mod mp_core;
+pub mod mp_calendar;
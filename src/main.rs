@@ -1,10 +1,69 @@
-mod mp_core;
+use moneypenny::{mp_core, mp_calendar};
 
+/// Used when no config path is given on the command line
+const DEFAULT_CONFIG_PATH: &str = "moneypenny.toml";
+
+/// moneypenny [config path] [add <phrase> | agenda [query] | conflicts [query] | export <path> [query]
+///                            | week [query] | month [query] | dashboard]
+///
+/// A query may also be the name of one of the config's [[views]].
+/// export writes ICS, or CSV, JSON, Markdown or HTML for a .csv, .json, .md or .html path.
 fn main() {
-    let message = mp_core::Message {
-        body: String::from("This is a test string."),
-        output_time: true,
-        sender: String::from("Test module"),
+    let mut args = std::env::args().skip(1);
+    let config_path = args.next().unwrap_or_else(|| String::from(DEFAULT_CONFIG_PATH));
+    let config = match mp_core::core_config::parse_config_file_to_struct(config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            mp_core::core_io::output_message(mp_core::Message {
+                body: format!("Config problem: {}", e),
+                output_time: true,
+                sender: String::from("Core"),
+            });
+            std::process::exit(1);
+        }
+    };
+    let command: Vec<String> = args.collect();
+    let now = chrono::Utc::now().with_timezone(&chrono::FixedOffset::east(0));
+    match command.split_first() {
+        // e.g. moneypenny moneypenny.toml add lunch with Priya tomorrow 1pm
+        Some((verb, phrase)) if verb == "add" => {
+            mp_calendar::add_event_from_phrase(&config, &phrase.join(" "), now);
+            return;
+        },
+        // e.g. moneypenny moneypenny.toml agenda status:confirmed before:+7d
+        Some((verb, query)) if verb == "agenda" => {
+            mp_calendar::output_agenda_for_query(&config, &query.join(" "), now);
+            return;
+        },
+        Some((verb, query)) if verb == "conflicts" => {
+            mp_calendar::output_conflicts_for_query(&config, &query.join(" "), now);
+            return;
+        },
+        // e.g. moneypenny moneypenny.toml export office.ics Office
+        //      moneypenny moneypenny.toml export week.md after:2024-05-13 before:+7d
+        Some((verb, arguments)) if verb == "export" && !arguments.is_empty() => {
+            mp_calendar::export_events_for_query(&config, &arguments[1..].join(" "), &arguments[0], now);
+            return;
+        },
+        // e.g. moneypenny moneypenny.toml week Office after:2024-05-13
+        Some((verb, query)) if verb == "week" || verb == "month" => {
+            let view = if verb == "week" { mp_calendar::GridView::Week } else { mp_calendar::GridView::Month };
+            // https://no-color.org
+            let use_colour = std::env::var_os("NO_COLOR").is_none();
+            mp_calendar::output_calendar_grid(&config, view, &query.join(" "), now, use_colour);
+            return;
+        },
+        Some((verb, _)) if verb == "dashboard" => {
+            mp_calendar::run_dashboard(&config);
+            return;
+        },
+        _ => ()
     };
-    mp_core::core_io::output_message(message);
+    mp_core::core_io::output_startup_message(&config);
+    let alarms = mp_calendar::start_configured_alarms(&config);
+    mp_calendar::serve_configured_feeds(&config);
+    // Without feeds to serve, stay up for the alarms
+    if let Some(alarms) = alarms {
+        let _ = alarms.join();
+    }
 }
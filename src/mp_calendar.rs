@@ -1,10 +1,36 @@
+// Written with explicit returns and single-arm matches, like the rest of the module
+#![allow(clippy::needless_return, clippy::single_match)]
+
 use std::str::FromStr;
 use std::cmp::Ordering;
+use std::fmt;
 use chrono::prelude::*;
 use crate::mp_core;
 
-#[derive(Debug, PartialEq, Eq, Clone)]
-enum EventStatus {
+pub mod cal_alarm;
+pub mod cal_caldav;
+pub mod cal_export;
+pub mod cal_feed;
+pub mod cal_index;
+pub mod cal_merge;
+pub mod cal_phrase;
+pub mod cal_query;
+pub mod cal_recur;
+pub mod cal_store;
+pub mod cal_task;
+mod cal_text;
+pub mod cal_tui;
+pub mod cal_tz;
+
+use cal_alarm::MpAlarm;
+use cal_recur::RecurrenceRule;
+use cal_tz::MpTimeZone;
+
+/// How often start_configured_alarms checks for alarms that have come due
+const ALARM_TICK_SECONDS: u64 = 30;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum EventStatus {
     Tentative,
     Confirmed,
     Cancelled
@@ -22,21 +48,176 @@ impl FromStr for EventStatus {
     }
 }
 
-#[derive(Debug, Eq, Clone)]
+impl EventStatus {
+    pub fn to_ical_str(self) -> &'static str {
+        match self {
+            EventStatus::Tentative => return "TENTATIVE",
+            EventStatus::Confirmed => return "CONFIRMED",
+            EventStatus::Cancelled => return "CANCELLED"
+        }
+    }
+}
+
+/// Everything that can go wrong reading, interpreting or writing a calendar
+#[derive(Debug)]
+pub enum MpCalendarError {
+    Io { path: String, source: std::io::Error },
+    // line is the (unfolded) line number where the parser gave up, when it knows it
+    Parse { path: String, line: Option<usize>, message: String },
+    UnsupportedVersion(String),
+    InvalidDate { property: String, value: String },
+    InvalidRecurrence { property: String, value: String, reason: String },
+    UnknownStatus(String),
+    // A numeric property such as PRIORITY that isn't a number in its allowed range
+    InvalidValue { property: String, value: String },
+    MissingProperty(String),
+    // A description of an event that couldn't be turned into one
+    InvalidPhrase { phrase: String, reason: String },
+    InvalidQuery { query: String, reason: String },
+    // Nowhere to put a new event: no event store, and every calendar is read-only
+    NoWritableCalendar,
+    Database { path: String, source: rusqlite::Error },
+    // The event store was created by a newer moneypenny than this one
+    UnsupportedSchema { path: String, version: u32 },
+    // The request never got a usable answer: connection failure, or a reply that couldn't be read
+    Remote { url: String, message: String },
+    HttpStatus { url: String, status: u16 },
+    // The server's copy changed since its ETag or sync token was read; fetch it again and retry
+    PreconditionFailed { url: String },
+    EndBeforeStart { start: String, end: String },
+    // Wraps an error with the component it came from, e.g. the third VEVENT
+    InComponent { component: String, index: usize, uid: Option<String>, source: Box<MpCalendarError> }
+}
+
+impl MpCalendarError {
+    fn in_component(self, component: &str, index: usize, uid: Option<String>) -> MpCalendarError {
+        return MpCalendarError::InComponent { component: String::from(component), index, uid, source: Box::new(self) };
+    }
+
+    /// Fills in the property name for errors raised by helpers that only see the value
+    fn for_property(self, name: &str) -> MpCalendarError {
+        match self {
+            MpCalendarError::InvalidDate { value, .. } => return MpCalendarError::InvalidDate { property: String::from(name), value },
+            other => return other
+        }
+    }
+}
+
+impl fmt::Display for MpCalendarError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MpCalendarError::Io { path, source } => write!(f, "could not access {}: {}", path, source),
+            MpCalendarError::Parse { path, line: Some(line), message } => write!(f, "could not parse {} at line {}: {}", path, line, message),
+            MpCalendarError::Parse { path, line: None, message } => write!(f, "could not parse {}: {}", path, message),
+            MpCalendarError::UnsupportedVersion(version) => write!(f, "unsupported iCalendar VERSION {}", version),
+            MpCalendarError::InvalidDate { property, value } => write!(f, "invalid {} value '{}'", property, value),
+            MpCalendarError::InvalidRecurrence { property, value, reason } => write!(f, "invalid {} value '{}': {}", property, value, reason),
+            MpCalendarError::UnknownStatus(status) => write!(f, "unknown STATUS '{}'", status),
+            MpCalendarError::InvalidValue { property, value } => write!(f, "invalid {} value '{}'", property, value),
+            MpCalendarError::MissingProperty(property) => write!(f, "missing required {}", property),
+            MpCalendarError::InvalidPhrase { phrase, reason } => write!(f, "could not understand '{}': {}", phrase, reason),
+            MpCalendarError::InvalidQuery { query, reason } => write!(f, "invalid query '{}': {}", query, reason),
+            MpCalendarError::NoWritableCalendar => write!(f, "there is no event store or writable calendar to save to"),
+            MpCalendarError::Database { path, source } => write!(f, "event store {} failed: {}", path, source),
+            MpCalendarError::Remote { url, message } => write!(f, "request to {} failed: {}", url, message),
+            MpCalendarError::HttpStatus { url, status } => write!(f, "{} answered with HTTP status {}", url, status),
+            MpCalendarError::PreconditionFailed { url } => write!(f, "{} has changed on the server since it was last fetched", url),
+            MpCalendarError::UnsupportedSchema { path, version } => write!(f, "event store {} has schema version {}, which is newer than this moneypenny supports", path, version),
+            MpCalendarError::EndBeforeStart { start, end } => write!(f, "DTEND {} is before DTSTART {}", end, start),
+            MpCalendarError::InComponent { component, index, uid: Some(uid), source } => write!(f, "{} {} (UID {}): {}", component, index + 1, uid, source),
+            MpCalendarError::InComponent { component, index, uid: None, source } => write!(f, "{} {}: {}", component, index + 1, source)
+        }
+    }
+}
+
+impl std::error::Error for MpCalendarError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MpCalendarError::Io { source, .. } => Some(source),
+            MpCalendarError::Database { source, .. } => Some(source),
+            MpCalendarError::InComponent { source, .. } => Some(source.as_ref()),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Eq, Clone, Default)]
 pub struct MpEvent {
     // TODO consider making name and start time non optional
     name: Option<String>,
     start_time: Option<DateTime<FixedOffset>>,
     end_time: Option<DateTime<FixedOffset>>,
+    // The end was read from a DURATION rather than a DTEND, so it's written back as one
+    end_as_duration: bool,
     location: Option<String>,
     description: Option<String>,
-    status: Option<EventStatus>
+    status: Option<EventStatus>,
+    // TRANSP:TRANSPARENT, i.e. the event doesn't make anyone busy
+    transparent: bool,
+    // When the ICS object was created, written out as DTSTAMP
+    dtstamp: Option<DateTime<FixedOffset>>,
+    // SEQUENCE, the organiser's revision number, and LAST-MODIFIED; together they say which of two
+    // copies of an event is newer
+    sequence: Option<u32>,
+    last_modified: Option<DateTime<FixedOffset>>,
+    // A VALUE=DATE event: start and end are local midnights and the end is exclusive
+    all_day: bool,
+    // Zone DTSTART was given in, used to expand recurrences across DST changes. None means UTC.
+    timezone: Option<MpTimeZone>,
+    uid: Option<String>,
+    rrule: Option<RecurrenceRule>,
+    rdates: Vec<DateTime<FixedOffset>>,
+    exdates: Vec<DateTime<FixedOffset>>,
+    // Set on a single instance of a recurring event: the start time of the instance it represents
+    recurrence_id: Option<DateTime<FixedOffset>>,
+    // Modified instances of this recurring event, i.e. VEVENTs sharing its UID with a RECURRENCE-ID
+    overrides: Vec<MpEvent>,
+    // Properties moneypenny doesn't interpret (ORGANIZER, ATTENDEE, X-...), kept so they can be written back
+    extra_properties: Vec<MpProperty>,
+    alarms: Vec<MpAlarm>
+}
+
+/// An ICS property carried through untouched: the value is kept exactly as read, still escaped
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct MpProperty {
+    pub name: String,
+    pub params: Vec<(String, Vec<String>)>,
+    pub value: Option<String>
 }
 
 impl MpEvent {
+    pub fn name(&self) -> Option<&str> {
+        return self.name.as_deref();
+    }
+
+    pub fn uid(&self) -> Option<&str> {
+        return self.uid.as_deref();
+    }
+
+    pub fn status(&self) -> Option<EventStatus> {
+        return self.status;
+    }
+
+    pub fn start_time(&self) -> Option<DateTime<FixedOffset>> {
+        return self.start_time;
+    }
+
+    pub fn end_time(&self) -> Option<DateTime<FixedOffset>> {
+        return self.end_time;
+    }
+
+    pub fn sequence(&self) -> Option<u32> {
+        return self.sequence;
+    }
+
+    pub fn last_modified(&self) -> Option<DateTime<FixedOffset>> {
+        return self.last_modified;
+    }
+
     fn cmp_start_time(&self, other: &MpEvent) -> Option<Ordering> {
         let lhs_time = match self.start_time {
             Some(time) => time,
+            None if other.start_time.is_none() => return Some(Ordering::Equal),
             None => return Some(Ordering::Greater)
         };
         let rhs_time = match other.start_time {
@@ -49,6 +230,7 @@ impl MpEvent {
     fn cmp_end_time(&self, other: &MpEvent) -> Option<Ordering> {
         let lhs_time = match self.end_time {
             Some(time) => time,
+            None if other.end_time.is_none() => return Some(Ordering::Equal),
             None => return Some(Ordering::Greater)
         };
         let rhs_time = match other.end_time {
@@ -59,7 +241,7 @@ impl MpEvent {
     }
 
     /// Assumes lhs start time before rhs start time
-    fn ordered_has_overlap(&self, other: &MpEvent) -> bool {
+    pub fn ordered_has_overlap(&self, other: &MpEvent) -> bool {
         let lhs_end_time = match self.end_time {
             Some(time) => time,
             None => return false
@@ -68,55 +250,113 @@ impl MpEvent {
             Some(time) => time,
             None => return false
         };
+        // An all-day DTEND is the (exclusive) start of the next day, so back-to-back days don't overlap
+        if self.all_day {
+            return lhs_end_time.gt(&rhs_start_time);
+        }
         return lhs_end_time.ge(&rhs_start_time);
     }
 
-    /// Convert an MpEvent into a string for it's ICS notation
-    fn deserialise_to_ics_string(&self) -> String {
-        let mut ics_event = String::from("BEGIN:VEVENT\n");
-        let MpEvent {name, start_time, end_time, location, description, status} = self;
-        match name {
-            Some(name) => {
-                let strapp = &format!("SUMMARY:{}\n", name);
-                ics_event.push_str(&strapp)
-            },
-            None => {}
+    /// True if [start, end) intersects [window_start, window_end). Events with no end are instants.
+    fn intersects_window(&self, window_start: DateTime<FixedOffset>, window_end: DateTime<FixedOffset>) -> bool {
+        let start = match self.start_time {
+            Some(time) => time,
+            None => return false
         };
-        match start_time {
-            Some(time_utc) => {
-                let time = cal_io::convert_fixed_offset_to_ical_time(*time_utc);
-                ics_event.push_str(&format!("DTSTART:{}\n", time));
-            },
-            None => ()
+        let end = match self.end_time {
+            Some(time) => time,
+            None => start
         };
-        match end_time {
-            Some(time_utc) => {
-                let time = cal_io::convert_fixed_offset_to_ical_time(*time_utc);
-                ics_event.push_str(&format!("DTEND:{}\n", time));
-            },
-            None => ()
+        if end == start {
+            return start >= window_start && start < window_end;
+        }
+        return start < window_end && end > window_start;
+    }
+
+    fn is_recurring(&self) -> bool {
+        return self.rrule.is_some() || !self.rdates.is_empty() || !self.overrides.is_empty();
+    }
+
+    /// Expands a recurring event into one non-recurring MpEvent per occurrence that intersects
+    /// [window_start, window_end). The recurrence set is DTSTART, the RRULE and any RDATEs, minus EXDATEs,
+    /// with instances replaced by their RECURRENCE-ID overrides. Each occurrence keeps the start time it
+    /// was generated at as its recurrence_id. A non-recurring event is returned as-is if it intersects
+    /// the window. Events with no start time can't be placed in a window and produce nothing.
+    pub fn expand_occurrences(&self, window_start: DateTime<FixedOffset>, window_end: DateTime<FixedOffset>) -> Vec<MpEvent> {
+        let start_time = match self.start_time {
+            Some(time) => time,
+            None => return vec![]
         };
-        match location {
-            Some(loc) => { ics_event.push_str(&format!("LOCATION:{}\n", loc)) },
-            None => ()
+        if !self.is_recurring() {
+            if self.intersects_window(window_start, window_end) {
+                return vec![self.clone()];
+            }
+            return vec![];
+        }
+        let zone = match &self.timezone {
+            Some(zone) => zone.clone(),
+            None => MpTimeZone::fixed(*start_time.offset())
         };
-        match description {
-            Some(desc) => { ics_event.push_str(&format!("DESCRIPTION:{}\n", desc)) },
-            None => ()
+        // All-day events span whole local days, so measure them in wall time to survive DST changes
+        let duration = match self.end_time {
+            Some(end_time) if self.all_day => zone.convert(end_time).naive_local() - zone.convert(start_time).naive_local(),
+            Some(end_time) => end_time - start_time,
+            None => chrono::Duration::zero()
         };
-        match status {
-            Some(enum_status) => {
-                let status;
-                match enum_status {
-                    EventStatus::Tentative => status = String::from("TENTATIVE"),
-                    EventStatus::Confirmed => status = String::from("CONFIRMED"),
-                    EventStatus::Cancelled => status = String::from("CANCELLED")
-                }
-                ics_event.push_str(&format!("STATUS:{}\n", status)) },
-            None => ()
+        let mut instance_starts: Vec<DateTime<FixedOffset>> = match &self.rrule {
+            Some(rule) => {
+                let limit = zone.convert(window_end).naive_local();
+                rule.occurrences(zone.convert(start_time).naive_local(), &zone, limit).into_iter()
+                    .map(|local_start| zone.from_local_datetime(local_start))
+                    .collect()
+            }
+            None => vec![start_time]
         };
-        ics_event.push_str(&String::from("END:VEVENT\n"));
-        return ics_event;
+        instance_starts.extend(self.rdates.iter().cloned());
+        instance_starts.sort();
+        instance_starts.dedup();
+
+        let mut occurrences: Vec<MpEvent> = vec![];
+        for instance_start in instance_starts {
+            if self.exdates.contains(&instance_start) {
+                continue;
+            }
+            if self.overrides.iter().any(|over| over.recurrence_id == Some(instance_start)) {
+                continue;
+            }
+            let mut occurrence = self.as_single_instance();
+            occurrence.recurrence_id = Some(instance_start);
+            occurrence.start_time = Some(instance_start);
+            occurrence.end_time = match self.end_time {
+                Some(_) if self.all_day => Some(zone.from_local_datetime(zone.convert(instance_start).naive_local() + duration)),
+                Some(_) => Some(instance_start + duration),
+                None => None
+            };
+            if occurrence.intersects_window(window_start, window_end) {
+                occurrences.push(occurrence);
+            }
+        }
+        // Overrides are placed by their own (possibly moved) times, not the instance they replace
+        for over in &self.overrides {
+            let cancelled_by_exdate = match over.recurrence_id {
+                Some(recurrence_id) => self.exdates.contains(&recurrence_id),
+                None => false
+            };
+            if !cancelled_by_exdate && over.intersects_window(window_start, window_end) {
+                occurrences.push(over.as_single_instance());
+            }
+        }
+        return occurrences;
+    }
+
+    /// A copy of this event with all recurrence information stripped, apart from its recurrence_id
+    fn as_single_instance(&self) -> MpEvent {
+        let mut instance = self.clone();
+        instance.rrule = None;
+        instance.rdates = vec![];
+        instance.exdates = vec![];
+        instance.overrides = vec![];
+        return instance;
     }
 }
 
@@ -144,38 +384,24 @@ impl PartialEq for MpEvent {
 }
 
 impl PartialOrd for MpEvent {
-    // Use start time cmp function as default for ordering trait
+    // Defer to the total ordering so sort() and partial_cmp agree
     fn partial_cmp(&self, other: &MpEvent) -> Option<Ordering> {
-        return MpEvent::cmp_start_time(self, other);
+        return Some(self.cmp(other));
     }
 }
 
 impl Ord for MpEvent {
     fn cmp(&self, other: &MpEvent) -> Ordering {
-        let ord_start = match MpEvent::cmp_start_time(self, other) {
-            Some(Ordering::Equal) => Ordering::Equal,
-            Some(Ordering::Greater) => return Ordering::Greater,
-            Some(Ordering::Less) => return Ordering::Less,
-            None => panic!()
-        };
+        let ord_start = MpEvent::cmp_start_time(self, other).unwrap_or(Ordering::Equal);
         // Assumes event with same start time but later finish is chronologically 'later'
-        let ord_end = match MpEvent::cmp_end_time(self, other) {
-            Some(Ordering::Equal) => Ordering::Equal,
-            Some(Ordering::Greater) => return Ordering::Greater,
-            Some(Ordering::Less) => return Ordering::Less,
-            None => panic!()
-        };
-        let ord_name = match self.name.cmp(&other.name) {
-            Ordering::Equal => Ordering::Equal,
-            Ordering::Greater => return Ordering::Greater,
-            Ordering::Less => return Ordering::Less
-        };
-        if ord_start == Ordering::Equal && ord_end == Ordering::Equal && ord_name == Ordering::Equal {
-            return Ordering::Equal;
-        } else {
-            output_mp_calendar_message(String::from("MpEvent Ord is breaking it's contract"));
-            panic!();
-        }
+        let ord_end = MpEvent::cmp_end_time(self, other).unwrap_or(Ordering::Equal);
+        // Remaining fields only break ties so that the ordering stays total
+        return ord_start
+            .then(ord_end)
+            .then_with(|| self.name.cmp(&other.name))
+            .then_with(|| self.location.cmp(&other.location))
+            .then_with(|| self.uid.cmp(&other.uid))
+            .then_with(|| self.description.cmp(&other.description));
     }
 }
 
@@ -183,7 +409,7 @@ impl Ord for MpEvent {
 mod calendar_mpevent_tests {
     use super::*;
 
-    fn make_event(start_secs: i64, end_secs: i64) -> MpEvent {
+    pub(super) fn make_event(start_secs: i64, end_secs: i64) -> MpEvent {
         let dt1 = FixedOffset::west(0).timestamp(start_secs, 0);
         let dt2 = FixedOffset::west(0).timestamp(end_secs, 0);
         let this_event = MpEvent {
@@ -192,7 +418,8 @@ mod calendar_mpevent_tests {
             end_time: Some(dt2),
             location: None,
             description: None,
-            status:None
+            status: None,
+            ..Default::default()
         };
         return this_event;
     }
@@ -224,6 +451,37 @@ mod calendar_mpevent_tests {
     }
 
     #[test]
+    fn test_expand_occurrences_places_overrides_by_their_own_time() {
+        use std::str::FromStr;
+        let mut series = make_event(0, 600);
+        series.uid = Some(String::from("series"));
+        series.rrule = Some(RecurrenceRule::from_str("FREQ=DAILY;COUNT=3").unwrap());
+        series.exdates = vec![FixedOffset::west(0).timestamp(2 * 86400, 0)];
+        // Second instance moved forward by a week
+        let mut moved = make_event(8 * 86400, 8 * 86400 + 600);
+        moved.recurrence_id = Some(FixedOffset::west(0).timestamp(86400, 0));
+        series.overrides = vec![moved];
+        let first_day = series.expand_occurrences(FixedOffset::west(0).timestamp(0, 0), FixedOffset::west(0).timestamp(3 * 86400, 0));
+        assert_eq!(1, first_day.len());
+        assert_eq!(Some(FixedOffset::west(0).timestamp(0, 0)), first_day[0].recurrence_id);
+        let week_later = series.expand_occurrences(FixedOffset::west(0).timestamp(7 * 86400, 0), FixedOffset::west(0).timestamp(9 * 86400, 0));
+        assert_eq!(1, week_later.len());
+        assert_eq!(Some(FixedOffset::west(0).timestamp(8 * 86400, 0)), week_later[0].start_time);
+    }
+
+    #[test]
+    fn test_ord_is_total_without_start_times() {
+        let untimed_a = MpEvent { name: Some(String::from("A")), ..Default::default() };
+        let untimed_b = MpEvent { name: Some(String::from("B")), ..Default::default() };
+        let timed = make_event(100, 200);
+        assert_eq!(Ordering::Less, untimed_a.cmp(&untimed_b));
+        assert_eq!(Ordering::Greater, untimed_b.cmp(&untimed_a));
+        assert_eq!(Ordering::Less, timed.cmp(&untimed_a));
+        assert_eq!(Ordering::Equal, untimed_a.cmp(&untimed_a.clone()));
+    }
+
+    #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_ordered_has_overlap() {
         let event1 = make_event(100, 300);
         let event2 = make_event(200, 400);
@@ -248,199 +506,3537 @@ fn output_mp_calendar_message(str_message: String) {
     mp_core::core_io::output_message(message);
 }
 
-pub mod cal_io {
-    use ical::parser::ical::component::IcalCalendar;
-    use std::io::prelude::*;
-    use std::io::Error;
-    use std::fs::File;
-    use super::{DateTime, Utc, FixedOffset, TimeZone}; // Chrono imports
-    use super::{MpEvent, EventStatus, FromStr}; // MP imports
+/// Outputs a problem found loading the calendars unless it's been reported before. The alarm timer
+/// and the feeds load the calendars again and again, and would otherwise repeat it every time.
+fn output_load_warning(str_message: String) {
+    if first_report_of(&str_message) {
+        output_mp_calendar_message(str_message);
+    }
+}
 
-    pub fn parse_file_to_ical_calendar(path: String) -> Result<IcalCalendar, ical::parser::ParserError> {
-        use std::io::BufReader;
-        //use std::fs::File;
+fn first_report_of(warning: &str) -> bool {
+    static REPORTED: std::sync::Mutex<Vec<String>> = std::sync::Mutex::new(Vec::new());
+    let mut reported = REPORTED.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if reported.iter().any(|seen| seen == warning) {
+        return false;
+    }
+    reported.push(String::from(warning));
+    return true;
+}
 
-        let buf = BufReader::new(File::open(path).unwrap());
-        let mut reader = ical::IcalParser::new(buf);
-        
-        let cal_option = reader.next();
-        match cal_option {
-            Some(cal_result) => {
-                super::output_mp_calendar_message(String::from("ICalParser successfully read from file"));
-                return cal_result;
-            }
-            None => {
-                super::output_mp_calendar_message(String::from("ICalParser could not read from file"));
-                return Err(ical::parser::ParserError::NotComplete);
-            }
-        }
+/// What the tests of loading, saving and serving the configured calendars all need
+#[cfg(test)]
+pub(crate) mod test_support {
+    use crate::mp_core::core_config::{Config, CalendarConfig};
+
+    /// An empty directory of the system's temp dir for the test called name, in this run only
+    pub fn scratch_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("moneypenny_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        return dir;
+    }
+
+    /// A config greeting Tom with the given zone and calendars, and the defaults for the rest
+    pub fn test_config(timezone: Option<&str>, calendars: Vec<CalendarConfig>) -> Config {
+        return Config {
+            name: String::from("Tom"),
+            greeting: String::from("Hello"),
+            timezone: timezone.map(String::from),
+            calendars,
+            ..Default::default()
+        };
     }
+}
 
-    /// This will create a new file to write to, or COMPLETELY OVERWRITE an existing one. Refactor for poss append?
-    pub fn deserialise_mpevents_to_ics_file(write_path: String, events: Vec<MpEvent>) { // todo return
-        use std::path::Path;
+/// Reads the events of every calendar listed in the config, each with floating times in its own
+/// timezone or else the config's. A calendar that can't be read is reported and left out rather
+/// than stopping the others from loading. Invalid entries are skipped; validate_config reports them.
+/// Each calendar is merged into those before it, so an event in more than one appears once, as
+/// its newest revision, and the duplicates found are reported.
+pub fn load_configured_calendars(config: &mp_core::core_config::Config) -> Vec<MpEvent> {
+    return merge_configured_calendars(config, true);
+}
 
-        let path = Path::new(&write_path);
-        let display = path.display(); // gives string of filename
+/// As load_configured_calendars without reporting the duplicates, for callers that load the
+/// calendars again and again, such as the feeds and the alarm timer
+pub fn load_configured_calendars_quietly(config: &mp_core::core_config::Config) -> Vec<MpEvent> {
+    return merge_configured_calendars(config, false);
+}
 
-        // Open a file in write-only mode, returns `io::Result<File>`
-        let mut file = match File::create(&path) {
-            Err(why) => {
-                super::output_mp_calendar_message(format!("Couldn't create {}: {}", display, why));
-            },
-            Ok(mut open_file) => {
-                let cal_start = "BEGIN:VCALENDAR\nVERSION:2.0\nCALSCALE:GREGORIAN\n"; // TODO take these elsewhere
-                let cal_end = "END:VCALENDAR";
-                open_file.write(cal_start.as_bytes()).unwrap();
-                for event in events{
-                    let event_string = event.deserialise_to_ics_string();
-                    match open_file.write_all(event_string.as_bytes()) {
-                        Ok(_) => {}, // continue
-                        Err(e) => super::output_mp_calendar_message(format!("Failed to deserialise MPEvents to ICS file {}: {}", display, e))
-                    }
+fn merge_configured_calendars(config: &mp_core::core_config::Config, report_duplicates: bool) -> Vec<MpEvent> {
+    let local_zone = cal_tz::configured_local_zone(config.timezone.as_deref());
+    let mut events: Vec<MpEvent> = vec![];
+    for calendar in &config.calendars {
+        if calendar.validate().is_err() {
+            continue;
+        }
+        match load_configured_calendar(config, calendar) {
+            Ok(calendar_events) => {
+                let (merged, report) = cal_merge::merge_events(events, calendar_events);
+                events = merged;
+                if report_duplicates && report.has_duplicates() {
+                    output_mp_calendar_message(format_merge_report(calendar.display_name(), &report, &local_zone));
                 }
-                open_file.write(cal_end.as_bytes()).unwrap();
-            }
+            },
+            Err(e) => output_load_warning(format!("Could not load calendar '{}': {}", calendar.display_name(), e))
         };
     }
+    return events;
+}
 
-    pub fn extract_events_from_ical(cal: IcalCalendar) -> Vec<MpEvent> {
-        let events = cal.events;
-        let mut mp_events: Vec<MpEvent> = vec![];
-        for event in events {
-            let mut mp_event = MpEvent {
-                name: None,
-                start_time: None,
-                end_time: None,
-                location: None,
-                description: None,
-                status: None
-            };
-            let event_props = event.properties;
-            for prop in event_props {
-                let name = prop.name;
-                if name == "SUMMARY" {
-                    mp_event.name = prop.value;
-                } else if name == "DTSTART" {
-                    mp_event.start_time = convert_ical_time_to_fixed_offset(prop.value, prop.params);
-                } else if name == "DTEND" {
-                    mp_event.end_time = convert_ical_time_to_fixed_offset(prop.value, prop.params);
-                } else if name == "LOCATION" {
-                    mp_event.location = prop.value;
-                } else if name == "DESCRIPTION" {
-                    mp_event.description = prop.value;
-                } else if name == "STATUS" {
-                    match prop.value {
-                        Some(str) => {
-                            mp_event.status = Some(EventStatus::from_str(&str).unwrap());
-                        }
-                        // TODO: assuming an event without a status is tentative may not be the best call. Maybe leave at None?
-                        None => mp_event.status = Some(EventStatus::Tentative)
-                    }
-                }
-            }
-            mp_events.push(mp_event);
+/// Reads the events of one [[calendars]] entry, floating times in its timezone or else the config's
+pub fn load_configured_calendar(config: &mp_core::core_config::Config,
+                                calendar: &mp_core::core_config::CalendarConfig) -> Result<Vec<MpEvent>, MpCalendarError> {
+    let local_zone = cal_tz::configured_local_zone(calendar.timezone.as_deref().or(config.timezone.as_deref()));
+    return cal_io::read_events_from_ics_file(calendar.path.clone(), &local_zone);
+}
+
+#[cfg(test)]
+mod configured_calendar_tests {
+    use super::*;
+
+    #[test]
+    fn test_load_warnings_are_reported_once() {
+        let warning = format!("Could not load calendar 'Missing {}': not found", std::process::id());
+        assert!(first_report_of(&warning));
+        assert!(!first_report_of(&warning));
+        assert!(first_report_of(&format!("{} again", warning)));
+    }
+
+    #[test]
+    fn test_load_configured_calendars_skips_unreadable_files() {
+        let config = mp_core::core_config::parse_config_file_to_struct(String::from("src/test/test_config_calendars.toml")).unwrap();
+        assert_eq!(2, config.calendars.len());
+        let events = load_configured_calendars(&config);
+        assert_eq!(9, events.len());
+    }
+}
+
+/// Serves the calendars as described by the [feed] table until the process is stopped. Does
+/// nothing if there isn't one.
+pub fn serve_configured_feeds(config: &mp_core::core_config::Config) {
+    if config.feed.is_none() {
+        return;
+    }
+    match cal_feed::FeedServer::bind(config) {
+        Ok(server) => {
+            output_mp_calendar_message(format!("Serving calendar feeds at {}", server.url()));
+            server.serve_forever();
+        },
+        Err(e) => output_mp_calendar_message(format!("Could not start calendar feeds: {}", e))
+    }
+}
+
+/// Starts delivering alarms as described by alarm_state, checking every ALARM_TICK_SECONDS on a
+/// thread of its own, which runs until the process is stopped. Returns None if there's no
+/// alarm_state, as alarms delivered without one would go off again after a restart.
+pub fn start_configured_alarms(config: &mp_core::core_config::Config) -> Option<std::thread::JoinHandle<()>> {
+    let state_path = config.alarm_state.clone()?;
+    let config = config.clone();
+    let handle = std::thread::spawn(move || {
+        let mut scheduler = cal_alarm::AlarmScheduler::new(state_path);
+        loop {
+            tick_configured_alarms(&config, &mut scheduler, Utc::now().with_timezone(&FixedOffset::east(0)));
+            std::thread::sleep(std::time::Duration::from_secs(ALARM_TICK_SECONDS));
         }
-        return mp_events;
+    });
+    return Some(handle);
+}
+
+/// Outputs a Calendar message for each alarm of the configured calendars (or the event store, if
+/// there is one) that has come due since the scheduler last checked, and returns them
+pub fn tick_configured_alarms(config: &mp_core::core_config::Config,
+                              scheduler: &mut cal_alarm::AlarmScheduler,
+                              now: DateTime<FixedOffset>) -> Vec<cal_alarm::FiredAlarm> {
+    let events = match &config.store {
+        Some(_) => load_all_events(config, &cal_tz::configured_local_zone(config.timezone.as_deref())),
+        None => load_configured_calendars_quietly(config)
+    };
+    return scheduler.tick(&events, now);
+}
+
+#[cfg(test)]
+mod configured_alarm_tests {
+    use super::*;
+
+    #[test]
+    fn test_configured_alarms_go_off_once() {
+        let dir = test_support::scratch_dir("configured_alarms");
+        let state_path = dir.join("alarms.toml");
+        let config = mp_core::core_config::Config {
+            alarm_state: Some(state_path.to_string_lossy().into_owned()),
+            ..test_support::test_config(None, vec![mp_core::core_config::CalendarConfig { path: String::from("src/test/test_alarms.ics"), ..Default::default() }])
+        };
+        assert!(start_configured_alarms(&mp_core::core_config::Config { alarm_state: None, ..config.clone() }).is_none());
+        let mut scheduler = cal_alarm::AlarmScheduler::new(config.alarm_state.clone().unwrap());
+        let tick = |scheduler: &mut cal_alarm::AlarmScheduler, now: &str| {
+            return tick_configured_alarms(&config, scheduler, DateTime::parse_from_rfc3339(now).unwrap()).into_iter()
+                .map(|alarm| alarm.body)
+                .collect::<Vec<String>>();
+        };
+        assert!(tick(&mut scheduler, "2024-01-08T08:40:00+00:00").is_empty());
+        assert_eq!(vec!["Reminder: Team standup at Mon  8 Jan 09:00, Room 2"], tick(&mut scheduler, "2024-01-08T08:45:30+00:00"));
+        assert!(tick(&mut scheduler, "2024-01-08T08:46:00+00:00").is_empty());
+        // The state file carries on where the last run stopped
+        let mut restarted = cal_alarm::AlarmScheduler::new(config.alarm_state.clone().unwrap());
+        assert_eq!(2, tick(&mut restarted, "2024-01-08T08:56:00+00:00").len());
+        let _ = std::fs::remove_dir_all(&dir);
     }
+}
 
-    fn convert_ical_time_to_fixed_offset(ical_time: Option<String>, ical_tz: Option<Vec<(String, Vec<String>)>>) -> Option<DateTime<FixedOffset>> {
-        match ical_tz {
-            Some(tz_vec) => {
-                // TODO
-                // do something with timezone offset
-            }
-            None => ()
+/// Runs the full-screen calendar dashboard until it's quit, reporting it if the terminal can't be used
+pub fn run_dashboard(config: &mp_core::core_config::Config) {
+    match cal_tui::run(config) {
+        Ok(()) => (),
+        Err(e) => output_mp_calendar_message(format!("Could not run the calendar dashboard: {}", e))
+    };
+}
+
+/// Turns a phrase such as "lunch with Priya tomorrow 1pm" into an event, outputs what was understood,
+/// then saves it into the event store, or else the first calendar that isn't read-only. Returns
+/// the event if it was saved.
+pub fn add_event_from_phrase(config: &mp_core::core_config::Config, phrase: &str, now: DateTime<FixedOffset>) -> Option<MpEvent> {
+    let local_zone = cal_tz::configured_local_zone(config.timezone.as_deref());
+    let event = match cal_phrase::parse_event_phrase(phrase, now, &local_zone) {
+        Ok(event) => event,
+        Err(e) => {
+            output_mp_calendar_message(format!("Could not add event: {}", e));
+            return None;
         }
-        match ical_time {
-            Some(ical_time) => {
-                let offset = 0; // todo
-                let split_vec: Vec<&str> = ical_time.split("T").collect();
-                let date_str = split_vec[0]; // e.g. 20130802
-                let time_str = split_vec[1]; // e.g. 200000(Z)
-                let (year_str, month_str, day_str) = (&date_str[..4], &date_str[4..6], &date_str[6..8]);
-                let (hr_str, min_str, sec_str) = (&time_str[..2], &time_str[2..4], &time_str[4..6]);
-                let dt_str = format!("{} {} {} {} {} {}", year_str, month_str, day_str, hr_str, min_str, sec_str);
-                let format_str = String::from("%Y %m %d %H %M %S");
-                let time: Result<DateTime<FixedOffset>, _> = FixedOffset::west(offset).datetime_from_str(&dt_str, &format_str);
-                match time {
-                    Ok(time_fo) => return Some(time_fo),
-                    Err(e) => {
-                        super::output_mp_calendar_message(format!("Error converting Ical time to FixedOffset: {}", e.to_string()));
-                        return None
-                    }
-                }
-            }
-            None => return None
+    };
+    output_mp_calendar_message(format!("Adding {}", format_new_event(&event, &local_zone)));
+    match save_new_event(config, &event) {
+        Ok(destination) => {
+            output_mp_calendar_message(format!("Saved to {}", destination));
+            return Some(event);
+        },
+        Err(e) => {
+            output_mp_calendar_message(format!("Could not save event: {}", e));
+            return None;
         }
     }
+}
 
-    /// Converts fixed offset time to string ical format YYYYMMDD'T'HHMMSS
-    pub fn convert_fixed_offset_to_ical_time(fo_time: DateTime<FixedOffset>) -> String {
-        let format = String::from("%Y%m%dT%H%M%S");
-        let ical_str = format!("{}", fo_time.format(&format));
-        return ical_str;
+// Returns where the event went
+fn save_new_event(config: &mp_core::core_config::Config, event: &MpEvent) -> Result<String, MpCalendarError> {
+    match &config.store {
+        Some(store_path) => {
+            let local_zone = cal_tz::configured_local_zone(config.timezone.as_deref());
+            cal_store::MpEventStore::open(store_path, local_zone)?.save_event(event)?;
+            return Ok(format!("event store {}", store_path));
+        },
+        None => ()
+    };
+    let calendar = config.calendars.iter()
+        .find(|calendar| !calendar.read_only && calendar.validate().is_ok())
+        .ok_or(MpCalendarError::NoWritableCalendar)?;
+    cal_io::append_event_to_ics_file(calendar.path.clone(), event.clone())?;
+    return Ok(format!("calendar '{}'", calendar.display_name()));
+}
+
+/// Name, local time, location and recurrence, e.g. "Retro, Fri 23 Oct 15:00-16:00, every other Friday"
+fn format_new_event(event: &MpEvent, local_zone: &MpTimeZone) -> String {
+    let mut description = name_of(event);
+    match (event.start_time.map(|start| local_zone.convert(start)), event.end_time.map(|end| local_zone.convert(end))) {
+        (Some(start), Some(end)) if event.all_day && end.date() > start.date().succ() => {
+            description.push_str(&format!(", {} to {}, all day", start.format("%a %e %b"), end.date().pred().format("%a %e %b")));
+        },
+        (Some(start), _) if event.all_day => description.push_str(&format!(", {}, all day", start.format("%a %e %b"))),
+        (Some(start), Some(end)) if end.date() != start.date() => {
+            description.push_str(&format!(", {}-{}", start.format("%a %e %b %H:%M"), end.format("%a %e %b %H:%M")));
+        },
+        (Some(start), Some(end)) => description.push_str(&format!(", {}-{}", start.format("%a %e %b %H:%M"), end.format("%H:%M"))),
+        (Some(start), None) => description.push_str(&format!(", {}", start.format("%a %e %b %H:%M"))),
+        (None, _) => ()
+    };
+    match &event.location {
+        Some(location) => description.push_str(&format!(" @ {}", location)),
+        None => ()
+    };
+    match &event.rrule {
+        Some(rule) => description.push_str(&format!(", {}", cal_phrase::describe_rule(rule))),
+        None => ()
+    };
+    return description;
+}
+
+#[cfg(test)]
+mod new_event_tests {
+    use super::*;
+
+    #[test]
+    fn test_add_event_from_phrase() {
+        let dir = test_support::scratch_dir("add_event");
+        let writable = dir.join("personal.ics").to_string_lossy().into_owned();
+        std::fs::copy("src/test/test_tasks.ics", &writable).unwrap();
+        let config = test_support::test_config(Some("Europe/London"), vec![
+            mp_core::core_config::CalendarConfig { path: String::from("src/test/test_conflicts.ics"), read_only: true, ..Default::default() },
+            mp_core::core_config::CalendarConfig { path: writable.clone(), name: Some(String::from("Personal")), ..Default::default() }
+        ]);
+        let now = DateTime::parse_from_rfc3339("2026-10-17T09:00:00+00:00").unwrap();
+        let london = MpTimeZone::from_iana_name("Europe/London").unwrap();
+        let before = cal_io::extract_tasks_from_ical(cal_io::parse_file_to_ical_calendar(writable.clone()).unwrap(), &london).unwrap();
+
+        let added = add_event_from_phrase(&config, "lunch with Priya tomorrow 1pm for an hour at the canteen", now).unwrap();
+        assert_eq!("Lunch with Priya, Sun 18 Oct 13:00-14:00 @ the canteen", format_new_event(&added, &london));
+        let saved = cal_io::read_events_from_ics_file(writable.clone(), &london).unwrap();
+        assert_eq!(vec![Some("Launch planning"), Some("Lunch with Priya")], saved.iter().map(|event| event.name()).collect::<Vec<_>>());
+        assert_eq!(added.start_time, saved[1].start_time);
+        // The tasks already in the calendar are kept too
+        let after = cal_io::extract_tasks_from_ical(cal_io::parse_file_to_ical_calendar(writable.clone()).unwrap(), &london).unwrap();
+        assert_eq!(before.len(), after.len());
+
+        let retro = cal_phrase::parse_event_phrase("retro every other Friday", now, &london).unwrap();
+        assert_eq!("Retro, Fri 23 Oct, all day, every other Friday", format_new_event(&retro, &london));
+        let holiday = cal_phrase::parse_event_phrase("holiday 2 november for 3 days", now, &london).unwrap();
+        assert_eq!("Holiday, Mon  2 Nov to Wed  4 Nov, all day", format_new_event(&holiday, &london));
+
+        assert!(add_event_from_phrase(&config, "sometime soon", now).is_none());
+        let read_only = mp_core::core_config::Config { calendars: vec![config.calendars[0].clone()], ..config.clone() };
+        assert!(matches!(save_new_event(&read_only, &added), Err(MpCalendarError::NoWritableCalendar)));
+        let _ = std::fs::remove_dir_all(&dir);
     }
+}
 
-    #[cfg(test)]
-    mod cal_io_tests {
-        use crate::mp_calendar::cal_io::*;
+/// The local day containing now, midnight to midnight in local_zone
+pub fn day_window(now: DateTime<FixedOffset>, local_zone: &MpTimeZone) -> (DateTime<FixedOffset>, DateTime<FixedOffset>) {
+    let today = local_zone.convert(now).date().naive_local();
+    let start = local_zone.from_local_datetime(today.and_hms(0, 0, 0));
+    let end = local_zone.from_local_datetime(today.succ().and_hms(0, 0, 0));
+    return (start, end);
+}
 
-        #[test]
-        fn test_convert_ical_time_to_fixed_offset() {
-            let test_ical_time = Some(String::from("20130802T200000Z"));
-            let test_ical_tz_vec = None;
-            let test_time_fixed_offset = convert_ical_time_to_fixed_offset(test_ical_time, test_ical_tz_vec).unwrap();
-            let expected_time_fixed_offset = DateTime::parse_from_rfc3339(&String::from("2013-08-02T20:00:00-00:00")).unwrap();
-            assert_eq!(expected_time_fixed_offset, test_time_fixed_offset);
-        }
+/// The span the startup agenda covers, in local_zone
+pub fn agenda_window(window: mp_core::core_config::AgendaWindow,
+                     now: DateTime<FixedOffset>,
+                     local_zone: &MpTimeZone) -> (DateTime<FixedOffset>, DateTime<FixedOffset>) {
+    use mp_core::core_config::AgendaWindow;
 
-        #[test]
-        fn test_convert_fixed_offset_to_ical_time() {
-            let time_fixedoff = DateTime::parse_from_rfc3339(&String::from("2013-08-02T20:00:00-00:00")).unwrap();
-            let expected_ical_time = String::from("20130802T200000");
-            let test_ical_time = convert_fixed_offset_to_ical_time(time_fixedoff);
-            assert_eq!(expected_ical_time, test_ical_time);
+    let (today_start, today_end) = day_window(now, local_zone);
+    match window {
+        AgendaWindow::Today => return (today_start, today_end),
+        AgendaWindow::Next24Hours => return (now, now + chrono::Duration::hours(24)),
+        AgendaWindow::RestOfWeek => {
+            let days_left = 6 - local_zone.convert(now).weekday().num_days_from_monday() as i64;
+            let week_end = local_zone.convert(today_end).naive_local() + chrono::Duration::days(days_left);
+            return (today_start, local_zone.from_local_datetime(week_end));
         }
     }
 }
 
-pub mod cal_ops {
-    use super::{MpEvent, DateTime, FixedOffset, EventStatus};
+/// Loads the configured calendars and outputs the agenda for the configured window, followed by
+/// the conflict report for the same window. With an event store configured the calendars are saved
+/// into it first and the agenda covers everything it holds. With an agenda_view configured only
+/// the events matching it are shown. Does nothing if neither calendars nor a store are configured.
+pub fn output_startup_agenda(config: &mp_core::core_config::Config, now: DateTime<FixedOffset>) {
+    if config.calendars.is_empty() && config.store.is_none() {
+        return;
+    }
+    let local_zone = cal_tz::configured_local_zone(config.timezone.as_deref());
+    let (window_start, window_end) = agenda_window(config.agenda_window, now, &local_zone);
+    let mut heading = String::from(agenda_heading(config.agenda_window));
+    let query = match &config.agenda_view {
+        Some(view) => match parse_configured_query(config, view, now) {
+            Ok(query) => {
+                heading.push_str(&format!(" ({})", view));
+                query
+            },
+            Err(e) => {
+                output_mp_calendar_message(format!("Showing every event as the agenda view can't be used: {}", e));
+                cal_query::EventQuery::default()
+            }
+        },
+        None => cal_query::EventQuery::default()
+    };
+    let occurrences = load_matching_occurrences(config, &query, window_start, window_end, &local_zone);
+    output_mp_calendar_message(format_agenda(&occurrences, &heading, now, &local_zone));
+    output_conflict_report(&occurrences, window_start, window_end, &local_zone);
+}
 
-    /// By default .sort() uses partial_cmp, this uses cmp for comparison by total ordering (Ord not PartialOrd)
-    pub fn sort_mpevents_chronologically_by_start(mut events: Vec<MpEvent>) -> Vec<MpEvent> {
-        events.sort_by(|a, b| a.cmp(b));
-        return events;
+/// text with a [[views]] name in place of the whole of it, or of its first word, replaced by that
+/// view's query, so "Office before:+7d" narrows the Office view
+pub fn resolve_view(config: &mp_core::core_config::Config, text: &str) -> String {
+    let text = text.trim();
+    match config.views.iter().find(|view| view.name == text) {
+        Some(view) => return view.query.clone(),
+        None => ()
+    };
+    let (first, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    match config.views.iter().find(|view| view.name == first) {
+        Some(view) => return format!("{} {}", view.query, rest.trim_start()),
+        None => return String::from(text)
     }
+}
 
-    /// Creates a new MPEvent from a series of inputs
-    fn create_new_MPEvent(name: Option<String>,
-                        start_time: Option<DateTime<FixedOffset>>,
-                        end_time: Option<DateTime<FixedOffset>>,
-                        location: Option<String>,
-                        description: Option<String>,
-                        status: Option<EventStatus>) -> MpEvent {
-        return MpEvent{ name, start_time, end_time, location, description, status };
+/// Parses a query, or the query of the view it names, resolving dates in the config's timezone
+pub fn parse_configured_query(config: &mp_core::core_config::Config,
+                              text: &str,
+                              now: DateTime<FixedOffset>) -> Result<cal_query::EventQuery, MpCalendarError> {
+    let local_zone = cal_tz::configured_local_zone(config.timezone.as_deref());
+    return cal_query::EventQuery::parse(&resolve_view(config, text), now, &local_zone);
+}
+
+/// Outputs the agenda of the occurrences matching a query or view. Its after: and before: bound
+/// the agenda, with the configured agenda window filling in any that are missing.
+pub fn output_agenda_for_query(config: &mp_core::core_config::Config, text: &str, now: DateTime<FixedOffset>) {
+    let local_zone = cal_tz::configured_local_zone(config.timezone.as_deref());
+    match parse_configured_query(config, text, now) {
+        Ok(query) => {
+            let (window_start, window_end) = agenda_window(config.agenda_window, now, &local_zone);
+            let occurrences = load_matching_occurrences(config, &query, window_start, window_end, &local_zone);
+            output_mp_calendar_message(format_agenda(&occurrences, &format!("'{}'", text), now, &local_zone));
+        },
+        Err(e) => output_mp_calendar_message(e.to_string())
+    };
+}
+
+/// Outputs the double bookings among the occurrences matching a query or view, in the same window
+/// as output_agenda_for_query
+pub fn output_conflicts_for_query(config: &mp_core::core_config::Config, text: &str, now: DateTime<FixedOffset>) {
+    let local_zone = cal_tz::configured_local_zone(config.timezone.as_deref());
+    match parse_configured_query(config, text, now) {
+        Ok(query) => {
+            let (window_start, window_end) = agenda_window(config.agenda_window, now, &local_zone);
+            let occurrences = load_matching_occurrences(config, &query, window_start, window_end, &local_zone);
+            output_mp_calendar_message(format_conflict_report(&cal_ops::find_conflicts(&occurrences), &local_zone));
+        },
+        Err(e) => output_mp_calendar_message(e.to_string())
+    };
+}
+
+#[cfg(test)]
+mod configured_query_tests {
+    use super::*;
+
+    #[test]
+    fn test_configured_queries_and_views() {
+        let config = mp_core::core_config::parse_config_file_to_struct(String::from("src/test/test_config_calendars.toml")).unwrap();
+        assert_eq!("-status:cancelled location:~Room", resolve_view(&config, "Office"));
+        assert_eq!("office", resolve_view(&config, "office"));
+        assert_eq!("-status:cancelled location:~Room before:+7d", resolve_view(&config, "Office before:+7d"));
+
+        let london = MpTimeZone::from_iana_name("Europe/London").unwrap();
+        let now = DateTime::parse_from_rfc3339("2024-01-08T08:00:00+00:00").unwrap();
+        let (window_start, window_end) = day_window(now, &london);
+        let names = |text: &str| {
+            let query = parse_configured_query(&config, text, now).unwrap();
+            let occurrences = load_matching_occurrences(&config, &query, window_start, window_end, &london);
+            return occurrences.iter().map(|event| event.name().unwrap().to_string()).collect::<Vec<String>>();
+        };
+        assert_eq!(vec!["Vendor call"], names("Office"));
+        assert_eq!(vec!["Cancelled 1:1"], names("status:cancelled"));
+        // after: and before: replace the default window
+        assert_eq!(vec!["Standup", "Standup", "Standup"], names("name:Standup before:2024-01-11"));
+        assert!(parse_configured_query(&config, "Ofice -", now).is_err());
     }
+}
 
-    pub mod cal_ops_tests {
-        use crate::mp_calendar::cal_ops::*;
+/// Writes the events matching a query or view to a file and reports how many there were. The
+/// file's extension picks the format (see cal_export::ExportFormat) and export_fields the columns of
+/// the tabular ones. Without after: or before: whole recurring series are written; with either, the
+/// occurrences in the query's window are.
+pub fn export_events_for_query(config: &mp_core::core_config::Config, text: &str, path: &str, now: DateTime<FixedOffset>) {
+    let local_zone = cal_tz::configured_local_zone(config.timezone.as_deref());
+    let exported = parse_configured_query(config, text, now).and_then(|query| {
+        let fields = cal_export::parse_export_fields(&config.export_fields)?;
+        let mut events = if query.after().is_none() && query.before().is_none() {
+            cal_ops::filter_mpevents(&load_all_events(config, &local_zone), &query)
+        } else {
+            let (window_start, window_end) = agenda_window(config.agenda_window, now, &local_zone);
+            load_matching_occurrences(config, &query, window_start, window_end, &local_zone)
+        };
+        events.sort();
+        let title = if text.trim().is_empty() { String::from("Events") } else { format!("Events: {}", text.trim()) };
+        let exporter = cal_export::ExportFormat::from_path(path).exporter(&title);
+        std::fs::write(path, exporter.export(&events, &fields, &local_zone))
+            .map_err(|e| MpCalendarError::Io { path: String::from(path), source: e })?;
+        return Ok(events.len());
+    });
+    match exported {
+        Ok(count) => {
+            let plural = if count == 1 { "" } else { "s" };
+            output_mp_calendar_message(format!("Exported {} event{} to {}", count, plural, path));
+        },
+        Err(e) => output_mp_calendar_message(format!("Could not export '{}': {}", text, e))
+    };
+}
 
-        #[test]
-        pub fn test_sort_mpevents_chronologically_by_start() {
-            let time_1 = DateTime::parse_from_rfc3339(&String::from("2013-08-02T20:00:00-00:00")).unwrap();
-            let time_2 = DateTime::parse_from_rfc3339(&String::from("2013-08-03T20:00:00-00:00")).unwrap();
-            let time_3 = DateTime::parse_from_rfc3339(&String::from("2013-08-03T22:00:00-00:00")).unwrap();
-            let time_4 = DateTime::parse_from_rfc3339(&String::from("2013-08-04T22:00:00-00:00")).unwrap();
-            let event_1 = MpEvent {name: Some(String::from("Event1")),  start_time: Some(time_1), end_time: None, description: None, location: None, status: None };
-            let event_2 = MpEvent {name: Some(String::from("Event2")),  start_time: Some(time_2), end_time: Some(time_3), description: None, location: None, status: None };
-            let event_3 = MpEvent {name: Some(String::from("Event3")),  start_time: Some(time_2), end_time: Some(time_4), description: None, location: None, status: None };
-            let event_4 = MpEvent {name: Some(String::from("Event4")),  start_time: Some(time_3), end_time: None, description: None, location: None, status: None };
-            let mut unsorted_events: Vec<MpEvent> = vec!(event_3.clone(), event_2.clone(), event_4.clone(), event_1.clone());
-            let exp_sorted_events: Vec<MpEvent> = vec!(event_1, event_2, event_3, event_4);
-            let sorted_events = sort_mpevents_chronologically_by_start(unsorted_events);
-            assert_eq!(exp_sorted_events, sorted_events);
+#[cfg(test)]
+mod configured_export_tests {
+    use super::*;
+
+    #[test]
+    fn test_export_events_for_query() {
+        let config = mp_core::core_config::parse_config_file_to_struct(String::from("src/test/test_config_calendars.toml")).unwrap();
+        let london = MpTimeZone::from_iana_name("Europe/London").unwrap();
+        let now = DateTime::parse_from_rfc3339("2024-01-08T08:00:00+00:00").unwrap();
+        let dir = test_support::scratch_dir("export");
+        let path = dir.join("standups.ics").to_string_lossy().to_string();
+        let exported = |text: &str| {
+            export_events_for_query(&config, text, &path, now);
+            let events = cal_io::read_events_from_ics_file(path.clone(), &london).unwrap();
+            return events.iter().map(|event| (event.name().unwrap().to_string(), event.is_recurring())).collect::<Vec<(String, bool)>>();
+        };
+        // Whole series without bounds, occurrences with them
+        assert_eq!(vec![(String::from("Standup"), true)], exported("name:Standup"));
+        assert_eq!(vec![(String::from("Standup"), false)], exported("name:Standup after:2024-01-09 before:2024-01-10"));
+        // The same selection in another format, with the configured columns
+        let csv_path = dir.join("standups.csv").to_string_lossy().to_string();
+        export_events_for_query(&config, "name:Standup after:2024-01-09 before:2024-01-10", &csv_path, now);
+        assert_eq!("start,name,location\r\n2024-01-09 09:00,Standup,\r\n", std::fs::read_to_string(&csv_path).unwrap());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
+
+/// Which of the text grids output_calendar_grid draws
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum GridView {
+    Week,
+    Month
+}
+
+/// Outputs the week or month containing the query's after: (or else now) as a text grid of the
+/// configured calendars, each in its configured colour, showing only the occurrences matching the
+/// query or view. Events only in the event store aren't drawn.
+pub fn output_calendar_grid(config: &mp_core::core_config::Config,
+                            view: GridView,
+                            text: &str,
+                            now: DateTime<FixedOffset>,
+                            use_colour: bool) {
+    let local_zone = cal_tz::configured_local_zone(config.timezone.as_deref());
+    let query = match parse_configured_query(config, text, now) {
+        Ok(query) => query,
+        Err(e) => {
+            output_mp_calendar_message(e.to_string());
+            return;
+        }
+    };
+    let date = local_zone.convert(query.after().unwrap_or(now)).date().naive_local();
+    let days = match view {
+        GridView::Week => cal_ops::week_grid_days(date),
+        GridView::Month => cal_ops::month_grid_days(date)
+    };
+    let (window_start, _) = cal_ops::grid_day_bounds(days[0], &local_zone);
+    let (_, window_end) = cal_ops::grid_day_bounds(days[days.len() - 1], &local_zone);
+    let mut calendars: Vec<cal_ops::GridCalendar> = vec![];
+    for calendar in &config.calendars {
+        if calendar.validate().is_err() {
+            continue;
+        }
+        match load_configured_calendar(config, calendar) {
+            Ok(events) => calendars.push(cal_ops::GridCalendar {
+                events: cal_ops::query_mpevents(events, &query, window_start, window_end),
+                colour: calendar.colour.clone()
+            }),
+            Err(e) => output_load_warning(format!("Could not load calendar '{}': {}", calendar.display_name(), e))
+        };
+    }
+    let grid = match view {
+        GridView::Week => cal_ops::render_week_view(&calendars, date, &local_zone, use_colour),
+        GridView::Month => cal_ops::render_month_view(&calendars, date, &local_zone, use_colour)
+    };
+    output_mp_calendar_message(grid);
+}
+
+/// The configured calendars' events, or with an event store configured, everything in it once
+/// it's been synced with them
+fn load_all_events(config: &mp_core::core_config::Config, local_zone: &MpTimeZone) -> Vec<MpEvent> {
+    match &config.store {
+        Some(store_path) => match open_and_sync_store(config, store_path, local_zone).and_then(|store| store.all_events()) {
+            Ok(stored) => return stored,
+            Err(e) => {
+                output_mp_calendar_message(format!("Could not use event store: {}", e));
+                return load_configured_calendars(config);
+            }
+        },
+        None => return load_configured_calendars(config)
+    }
+}
+
+/// The occurrences matching query in its window, taken from the event store if one is configured
+/// (after syncing it with the calendars) or else straight from the calendars
+fn load_matching_occurrences(config: &mp_core::core_config::Config,
+                             query: &cal_query::EventQuery,
+                             default_start: DateTime<FixedOffset>,
+                             default_end: DateTime<FixedOffset>,
+                             local_zone: &MpTimeZone) -> Vec<MpEvent> {
+    match &config.store {
+        Some(store_path) => match open_and_sync_store(config, store_path, local_zone)
+            .and_then(|store| store.query(query, default_start, default_end)) {
+            Ok(occurrences) => return occurrences,
+            Err(e) => {
+                output_mp_calendar_message(format!("Could not use event store: {}", e));
+                return cal_ops::query_mpevents(load_configured_calendars(config), query, default_start, default_end);
+            }
+        },
+        None => return cal_ops::query_mpevents(load_configured_calendars(config), query, default_start, default_end)
+    }
+}
+
+/// Opens the event store and syncs it with each configured calendar, so events deleted from a
+/// calendar file are deleted from the store. A calendar that can't be read is left as it was stored.
+/// The calendars are merged as load_configured_calendars merges them, so an event in more than one
+/// is stored once, as its newest revision, whichever calendar that's in.
+fn open_and_sync_store(config: &mp_core::core_config::Config,
+                       store_path: &str,
+                       local_zone: &MpTimeZone) -> Result<cal_store::MpEventStore, MpCalendarError> {
+    let mut store = cal_store::MpEventStore::open(store_path, local_zone.clone())?;
+    let mut paths: Vec<&str> = vec![];
+    let mut sources: Vec<Vec<MpEvent>> = vec![];
+    for calendar in &config.calendars {
+        if calendar.validate().is_err() {
+            continue;
+        }
+        match load_configured_calendar(config, calendar) {
+            Ok(events) => {
+                paths.push(&calendar.path);
+                sources.push(events);
+            },
+            Err(e) => output_load_warning(format!("Could not load calendar '{}': {}", calendar.display_name(), e))
+        };
+    }
+    for (path, events) in paths.into_iter().zip(cal_merge::merge_by_source(sources)) {
+        store.sync_source(path, &events)?;
+    }
+    return Ok(store);
+}
+
+#[cfg(test)]
+mod configured_store_tests {
+    use super::*;
+
+    #[test]
+    fn test_store_follows_events_deleted_from_calendars() {
+        let dir = test_support::scratch_dir("store_follow");
+        let calendar = dir.join("work.ics").to_string_lossy().into_owned();
+        std::fs::copy("src/test/test_conflicts.ics", &calendar).unwrap();
+        let config = mp_core::core_config::Config {
+            store: Some(dir.join("store.sqlite").to_string_lossy().into_owned()),
+            ..test_support::test_config(None, vec![mp_core::core_config::CalendarConfig { path: calendar.clone(), ..Default::default() }])
+        };
+        let uids = || load_all_events(&config, &MpTimeZone::utc()).into_iter().filter_map(|event| event.uid).collect::<Vec<String>>();
+        let before = uids();
+        assert!(before.contains(&String::from("conflict-interview@example.com")));
+
+        cal_io::update_events_in_ics_file(calendar, &[], &[String::from("conflict-interview@example.com")]).unwrap();
+        let after = uids();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(before.len() - 1, after.len());
+        assert!(!after.contains(&String::from("conflict-interview@example.com")));
+    }
+
+    #[test]
+    fn test_store_keeps_the_newest_revision_across_calendars() {
+        let dir = test_support::scratch_dir("store_merge");
+        let calendar = |path: &str| mp_core::core_config::CalendarConfig { path: String::from(path), ..Default::default() };
+        let config = mp_core::core_config::Config {
+            store: Some(dir.join("store.sqlite").to_string_lossy().into_owned()),
+            ..test_support::test_config(None, vec![calendar("src/test/test_merge_personal.ics"), calendar("src/test/test_merge_work.ics")])
+        };
+        // Synced twice, so the second sync starts from what the first stored
+        load_all_events(&config, &MpTimeZone::utc());
+        let events = load_all_events(&config, &MpTimeZone::utc());
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut names: Vec<&str> = events.iter().map(|event| event.name().unwrap()).collect();
+        names.sort_unstable();
+        // Lunch is in both under different UIDs, and stored once
+        assert_eq!(vec!["Dentist", "Design review", "Standup", "lunch with  Sam!"], names);
+        let review = events.iter().find(|event| event.uid() == Some("merge-review@example.com")).unwrap();
+        // The rescheduled review is in the first calendar, synced before the second's older copy
+        assert_eq!(Some(2), review.sequence());
+    }
+}
+
+/// How the agenda heading describes each window
+fn agenda_heading(window: mp_core::core_config::AgendaWindow) -> &'static str {
+    use mp_core::core_config::AgendaWindow;
+
+    match window {
+        AgendaWindow::Today => return "today",
+        AgendaWindow::Next24Hours => return "the next 24 hours",
+        AgendaWindow::RestOfWeek => return "the rest of the week"
+    }
+}
+
+/// The event's name, or "Untitled event" if it hasn't got one
+fn name_of(event: &MpEvent) -> String {
+    return event.name.clone().unwrap_or_else(|| String::from("Untitled event"));
+}
+
+/// Lists expanded, sorted occurrences under a heading for each local day, with local times and
+/// locations. The next event to start after now is marked with '>' and double bookings are flagged.
+fn format_agenda(occurrences: &[MpEvent],
+                 heading: &str,
+                 now: DateTime<FixedOffset>,
+                 local_zone: &MpTimeZone) -> String {
+    let conflicts = cal_ops::find_conflicts(occurrences);
+    // Each with its index in occurrences, which is how the conflicts refer to it
+    let occurrences: Vec<(usize, &MpEvent)> = occurrences.iter()
+        .enumerate()
+        .filter(|(_, event)| event.status != Some(EventStatus::Cancelled))
+        .collect();
+    if occurrences.is_empty() {
+        return format!("Nothing in your calendars for {}", heading);
+    }
+    let next_index = occurrences.iter().position(|(_, event)| event.start_time.is_some_and(|start| start >= now));
+
+    let mut agenda = format!("Agenda for {}:", heading);
+    let mut current_day: Option<NaiveDate> = None;
+    for (index, (source, event)) in occurrences.iter().enumerate() {
+        // Anything without a start can't have been placed in the window
+        let start = local_zone.convert(event.start_time.unwrap());
+        let day = start.date().naive_local();
+        if current_day != Some(day) {
+            agenda.push_str(&format!("\n  {}", start.format("%a %e %b")));
+            current_day = Some(day);
+        }
+        let times = match event.end_time.map(|end| local_zone.convert(end)) {
+            _ if event.all_day => String::from("All day"),
+            Some(end) if end.date() != start.date() => format!("{}-{}", start.format("%H:%M"), end.format("%a %H:%M")),
+            Some(end) => format!("{}-{}", start.format("%H:%M"), end.format("%H:%M")),
+            None => start.format("%H:%M").to_string()
+        };
+        let marker = if next_index == Some(index) { ">" } else { " " };
+        agenda.push_str(&format!("\n    {} {:<11}  {}", marker, times, name_of(event)));
+        match &event.location {
+            Some(location) => agenda.push_str(&format!(" @ {}", location)),
+            None => ()
+        };
+        let clashes: Vec<String> = conflicts.iter()
+            .filter_map(|conflict| Some((conflict, conflict.indices.iter().position(|index| index == source)?)))
+            .flat_map(|(conflict, member)| conflict.overlaps.iter()
+                .filter_map(|(i, j, _)| match (*i == member, *j == member) {
+                    (true, false) => Some(name_of(&conflict.events[*j])),
+                    (false, true) => Some(name_of(&conflict.events[*i])),
+                    _ => None
+                })
+                .collect::<Vec<String>>())
+            .collect();
+        if !clashes.is_empty() {
+            agenda.push_str(&format!("  [conflicts with {}]", clashes.join(", ")));
+        }
+    }
+    return agenda;
+}
+
+#[cfg(test)]
+mod agenda_tests {
+    use super::*;
+    use super::calendar_mpevent_tests::make_event;
+
+    #[test]
+    fn test_agenda_window() {
+        use mp_core::core_config::AgendaWindow;
+        let london = MpTimeZone::from_iana_name("Europe/London").unwrap();
+        let now = DateTime::parse_from_rfc3339("2024-03-27T08:30:00+00:00").unwrap();
+        let window = |kind: AgendaWindow| {
+            let (start, end) = agenda_window(kind, now, &london);
+            return (start.to_rfc3339(), end.to_rfc3339());
+        };
+        assert_eq!((String::from("2024-03-27T00:00:00+00:00"), String::from("2024-03-28T00:00:00+00:00")), window(AgendaWindow::Today));
+        assert_eq!((String::from("2024-03-27T08:30:00+00:00"), String::from("2024-03-28T08:30:00+00:00")), window(AgendaWindow::Next24Hours));
+        // The week ends at midnight on Sunday night, after the clocks have gone forward
+        assert_eq!((String::from("2024-03-27T00:00:00+00:00"), String::from("2024-04-01T00:00:00+01:00")), window(AgendaWindow::RestOfWeek));
+    }
+
+    #[test]
+    fn test_format_agenda() {
+        use mp_core::core_config::AgendaWindow;
+        let events = cal_io::read_events_from_ics_file(String::from("src/test/test_conflicts.ics"), &MpTimeZone::utc()).unwrap();
+        let london = MpTimeZone::from_iana_name("Europe/London").unwrap();
+        let now = DateTime::parse_from_rfc3339("2024-01-08T09:20:00+00:00").unwrap();
+        let (window_start, window_end) = agenda_window(AgendaWindow::RestOfWeek, now, &london);
+        let occurrences = cal_ops::expand_mpevents_in_window(events, window_start, window_end);
+        let agenda = format_agenda(&occurrences, agenda_heading(AgendaWindow::RestOfWeek), now, &london);
+        let expected = "Agenda for the rest of the week:\n  \
+                        Mon  8 Jan\n      \
+                        09:00-09:30  Standup  [conflicts with Design review]\n      \
+                        09:15-10:15  Design review  [conflicts with Standup, Vendor call]\n    \
+                        > 10:00-11:00  Vendor call @ Room 2  [conflicts with Design review]\n      \
+                        12:00-13:00  Lunch\n      \
+                        13:00-13:30  Walk\n  \
+                        Tue  9 Jan\n      \
+                        09:00-09:30  Standup\n      \
+                        09:00-12:00  Focus block\n  \
+                        Wed 10 Jan\n      \
+                        09:00-09:30  Planning\n      \
+                        14:00-14:30  Standup  [conflicts with Interview]\n      \
+                        14:00-15:00  Interview  [conflicts with Standup]";
+        assert_eq!(expected, agenda);
+        assert_eq!("Nothing in your calendars for today", format_agenda(&[], agenda_heading(AgendaWindow::Today), now, &london));
+
+        // Untitled events, and events with the same name and start, are told apart by position
+        let named = |name: &str, event: MpEvent| MpEvent { name: Some(String::from(name)), ..event };
+        let occurrences = vec![named("Standup", make_event(0, 1800)), named("Standup", make_event(0, 3600)), make_event(7200, 10800), make_event(9000, 10800)];
+        let agenda = format_agenda(&occurrences, agenda_heading(AgendaWindow::Today), now, &MpTimeZone::utc());
+        let expected = "Agenda for today:\n  \
+                        Thu  1 Jan\n      \
+                        00:00-00:30  Standup  [conflicts with Standup]\n      \
+                        00:00-01:00  Standup  [conflicts with Standup]\n      \
+                        02:00-03:00  Untitled event  [conflicts with Untitled event]\n      \
+                        02:30-03:00  Untitled event  [conflicts with Untitled event]";
+        assert_eq!(expected, agenda);
+    }
+}
+
+/// Outputs the double bookings among events in [window_start, window_end) as a Calendar message
+pub fn output_conflict_report(events: &[MpEvent],
+                              window_start: DateTime<FixedOffset>,
+                              window_end: DateTime<FixedOffset>,
+                              local_zone: &MpTimeZone) {
+    let conflicts = cal_ops::find_conflicts_in_window(events, window_start, window_end);
+    output_mp_calendar_message(format_conflict_report(&conflicts, local_zone));
+}
+
+/// One line per conflict group giving when it starts and what's in it, followed by an indented
+/// line for each overlapping pair
+fn format_conflict_report(conflicts: &[cal_ops::EventConflict], local_zone: &MpTimeZone) -> String {
+    if conflicts.is_empty() {
+        return String::from("No double bookings");
+    }
+    let plural = if conflicts.len() == 1 { "" } else { "s" };
+    let mut report = format!("{} double booking{}:", conflicts.len(), plural);
+    for conflict in conflicts {
+        // Every event in a conflict has a start time
+        let start = local_zone.convert(conflict.events[0].start_time.unwrap());
+        let names: Vec<String> = conflict.events.iter().map(name_of).collect();
+        report.push_str(&format!("\n  {}: {}", start.format("%a %e %b %H:%M"), names.join(", ")));
+        for (i, j, overlap) in &conflict.overlaps {
+            report.push_str(&format!("\n    {} and {} overlap by {}",
+                                     name_of(&conflict.events[*i]), name_of(&conflict.events[*j]), format_duration(*overlap)));
+        }
+    }
+    return report;
+}
+
+#[cfg(test)]
+mod conflict_report_tests {
+    use super::*;
+    use super::calendar_mpevent_tests::make_event;
+
+    #[test]
+    fn test_format_conflict_report() {
+        let conflicts = cal_ops::find_conflicts(&[make_event(0, 5400), make_event(1800, 3600), make_event(3600, 7200)]);
+        let report = format_conflict_report(&conflicts, &MpTimeZone::utc());
+        let expected = "1 double booking:\n  Thu  1 Jan 00:00: Untitled event, Untitled event, Untitled event\n    \
+                        Untitled event and Untitled event overlap by 30m\n    \
+                        Untitled event and Untitled event overlap by 30m";
+        assert_eq!(expected, report);
+        assert_eq!("No double bookings", format_conflict_report(&[], &MpTimeZone::utc()));
+        assert_eq!("1h 15m", format_duration(chrono::Duration::minutes(75)));
+        assert_eq!("2h", format_duration(chrono::Duration::hours(2)));
+    }
+}
+
+/// Counts of what merging a calendar did, then a line for each duplicate saying which copy was
+/// kept and how the two were matched
+fn format_merge_report(calendar_name: &str, report: &cal_merge::MergeReport, local_zone: &MpTimeZone) -> String {
+    let plural = if report.dropped.len() == 1 { "" } else { "s" };
+    let mut summary = format!("Merged '{}': {} added, {} updated, {} duplicate{} dropped",
+                              calendar_name, report.added.len(), report.updated.len(), report.dropped.len(), plural);
+    let describe = |event: &MpEvent| match event.start_time {
+        Some(start) => format!("{} ({})", name_of(event), local_zone.convert(start).format("%a %e %b %H:%M")),
+        None => name_of(event)
+    };
+    let matched_by = |pair: &cal_merge::MergedPair| match pair.matched_by {
+        cal_merge::MergeMatch::Uid => "same UID",
+        cal_merge::MergeMatch::NameAndStart => "same name and start"
+    };
+    for pair in &report.updated {
+        summary.push_str(&format!("\n  Updated {} to the newer {}, {}", describe(&pair.discarded), describe(&pair.kept), matched_by(pair)));
+    }
+    for pair in &report.dropped {
+        summary.push_str(&format!("\n  Dropped {}, a copy of {}, {}", describe(&pair.discarded), describe(&pair.kept), matched_by(pair)));
+    }
+    return summary;
+}
+
+#[cfg(test)]
+mod merge_report_tests {
+    use super::*;
+
+    #[test]
+    fn test_format_merge_report() {
+        let read = |path: &str| cal_io::read_events_from_ics_file(String::from(path), &MpTimeZone::utc()).unwrap();
+        let (_, report) = cal_merge::merge_events(read("src/test/test_merge_work.ics"), read("src/test/test_merge_personal.ics"));
+        let expected = "Merged 'Personal': 1 added, 1 updated, 2 duplicates dropped\n  \
+                        Updated Design review (Mon 11 Mar 14:00) to the newer Design review (Mon 11 Mar 15:00), same UID\n  \
+                        Dropped Standup (Mon 11 Mar 09:00), a copy of Standup (Mon 11 Mar 09:00), same UID\n  \
+                        Dropped lunch with  Sam! (Tue 12 Mar 12:00), a copy of Lunch with Sam (Tue 12 Mar 12:00), same name and start";
+        assert_eq!(expected, format_merge_report("Personal", &report, &MpTimeZone::utc()));
+    }
+}
+
+/// Outputs what changed between two snapshots of a calendar as a Calendar message
+pub fn output_calendar_diff(old: &[MpEvent], new: &[MpEvent], local_zone: &MpTimeZone) {
+    let changes = cal_ops::diff_calendars(old, new);
+    output_mp_calendar_message(format_calendar_diff(&changes, local_zone));
+}
+
+/// One line per change, in local times
+fn format_calendar_diff(changes: &[cal_ops::EventChange], local_zone: &MpTimeZone) -> String {
+    use cal_ops::EventChange;
+
+    if changes.is_empty() {
+        return String::from("No calendar changes");
+    }
+    let plural = if changes.len() == 1 { "" } else { "s" };
+    let mut report = format!("{} calendar change{}:", changes.len(), plural);
+    let times_of = |event: &MpEvent| {
+        let start = match event.start_time {
+            Some(start) => local_zone.convert(start),
+            None => return String::from("no time")
+        };
+        let mut times = match event.end_time.map(|end| local_zone.convert(end)) {
+            _ if event.all_day => start.format("%a %e %b, all day").to_string(),
+            Some(end) if end.date() != start.date() => format!("{}-{}", start.format("%a %e %b %H:%M"), end.format("%a %e %b %H:%M")),
+            Some(end) => format!("{}-{}", start.format("%a %e %b %H:%M"), end.format("%H:%M")),
+            None => start.format("%a %e %b %H:%M").to_string()
+        };
+        match &event.rrule {
+            Some(rule) => times.push_str(&format!(", repeating {}", rule)),
+            None => ()
+        };
+        return times;
+    };
+    let location_of = |event: &MpEvent| event.location.clone().unwrap_or_else(|| String::from("no location"));
+    let status_of = |event: &MpEvent| match event.status {
+        Some(status) => status.to_ical_str().to_lowercase(),
+        None => String::from("no status")
+    };
+    for change in changes {
+        let line = match change {
+            EventChange::Added(event) => format!("Added {}, {}", name_of(event), times_of(event)),
+            EventChange::Removed(event) => format!("Removed {}, {}", name_of(event), times_of(event)),
+            EventChange::Rescheduled { before, after } => {
+                format!("Moved {} from {} to {}", name_of(after), times_of(before), times_of(after))
+            },
+            EventChange::Relocated { before, after } => {
+                format!("{} ({}) moved from {} to {}", name_of(after), times_of(after), location_of(before), location_of(after))
+            },
+            EventChange::StatusChanged { before, after } => {
+                format!("{} ({}) is now {}, was {}", name_of(after), times_of(after), status_of(after), status_of(before))
+            }
+        };
+        report.push_str(&format!("\n  {}", line));
+    }
+    return report;
+}
+
+#[cfg(test)]
+mod calendar_diff_tests {
+    use super::*;
+
+    #[test]
+    fn test_format_calendar_diff() {
+        let read = |path: &str| cal_io::read_events_from_ics_file(String::from(path), &MpTimeZone::utc()).unwrap();
+        let changes = cal_ops::diff_calendars(&read("src/test/test_diff_before.ics"), &read("src/test/test_diff_after.ics"));
+        let expected = "6 calendar changes:\n  \
+                        Moved Design review from Mon 11 Mar 14:00-15:00 to Mon 11 Mar 15:00-16:00\n  \
+                        Design review (Mon 11 Mar 15:00-16:00) moved from Room 2 to Room 4\n  \
+                        Removed Lunch, Tue 12 Mar 12:00-13:00\n  \
+                        Added Dentist, Wed 13 Mar 08:30-09:00\n  \
+                        Moved Standup from Wed 13 Mar 09:00-09:15 to Wed 13 Mar 09:30-09:45\n  \
+                        Vendor call (Wed 13 Mar 10:00-11:00) is now cancelled, was confirmed";
+        assert_eq!(expected, format_calendar_diff(&changes, &MpTimeZone::utc()));
+        assert_eq!("No calendar changes", format_calendar_diff(&[], &MpTimeZone::utc()));
+    }
+}
+
+/// Hours and minutes, e.g. "1h 30m", "45m" or "2h"
+fn format_duration(duration: chrono::Duration) -> String {
+    let minutes = duration.num_minutes();
+    match (minutes / 60, minutes % 60) {
+        (0, mins) => return format!("{}m", mins),
+        (hours, 0) => return format!("{}h", hours),
+        (hours, mins) => return format!("{}h {}m", hours, mins)
+    }
+}
+
+pub mod cal_io {
+    use ical::parser::ical::component::IcalCalendar;
+    use ical::parser::ParserError;
+    use ical::property::{Property, PropertyError};
+    use std::io::prelude::*;
+    use std::fs::File;
+    use super::{DateTime, NaiveDate, NaiveDateTime, FixedOffset, TimeZone, Utc, Datelike}; // Chrono imports
+    use super::{MpEvent, MpProperty, MpCalendarError, EventStatus, RecurrenceRule, MpTimeZone, FromStr}; // MP imports
+    use super::cal_alarm::{MpAlarm, AlarmAction, AlarmTrigger, parse_ical_duration, format_ical_duration};
+    use super::cal_task::{MpTask, TaskStatus};
+    use super::cal_ops::{BusyPeriod, FreeBusyType};
+    use super::cal_tz::{TimeZoneContext, format_utc_offset};
+
+    pub fn parse_file_to_ical_calendar(path: String) -> Result<IcalCalendar, MpCalendarError> {
+        use std::io::BufReader;
+
+        let file = File::open(&path).map_err(|e| MpCalendarError::Io { path: path.clone(), source: e })?;
+        return parse_ical_calendar(BufReader::new(file), path);
+    }
+
+    /// As parse_file_to_ical_calendar for ICS already in memory. source names it in errors.
+    pub fn parse_str_to_ical_calendar(ics: &str, source: String) -> Result<IcalCalendar, MpCalendarError> {
+        return parse_ical_calendar(ics.as_bytes(), source);
+    }
+
+    fn parse_ical_calendar<B: BufRead>(buf: B, path: String) -> Result<IcalCalendar, MpCalendarError> {
+        let mut reader = ical::IcalParser::new(buf);
+
+        let cal_option = reader.next();
+        match cal_option {
+            Some(Ok(cal)) => return Ok(cal),
+            Some(Err(e)) => {
+                let line = match &e {
+                    ParserError::PropertyError(prop_error) => property_error_line(prop_error),
+                    _ => None
+                };
+                return Err(MpCalendarError::Parse { path, line, message: e.to_string() });
+            }
+            None => {
+                return Err(MpCalendarError::Parse { path, line: None, message: String::from("no VCALENDAR found") });
+            }
+        }
+    }
+
+    /// Parses a file and extracts its events in one go, floating times read in local_zone
+    pub fn read_events_from_ics_file(path: String, local_zone: &MpTimeZone) -> Result<Vec<MpEvent>, MpCalendarError> {
+        let cal = parse_file_to_ical_calendar(path)?;
+        return extract_events_from_ical(cal, local_zone);
+    }
+
+    pub fn read_events_from_ics_string(ics: &str, source: String, local_zone: &MpTimeZone) -> Result<Vec<MpEvent>, MpCalendarError> {
+        let cal = parse_str_to_ical_calendar(ics, source)?;
+        return extract_events_from_ical(cal, local_zone);
+    }
+
+    fn property_error_line(prop_error: &PropertyError) -> Option<usize> {
+        match prop_error {
+            PropertyError::MissingName { line } => Some(*line),
+            PropertyError::MissingClosingQuote { line } => Some(*line),
+            PropertyError::MissingDelimiter { line, .. } => Some(*line),
+            PropertyError::MissingContentAfter { line, .. } => Some(*line),
+            PropertyError::MissingParamKey { line } => Some(*line)
+        }
+    }
+
+    /// This will create a new file to write to, or COMPLETELY OVERWRITE an existing one. Use
+    /// update_events_in_ics_file to change some of the events in an existing file.
+    pub fn deserialise_mpevents_to_ics_file(write_path: String, events: Vec<MpEvent>) -> Result<(), MpCalendarError> {
+        return deserialise_calendar_to_ics_file(write_path, events, vec![]);
+    }
+
+    /// Adds event to the calendar file at path, before its END:VCALENDAR. The file is created if it
+    /// doesn't exist yet.
+    pub fn append_event_to_ics_file(path: String, event: MpEvent) -> Result<(), MpCalendarError> {
+        return update_events_in_ics_file(path, &[event], &[]);
+    }
+
+    /// Edits the calendar file at path in place: each of saved replaces the VEVENTs sharing its UID
+    /// (its overrides included), or is added before END:VCALENDAR if there are none, and the VEVENTs
+    /// with a UID in removed are taken out. Every other line is kept exactly as it was, so calendar
+    /// properties such as X-WR-CALNAME, components moneypenny doesn't model like VJOURNAL, and the
+    /// file's own VTIMEZONEs survive. Only zones the file doesn't already define are added. The file
+    /// is created if it doesn't exist yet.
+    pub fn update_events_in_ics_file(path: String, saved: &[MpEvent], removed: &[String]) -> Result<(), MpCalendarError> {
+        let existing = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return deserialise_mpevents_to_ics_file(path, saved.to_vec()),
+            Err(e) => return Err(MpCalendarError::Io { path, source: e })
+        };
+        let now = FixedOffset::east(0).from_utc_datetime(&Utc::now().naive_utc());
+        let uid_of = |event: &MpEvent| event.uid.clone().unwrap_or_else(|| generate_uid(event));
+        let saved_uids: Vec<String> = saved.iter().map(uid_of).collect();
+        let mut written: Vec<bool> = vec![false; saved.len()];
+        let lines = split_content_lines(&existing);
+        let declared: Vec<String> = lines.iter()
+            .filter_map(|(_, content)| content.strip_prefix("TZID:").map(String::from))
+            .collect();
+
+        let mut ics = String::with_capacity(existing.len());
+        let mut depth = 0;
+        let mut closed = false;
+        let mut index = 0;
+        while index < lines.len() {
+            let (raw, content) = &lines[index];
+            if depth == 1 && content.eq_ignore_ascii_case("BEGIN:VEVENT") {
+                let end = lines[index..].iter()
+                    .position(|(_, content)| content.eq_ignore_ascii_case("END:VEVENT"))
+                    .map(|offset| index + offset)
+                    .ok_or_else(|| MpCalendarError::Parse { path: path.clone(), line: None, message: String::from("VEVENT has no END:VEVENT") })?;
+                let uid = lines[index..end].iter().find_map(|(_, content)| content_line_value(content, "UID"));
+                match uid.and_then(|uid| if removed.contains(&uid) { Some(None) } else { saved_uids.iter().position(|saved| *saved == uid).map(Some) }) {
+                    // Removed, or replaced by a saved event that's already been written in its place
+                    Some(None) => (),
+                    Some(Some(position)) if written[position] => (),
+                    Some(Some(position)) => {
+                        push_vevent_with_overrides(&mut ics, &saved[position], &saved_uids[position], now);
+                        written[position] = true;
+                    },
+                    None => lines[index..=end].iter().for_each(|(raw, _)| ics.push_str(raw))
+                };
+                index = end + 1;
+                continue;
+            }
+            if depth == 1 && content.eq_ignore_ascii_case("END:VCALENDAR") {
+                let new_zones = zones_to_declare(saved, &[], now.year()).into_iter()
+                    .filter(|(zone, _, _)| !declared.iter().any(|tzid| tzid == zone.tzid()));
+                for (zone, from_year, to_year) in new_zones {
+                    push_vtimezone(&mut ics, &zone, from_year, to_year);
+                }
+                for (position, event) in saved.iter().enumerate().filter(|(position, _)| !written[*position]) {
+                    push_vevent_with_overrides(&mut ics, event, &saved_uids[position], now);
+                }
+                closed = true;
+            }
+            if content.get(..6).is_some_and(|prefix| prefix.eq_ignore_ascii_case("BEGIN:")) {
+                depth += 1;
+            } else if content.get(..4).is_some_and(|prefix| prefix.eq_ignore_ascii_case("END:")) {
+                depth -= 1;
+            }
+            ics.push_str(raw);
+            index += 1;
+        }
+        if !closed {
+            return Err(MpCalendarError::Parse { path, line: None, message: String::from("no END:VCALENDAR to add events before") });
+        }
+        return std::fs::write(&path, ics).map_err(|e| MpCalendarError::Io { path, source: e });
+    }
+
+    /// Splits ICS text into its lines as written, each with any folded continuation lines and line
+    /// ending, alongside the unfolded content
+    fn split_content_lines(text: &str) -> Vec<(&str, String)> {
+        let mut lines: Vec<(&str, String)> = vec![];
+        let mut start = 0;
+        for (offset, line) in text.split_inclusive('\n').scan(0, |position, line| {
+            let offset = *position;
+            *position += line.len();
+            return Some((offset, line));
+        }) {
+            let content = line.trim_end_matches(['\r', '\n']);
+            match content.strip_prefix([' ', '\t']) {
+                Some(continuation) if !lines.is_empty() => {
+                    let last = lines.len() - 1;
+                    lines[last].0 = &text[start..offset + line.len()];
+                    lines[last].1.push_str(continuation);
+                },
+                _ => {
+                    start = offset;
+                    lines.push((line, String::from(content)));
+                }
+            };
+        }
+        return lines;
+    }
+
+    /// The value of an unfolded content line if it's the named property, parameters or not
+    fn content_line_value(content: &str, name: &str) -> Option<String> {
+        let end_of_name = content.find([';', ':'])?;
+        if !content[..end_of_name].eq_ignore_ascii_case(name) {
+            return None;
+        }
+        let colon = content.find(':')?;
+        return Some(String::from(&content[colon + 1..]));
+    }
+
+    /// As deserialise_mpevents_to_ics_file, with tasks written as VTODOs after the events
+    pub fn deserialise_calendar_to_ics_file(write_path: String, events: Vec<MpEvent>, tasks: Vec<MpTask>) -> Result<(), MpCalendarError> {
+        let io_error = |e: std::io::Error| MpCalendarError::Io { path: write_path.clone(), source: e };
+        let mut open_file = File::create(&write_path).map_err(io_error)?;
+        let ics = deserialise_calendar_to_ics_string(&events, &tasks);
+        open_file.write_all(ics.as_bytes()).map_err(io_error)?;
+        return Ok(());
+    }
+
+    pub fn deserialise_mpevents_to_ics_string(events: &[MpEvent]) -> String {
+        return deserialise_calendar_to_ics_string(events, &[]);
+    }
+
+    /// Writes a complete RFC 5545 VCALENDAR: CRLF line endings, lines folded at 75 octets, TEXT values
+    /// escaped, a UID and DTSTAMP on every component, and a VTIMEZONE for every TZID referenced
+    pub fn deserialise_calendar_to_ics_string(events: &[MpEvent], tasks: &[MpTask]) -> String {
+        let now = FixedOffset::east(0).from_utc_datetime(&Utc::now().naive_utc());
+        let mut ics = String::new();
+        push_content_line(&mut ics, "BEGIN", &[], "VCALENDAR");
+        push_content_line(&mut ics, "VERSION", &[], "2.0");
+        push_content_line(&mut ics, "PRODID", &[], PRODID);
+        push_content_line(&mut ics, "CALSCALE", &[], "GREGORIAN");
+        for (zone, from_year, to_year) in zones_to_declare(events, tasks, now.year()) {
+            push_vtimezone(&mut ics, &zone, from_year, to_year);
+        }
+        for event in events {
+            let uid = match &event.uid {
+                Some(uid) => uid.clone(),
+                None => generate_uid(event)
+            };
+            push_vevent_with_overrides(&mut ics, event, &uid, now);
+        }
+        for task in tasks {
+            push_vtodo(&mut ics, task, now);
+        }
+        push_content_line(&mut ics, "END", &[], "VCALENDAR");
+        return ics;
+    }
+
+    /// Publishes busy time as a VCALENDAR holding a single VFREEBUSY over [window_start, window_end).
+    /// Periods are written in UTC, one FREEBUSY line per FBTYPE, and say nothing about the events behind them.
+    pub fn deserialise_busy_periods_to_vfreebusy_string(periods: &[BusyPeriod],
+                                                        window_start: DateTime<FixedOffset>,
+                                                        window_end: DateTime<FixedOffset>,
+                                                        organizer: Option<&str>) -> String {
+        let now = FixedOffset::east(0).from_utc_datetime(&Utc::now().naive_utc());
+        let mut ics = String::new();
+        push_content_line(&mut ics, "BEGIN", &[], "VCALENDAR");
+        push_content_line(&mut ics, "VERSION", &[], "2.0");
+        push_content_line(&mut ics, "PRODID", &[], PRODID);
+        push_content_line(&mut ics, "METHOD", &[], "PUBLISH");
+        push_content_line(&mut ics, "BEGIN", &[], "VFREEBUSY");
+        let window_start_utc = convert_fixed_offset_to_ical_utc_time(window_start);
+        let window_end_utc = convert_fixed_offset_to_ical_utc_time(window_end);
+        push_content_line(&mut ics, "UID", &[], &format!("freebusy-{}-{}@moneypenny", window_start_utc, window_end_utc));
+        push_content_line(&mut ics, "DTSTAMP", &[], &convert_fixed_offset_to_ical_utc_time(now));
+        push_content_line(&mut ics, "DTSTART", &[], &window_start_utc);
+        push_content_line(&mut ics, "DTEND", &[], &window_end_utc);
+        match organizer {
+            Some(organizer) => push_content_line(&mut ics, "ORGANIZER", &[], organizer),
+            None => ()
+        };
+        for fb_type in &[FreeBusyType::Busy, FreeBusyType::BusyTentative] {
+            let values: Vec<String> = periods.iter()
+                .filter(|period| period.fb_type == *fb_type)
+                .map(|period| format!("{}/{}", convert_fixed_offset_to_ical_utc_time(period.start), convert_fixed_offset_to_ical_utc_time(period.end)))
+                .collect();
+            if !values.is_empty() {
+                push_content_line(&mut ics, "FREEBUSY", &[("FBTYPE", String::from(fb_type.to_ical_str()))], &values.join(","));
+            }
+        }
+        push_content_line(&mut ics, "END", &[], "VFREEBUSY");
+        push_content_line(&mut ics, "END", &[], "VCALENDAR");
+        return ics;
+    }
+
+    const PRODID: &str = concat!("-//moneypenny//moneypenny ", env!("CARGO_PKG_VERSION"), "//EN");
+
+    // Overridden instances are written as separate VEVENTs sharing the UID
+    fn push_vevent_with_overrides(ics: &mut String, event: &MpEvent, uid: &str, now: DateTime<FixedOffset>) {
+        push_vevent(ics, event, uid, now);
+        for over in &event.overrides {
+            push_vevent(ics, over, uid, now);
+        }
+    }
+
+    fn push_vevent(ics: &mut String, event: &MpEvent, uid: &str, now: DateTime<FixedOffset>) {
+        push_content_line(ics, "BEGIN", &[], "VEVENT");
+        push_content_line(ics, "UID", &[], uid);
+        let dtstamp = event.dtstamp.unwrap_or(now);
+        push_content_line(ics, "DTSTAMP", &[], &convert_fixed_offset_to_ical_utc_time(dtstamp));
+        match event.sequence {
+            Some(sequence) => push_content_line(ics, "SEQUENCE", &[], &sequence.to_string()),
+            None => ()
+        };
+        match event.last_modified {
+            Some(time) => push_content_line(ics, "LAST-MODIFIED", &[], &convert_fixed_offset_to_ical_utc_time(time)),
+            None => ()
+        };
+        match event.start_time {
+            Some(time) => push_time_property(ics, "DTSTART", event, time),
+            None => ()
+        };
+        match (event.start_time, event.end_time) {
+            (Some(start), Some(end)) if event.end_as_duration => {
+                push_content_line(ics, "DURATION", &[], &format_ical_duration(length_between(start, end, &event.timezone)));
+            }
+            (_, Some(time)) => push_time_property(ics, "DTEND", event, time),
+            (_, None) => ()
+        };
+        match event.recurrence_id {
+            Some(time) => push_time_property(ics, "RECURRENCE-ID", event, time),
+            None => ()
+        };
+        match &event.name {
+            Some(name) => push_content_line(ics, "SUMMARY", &[], &escape_text(name)),
+            None => ()
+        };
+        match &event.location {
+            Some(loc) => push_content_line(ics, "LOCATION", &[], &escape_text(loc)),
+            None => ()
+        };
+        match &event.description {
+            Some(desc) => push_content_line(ics, "DESCRIPTION", &[], &escape_text(desc)),
+            None => ()
+        };
+        match event.status {
+            Some(status) => push_content_line(ics, "STATUS", &[], status.to_ical_str()),
+            None => ()
+        };
+        if event.transparent {
+            push_content_line(ics, "TRANSP", &[], "TRANSPARENT");
+        }
+        match &event.rrule {
+            Some(rule) => push_content_line(ics, "RRULE", &[], &rule.to_string()),
+            None => ()
+        };
+        for rdate in &event.rdates {
+            push_time_property(ics, "RDATE", event, *rdate);
+        }
+        for exdate in &event.exdates {
+            push_time_property(ics, "EXDATE", event, *exdate);
+        }
+        for prop in &event.extra_properties {
+            push_mp_property(ics, prop);
+        }
+        for alarm in &event.alarms {
+            push_valarm(ics, alarm);
+        }
+        push_content_line(ics, "END", &[], "VEVENT");
+    }
+
+    fn push_valarm(ics: &mut String, alarm: &MpAlarm) {
+        push_content_line(ics, "BEGIN", &[], "VALARM");
+        push_content_line(ics, "ACTION", &[], &alarm.action.to_string());
+        match &alarm.trigger {
+            AlarmTrigger::Relative { offset, from_end: true } => {
+                push_content_line(ics, "TRIGGER", &[("RELATED", String::from("END"))], &format_ical_duration(*offset));
+            }
+            AlarmTrigger::Relative { offset, from_end: false } => {
+                push_content_line(ics, "TRIGGER", &[], &format_ical_duration(*offset));
+            }
+            AlarmTrigger::Absolute(time) => {
+                push_content_line(ics, "TRIGGER", &[("VALUE", String::from("DATE-TIME"))], &convert_fixed_offset_to_ical_utc_time(*time));
+            }
+        };
+        match &alarm.description {
+            Some(desc) => push_content_line(ics, "DESCRIPTION", &[], &escape_text(desc)),
+            None => ()
+        };
+        match alarm.repeat_interval {
+            Some(interval) => {
+                push_content_line(ics, "REPEAT", &[], &alarm.repeat.to_string());
+                push_content_line(ics, "DURATION", &[], &format_ical_duration(interval));
+            }
+            None => ()
+        };
+        for prop in &alarm.extra_properties {
+            push_mp_property(ics, prop);
+        }
+        push_content_line(ics, "END", &[], "VALARM");
+    }
+
+    fn push_vtodo(ics: &mut String, task: &MpTask, now: DateTime<FixedOffset>) {
+        push_content_line(ics, "BEGIN", &[], "VTODO");
+        let uid = match &task.uid {
+            Some(uid) => uid.clone(),
+            None => generate_task_uid(task)
+        };
+        push_content_line(ics, "UID", &[], &uid);
+        let dtstamp = task.dtstamp.unwrap_or(now);
+        push_content_line(ics, "DTSTAMP", &[], &convert_fixed_offset_to_ical_utc_time(dtstamp));
+        match &task.name {
+            Some(name) => push_content_line(ics, "SUMMARY", &[], &escape_text(name)),
+            None => ()
+        };
+        match &task.description {
+            Some(desc) => push_content_line(ics, "DESCRIPTION", &[], &escape_text(desc)),
+            None => ()
+        };
+        match task.due {
+            Some(time) => push_time_value(ics, "DUE", task.due_all_day, &task.timezone, time),
+            None => ()
+        };
+        // COMPLETED is always a UTC DATE-TIME
+        match task.completed {
+            Some(time) => push_content_line(ics, "COMPLETED", &[], &convert_fixed_offset_to_ical_utc_time(time)),
+            None => ()
+        };
+        match task.percent_complete {
+            Some(percent) => push_content_line(ics, "PERCENT-COMPLETE", &[], &percent.to_string()),
+            None => ()
+        };
+        match task.priority {
+            Some(priority) => push_content_line(ics, "PRIORITY", &[], &priority.to_string()),
+            None => ()
+        };
+        match task.status {
+            Some(status) => push_content_line(ics, "STATUS", &[], status.to_ical_str()),
+            None => ()
+        };
+        match &task.related_to {
+            Some(parent_uid) => push_content_line(ics, "RELATED-TO", &[], parent_uid),
+            None => ()
+        };
+        for prop in &task.extra_properties {
+            push_mp_property(ics, prop);
+        }
+        push_content_line(ics, "END", &[], "VTODO");
+    }
+
+    /// The zone times are written in by TZID, or None if they're written as UTC or as dates
+    fn named_zone(all_day: bool, timezone: &Option<MpTimeZone>) -> Option<&MpTimeZone> {
+        match timezone {
+            Some(zone) if !all_day && !zone.is_fixed() => return Some(zone),
+            _ => return None
+        }
+    }
+
+    fn push_time_property(ics: &mut String, name: &str, event: &MpEvent, time: DateTime<FixedOffset>) {
+        push_time_value(ics, name, event.all_day, &event.timezone, time);
+    }
+
+    /// All-day values as VALUE=DATE, zoned values as TZID plus local time, everything else as UTC
+    fn push_time_value(ics: &mut String, name: &str, all_day: bool, timezone: &Option<MpTimeZone>, time: DateTime<FixedOffset>) {
+        if all_day {
+            let local_date = match timezone {
+                Some(zone) => convert_fixed_offset_to_ical_date(zone.convert(time)),
+                None => convert_fixed_offset_to_ical_date(time)
+            };
+            push_content_line(ics, name, &[("VALUE", String::from("DATE"))], &local_date);
+            return;
+        }
+        match named_zone(all_day, timezone) {
+            Some(zone) => {
+                let local_time = convert_fixed_offset_to_ical_time(zone.convert(time));
+                push_content_line(ics, name, &[("TZID", String::from(zone.tzid()))], &local_time);
+            }
+            None => push_content_line(ics, name, &[], &convert_fixed_offset_to_ical_utc_time(time))
+        };
+    }
+
+    /// Each distinct TZID used by the events and tasks, with the span of years its VTIMEZONE has to cover
+    fn zones_to_declare(events: &[MpEvent], tasks: &[MpTask], this_year: i32) -> Vec<(MpTimeZone, i32, i32)> {
+        let all_events = events.iter().chain(events.iter().flat_map(|event| event.overrides.iter()));
+        let event_zones = all_events
+            .map(|event| (named_zone(event.all_day, &event.timezone), event.start_time));
+        let task_zones = tasks.iter()
+            .map(|task| (named_zone(task.due_all_day, &task.timezone), task.due));
+        let mut zones: Vec<(MpTimeZone, i32, i32)> = vec![];
+        for (zone, time) in event_zones.chain(task_zones) {
+            let zone = match zone {
+                Some(zone) => zone,
+                None => continue
+            };
+            let year = time.map(|time| time.year()).unwrap_or(this_year);
+            match zones.iter_mut().find(|(known, _, _)| known == zone) {
+                Some((_, from_year, to_year)) => {
+                    *from_year = (*from_year).min(year);
+                    *to_year = (*to_year).max(year);
+                }
+                None => zones.push((zone.clone(), year.min(this_year), year.max(this_year)))
+            };
+        }
+        return zones;
+    }
+
+    fn push_vtimezone(ics: &mut String, zone: &MpTimeZone, from_year: i32, to_year: i32) {
+        push_content_line(ics, "BEGIN", &[], "VTIMEZONE");
+        push_content_line(ics, "TZID", &[], zone.tzid());
+        for transition in zone.transitions_between(from_year - 1, to_year + 1) {
+            let kind = if transition.is_daylight { "DAYLIGHT" } else { "STANDARD" };
+            push_content_line(ics, "BEGIN", &[], kind);
+            push_content_line(ics, "DTSTART", &[], &transition.dtstart.format("%Y%m%dT%H%M%S").to_string());
+            push_content_line(ics, "TZOFFSETFROM", &[], &format_utc_offset(transition.offset_from));
+            push_content_line(ics, "TZOFFSETTO", &[], &format_utc_offset(transition.offset_to));
+            match &transition.name {
+                Some(name) => push_content_line(ics, "TZNAME", &[], &escape_text(name)),
+                None => ()
+            };
+            match &transition.rrule {
+                Some(rule) => push_content_line(ics, "RRULE", &[], &rule.to_string()),
+                None => ()
+            };
+            for rdate in &transition.rdates {
+                push_content_line(ics, "RDATE", &[], &rdate.format("%Y%m%dT%H%M%S").to_string());
+            }
+            push_content_line(ics, "END", &[], kind);
+        }
+        push_content_line(ics, "END", &[], "VTIMEZONE");
+    }
+
+    /// A stable UID for an event that never had one, so rewriting a file doesn't churn its UIDs
+    pub(super) fn generate_uid(event: &MpEvent) -> String {
+        let fields = [
+            event.name.clone(),
+            event.start_time.map(|time| time.timestamp().to_string()),
+            event.end_time.map(|time| time.timestamp().to_string()),
+            event.location.clone()
+        ];
+        return format!("{}@moneypenny", stable_hash(&fields));
+    }
+
+    fn generate_task_uid(task: &MpTask) -> String {
+        let fields = [task.name.clone(), task.description.clone(), task.due.map(|time| time.timestamp().to_string())];
+        return format!("{}@moneypenny", stable_hash(&fields));
+    }
+
+    /// The SHA-1 of the fields in hex. Unlike std's hashers it's the same on every build and release,
+    /// so UIDs and ETags derived from it don't change when moneypenny is upgraded. Each field is
+    /// length-prefixed, so neighbouring fields can't run into each other.
+    pub(super) fn stable_hash(fields: &[Option<String>]) -> String {
+        let mut hasher = sha1_smol::Sha1::new();
+        for field in fields {
+            match field {
+                Some(value) => hasher.update(format!("{}:{};", value.len(), value).as_bytes()),
+                None => hasher.update(b"-;")
+            };
+        }
+        return hasher.digest().to_string();
+    }
+
+    /// Appends one content line, folded so no physical line exceeds 75 octets, terminated with CRLF
+    fn push_content_line(ics: &mut String, name: &str, params: &[(&str, String)], value: &str) {
+        let mut line = String::from(name);
+        for (key, param_value) in params {
+            line.push_str(&format!(";{}={}", key, quote_param_value(param_value)));
+        }
+        line.push(':');
+        line.push_str(value);
+        ics.push_str(&fold_content_line(&line));
+        ics.push_str("\r\n");
+    }
+
+    /// Writes back a property read by extract_mpevent, parameters and value exactly as they came in
+    fn push_mp_property(ics: &mut String, prop: &MpProperty) {
+        let mut line = prop.name.clone();
+        line.push_str(&format_property_params(&prop.params));
+        line.push(':');
+        match &prop.value {
+            Some(value) => line.push_str(value),
+            None => ()
+        };
+        ics.push_str(&fold_content_line(&line));
+        ics.push_str("\r\n");
+    }
+
+    /// Parameters as they appear in a content line, e.g. ;ROLE=CHAIR;CN="Smith, Jo"
+    pub(super) fn format_property_params(params: &[(String, Vec<String>)]) -> String {
+        let mut formatted = String::new();
+        for (key, values) in params {
+            let quoted: Vec<String> = values.iter().map(|value| quote_param_value(value)).collect();
+            formatted.push_str(&format!(";{}={}", key, quoted.join(",")));
+        }
+        return formatted;
+    }
+
+    /// Parameter values containing delimiters have to be quoted
+    fn quote_param_value(param_value: &str) -> String {
+        if param_value.contains(&[':', ';', ','][..]) {
+            return format!("\"{}\"", param_value);
+        }
+        return String::from(param_value);
+    }
+
+    /// Splits a line into 75 octet chunks joined by CRLF and a space, never inside a UTF-8 character.
+    /// Readers commonly trim trailing whitespace from each physical line, so a fold never goes
+    /// straight after a space; the spaces are carried over to start the next line instead.
+    fn fold_content_line(line: &str) -> String {
+        let mut folded = String::with_capacity(line.len() + line.len() / 74 * 3);
+        let mut physical_line = String::new();
+        for c in line.chars() {
+            while physical_line.len() + c.len_utf8() > 75 {
+                let content = physical_line.trim_end_matches(&[' ', '\t'][..]);
+                // A line that is nothing but whitespace (after the fold's own space) is left as it is
+                let keep = if content.len() > 1 { content.len() } else { physical_line.len() };
+                let carried = physical_line.split_off(keep);
+                folded.push_str(&physical_line);
+                folded.push_str("\r\n");
+                physical_line = format!(" {}", carried);
+            }
+            physical_line.push(c);
+        }
+        folded.push_str(&physical_line);
+        return folded;
+    }
+
+    /// Escapes a TEXT value: backslash, semicolon, comma and newline
+    fn escape_text(text: &str) -> String {
+        let mut escaped = String::with_capacity(text.len());
+        for c in text.replace("\r\n", "\n").chars() {
+            match c {
+                '\\' => escaped.push_str("\\\\"),
+                ';' => escaped.push_str("\\;"),
+                ',' => escaped.push_str("\\,"),
+                '\n' => escaped.push_str("\\n"),
+                _ => escaped.push(c)
+            };
+        }
+        return escaped;
+    }
+
+    /// Reverses escape_text; the ical parser hands TEXT values over still escaped
+    fn unescape_text(text: &str) -> String {
+        let mut unescaped = String::with_capacity(text.len());
+        let mut chars = text.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                unescaped.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('n') | Some('N') => unescaped.push('\n'),
+                Some(other) => unescaped.push(other),
+                None => unescaped.push('\\')
+            };
+        }
+        return unescaped;
+    }
+
+    /// Floating times (no 'Z' and no TZID) are read as local_zone. A malformed VEVENT is reported
+    /// as a Calendar message, the first time it's seen, and skipped; only a calendar that can't be read
+    /// at all is an error.
+    pub fn extract_events_from_ical(cal: IcalCalendar, local_zone: &MpTimeZone) -> Result<Vec<MpEvent>, MpCalendarError> {
+        let mut warnings: Vec<String> = vec![];
+        let events = extract_events_from_ical_with_warnings(cal, local_zone, &mut warnings)?;
+        warnings.into_iter().for_each(super::output_load_warning);
+        return Ok(events);
+    }
+
+    /// As extract_events_from_ical, but what would be reported is added to warnings instead, for
+    /// callers such as the dashboard that can't have anything written to the terminal
+    pub fn extract_events_from_ical_with_warnings(cal: IcalCalendar,
+                                                  local_zone: &MpTimeZone,
+                                                  warnings: &mut Vec<String>) -> Result<Vec<MpEvent>, MpCalendarError> {
+        check_ical_version(&cal)?;
+        let zones = TimeZoneContext::from_ical_timezones(&cal.timezones, local_zone.clone());
+        let mut mp_events: Vec<MpEvent> = vec![];
+        for (index, event) in cal.events.into_iter().enumerate() {
+            let uid = event.properties.iter().find(|prop| prop.name == "UID").and_then(|prop| prop.value.clone());
+            let mut mp_event = match extract_mpevent(event.properties, &zones, warnings) {
+                Ok(mp_event) => mp_event,
+                Err(e) => {
+                    let e = e.in_component("VEVENT", index, uid);
+                    warnings.push(format!("Skipping malformed event: {}", e));
+                    continue;
+                }
+            };
+            // A bad VALARM loses the reminder, not the event
+            for (alarm_index, alarm) in event.alarms.into_iter().enumerate() {
+                match extract_mpalarm(alarm.properties, &zones) {
+                    Ok(mp_alarm) => mp_event.alarms.push(mp_alarm),
+                    Err(e) => {
+                        let e = e.in_component("VALARM", alarm_index, None).in_component("VEVENT", index, uid.clone());
+                        warnings.push(format!("Skipping malformed alarm: {}", e));
+                    }
+                }
+            }
+            mp_events.push(mp_event);
+        }
+        return Ok(merge_recurrence_overrides(mp_events));
+    }
+
+    /// Reads the VTODOs of a calendar, with the same handling of floating times and malformed
+    /// components as extract_events_from_ical
+    pub fn extract_tasks_from_ical(cal: IcalCalendar, local_zone: &MpTimeZone) -> Result<Vec<MpTask>, MpCalendarError> {
+        let mut warnings: Vec<String> = vec![];
+        let tasks = extract_tasks_from_ical_with_warnings(cal, local_zone, &mut warnings)?;
+        warnings.into_iter().for_each(super::output_load_warning);
+        return Ok(tasks);
+    }
+
+    /// As extract_tasks_from_ical, adding what would be reported to warnings
+    pub fn extract_tasks_from_ical_with_warnings(cal: IcalCalendar,
+                                                 local_zone: &MpTimeZone,
+                                                 warnings: &mut Vec<String>) -> Result<Vec<MpTask>, MpCalendarError> {
+        check_ical_version(&cal)?;
+        let zones = TimeZoneContext::from_ical_timezones(&cal.timezones, local_zone.clone());
+        let mut mp_tasks: Vec<MpTask> = vec![];
+        for (index, todo) in cal.todos.into_iter().enumerate() {
+            let uid = todo.properties.iter().find(|prop| prop.name == "UID").and_then(|prop| prop.value.clone());
+            match extract_mptask(todo.properties, &zones) {
+                Ok(mp_task) => mp_tasks.push(mp_task),
+                Err(e) => {
+                    let e = e.in_component("VTODO", index, uid);
+                    warnings.push(format!("Skipping malformed task: {}", e));
+                }
+            }
+        }
+        return Ok(mp_tasks);
+    }
+
+    fn check_ical_version(cal: &IcalCalendar) -> Result<(), MpCalendarError> {
+        let version = cal.properties.iter().find(|prop| prop.name == "VERSION").and_then(|prop| prop.value.clone());
+        match version {
+            Some(version) if version.trim() != "2.0" => return Err(MpCalendarError::UnsupportedVersion(version)),
+            _ => return Ok(())
+        }
+    }
+
+    fn extract_mpevent(event_props: Vec<Property>, zones: &TimeZoneContext, warnings: &mut Vec<String>) -> Result<MpEvent, MpCalendarError> {
+        let mut mp_event = MpEvent::default();
+        let mut duration: Option<chrono::Duration> = None;
+        for prop in event_props {
+            let name = prop.name;
+            if name == "SUMMARY" {
+                mp_event.name = prop.value.map(|value| unescape_text(&value));
+            } else if name == "DTSTART" {
+                mp_event.all_day = match &prop.value {
+                    Some(value) => is_ical_date(value, &prop.params),
+                    None => false
+                };
+                mp_event.timezone = match &prop.value {
+                    Some(value) => resolve_ical_time_zone(value, &prop.params, zones),
+                    None => None
+                };
+                mp_event.start_time = convert_ical_time_to_fixed_offset(prop.value, prop.params, zones)
+                    .map_err(|e| e.for_property(&name))?;
+            } else if name == "DTEND" {
+                mp_event.end_time = convert_ical_time_to_fixed_offset(prop.value, prop.params, zones)
+                    .map_err(|e| e.for_property(&name))?;
+            } else if name == "DURATION" {
+                let value = prop.value.unwrap_or_default();
+                duration = Some(parse_ical_duration(&value).map_err(|_| MpCalendarError::InvalidValue { property: name, value })?);
+            } else if name == "LOCATION" {
+                mp_event.location = prop.value.map(|value| unescape_text(&value));
+            } else if name == "DESCRIPTION" {
+                mp_event.description = prop.value.map(|value| unescape_text(&value));
+            } else if name == "DTSTAMP" {
+                mp_event.dtstamp = convert_ical_time_to_fixed_offset(prop.value, prop.params, zones)
+                    .map_err(|e| e.for_property(&name))?;
+            } else if name == "LAST-MODIFIED" {
+                mp_event.last_modified = convert_ical_time_to_fixed_offset(prop.value, prop.params, zones)
+                    .map_err(|e| e.for_property(&name))?;
+            } else if name == "SEQUENCE" {
+                mp_event.sequence = match prop.value {
+                    Some(str) => match str.trim().parse::<u32>() {
+                        Ok(number) => Some(number),
+                        Err(_) => return Err(MpCalendarError::InvalidValue { property: name, value: str })
+                    },
+                    None => None
+                };
+            } else if name == "STATUS" {
+                match prop.value {
+                    Some(str) => {
+                        let status = EventStatus::from_str(&str).map_err(|_| MpCalendarError::UnknownStatus(str))?;
+                        mp_event.status = Some(status);
+                    }
+                    // TODO: assuming an event without a status is tentative may not be the best call. Maybe leave at None?
+                    None => mp_event.status = Some(EventStatus::Tentative)
+                }
+            } else if name == "TRANSP" {
+                mp_event.transparent = prop.value.as_deref() == Some("TRANSPARENT");
+            } else if name == "RRULE" {
+                match prop.value {
+                    // Kept as it was read so it's written back, but only the first instance is used
+                    Some(str) if super::cal_recur::unsupported_part(&str).is_some() => {
+                        warnings.push(format!("Ignoring RRULE '{}', which uses unsupported {}: only its first occurrence is shown",
+                                              str, super::cal_recur::unsupported_part(&str).unwrap_or_default()));
+                        mp_event.extra_properties.push(MpProperty { name, params: prop.params.unwrap_or_default(), value: Some(str) });
+                    }
+                    Some(str) => {
+                        let rule = RecurrenceRule::from_str(&str).map_err(|reason| {
+                            MpCalendarError::InvalidRecurrence { property: name.clone(), value: str.clone(), reason }
+                        })?;
+                        mp_event.rrule = Some(rule);
+                    }
+                    None => ()
+                }
+            } else if name == "UID" {
+                mp_event.uid = prop.value;
+            } else if name == "RDATE" {
+                let rdates = convert_ical_time_list_to_fixed_offsets(prop.value, prop.params, zones)
+                    .map_err(|e| e.for_property(&name))?;
+                mp_event.rdates.extend(rdates);
+            } else if name == "EXDATE" {
+                let exdates = convert_ical_time_list_to_fixed_offsets(prop.value, prop.params, zones)
+                    .map_err(|e| e.for_property(&name))?;
+                mp_event.exdates.extend(exdates);
+            } else if name == "RECURRENCE-ID" {
+                mp_event.recurrence_id = convert_ical_time_to_fixed_offset(prop.value, prop.params, zones)
+                    .map_err(|e| e.for_property(&name))?;
+            } else {
+                mp_event.extra_properties.push(MpProperty { name, params: prop.params.unwrap_or_default(), value: prop.value });
+            }
+        }
+        // A DURATION only counts without a DTEND, which can't be given alongside it
+        match (mp_event.start_time, mp_event.end_time, duration) {
+            (Some(start), None, Some(length)) => {
+                let end = end_after(start, length, &mp_event.timezone).ok_or_else(|| {
+                    MpCalendarError::InvalidValue { property: String::from("DURATION"), value: format_ical_duration(length) }
+                })?;
+                mp_event.end_time = Some(end);
+                mp_event.end_as_duration = true;
+            }
+            _ => ()
+        };
+        // An all-day event with no DTEND lasts the one day
+        match (mp_event.all_day, mp_event.start_time, mp_event.end_time, &mp_event.timezone) {
+            (true, Some(start), None, Some(zone)) => {
+                mp_event.end_time = Some(zone.from_local_datetime(start.naive_local() + chrono::Duration::days(1)));
+            }
+            _ => ()
+        };
+        match (mp_event.start_time, mp_event.end_time) {
+            (Some(start), Some(end)) if end < start => {
+                return Err(MpCalendarError::EndBeforeStart { start: start.to_rfc3339(), end: end.to_rfc3339() });
+            }
+            _ => ()
+        };
+        return Ok(mp_event);
+    }
+
+    // start plus length, the whole days of it in the local time of timezone, so that "P1D" ends at the
+    // same time of day across a change of the clocks (RFC 5545 3.3.6)
+    fn end_after(start: DateTime<FixedOffset>, length: chrono::Duration, timezone: &Option<MpTimeZone>) -> Option<DateTime<FixedOffset>> {
+        match timezone {
+            Some(zone) => {
+                let whole_days = chrono::Duration::days(length.num_days());
+                let local_end = zone.convert(start).naive_local().checked_add_signed(whole_days)?;
+                return zone.from_local_datetime(local_end).checked_add_signed(length - whole_days);
+            }
+            None => return start.checked_add_signed(length)
+        }
+    }
+
+    // The length end_after turns back into end
+    fn length_between(start: DateTime<FixedOffset>, end: DateTime<FixedOffset>, timezone: &Option<MpTimeZone>) -> chrono::Duration {
+        match timezone {
+            Some(zone) => {
+                let local_start = zone.convert(start).naive_local();
+                let whole_days = chrono::Duration::days((zone.convert(end).naive_local() - local_start).num_days());
+                return whole_days + (end - zone.from_local_datetime(local_start + whole_days));
+            }
+            None => return end - start
+        }
+    }
+
+    fn extract_mptask(todo_props: Vec<Property>, zones: &TimeZoneContext) -> Result<MpTask, MpCalendarError> {
+        let mut mp_task = MpTask::default();
+        for prop in todo_props {
+            let name = prop.name;
+            if name == "SUMMARY" {
+                mp_task.name = prop.value.map(|value| unescape_text(&value));
+            } else if name == "DESCRIPTION" {
+                mp_task.description = prop.value.map(|value| unescape_text(&value));
+            } else if name == "UID" {
+                mp_task.uid = prop.value;
+            } else if name == "DTSTAMP" {
+                mp_task.dtstamp = convert_ical_time_to_fixed_offset(prop.value, prop.params, zones)
+                    .map_err(|e| e.for_property(&name))?;
+            } else if name == "DUE" {
+                match &prop.value {
+                    Some(value) => {
+                        mp_task.due_all_day = is_ical_date(value, &prop.params);
+                        mp_task.timezone = resolve_ical_time_zone(value, &prop.params, zones);
+                    }
+                    None => ()
+                };
+                mp_task.due = convert_ical_time_to_fixed_offset(prop.value, prop.params, zones)
+                    .map_err(|e| e.for_property(&name))?;
+            } else if name == "COMPLETED" {
+                mp_task.completed = convert_ical_time_to_fixed_offset(prop.value, prop.params, zones)
+                    .map_err(|e| e.for_property(&name))?;
+            } else if name == "PERCENT-COMPLETE" || name == "PRIORITY" {
+                let max = if name == "PRIORITY" { 9 } else { 100 };
+                let number = match prop.value {
+                    Some(str) => match str.trim().parse::<u8>() {
+                        Ok(number) if number <= max => Some(number),
+                        _ => return Err(MpCalendarError::InvalidValue { property: name, value: str })
+                    },
+                    None => None
+                };
+                if name == "PRIORITY" {
+                    mp_task.priority = number;
+                } else {
+                    mp_task.percent_complete = number;
+                }
+            } else if name == "STATUS" {
+                match prop.value {
+                    Some(str) => {
+                        let status = TaskStatus::from_str(&str).map_err(|_| MpCalendarError::UnknownStatus(str))?;
+                        mp_task.status = Some(status);
+                    }
+                    None => ()
+                };
+            } else if name == "RELATED-TO" && is_parent_relation(&prop.params) {
+                mp_task.related_to = prop.value;
+            } else {
+                mp_task.extra_properties.push(MpProperty { name, params: prop.params.unwrap_or_default(), value: prop.value });
+            }
+        }
+        return Ok(mp_task);
+    }
+
+    /// TRIGGER is a duration relative to the event (RELATED=END for its end) or, with VALUE=DATE-TIME,
+    /// an absolute UTC time
+    fn extract_mpalarm(alarm_props: Vec<Property>, zones: &TimeZoneContext) -> Result<MpAlarm, MpCalendarError> {
+        let mut action: Option<AlarmAction> = None;
+        let mut trigger: Option<AlarmTrigger> = None;
+        let mut description: Option<String> = None;
+        let mut repeat: u32 = 0;
+        let mut repeat_interval: Option<chrono::Duration> = None;
+        let mut extra_properties: Vec<MpProperty> = vec![];
+        for prop in alarm_props {
+            let name = prop.name;
+            let value = prop.value.clone().unwrap_or_default();
+            let invalid = || MpCalendarError::InvalidValue { property: name.clone(), value: value.clone() };
+            if name == "ACTION" {
+                action = Some(AlarmAction::from_str(&value).map_err(|_| invalid())?);
+            } else if name == "TRIGGER" {
+                let related_to_end = first_param_value(&prop.params, "RELATED") == Some("END");
+                if first_param_value(&prop.params, "VALUE") == Some("DATE-TIME") {
+                    let time = convert_ical_time_to_fixed_offset(prop.value, prop.params, zones)
+                        .map_err(|e| e.for_property(&name))?;
+                    trigger = time.map(AlarmTrigger::Absolute);
+                } else {
+                    let offset = parse_ical_duration(&value).map_err(|_| invalid())?;
+                    trigger = Some(AlarmTrigger::Relative { offset, from_end: related_to_end });
+                }
+            } else if name == "DESCRIPTION" {
+                description = prop.value.map(|value| unescape_text(&value));
+            } else if name == "REPEAT" {
+                repeat = value.trim().parse::<u32>().map_err(|_| invalid())?;
+            } else if name == "DURATION" {
+                repeat_interval = Some(parse_ical_duration(&value).map_err(|_| invalid())?);
+            } else {
+                extra_properties.push(MpProperty { name, params: prop.params.unwrap_or_default(), value: prop.value });
+            }
+        }
+        let action = action.ok_or_else(|| MpCalendarError::MissingProperty(String::from("ACTION")))?;
+        let trigger = trigger.ok_or_else(|| MpCalendarError::MissingProperty(String::from("TRIGGER")))?;
+        let mut mp_alarm = MpAlarm::new(action, trigger);
+        mp_alarm.description = description;
+        // REPEAT and DURATION only mean anything together
+        if repeat_interval.is_some() {
+            mp_alarm.repeat = repeat;
+            mp_alarm.repeat_interval = repeat_interval;
+        }
+        mp_alarm.extra_properties = extra_properties;
+        return Ok(mp_alarm);
+    }
+
+    fn first_param_value<'a>(params: &'a Option<Vec<(String, Vec<String>)>>, key: &str) -> Option<&'a str> {
+        return params.as_ref()
+            .and_then(|params| params.iter().find(|(param_key, _)| param_key == key))
+            .and_then(|(_, values)| values.first())
+            .map(|value| value.as_str());
+    }
+
+    /// RELATED-TO points at the parent unless its RELTYPE says otherwise
+    fn is_parent_relation(params: &Option<Vec<(String, Vec<String>)>>) -> bool {
+        match first_param_value(params, "RELTYPE") {
+            Some(reltype) => return reltype == "PARENT",
+            None => return true
+        }
+    }
+
+    /// Moves every event carrying a RECURRENCE-ID into the overrides of the master event with the
+    /// same UID. Overrides with no matching master are kept as standalone events.
+    fn merge_recurrence_overrides(events: Vec<MpEvent>) -> Vec<MpEvent> {
+        let (overrides, mut masters): (Vec<MpEvent>, Vec<MpEvent>) = events.into_iter()
+            .partition(|event| event.recurrence_id.is_some() && event.uid.is_some());
+        for over in overrides {
+            let master = masters.iter_mut()
+                .find(|master| master.recurrence_id.is_none() && master.uid == over.uid);
+            match master {
+                Some(master) => master.overrides.push(over),
+                None => masters.push(over)
+            }
+        }
+        return masters;
+    }
+
+    /// RDATE and EXDATE hold comma separated lists. A PERIOD value (start/end) is reduced to its start.
+    fn convert_ical_time_list_to_fixed_offsets(ical_times: Option<String>, ical_tz: Option<Vec<(String, Vec<String>)>>, zones: &TimeZoneContext) -> Result<Vec<DateTime<FixedOffset>>, MpCalendarError> {
+        let ical_times = match ical_times {
+            Some(times) => times,
+            None => return Ok(vec![])
+        };
+        let mut times: Vec<DateTime<FixedOffset>> = vec![];
+        for time in ical_times.split(',') {
+            let start = time.split('/').next().unwrap_or(time);
+            match convert_ical_time_to_fixed_offset(Some(String::from(start)), ical_tz.clone(), zones)? {
+                Some(time) => times.push(time),
+                None => ()
+            };
+        }
+        return Ok(times);
+    }
+
+    /// The zone an ICS time is expressed in: None for UTC (a 'Z' suffix), the zone named by its TZID
+    /// parameter if there is one, and otherwise the configured local zone for floating times
+    fn resolve_ical_time_zone(ical_time: &str, ical_tz: &Option<Vec<(String, Vec<String>)>>, zones: &TimeZoneContext) -> Option<MpTimeZone> {
+        if ical_time.ends_with('Z') {
+            return None;
+        }
+        let tzid = match ical_tz {
+            Some(tz_vec) => tz_vec.iter()
+                .find(|(key, _)| key == "TZID")
+                .and_then(|(_, values)| values.first()),
+            None => None
+        };
+        match tzid {
+            Some(tzid) => match zones.resolve_tzid(tzid) {
+                Some(zone) => return Some(zone),
+                None => {
+                    super::output_mp_calendar_message(format!("Unknown TZID {}, using local time", tzid));
+                    return Some(zones.local_zone().clone());
+                }
+            },
+            None => return Some(zones.local_zone().clone())
+        }
+    }
+
+    /// A DATE (rather than DATE-TIME) value, flagged by VALUE=DATE or recognisable by having no time part
+    fn is_ical_date(ical_time: &str, ical_tz: &Option<Vec<(String, Vec<String>)>>) -> bool {
+        let value_is_date = match ical_tz {
+            Some(tz_vec) => tz_vec.iter().any(|(key, values)| key == "VALUE" && values.iter().any(|value| value == "DATE")),
+            None => false
+        };
+        return value_is_date || !ical_time.contains('T');
+    }
+
+    /// Dates are read as local midnight in the zone the DATE is floating in
+    fn convert_ical_time_to_fixed_offset(ical_time: Option<String>, ical_tz: Option<Vec<(String, Vec<String>)>>, zones: &TimeZoneContext) -> Result<Option<DateTime<FixedOffset>>, MpCalendarError> {
+        match ical_time {
+            Some(ical_time) => {
+                let zone = resolve_ical_time_zone(&ical_time, &ical_tz, zones);
+                let time = if is_ical_date(&ical_time, &ical_tz) {
+                    NaiveDate::parse_from_str(&ical_time, "%Y%m%d").map(|date| date.and_hms(0, 0, 0))
+                } else {
+                    let format_str = String::from("%Y%m%dT%H%M%S");
+                    NaiveDateTime::parse_from_str(ical_time.trim_end_matches('Z'), &format_str)
+                };
+                match time {
+                    Ok(local_time) => match zone {
+                        Some(zone) => return Ok(Some(zone.from_local_datetime(local_time))),
+                        None => return Ok(Some(FixedOffset::east(0).from_utc_datetime(&local_time)))
+                    },
+                    Err(_) => return Err(MpCalendarError::InvalidDate { property: String::new(), value: ical_time })
+                }
+            }
+            None => return Ok(None)
+        }
+    }
+
+    /// Converts the local date of a fixed offset time to string ical DATE format YYYYMMDD
+    pub fn convert_fixed_offset_to_ical_date(fo_time: DateTime<FixedOffset>) -> String {
+        return format!("{}", fo_time.format("%Y%m%d"));
+    }
+
+    /// Converts fixed offset time to string ical UTC format YYYYMMDD'T'HHMMSS'Z'
+    pub fn convert_fixed_offset_to_ical_utc_time(fo_time: DateTime<FixedOffset>) -> String {
+        return format!("{}", fo_time.naive_utc().format("%Y%m%dT%H%M%SZ"));
+    }
+
+    /// Converts fixed offset time to string ical format YYYYMMDD'T'HHMMSS
+    pub fn convert_fixed_offset_to_ical_time(fo_time: DateTime<FixedOffset>) -> String {
+        let format = String::from("%Y%m%dT%H%M%S");
+        let ical_str = format!("{}", fo_time.format(&format));
+        return ical_str;
+    }
+
+    #[cfg(test)]
+    mod cal_io_tests {
+        use crate::mp_calendar::cal_io::*;
+
+        #[test]
+        fn test_convert_ical_time_to_fixed_offset() {
+            let test_ical_time = Some(String::from("20130802T200000Z"));
+            let test_ical_tz_vec = None;
+            let zones = TimeZoneContext::new(MpTimeZone::utc());
+            let test_time_fixed_offset = convert_ical_time_to_fixed_offset(test_ical_time, test_ical_tz_vec, &zones).unwrap().unwrap();
+            let expected_time_fixed_offset = DateTime::parse_from_rfc3339(&String::from("2013-08-02T20:00:00-00:00")).unwrap();
+            assert_eq!(expected_time_fixed_offset, test_time_fixed_offset);
+        }
+
+        #[test]
+        fn test_extract_recurring_event_from_ical() {
+            let cal = parse_file_to_ical_calendar(String::from("src/test/test_recurring.ics")).unwrap();
+            let events = extract_events_from_ical(cal, &MpTimeZone::utc()).unwrap();
+            assert_eq!(1, events.len());
+            let rule = events[0].rrule.as_ref().unwrap();
+            assert_eq!("FREQ=WEEKLY;COUNT=6;BYDAY=MO,WE,FR", rule.to_string());
+            let window_start = DateTime::parse_from_rfc3339("2024-01-01T00:00:00+00:00").unwrap();
+            let window_end = DateTime::parse_from_rfc3339("2025-01-01T00:00:00+00:00").unwrap();
+            assert_eq!(6, events[0].expand_occurrences(window_start, window_end).len());
+        }
+
+        #[test]
+        fn test_unsupported_rrule_keeps_event() {
+            let ics = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nBEGIN:VEVENT\r\nUID:twice-daily@example.com\r\nSUMMARY:Medication\r\n\
+                       DTSTART:20240101T090000Z\r\nRRULE:FREQ=DAILY;BYHOUR=9,21\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n";
+            let events = read_events_from_ics_string(ics, String::from("inline"), &MpTimeZone::utc()).unwrap();
+            assert_eq!(1, events.len());
+            assert!(events[0].rrule.is_none());
+            let window_start = DateTime::parse_from_rfc3339("2024-01-01T00:00:00+00:00").unwrap();
+            let window_end = DateTime::parse_from_rfc3339("2024-02-01T00:00:00+00:00").unwrap();
+            assert_eq!(1, events[0].expand_occurrences(window_start, window_end).len());
+            assert!(deserialise_mpevents_to_ics_string(&events).contains("\r\nRRULE:FREQ=DAILY;BYHOUR=9,21\r\n"));
+        }
+
+        #[test]
+        fn test_update_events_in_ics_file_keeps_unmodelled_content() {
+            let path = std::env::temp_dir().join(format!("mp_update_test_{}.ics", std::process::id())).to_string_lossy().to_string();
+            let original = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nX-WR-CALNAME:Home\r\n\
+                            BEGIN:VEVENT\r\nUID:keep@example.com\r\nSUMMARY:Kept\r\nDTSTART:20240101T090000Z\r\nX-CUSTOM:folded \r\n value\r\nEND:VEVENT\r\n\
+                            BEGIN:VJOURNAL\r\nUID:journal@example.com\r\nSUMMARY:Notes\r\nEND:VJOURNAL\r\n\
+                            BEGIN:VEVENT\r\nUID:edit@\r\n example.com\r\nSUMMARY:Before\r\nDTSTART:20240102T090000Z\r\nEND:VEVENT\r\n\
+                            BEGIN:VEVENT\r\nUID:drop@example.com\r\nSUMMARY:Dropped\r\nDTSTART:20240103T090000Z\r\nEND:VEVENT\r\n\
+                            END:VCALENDAR\r\n";
+            std::fs::write(&path, original).unwrap();
+            let mut events = read_events_from_ics_string(original, path.clone(), &MpTimeZone::utc()).unwrap();
+            events[1].name = Some(String::from("After"));
+            let added = MpEvent { uid: Some(String::from("new@example.com")), name: Some(String::from("Added")), ..events[0].clone() };
+
+            update_events_in_ics_file(path.clone(), &[events[1].clone(), added], &[String::from("drop@example.com")]).unwrap();
+            let updated = std::fs::read_to_string(&path).unwrap();
+            let _ = std::fs::remove_file(&path);
+            assert!(updated.starts_with("BEGIN:VCALENDAR\r\nVERSION:2.0\r\nX-WR-CALNAME:Home\r\n"));
+            assert!(updated.contains("SUMMARY:Kept\r\nDTSTART:20240101T090000Z\r\nX-CUSTOM:folded \r\n value\r\nEND:VEVENT\r\n\
+                                      BEGIN:VJOURNAL\r\nUID:journal@example.com\r\nSUMMARY:Notes\r\nEND:VJOURNAL\r\n"));
+            assert!(!updated.contains("Dropped"));
+            assert!(updated.ends_with("END:VCALENDAR\r\n"));
+            let names: Vec<String> = read_events_from_ics_string(&updated, path, &MpTimeZone::utc()).unwrap().into_iter()
+                .map(|event| event.name.unwrap())
+                .collect();
+            // The edited event stays where it was and the new one goes at the end
+            assert_eq!(vec!["Kept", "After", "Added"], names);
+        }
+
+        #[test]
+        fn test_update_events_in_ics_file_keeps_non_ascii_lines() {
+            let path = std::env::temp_dir().join(format!("mp_update_non_ascii_{}.ics", std::process::id())).to_string_lossy().to_string();
+            // A stray top-level line whose first few bytes split a multi-byte character
+            let original = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nCafé rota\r\nÉté\r\nEND:VCALENDAR\r\n";
+            std::fs::write(&path, original).unwrap();
+            let event = MpEvent { uid: Some(String::from("new@example.com")), name: Some(String::from("Added")), ..Default::default() };
+            let result = update_events_in_ics_file(path.clone(), &[event], &[]);
+            let updated = std::fs::read_to_string(&path).unwrap();
+            let _ = std::fs::remove_file(&path);
+            assert!(result.is_ok());
+            assert!(updated.starts_with("BEGIN:VCALENDAR\r\nVERSION:2.0\r\nCafé rota\r\nÉté\r\n"));
+            assert!(updated.contains("SUMMARY:Added"));
+            assert!(updated.ends_with("END:VCALENDAR\r\n"));
+        }
+
+        #[test]
+        fn test_update_events_in_ics_file_needs_end_of_calendar() {
+            let path = std::env::temp_dir().join(format!("mp_update_truncated_{}.ics", std::process::id())).to_string_lossy().to_string();
+            std::fs::write(&path, "BEGIN:VCALENDAR\r\nVERSION:2.0\r\n").unwrap();
+            let event = MpEvent { name: Some(String::from("Holiday")), ..Default::default() };
+            let result = update_events_in_ics_file(path.clone(), &[event], &[]);
+            assert_eq!("BEGIN:VCALENDAR\r\nVERSION:2.0\r\n", std::fs::read_to_string(&path).unwrap());
+            let _ = std::fs::remove_file(&path);
+            assert!(matches!(result, Err(MpCalendarError::Parse { .. })));
+        }
+
+        #[test]
+        fn test_extract_exdate_rdate_and_overrides_from_ical() {
+            let cal = parse_file_to_ical_calendar(String::from("src/test/test_overrides.ics")).unwrap();
+            let events = extract_events_from_ical(cal, &MpTimeZone::utc()).unwrap();
+            assert_eq!(2, events.len());
+            assert_eq!(1, events[0].overrides.len());
+            assert_eq!(2, events[0].rdates.len());
+            assert_eq!(Some(String::from("Orphaned instance")), events[1].name);
+            let window_start = DateTime::parse_from_rfc3339("2024-01-01T00:00:00+00:00").unwrap();
+            let window_end = DateTime::parse_from_rfc3339("2024-02-01T00:00:00+00:00").unwrap();
+            let occurrences = crate::mp_calendar::cal_ops::expand_mpevents_in_window(events, window_start, window_end);
+            let starts: Vec<String> = occurrences.iter()
+                .map(|event| convert_fixed_offset_to_ical_time(event.start_time.unwrap()))
+                .collect();
+            let expected = vec!["20240101T090000", "20240102T100000", "20240105T090000", "20240108T140000",
+                                "20240110T090000", "20240112T090000", "20240113T090000", "20240120T090000"];
+            assert_eq!(expected, starts);
+            assert_eq!(Some(String::from("Team standup (moved)")), occurrences[3].name);
+            let moved_from = DateTime::parse_from_rfc3339("2024-01-08T09:00:00+00:00").unwrap();
+            assert_eq!(Some(moved_from), occurrences[3].recurrence_id);
+        }
+
+        #[test]
+        fn test_extract_events_resolves_timezones() {
+            let cal = parse_file_to_ical_calendar(String::from("src/test/test_timezones.ics")).unwrap();
+            let new_york = MpTimeZone::from_iana_name("America/New_York").unwrap();
+            let events = extract_events_from_ical(cal, &new_york).unwrap();
+            let starts: Vec<String> = events.iter().map(|event| event.start_time.unwrap().to_rfc3339()).collect();
+            let expected = vec!["2024-03-25T09:00:00+00:00", "2024-07-15T10:00:00+02:00",
+                                "2024-01-15T12:00:00-05:00", "2024-01-15T12:00:00+00:00"];
+            assert_eq!(expected, starts);
+            assert_eq!("Custom/Westminster", events[0].timezone.as_ref().unwrap().tzid());
+            assert_eq!(Some(new_york), events[2].timezone);
+            assert_eq!(None, events[3].timezone);
+            // The second weekly instance falls after the clock change and keeps its 09:00 wall time
+            let window_start = DateTime::parse_from_rfc3339("2024-03-01T00:00:00+00:00").unwrap();
+            let window_end = DateTime::parse_from_rfc3339("2024-05-01T00:00:00+00:00").unwrap();
+            let occurrences = events[0].expand_occurrences(window_start, window_end);
+            let occurrence_starts: Vec<String> = occurrences.iter().map(|event| event.start_time.unwrap().to_rfc3339()).collect();
+            assert_eq!(vec!["2024-03-25T09:00:00+00:00", "2024-04-01T09:00:00+01:00"], occurrence_starts);
+            assert_eq!("2024-04-01T10:00:00+01:00", occurrences[1].end_time.unwrap().to_rfc3339());
+        }
+
+        #[test]
+        fn test_extract_all_day_events() {
+            use crate::mp_calendar::cal_ops::sort_mpevents_chronologically_by_start;
+            let cal = parse_file_to_ical_calendar(String::from("src/test/test_all_day.ics")).unwrap();
+            let london = MpTimeZone::from_iana_name("Europe/London").unwrap();
+            let events = sort_mpevents_chronologically_by_start(extract_events_from_ical(cal, &london).unwrap());
+            let names: Vec<String> = events.iter().map(|event| event.name.clone().unwrap()).collect();
+            assert_eq!(vec!["New Year's Day", "Annual leave", "Budget meeting", "Working from home", "Dress-down Friday"], names);
+            assert!(events[0].all_day && !events[2].all_day);
+            assert_eq!("2024-01-02T00:00:00+00:00", events[0].end_time.unwrap().to_rfc3339());
+            // Leave overlaps the meeting in its middle but not the day after it ends
+            assert!(events[1].ordered_has_overlap(&events[2]));
+            assert!(!events[1].ordered_has_overlap(&events[3]));
+            let ics = deserialise_mpevents_to_ics_string(&events[1..2]);
+            assert!(ics.contains("DTSTART;VALUE=DATE:20240102\r\n"));
+            assert!(ics.contains("DTEND;VALUE=DATE:20240105\r\n"));
+            // Recurring all-day events stay on local midnights across the clock change
+            let window_start = DateTime::parse_from_rfc3339("2024-03-01T00:00:00+00:00").unwrap();
+            let window_end = DateTime::parse_from_rfc3339("2024-05-01T00:00:00+00:00").unwrap();
+            let fridays = events[4].expand_occurrences(window_start, window_end);
+            assert_eq!("2024-04-05T00:00:00+01:00", fridays[1].start_time.unwrap().to_rfc3339());
+            assert_eq!("2024-04-06T00:00:00+01:00", fridays[1].end_time.unwrap().to_rfc3339());
+            assert!(fridays[1].all_day);
+        }
+
+        #[test]
+        fn test_malformed_events_are_skipped() {
+            let cal = parse_file_to_ical_calendar(String::from("src/test/test_malformed.ics")).unwrap();
+            let events = extract_events_from_ical(cal, &MpTimeZone::utc()).unwrap();
+            assert_eq!(1, events.len());
+            assert_eq!(Some(String::from("Good event")), events[0].name);
+            let zones = TimeZoneContext::new(MpTimeZone::utc());
+            let bad_date = convert_ical_time_to_fixed_offset(Some(String::from("2024-01-03")), None, &zones).unwrap_err();
+            let message = bad_date.for_property("DTSTART").in_component("VEVENT", 2, Some(String::from("x@y"))).to_string();
+            assert_eq!("VEVENT 3 (UID x@y): invalid DTSTART value '2024-01-03'", message);
+        }
+
+        #[test]
+        fn test_calendar_level_errors() {
+            match parse_file_to_ical_calendar(String::from("src/test/does_not_exist.ics")) {
+                Err(MpCalendarError::Io { path, .. }) => assert_eq!("src/test/does_not_exist.ics", path),
+                other => panic!("expected an IO error, got {:?}", other)
+            };
+            match parse_file_to_ical_calendar(String::from("src/test/test_unparseable.ics")) {
+                Err(MpCalendarError::Parse { line, .. }) => assert_eq!(Some(5), line),
+                other => panic!("expected a parse error, got {:?}", other)
+            };
+            let mut cal = parse_file_to_ical_calendar(String::from("src/test/test_recurring.ics")).unwrap();
+            cal.properties.iter_mut().find(|prop| prop.name == "VERSION").unwrap().value = Some(String::from("1.0"));
+            assert!(extract_events_from_ical(cal, &MpTimeZone::utc()).is_err());
+            let unwritable = deserialise_mpevents_to_ics_file(String::from("src/test/no_such_dir/out.ics"), vec![]);
+            assert!(matches!(unwritable, Err(MpCalendarError::Io { .. })));
+        }
+
+        fn reparse_ics_string(ics: &str, local_zone: &MpTimeZone) -> Vec<MpEvent> {
+            let reader = std::io::BufReader::new(ics.as_bytes());
+            let cal = ical::IcalParser::new(reader).next().unwrap().unwrap();
+            return extract_events_from_ical(cal, local_zone).unwrap();
+        }
+
+        #[test]
+        fn test_content_lines_are_escaped_and_folded() {
+            let mut ics = String::new();
+            let summary = "Budget, planning; Q1\\Q2\nBring laptops — ☕ provided ".repeat(3);
+            push_content_line(&mut ics, "SUMMARY", &[], &escape_text(&summary));
+            push_content_line(&mut ics, "DTSTART", &[("TZID", String::from("Custom;Zone"))], "20240101T090000");
+            assert!(ics.starts_with("SUMMARY:Budget\\, planning\\; Q1\\\\Q2\\nBring"));
+            assert!(ics.contains("DTSTART;TZID=\"Custom;Zone\":20240101T090000\r\n"));
+            for physical_line in ics.split("\r\n") {
+                assert!(physical_line.len() <= 75);
+            }
+            let unfolded = ics.replace("\r\n ", "");
+            assert_eq!(summary, unescape_text(&unfolded["SUMMARY:".len()..unfolded.find("\r\n").unwrap()]));
+        }
+
+        #[test]
+        fn test_written_calendar_has_required_properties() {
+            let cal = parse_file_to_ical_calendar(String::from("src/test/test_recurring.ics")).unwrap();
+            let mut events = extract_events_from_ical(cal, &MpTimeZone::utc()).unwrap();
+            events[0].uid = None;
+            let ics = deserialise_mpevents_to_ics_string(&events);
+            assert!(ics.starts_with("BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//moneypenny//moneypenny "));
+            assert!(ics.ends_with("END:VCALENDAR\r\n"));
+            assert!(!ics.replace("\r\n", "").contains('\n'));
+            assert!(ics.contains("@moneypenny\r\n"));
+            assert!(ics.contains("\r\nDTSTAMP:"));
+            assert!(ics.contains("DTSTART:20240101T090000Z\r\n"));
+            // Generated UIDs are stable between writes
+            assert_eq!(generate_uid(&events[0]), generate_uid(&events[0].clone()));
+            // and between builds, as they're the SHA-1 of the fields rather than std's unspecified hash
+            assert_eq!("6f0aed3d635e3821aa49cc1103da853b2db83538", stable_hash(&[Some(String::from("abc")), None]));
+        }
+
+        #[test]
+        fn test_written_calendar_round_trips() {
+            let new_york = MpTimeZone::from_iana_name("America/New_York").unwrap();
+            for fixture in &["src/test/test_timezones.ics", "src/test/test_overrides.ics", "src/test/test_all_day.ics"] {
+                let cal = parse_file_to_ical_calendar(String::from(*fixture)).unwrap();
+                let original = extract_events_from_ical(cal, &new_york).unwrap();
+                let ics = deserialise_mpevents_to_ics_string(&original);
+                let reread = reparse_ics_string(&ics, &new_york);
+                assert_eq!(original.len(), reread.len(), "{}", fixture);
+                for (before, after) in original.iter().zip(reread.iter()) {
+                    assert_eq!(before.name, after.name);
+                    assert_eq!(before.start_time, after.start_time);
+                    assert_eq!(before.end_time, after.end_time);
+                    assert_eq!(before.location, after.location);
+                    assert_eq!(before.description, after.description);
+                    assert_eq!(before.all_day, after.all_day);
+                    assert_eq!(before.rrule.as_ref().map(|rule| rule.to_string()), after.rrule.as_ref().map(|rule| rule.to_string()));
+                    assert_eq!(before.rdates, after.rdates);
+                    assert_eq!(before.exdates, after.exdates);
+                    assert_eq!(before.overrides.len(), after.overrides.len());
+                    assert_eq!(before.timezone.as_ref().map(|zone| zone.tzid()), after.timezone.as_ref().map(|zone| zone.tzid()));
+                }
+                // Expansion across the clock changes comes out identical from the written VTIMEZONEs
+                let window_start = DateTime::parse_from_rfc3339("2023-12-01T00:00:00+00:00").unwrap();
+                let window_end = DateTime::parse_from_rfc3339("2024-12-01T00:00:00+00:00").unwrap();
+                let starts = |events: Vec<MpEvent>| -> Vec<Option<DateTime<FixedOffset>>> {
+                    crate::mp_calendar::cal_ops::expand_mpevents_in_window(events, window_start, window_end)
+                        .iter().map(|event| event.start_time).collect()
+                };
+                assert_eq!(starts(original), starts(reread), "{}", fixture);
+            }
+        }
+
+        #[test]
+        fn test_duration_gives_the_end_and_is_written_back() {
+            let london = MpTimeZone::from_iana_name("Europe/London").unwrap();
+            // A day from noon before the clocks go forward is still noon, 23 hours later
+            let ics = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nBEGIN:VEVENT\r\nUID:trip@example.com\r\nSUMMARY:Trip\r\n\
+                       DTSTART;TZID=Europe/London:20240330T120000\r\nDURATION:P1DT2H\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n";
+            let events = reparse_ics_string(ics, &london);
+            assert_eq!(Some(DateTime::parse_from_rfc3339("2024-03-31T14:00:00+01:00").unwrap()), events[0].end_time);
+            let written = deserialise_mpevents_to_ics_string(&events);
+            assert!(written.contains("\r\nDURATION:P1DT2H\r\n"));
+            assert!(!written.contains("DTEND"));
+            assert_eq!(events[0].end_time, reparse_ics_string(&written, &london)[0].end_time);
+            // and with both, DTEND wins
+            let both = ics.replace("DURATION:P1DT2H", "DTEND;TZID=Europe/London:20240330T130000\r\nDURATION:P1DT2H");
+            assert_eq!(Some(DateTime::parse_from_rfc3339("2024-03-30T13:00:00+00:00").unwrap()), reparse_ics_string(&both, &london)[0].end_time);
+        }
+
+        #[test]
+        fn test_unknown_properties_are_kept() {
+            let cal = parse_file_to_ical_calendar(String::from("src/test/test_outlook.ics")).unwrap();
+            let events = extract_events_from_ical(cal, &MpTimeZone::utc()).unwrap();
+            let extras = &events[0].extra_properties;
+            let organizer = extras.iter().find(|prop| prop.name == "ORGANIZER").unwrap();
+            assert_eq!(vec![(String::from("CN"), vec![String::from("Doe, John")])], organizer.params);
+            assert_eq!(Some(String::from("mailto:john.doe@example.com")), organizer.value);
+            assert_eq!(2, extras.iter().filter(|prop| prop.name == "ATTENDEE").count());
+            assert!(extras.iter().any(|prop| prop.name == "X-MICROSOFT-CDO-BUSYSTATUS"));
+            // Known properties are interpreted rather than carried
+            assert!(!extras.iter().any(|prop| prop.name == "SUMMARY" || prop.name == "UID"));
+            let ics = deserialise_mpevents_to_ics_string(&events);
+            assert!(ics.contains("\r\nORGANIZER;CN=\"Doe, John\":mailto:john.doe@example.com\r\n"));
+            assert!(ics.contains("\r\nCATEGORIES:Finance,Planning\r\n"));
+        }
+
+        #[test]
+        fn test_real_world_calendars_round_trip_losslessly() {
+            for fixture in &["src/test/test_outlook.ics", "src/test/test_google.ics"] {
+                let cal = parse_file_to_ical_calendar(String::from(*fixture)).unwrap();
+                let original = extract_events_from_ical(cal, &MpTimeZone::utc()).unwrap();
+                let first_write = deserialise_mpevents_to_ics_string(&original);
+                let reread = reparse_ics_string(&first_write, &MpTimeZone::utc());
+                for (before, after) in original.iter().zip(reread.iter()) {
+                    assert_eq!(before.extra_properties, after.extra_properties, "{}", fixture);
+                    assert_eq!(before.description, after.description, "{}", fixture);
+                    assert_eq!(before.uid, after.uid, "{}", fixture);
+                    assert_eq!(before.dtstamp, after.dtstamp, "{}", fixture);
+                    for (over_before, over_after) in before.overrides.iter().zip(after.overrides.iter()) {
+                        assert_eq!(over_before.extra_properties, over_after.extra_properties, "{}", fixture);
+                    }
+                }
+                // Once written, a calendar is a fixed point of read then write
+                assert_eq!(first_write, deserialise_mpevents_to_ics_string(&reread), "{}", fixture);
+            }
+        }
+
+        #[test]
+        fn test_folding_never_splits_after_whitespace() {
+            let text = format!("{}{}", "x".repeat(60), " word".repeat(30));
+            let folded = fold_content_line(&text);
+            for physical_line in folded.split("\r\n") {
+                assert!(physical_line.len() <= 75);
+                assert!(!physical_line.ends_with(' '));
+            }
+            let mut ics = String::new();
+            push_content_line(&mut ics, "DESCRIPTION", &[], &text);
+            let reparsed = ical::property::PropertyParser::from_reader(std::io::BufReader::new(ics.as_bytes()))
+                .next().unwrap().unwrap();
+            assert_eq!(Some(text), reparsed.value);
+        }
+
+        #[test]
+        fn test_extract_tasks_from_ical() {
+            let cal = parse_file_to_ical_calendar(String::from("src/test/test_tasks.ics")).unwrap();
+            let london = MpTimeZone::from_iana_name("Europe/London").unwrap();
+            let events = extract_events_from_ical(cal.clone(), &london).unwrap();
+            let tasks = extract_tasks_from_ical(cal, &london).unwrap();
+            assert_eq!(1, events.len());
+            // The task with PRIORITY:12 is skipped
+            assert_eq!(4, tasks.len());
+            assert_eq!(Some("Write the announcement, blog and email"), tasks[1].name());
+            assert_eq!("2024-03-29T17:00:00+00:00", tasks[0].due().unwrap().to_rfc3339());
+            assert_eq!(Some(TaskStatus::InProcess), tasks[0].status());
+            assert_eq!(Some(30), tasks[0].percent_complete);
+            assert!(tasks[1].due_all_day);
+            assert!(tasks[2].is_complete());
+            assert!(tasks[1].is_subtask_of(&tasks[0]) && tasks[2].is_subtask_of(&tasks[0]));
+            // A sibling relation isn't a parent, so it's carried as an unknown property
+            assert!(!tasks[3].is_subtask_of(&tasks[1]));
+            assert_eq!("RELATED-TO", tasks[3].extra_properties[0].name);
+        }
+
+        #[test]
+        fn test_tasks_round_trip() {
+            let cal = parse_file_to_ical_calendar(String::from("src/test/test_tasks.ics")).unwrap();
+            let london = MpTimeZone::from_iana_name("Europe/London").unwrap();
+            let events = extract_events_from_ical(cal.clone(), &london).unwrap();
+            let mut tasks = extract_tasks_from_ical(cal, &london).unwrap();
+            tasks[1].mark_complete(DateTime::parse_from_rfc3339("2024-03-20T10:00:00+00:00").unwrap());
+            let ics = deserialise_calendar_to_ics_string(&events, &tasks);
+            assert!(ics.contains("\r\nDUE;TZID=Europe/London:20240329T170000\r\n"));
+            assert!(ics.contains("\r\nDUE;VALUE=DATE:20240322\r\n"));
+            assert!(ics.contains("\r\nCOMPLETED:20240320T100000Z\r\n"));
+            assert!(ics.contains("BEGIN:VTIMEZONE\r\nTZID:Europe/London\r\n"));
+            let reader = std::io::BufReader::new(ics.as_bytes());
+            let reread = ical::IcalParser::new(reader).next().unwrap().unwrap();
+            assert_eq!(1, extract_events_from_ical(reread.clone(), &london).unwrap().len());
+            let reread_tasks = extract_tasks_from_ical(reread, &london).unwrap();
+            assert_eq!(tasks.len(), reread_tasks.len());
+            for (before, after) in tasks.iter().zip(reread_tasks.iter()) {
+                assert_eq!(before.name, after.name);
+                assert_eq!(before.due, after.due);
+                assert_eq!(before.due_all_day, after.due_all_day);
+                assert_eq!(before.completed, after.completed);
+                assert_eq!(before.percent_complete, after.percent_complete);
+                assert_eq!(before.priority, after.priority);
+                assert_eq!(before.status, after.status);
+                assert_eq!(before.related_to, after.related_to);
+                assert_eq!(before.extra_properties, after.extra_properties);
+            }
+        }
+
+        #[test]
+        fn test_alarms_are_read_and_written() {
+            use crate::mp_calendar::cal_alarm::*;
+            let cal = parse_file_to_ical_calendar(String::from("src/test/test_alarms.ics")).unwrap();
+            let events = extract_events_from_ical(cal, &MpTimeZone::utc()).unwrap();
+            let standup = &events[0].alarms[0];
+            assert_eq!(AlarmTrigger::Relative { offset: chrono::Duration::minutes(-15), from_end: false }, standup.trigger);
+            assert_eq!((2, Some(chrono::Duration::minutes(5))), (standup.repeat, standup.repeat_interval));
+            assert_eq!(AlarmAction::Audio, events[0].overrides[0].alarms[0].action);
+            // The alarm with an unreadable TRIGGER is dropped, the event and its other alarms are kept
+            let deadline = &events[1].alarms;
+            assert_eq!(2, deadline.len());
+            assert_eq!(AlarmTrigger::Absolute(DateTime::parse_from_rfc3339("2024-01-09T08:00:00+00:00").unwrap()), deadline[0].trigger);
+            assert_eq!(AlarmTrigger::Relative { offset: chrono::Duration::zero(), from_end: true }, deadline[1].trigger);
+            assert_eq!(AlarmAction::Email, deadline[1].action);
+            let ics = deserialise_mpevents_to_ics_string(&events);
+            assert!(ics.contains("BEGIN:VALARM\r\nACTION:DISPLAY\r\nTRIGGER:-PT15M\r\nDESCRIPTION:Reminder\r\nREPEAT:2\r\nDURATION:PT5M\r\nEND:VALARM\r\n"));
+            assert!(ics.contains("TRIGGER;VALUE=DATE-TIME:20240109T080000Z\r\n"));
+            assert!(ics.contains("TRIGGER;RELATED=END:PT0S\r\n"));
+            let reread = reparse_ics_string(&ics, &MpTimeZone::utc());
+            assert_eq!(events[0].alarms, reread[0].alarms);
+            assert_eq!(events[0].overrides[0].alarms, reread[0].overrides[0].alarms);
+            assert_eq!(events[1].alarms, reread[1].alarms);
+        }
+
+        #[test]
+        fn test_deserialise_busy_periods_to_vfreebusy() {
+            use crate::mp_calendar::cal_ops::busy_periods_in_window;
+            let cal = parse_file_to_ical_calendar(String::from("src/test/test_freebusy.ics")).unwrap();
+            let events = extract_events_from_ical(cal, &MpTimeZone::utc()).unwrap();
+            let window_start = DateTime::parse_from_rfc3339("2024-01-08T00:00:00+00:00").unwrap();
+            let window_end = DateTime::parse_from_rfc3339("2024-01-10T00:00:00+01:00").unwrap();
+            let periods = busy_periods_in_window(&events, window_start, window_end);
+            let ics = deserialise_busy_periods_to_vfreebusy_string(&periods, window_start, window_end, Some("mailto:me@example.com"));
+            assert!(ics.contains("\r\nBEGIN:VFREEBUSY\r\n"));
+            assert!(ics.contains("\r\nDTSTART:20240108T000000Z\r\nDTEND:20240109T230000Z\r\n"));
+            assert!(ics.contains("\r\nORGANIZER:mailto:me@example.com\r\n"));
+            let unfolded = ics.replace("\r\n ", "");
+            assert!(unfolded.contains("\r\nFREEBUSY;FBTYPE=BUSY:20240108T090000Z/20240108T110000Z,20240109T090000Z/20240109T091500Z,20240109T120000Z/20240109T133000Z,20240109T150000Z/20240109T200000Z\r\n"));
+            assert!(unfolded.contains("\r\nFREEBUSY;FBTYPE=BUSY-TENTATIVE:20240108T110000Z/20240108T130000Z\r\n"));
+            // Nothing about the meetings themselves is published
+            assert!(!ics.contains("SUMMARY") && !ics.contains("Standup") && !ics.contains("fb-"));
+        }
+
+        #[test]
+        fn test_convert_fixed_offset_to_ical_time() {
+            let time_fixedoff = DateTime::parse_from_rfc3339(&String::from("2013-08-02T20:00:00-00:00")).unwrap();
+            let expected_ical_time = String::from("20130802T200000");
+            let test_ical_time = convert_fixed_offset_to_ical_time(time_fixedoff);
+            assert_eq!(expected_ical_time, test_ical_time);
+        }
+    }
+}
+
+pub mod cal_ops {
+    use super::{MpEvent, DateTime, FixedOffset, NaiveDate, NaiveTime, Weekday, Datelike, EventStatus};
+    use super::cal_tz::MpTimeZone;
+    use chrono::Duration;
+    use super::cal_task::MpTask;
+    use super::cal_merge;
+    use super::cal_query::EventQuery;
+    use super::cal_index::MpEventIndex;
+    use std::collections::HashMap;
+
+    /// By default .sort() uses partial_cmp, this uses cmp for comparison by total ordering (Ord not PartialOrd)
+    #[allow(clippy::unnecessary_sort_by)]
+    pub fn sort_mpevents_chronologically_by_start(mut events: Vec<MpEvent>) -> Vec<MpEvent> {
+        events.sort_by(|a, b| a.cmp(b));
+        return events;
+    }
+
+    /// Replaces every recurring event with its concrete occurrences in [window_start, window_end),
+    /// drops events outside the window, and returns the result in chronological order
+    pub fn expand_mpevents_in_window(events: Vec<MpEvent>,
+                                     window_start: DateTime<FixedOffset>,
+                                     window_end: DateTime<FixedOffset>) -> Vec<MpEvent> {
+        let expanded: Vec<MpEvent> = events.iter()
+            .flat_map(|event| event.expand_occurrences(window_start, window_end))
+            .collect();
+        return sort_mpevents_chronologically_by_start(expanded);
+    }
+
+    /// The events query matches, in the order given. Recurring events are matched as a whole, by
+    /// their first occurrence; expand them first to match each occurrence.
+    pub fn filter_mpevents(events: &[MpEvent], query: &EventQuery) -> Vec<MpEvent> {
+        return events.iter().filter(|event| query.matches(event)).cloned().collect();
+    }
+
+    /// The occurrences in the query's window that match it, in chronological order. Bounds the
+    /// query doesn't give come from [default_start, default_end).
+    pub fn query_mpevents(events: Vec<MpEvent>,
+                          query: &EventQuery,
+                          default_start: DateTime<FixedOffset>,
+                          default_end: DateTime<FixedOffset>) -> Vec<MpEvent> {
+        let (window_start, window_end) = query.window(default_start, default_end);
+        return filter_mpevents(&expand_mpevents_in_window(events, window_start, window_end), query);
+    }
+
+    /// A group of events that are double booked against each other, with every overlapping pair
+    /// as indices into events and the length of time the pair overlaps
+    #[derive(Debug, PartialEq, Eq, Clone)]
+    pub struct EventConflict {
+        pub events: Vec<MpEvent>,
+        // Where each of events is in the slice given to find_conflicts, so callers can tell apart
+        // events that are equal, such as two untitled events at the same time
+        pub indices: Vec<usize>,
+        pub overlaps: Vec<(usize, usize, Duration)>
+    }
+
+    /// Scans chronologically sorted events (already expanded, as by expand_mpevents_in_window) and
+    /// returns each group of events linked by overlaps, in order. Events that only touch aren't a
+    /// conflict, and neither are transparent or cancelled events.
+    pub fn find_conflicts(sorted_events: &[MpEvent]) -> Vec<EventConflict> {
+        let source_index: Vec<usize> = (0..sorted_events.len())
+            .filter(|index| !sorted_events[*index].transparent && sorted_events[*index].status != Some(EventStatus::Cancelled))
+            .filter(|index| sorted_events[*index].start_time.is_some() && sorted_events[*index].end_time.is_some())
+            .collect();
+        let candidates: Vec<&MpEvent> = source_index.iter().map(|index| &sorted_events[*index]).collect();
+        // group_of[i] is the index in conflicts of the group candidates[i] has joined, if any
+        let mut group_of: Vec<Option<usize>> = vec![None; candidates.len()];
+        let mut groups: Vec<Vec<(usize, usize, Duration)>> = vec![];
+        for i in 0..candidates.len() {
+            for j in (i + 1)..candidates.len() {
+                if !candidates[i].ordered_has_overlap(candidates[j]) {
+                    // Later events start later still, so can't overlap i either
+                    if candidates[j].start_time >= candidates[i].end_time {
+                        break;
+                    }
+                    continue;
+                }
+                let overlap_end = candidates[i].end_time.min(candidates[j].end_time).unwrap();
+                let overlap = overlap_end - candidates[j].start_time.unwrap();
+                if overlap <= Duration::zero() {
+                    continue;
+                }
+                let group = match (group_of[i], group_of[j]) {
+                    (Some(group_i), Some(group_j)) if group_i != group_j => {
+                        // j already links to another group, so the two become one
+                        let moved = std::mem::take(&mut groups[group_j]);
+                        groups[group_i].extend(moved);
+                        for member in group_of.iter_mut() {
+                            if *member == Some(group_j) {
+                                *member = Some(group_i);
+                            }
+                        }
+                        group_i
+                    }
+                    (Some(group), _) | (None, Some(group)) => group,
+                    (None, None) => {
+                        groups.push(vec![]);
+                        groups.len() - 1
+                    }
+                };
+                group_of[i] = Some(group);
+                group_of[j] = Some(group);
+                groups[group].push((i, j, overlap));
+            }
+        }
+        let mut conflicts: Vec<EventConflict> = vec![];
+        for (group, pairs) in groups.iter().enumerate() {
+            if pairs.is_empty() {
+                continue;
+            }
+            let members: Vec<usize> = (0..candidates.len()).filter(|index| group_of[*index] == Some(group)).collect();
+            let position = |index: usize| members.iter().position(|member| *member == index).unwrap();
+            let mut overlaps: Vec<(usize, usize, Duration)> = pairs.iter()
+                .map(|(i, j, overlap)| (position(*i), position(*j), *overlap))
+                .collect();
+            overlaps.sort_by_key(|(i, j, _)| (*i, *j));
+            let events = members.iter().map(|index| candidates[*index].clone()).collect();
+            let indices = members.iter().map(|index| source_index[*index]).collect();
+            conflicts.push(EventConflict { events, indices, overlaps });
+        }
+        conflicts.sort_by(|lhs, rhs| lhs.events[0].cmp(&rhs.events[0]));
+        return conflicts;
+    }
+
+    /// Expands recurrences in [window_start, window_end) and reports the conflicts among them
+    pub fn find_conflicts_in_window(events: &[MpEvent],
+                                    window_start: DateTime<FixedOffset>,
+                                    window_end: DateTime<FixedOffset>) -> Vec<EventConflict> {
+        return find_conflicts(&expand_mpevents_in_window(events.to_vec(), window_start, window_end));
+    }
+
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    pub enum FreeBusyType {
+        Busy,
+        BusyTentative
+    }
+
+    impl FreeBusyType {
+        pub fn to_ical_str(self) -> &'static str {
+            match self {
+                FreeBusyType::Busy => return "BUSY",
+                FreeBusyType::BusyTentative => return "BUSY-TENTATIVE"
+            }
+        }
+    }
+
+    /// A stretch of time someone is busy, with nothing about what they're busy with
+    #[derive(Debug, PartialEq, Eq, Clone)]
+    pub struct BusyPeriod {
+        pub start: DateTime<FixedOffset>,
+        pub end: DateTime<FixedOffset>,
+        pub fb_type: FreeBusyType
+    }
+
+    /// Merges the events occurring in [window_start, window_end) into non-overlapping busy periods,
+    /// clipped to the window and in order. Transparent and cancelled events don't count, and neither do
+    /// events with no duration. Tentative events make BUSY-TENTATIVE periods, but only where there's
+    /// no confirmed BUSY period at the same time.
+    pub fn busy_periods_in_window(events: &[MpEvent],
+                                  window_start: DateTime<FixedOffset>,
+                                  window_end: DateTime<FixedOffset>) -> Vec<BusyPeriod> {
+        let occupying: Vec<MpEvent> = expand_mpevents_in_window(events.to_vec(), window_start, window_end).into_iter()
+            .filter(|event| !event.transparent && event.status != Some(EventStatus::Cancelled))
+            .filter(|event| matches!((event.start_time, event.end_time), (Some(start), Some(end)) if end > start))
+            .collect();
+        let (tentative, confirmed): (Vec<MpEvent>, Vec<MpEvent>) = occupying.into_iter()
+            .partition(|event| event.status == Some(EventStatus::Tentative));
+        let busy = merge_overlapping_events(confirmed);
+        let mut periods: Vec<BusyPeriod> = vec![];
+        for merged in &busy {
+            periods.push(BusyPeriod { start: merged.start_time.unwrap(), end: merged.end_time.unwrap(), fb_type: FreeBusyType::Busy });
+        }
+        for merged in merge_overlapping_events(tentative) {
+            for (start, end) in subtract_events(&merged, &busy) {
+                periods.push(BusyPeriod { start, end, fb_type: FreeBusyType::BusyTentative });
+            }
+        }
+        for period in periods.iter_mut() {
+            period.start = period.start.max(window_start);
+            period.end = period.end.min(window_end);
+        }
+        periods.sort_by_key(|period| period.start);
+        return periods;
+    }
+
+    /// Folds chronologically sorted events into one event per run of overlapping or touching events.
+    /// The merged events only have start and end times.
+    fn merge_overlapping_events(events: Vec<MpEvent>) -> Vec<MpEvent> {
+        let mut merged: Vec<MpEvent> = vec![];
+        for event in sort_mpevents_chronologically_by_start(events) {
+            let span = MpEvent { start_time: event.start_time, end_time: event.end_time, ..Default::default() };
+            match merged.last_mut() {
+                Some(last) if last.ordered_has_overlap(&span) => {
+                    last.end_time = last.end_time.max(span.end_time);
+                }
+                _ => merged.push(span)
+            };
+        }
+        return merged;
+    }
+
+    /// The parts of event's span not covered by any of the sorted, non-overlapping covers
+    fn subtract_events(event: &MpEvent, covers: &[MpEvent]) -> Vec<(DateTime<FixedOffset>, DateTime<FixedOffset>)> {
+        let mut remaining: Vec<(DateTime<FixedOffset>, DateTime<FixedOffset>)> = vec![];
+        let (mut start, end) = (event.start_time.unwrap(), event.end_time.unwrap());
+        for cover in covers {
+            let (cover_start, cover_end) = (cover.start_time.unwrap(), cover.end_time.unwrap());
+            if cover_end <= start || cover_start >= end {
+                continue;
+            }
+            if cover_start > start {
+                remaining.push((start, cover_start));
+            }
+            start = start.max(cover_end);
+        }
+        if start < end {
+            remaining.push((start, end));
+        }
+        return remaining;
+    }
+
+    /// What find_meeting_slots looks for. new() gives 09:00 to 17:00 UTC, Monday to Friday, on the
+    /// quarter hour with no buffers; the fields can be changed before searching.
+    #[derive(Debug, Clone)]
+    pub struct SlotRequest {
+        pub duration: Duration,
+        pub window_start: DateTime<FixedOffset>,
+        pub window_end: DateTime<FixedOffset>,
+        // Local times in zone that each working day starts and ends at
+        pub working_hours: (NaiveTime, NaiveTime),
+        pub working_days: Vec<Weekday>,
+        pub zone: MpTimeZone,
+        // Free time needed either side of the meeting
+        pub buffer_before: Duration,
+        pub buffer_after: Duration,
+        // Candidate start times are this far apart
+        pub step: Duration
+    }
+
+    impl SlotRequest {
+        pub fn new(duration: Duration, window_start: DateTime<FixedOffset>, window_end: DateTime<FixedOffset>) -> SlotRequest {
+            return SlotRequest {
+                duration,
+                window_start,
+                window_end,
+                working_hours: (NaiveTime::from_hms(9, 0, 0), NaiveTime::from_hms(17, 0, 0)),
+                working_days: vec![Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri],
+                zone: MpTimeZone::utc(),
+                buffer_before: Duration::zero(),
+                buffer_after: Duration::zero(),
+                step: Duration::minutes(15)
+            };
+        }
+    }
+
+    /// A time everyone is free, apart from the calendars (by index) with a tentative event then
+    #[derive(Debug, PartialEq, Eq, Clone)]
+    pub struct CandidateSlot {
+        pub start: DateTime<FixedOffset>,
+        pub end: DateTime<FixedOffset>,
+        pub tentative_conflicts: Vec<usize>
+    }
+
+    /// Finds slots within working hours where no calendar has a confirmed event overlapping the
+    /// meeting or its buffers. Tentative events are soft conflicts: slots that clash with them are
+    /// still offered, ranked below slots with fewer soft conflicts. Ties are broken by start time.
+    pub fn find_meeting_slots(calendars: &[Vec<MpEvent>], request: &SlotRequest) -> Vec<CandidateSlot> {
+        if request.duration <= Duration::zero() || request.step <= Duration::zero() {
+            return vec![];
+        }
+        let padded_start = request.window_start - request.buffer_before;
+        let padded_end = request.window_end + request.buffer_after;
+        // Every step of every working day asks each calendar what it has on, so index them
+        let occupied: Vec<MpEventIndex> = calendars.iter()
+            .map(|events| MpEventIndex::from_events(expand_mpevents_in_window(events.clone(), padded_start, padded_end).into_iter()
+                .filter(|event| !event.transparent && event.status != Some(EventStatus::Cancelled))
+                .collect()))
+            .collect();
+
+        let mut slots: Vec<CandidateSlot> = vec![];
+        let mut day = request.zone.convert(request.window_start).date().naive_local();
+        let last_day = request.zone.convert(request.window_end).date().naive_local();
+        while day <= last_day {
+            if request.working_days.contains(&day.weekday()) {
+                let day_start = request.zone.from_local_datetime(day.and_time(request.working_hours.0));
+                let day_end = request.zone.from_local_datetime(day.and_time(request.working_hours.1));
+                let mut start = day_start.max(request.window_start);
+                while start + request.duration <= day_end.min(request.window_end) {
+                    match slot_conflicts(&occupied, start, start + request.duration, request) {
+                        Some(tentative_conflicts) => {
+                            slots.push(CandidateSlot { start, end: start + request.duration, tentative_conflicts });
+                        }
+                        None => ()
+                    };
+                    start = start + request.step;
+                }
+            }
+            day = day.succ();
+        }
+        slots.sort_by(|lhs, rhs| lhs.tentative_conflicts.len().cmp(&rhs.tentative_conflicts.len())
+            .then_with(|| lhs.start.cmp(&rhs.start)));
+        return slots;
+    }
+
+    /// None if any calendar has a hard conflict with the padded slot, otherwise the calendars with soft ones
+    fn slot_conflicts(occupied: &[MpEventIndex], start: DateTime<FixedOffset>, end: DateTime<FixedOffset>,
+                      request: &SlotRequest) -> Option<Vec<usize>> {
+        let (padded_start, padded_end) = (start - request.buffer_before, end + request.buffer_after);
+        let mut tentative_conflicts: Vec<usize> = vec![];
+        for (index, events) in occupied.iter().enumerate() {
+            for event in events.intersecting(padded_start, padded_end) {
+                if event.status != Some(EventStatus::Tentative) {
+                    return None;
+                }
+                if !tentative_conflicts.contains(&index) {
+                    tentative_conflicts.push(index);
+                }
+            }
+        }
+        return Some(tentative_conflicts);
+    }
+
+    /// Earliest due first, then highest priority; see MpTask::cmp_due_then_priority
+    pub fn sort_mptasks_by_due_and_priority(mut tasks: Vec<MpTask>) -> Vec<MpTask> {
+        tasks.sort_by(|lhs, rhs| lhs.cmp_due_then_priority(rhs));
+        return tasks;
+    }
+
+    /// The tasks whose RELATED-TO names parent
+    pub fn subtasks_of<'a>(tasks: &'a [MpTask], parent: &MpTask) -> Vec<&'a MpTask> {
+        return tasks.iter().filter(|task| task.is_subtask_of(parent)).collect();
+    }
+
+    /// One way an event differs between two snapshots of a calendar. An event that both moved and
+    /// changed room gives a Rescheduled and a Relocated change.
+    #[derive(Debug, PartialEq, Eq, Clone)]
+    pub enum EventChange {
+        Added(MpEvent),
+        Removed(MpEvent),
+        // Its start or end moved, or for a recurring event its RRULE, RDATEs or EXDATEs changed
+        Rescheduled { before: MpEvent, after: MpEvent },
+        Relocated { before: MpEvent, after: MpEvent },
+        StatusChanged { before: MpEvent, after: MpEvent }
+    }
+
+    impl EventChange {
+        /// The event as it was and as it is, None for the side it's missing from
+        pub fn before_and_after(&self) -> (Option<&MpEvent>, Option<&MpEvent>) {
+            match self {
+                EventChange::Added(event) => return (None, Some(event)),
+                EventChange::Removed(event) => return (Some(event), None),
+                EventChange::Rescheduled { before, after }
+                | EventChange::Relocated { before, after }
+                | EventChange::StatusChanged { before, after } => return (Some(before), Some(after))
+            }
+        }
+
+        /// The event as it is now, or as it was if it's been removed
+        pub fn event(&self) -> &MpEvent {
+            match self.before_and_after() {
+                (_, Some(after)) => return after,
+                (before, None) => return before.unwrap()
+            }
+        }
+
+        pub fn kind(&self) -> &'static str {
+            match self {
+                EventChange::Added(_) => return "added",
+                EventChange::Removed(_) => return "removed",
+                EventChange::Rescheduled { .. } => return "rescheduled",
+                EventChange::Relocated { .. } => return "relocated",
+                EventChange::StatusChanged { .. } => return "status_changed"
+            }
+        }
+    }
+
+    #[derive(PartialEq, Eq, Hash)]
+    enum DiffKey {
+        Uid(cal_merge::UidKey),
+        NameAndStart(cal_merge::NameAndStartKey)
+    }
+
+    fn diff_key(event: &MpEvent) -> Option<DiffKey> {
+        match cal_merge::uid_key(event) {
+            Some(key) => return Some(DiffKey::Uid(key)),
+            None => return cal_merge::name_and_start_key(event).map(DiffKey::NameAndStart)
+        }
+    }
+
+    // Every event, and every RECURRENCE-ID override of a recurring one, with the key it's matched by
+    fn diff_items(events: &[MpEvent]) -> Vec<(DiffKey, &MpEvent)> {
+        let mut items: Vec<(DiffKey, &MpEvent)> = vec![];
+        for event in events.iter().flat_map(|event| std::iter::once(event).chain(event.overrides.iter())) {
+            match diff_key(event) {
+                Some(key) => items.push((key, event)),
+                None => ()
+            };
+        }
+        return items;
+    }
+
+    // The occurrence the recurring event in events that instance belongs to has at instance's
+    // RECURRENCE-ID, as if nothing overrode it
+    fn regular_instance_of(events: &[MpEvent], instance: &MpEvent) -> Option<MpEvent> {
+        let recurrence_id = instance.recurrence_id?;
+        let series = events.iter().find(|event| event.recurrence_id.is_none() && event.uid.is_some() && event.uid == instance.uid)?;
+        let plain_series = MpEvent { overrides: vec![], ..series.clone() };
+        return plain_series.expand_occurrences(recurrence_id, recurrence_id + Duration::seconds(1)).into_iter()
+            .find(|occurrence| occurrence.recurrence_id == Some(recurrence_id));
+    }
+
+    fn compare_events(before: &MpEvent, after: &MpEvent) -> Vec<EventChange> {
+        let mut changes: Vec<EventChange> = vec![];
+        let moved = before.start_time != after.start_time || before.end_time != after.end_time || before.all_day != after.all_day;
+        let recurrence_changed = before.rrule != after.rrule || before.rdates != after.rdates || before.exdates != after.exdates;
+        if moved || recurrence_changed {
+            changes.push(EventChange::Rescheduled { before: before.clone(), after: after.clone() });
+        }
+        if before.location != after.location {
+            changes.push(EventChange::Relocated { before: before.clone(), after: after.clone() });
+        }
+        if before.status != after.status {
+            changes.push(EventChange::StatusChanged { before: before.clone(), after: after.clone() });
+        }
+        return changes;
+    }
+
+    /// Compares two snapshots of a calendar, e.g. yesterday's export and today's. Events are matched
+    /// by UID and RECURRENCE-ID, or by name and start time if they have no UID, so a moved event
+    /// without a UID shows as removed and added. An instance of a recurring event that gains or loses
+    /// an override is compared with where the series puts it. Events with no UID, name or start can't
+    /// be matched and are left out. Changes are in order of when the event now starts, or started if
+    /// it was removed.
+    pub fn diff_calendars(old: &[MpEvent], new: &[MpEvent]) -> Vec<EventChange> {
+        let old_items = diff_items(old);
+        let new_items = diff_items(new);
+        let old_by_key: HashMap<&DiffKey, &MpEvent> = old_items.iter().map(|(key, event)| (key, *event)).collect();
+        let new_by_key: HashMap<&DiffKey, &MpEvent> = new_items.iter().map(|(key, event)| (key, *event)).collect();
+
+        let mut changes: Vec<EventChange> = vec![];
+        for (key, after) in &new_items {
+            let before = match old_by_key.get(key) {
+                Some(before) => Some((*before).clone()),
+                None => regular_instance_of(old, after)
+            };
+            match before {
+                Some(before) => changes.extend(compare_events(&before, after)),
+                None => changes.push(EventChange::Added((*after).clone()))
+            };
+        }
+        for (key, before) in &old_items {
+            if new_by_key.contains_key(key) {
+                continue;
+            }
+            match regular_instance_of(new, before) {
+                Some(after) => changes.extend(compare_events(before, &after)),
+                None => changes.push(EventChange::Removed((*before).clone()))
+            };
+        }
+        changes.sort_by_key(|change| change.event().start_time);
+        return changes;
+    }
+
+    /// The changes as a JSON array with an object for each: its kind ("added", "removed",
+    /// "rescheduled", "relocated" or "status_changed"), the event's uid, recurrence_id and name, and
+    /// its start, end, location and status before and after. before is null for an added event and
+    /// after for a removed one. Times are RFC 3339.
+    pub fn calendar_diff_to_json(changes: &[EventChange]) -> String {
+        use serde_json::{json, Value};
+
+        let rfc3339 = |time: Option<DateTime<FixedOffset>>| time.map(|time| time.to_rfc3339());
+        let snapshot = |event: Option<&MpEvent>| match event {
+            Some(event) => json!({
+                "start": rfc3339(event.start_time),
+                "end": rfc3339(event.end_time),
+                "location": event.location,
+                "status": event.status.map(EventStatus::to_ical_str)
+            }),
+            None => Value::Null
+        };
+        let entries: Vec<Value> = changes.iter()
+            .map(|change| {
+                let event = change.event();
+                let (before, after) = change.before_and_after();
+                json!({
+                    "change": change.kind(),
+                    "uid": event.uid,
+                    "recurrence_id": rfc3339(event.recurrence_id),
+                    "name": event.name,
+                    "before": snapshot(before),
+                    "after": snapshot(after)
+                })
+            })
+            .collect();
+        return Value::Array(entries).to_string();
+    }
+
+    /// One calendar's events as drawn by the week and month views, with its configured colour
+    /// (#rgb or #rrggbb)
+    #[derive(Debug, PartialEq, Eq, Clone, Default)]
+    pub struct GridCalendar {
+        pub events: Vec<MpEvent>,
+        pub colour: Option<String>
+    }
+
+    // Characters across a day in either view, not counting the '|' between days
+    const GRID_DAY_WIDTH: usize = 14;
+    // Week view rows are half hours, and at least 08:00 to 18:00 is always shown
+    const WEEK_ROWS_PER_DAY: i64 = 48;
+    const WEEK_DEFAULT_ROWS: (i64, i64) = (16, 36);
+    // Events listed in a month view cell before the rest are counted as "+N more"
+    const MONTH_CELL_LINES: usize = 3;
+
+    /// The Monday to Sunday week containing date
+    pub fn week_grid_days(date: NaiveDate) -> Vec<NaiveDate> {
+        let monday = date - Duration::days(date.weekday().num_days_from_monday() as i64);
+        return (0..7).map(|offset| monday + Duration::days(offset)).collect();
+    }
+
+    /// The whole Monday to Sunday weeks covering date's month
+    pub fn month_grid_days(date: NaiveDate) -> Vec<NaiveDate> {
+        let first = NaiveDate::from_ymd(date.year(), date.month(), 1);
+        let next_first = match date.month() {
+            12 => NaiveDate::from_ymd(date.year() + 1, 1, 1),
+            month => NaiveDate::from_ymd(date.year(), month + 1, 1)
+        };
+        let start = week_grid_days(first)[0];
+        let end = week_grid_days(next_first.pred())[6];
+        return (0..=(end - start).num_days()).map(|offset| start + Duration::days(offset)).collect();
+    }
+
+    /// Local midnight at the start of day and of the day after
+    pub fn grid_day_bounds(day: NaiveDate, local_zone: &MpTimeZone) -> (DateTime<FixedOffset>, DateTime<FixedOffset>) {
+        return (local_zone.from_local_datetime(day.and_hms(0, 0, 0)), local_zone.from_local_datetime(day.succ().and_hms(0, 0, 0)));
+    }
+
+    // The occurrences on days that aren't cancelled, each with its calendar's colour and whether it's
+    // double booked, in the order of sort_mpevents_chronologically_by_start
+    fn grid_occurrences<'a>(calendars: &'a [GridCalendar],
+                            days: &[NaiveDate],
+                            local_zone: &MpTimeZone) -> Vec<(MpEvent, Option<&'a str>, bool)> {
+        let (window_start, _) = grid_day_bounds(days[0], local_zone);
+        let (_, window_end) = grid_day_bounds(days[days.len() - 1], local_zone);
+        let mut occurrences: Vec<(MpEvent, Option<&str>)> = calendars.iter()
+            .flat_map(|calendar| expand_mpevents_in_window(calendar.events.clone(), window_start, window_end)
+                .into_iter()
+                .filter(|event| event.status != Some(EventStatus::Cancelled))
+                .map(move |event| (event, calendar.colour.as_deref())))
+            .collect();
+        occurrences.sort_by(|lhs, rhs| lhs.0.cmp(&rhs.0));
+        let sorted: Vec<MpEvent> = occurrences.iter().map(|(event, _)| event.clone()).collect();
+        let mut double_booked = vec![false; sorted.len()];
+        for conflict in find_conflicts(&sorted) {
+            for index in conflict.indices {
+                double_booked[index] = true;
+            }
+        }
+        return occurrences.into_iter().zip(double_booked).map(|((event, colour), flagged)| (event, colour, flagged)).collect();
+    }
+
+    // The event's name, marked with '!' if it's double booked
+    fn grid_title(event: &MpEvent, double_booked: bool) -> String {
+        let name = super::name_of(event);
+        if double_booked {
+            return format!("!{}", name);
+        }
+        return name;
+    }
+
+    // text cut or padded to exactly width characters, ending in '…' if cut
+    pub(super) fn fit_to_width(text: &str, width: usize) -> String {
+        if text.chars().count() <= width {
+            return format!("{:<width$}", text, width = width);
+        }
+        let mut cut: String = text.chars().take(width.saturating_sub(1)).collect();
+        cut.push('…');
+        return cut;
+    }
+
+    // text in a #rgb or #rrggbb colour as an ANSI 24-bit escape, or unchanged without one
+    fn paint(text: String, colour: Option<&str>, use_colour: bool) -> String {
+        let hex = match colour.and_then(|colour| colour.strip_prefix('#')) {
+            Some(hex) if use_colour => hex,
+            _ => return text
+        };
+        let channels: Option<Vec<u8>> = match hex.len() {
+            3 => hex.chars().map(|digit| u8::from_str_radix(&digit.to_string().repeat(2), 16).ok()).collect(),
+            6 => (0..3).map(|channel| u8::from_str_radix(hex.get(channel * 2..channel * 2 + 2)?, 16).ok()).collect(),
+            _ => None
+        };
+        match channels {
+            Some(rgb) => return format!("\x1b[38;2;{};{};{}m{}\x1b[0m", rgb[0], rgb[1], rgb[2], text),
+            None => return text
+        }
+    }
+
+    // A timed event's place in one day of the week view: the rows it covers, its lane, and how many
+    // lanes the group of events it shares rows with is split into
+    struct WeekSlot<'a> {
+        event: &'a MpEvent,
+        colour: Option<&'a str>,
+        double_booked: bool,
+        first_row: i64,
+        end_row: i64,
+        lane: usize,
+        lanes: usize
+    }
+
+    // Puts chronologically ordered rows side by side: each event takes the first lane that's free by
+    // its first row, and every event in a group linked by shared rows gets the group's lane count
+    fn lay_out_week_day<'a>(timed: Vec<(&'a MpEvent, Option<&'a str>, bool, i64, i64)>) -> Vec<WeekSlot<'a>> {
+        let mut slots: Vec<WeekSlot> = vec![];
+        let mut group_start = 0;
+        let mut group_end_row = i64::MIN;
+        let mut lane_ends: Vec<i64> = vec![];
+        for (event, colour, double_booked, first_row, end_row) in timed {
+            if first_row >= group_end_row {
+                for slot in slots[group_start..].iter_mut() {
+                    slot.lanes = lane_ends.len();
+                }
+                group_start = slots.len();
+                lane_ends.clear();
+            }
+            let lane = match lane_ends.iter().position(|lane_end| *lane_end <= first_row) {
+                Some(lane) => {
+                    lane_ends[lane] = end_row;
+                    lane
+                },
+                None => {
+                    lane_ends.push(end_row);
+                    lane_ends.len() - 1
+                }
+            };
+            group_end_row = group_end_row.max(end_row);
+            slots.push(WeekSlot { event, colour, double_booked, first_row, end_row, lane, lanes: 0 });
+        }
+        for slot in slots[group_start..].iter_mut() {
+            slot.lanes = lane_ends.len();
+        }
+        return slots;
+    }
+
+    // One day's cell in a week view row. An event's title is on its first row and a bar continues it
+    // below; lanes too narrow to fit are left out.
+    fn week_cell(slots: &[WeekSlot], row: i64, use_colour: bool) -> String {
+        let covering: Vec<&WeekSlot> = slots.iter().filter(|slot| slot.first_row <= row && row < slot.end_row).collect();
+        let lanes = match covering.first() {
+            Some(slot) => slot.lanes,
+            None => return " ".repeat(GRID_DAY_WIDTH)
+        };
+        let lane_width = ((GRID_DAY_WIDTH + 1) / lanes).max(2) - 1;
+        let mut cell = String::new();
+        let mut used = 0;
+        for lane in 0..lanes {
+            let separator = if lane == 0 { 0 } else { 1 };
+            if used + separator + lane_width > GRID_DAY_WIDTH {
+                break;
+            }
+            cell.push_str(&" ".repeat(separator));
+            match covering.iter().find(|slot| slot.lane == lane) {
+                Some(slot) if slot.first_row == row => cell.push_str(&paint(fit_to_width(&grid_title(slot.event, slot.double_booked), lane_width), slot.colour, use_colour)),
+                Some(slot) => cell.push_str(&paint(fit_to_width("│", lane_width), slot.colour, use_colour)),
+                None => cell.push_str(&" ".repeat(lane_width))
+            };
+            used += separator + lane_width;
+        }
+        cell.push_str(&" ".repeat(GRID_DAY_WIDTH - used));
+        return cell;
+    }
+
+    /// The Monday to Sunday week containing date as a text grid: a column per day, a row per half
+    /// hour in local_zone, and a row above for each all-day event. Overlapping events are put side by
+    /// side, long titles are cut to fit, and double bookings are marked with '!'. Events are drawn in
+    /// their calendar's colour if use_colour is set. Cancelled events are left out.
+    pub fn render_week_view(calendars: &[GridCalendar], date: NaiveDate, local_zone: &MpTimeZone, use_colour: bool) -> String {
+        let days = week_grid_days(date);
+        let occurrences = grid_occurrences(calendars, &days, local_zone);
+        let mut all_day: Vec<Vec<(&MpEvent, Option<&str>, bool)>> = vec![];
+        let mut timed: Vec<Vec<WeekSlot>> = vec![];
+        for day in &days {
+            let (day_start, day_end) = grid_day_bounds(*day, local_zone);
+            let row_of = |time: DateTime<FixedOffset>| (local_zone.convert(time).naive_local() - day.and_hms(0, 0, 0)).num_minutes();
+            let mut all_day_events = vec![];
+            let mut timed_events = vec![];
+            for (event, colour, double_booked) in occurrences.iter().filter(|(event, _, _)| event.intersects_window(day_start, day_end)) {
+                // Anything placed in the window has a start
+                let start = event.start_time.unwrap();
+                let end = event.end_time.unwrap_or(start);
+                if event.all_day || (start <= day_start && end >= day_end) {
+                    all_day_events.push((event, *colour, *double_booked));
+                    continue;
+                }
+                let first_row = if start <= day_start { 0 } else { row_of(start).div_euclid(30) };
+                let end_row = if end >= day_end { WEEK_ROWS_PER_DAY } else { (row_of(end) + 29).div_euclid(30) };
+                timed_events.push((event, *colour, *double_booked, first_row, end_row.max(first_row + 1)));
+            }
+            all_day.push(all_day_events);
+            timed.push(lay_out_week_day(timed_events));
+        }
+        let first_row = timed.iter().flatten().map(|slot| slot.first_row).fold(WEEK_DEFAULT_ROWS.0, i64::min);
+        let end_row = timed.iter().flatten().map(|slot| slot.end_row).fold(WEEK_DEFAULT_ROWS.1, i64::max);
+
+        let mut view = format!("Week of {}", days[0].format("%a %e %b %Y"));
+        let headings: Vec<String> = days.iter().map(|day| fit_to_width(&day.format("%a %e %b").to_string(), GRID_DAY_WIDTH)).collect();
+        view.push_str(&format!("\n{:<7}|{}", "", headings.join("|")));
+        let rule = format!("\n{}+{}", "-".repeat(7), vec!["-".repeat(GRID_DAY_WIDTH); 7].join("+"));
+        view.push_str(&rule);
+        let all_day_rows = all_day.iter().map(|events| events.len()).max().unwrap_or(0);
+        for index in 0..all_day_rows {
+            let cells: Vec<String> = all_day.iter()
+                .map(|events| match events.get(index) {
+                    Some((event, colour, double_booked)) => paint(fit_to_width(&grid_title(event, *double_booked), GRID_DAY_WIDTH), *colour, use_colour),
+                    None => " ".repeat(GRID_DAY_WIDTH)
+                })
+                .collect();
+            let label = if index == 0 { "All day" } else { "" };
+            view.push_str(&format!("\n{:<7}|{}", label, cells.join("|")));
+        }
+        if all_day_rows > 0 {
+            view.push_str(&rule);
+        }
+        for row in first_row..end_row {
+            let label = format!("{:02}:{:02}", row / 2, (row % 2) * 30);
+            let cells: Vec<String> = timed.iter().map(|slots| week_cell(slots, row, use_colour)).collect();
+            view.push_str(&format!("\n{:<7}|{}", label, cells.join("|")));
+        }
+        return view;
+    }
+
+    /// The month containing date as a text grid of whole weeks, listing in each day's cell the start
+    /// time and title of its events in local_zone, cut to fit. Days beyond MONTH_CELL_LINES events
+    /// end with a count of the rest, days outside the month are left blank, and double bookings are
+    /// marked with '!'. Events are drawn in their calendar's colour if use_colour is set. Cancelled
+    /// events are left out.
+    pub fn render_month_view(calendars: &[GridCalendar], date: NaiveDate, local_zone: &MpTimeZone, use_colour: bool) -> String {
+        let days = month_grid_days(date);
+        let occurrences = grid_occurrences(calendars, &days, local_zone);
+        let mut view = date.format("%B %Y").to_string();
+        let headings: Vec<String> = days[..7].iter().map(|day| fit_to_width(&day.format("%a").to_string(), GRID_DAY_WIDTH)).collect();
+        view.push_str(&format!("\n{}", headings.join("|")));
+        let rule = format!("\n{}", vec!["-".repeat(GRID_DAY_WIDTH); 7].join("+"));
+        for week in days.chunks(7) {
+            view.push_str(&rule);
+            let mut entries: Vec<Vec<(String, Option<&str>)>> = vec![];
+            let mut dates: Vec<String> = vec![];
+            for day in week {
+                if day.month() != date.month() {
+                    entries.push(vec![]);
+                    dates.push(" ".repeat(GRID_DAY_WIDTH));
+                    continue;
+                }
+                let (day_start, day_end) = grid_day_bounds(*day, local_zone);
+                entries.push(occurrences.iter()
+                    .filter(|(event, _, _)| event.intersects_window(day_start, day_end))
+                    .map(|(event, colour, double_booked)| {
+                        let title = grid_title(event, *double_booked);
+                        // Events carried over from an earlier day show just their title
+                        match event.start_time {
+                            Some(start) if !event.all_day && start >= day_start => {
+                                return (format!("{} {}", local_zone.convert(start).format("%H:%M"), title), *colour);
+                            },
+                            _ => return (title, *colour)
+                        }
+                    })
+                    .collect());
+                dates.push(fit_to_width(&format!("{:>2}", day.day()), GRID_DAY_WIDTH));
+            }
+            view.push_str(&format!("\n{}", dates.join("|")));
+            let lines = entries.iter().map(|day_entries| day_entries.len()).max().unwrap_or(0).min(MONTH_CELL_LINES);
+            for line in 0..lines {
+                let cells: Vec<String> = entries.iter()
+                    .map(|day_entries| match day_entries.get(line) {
+                        _ if line == MONTH_CELL_LINES - 1 && day_entries.len() > MONTH_CELL_LINES => {
+                            fit_to_width(&format!("+{} more", day_entries.len() - line), GRID_DAY_WIDTH)
+                        },
+                        Some((text, colour)) => paint(fit_to_width(text, GRID_DAY_WIDTH), *colour, use_colour),
+                        None => " ".repeat(GRID_DAY_WIDTH)
+                    })
+                    .collect();
+                view.push_str(&format!("\n{}", cells.join("|")));
+            }
+        }
+        view.push_str(&rule);
+        return view;
+    }
+
+    /// Creates a new MPEvent from a series of inputs
+    pub fn create_new_mpevent(name: Option<String>,
+                              start_time: Option<DateTime<FixedOffset>>,
+                              end_time: Option<DateTime<FixedOffset>>,
+                              location: Option<String>,
+                              description: Option<String>,
+                              status: Option<EventStatus>) -> MpEvent {
+        return MpEvent{ name, start_time, end_time, location, description, status, ..Default::default() };
+    }
+
+    #[cfg(test)]
+    mod cal_ops_tests {
+        use crate::mp_calendar::cal_ops::*;
+
+        #[test]
+        pub fn test_sort_mpevents_chronologically_by_start() {
+            let time_1 = DateTime::parse_from_rfc3339(&String::from("2013-08-02T20:00:00-00:00")).unwrap();
+            let time_2 = DateTime::parse_from_rfc3339(&String::from("2013-08-03T20:00:00-00:00")).unwrap();
+            let time_3 = DateTime::parse_from_rfc3339(&String::from("2013-08-03T22:00:00-00:00")).unwrap();
+            let time_4 = DateTime::parse_from_rfc3339(&String::from("2013-08-04T22:00:00-00:00")).unwrap();
+            let event_1 = MpEvent {name: Some(String::from("Event1")),  start_time: Some(time_1), end_time: None, description: None, location: None, status: None, ..Default::default() };
+            let event_2 = MpEvent {name: Some(String::from("Event2")),  start_time: Some(time_2), end_time: Some(time_3), description: None, location: None, status: None, ..Default::default() };
+            let event_3 = MpEvent {name: Some(String::from("Event3")),  start_time: Some(time_2), end_time: Some(time_4), description: None, location: None, status: None, ..Default::default() };
+            let event_4 = MpEvent {name: Some(String::from("Event4")),  start_time: Some(time_3), end_time: None, description: None, location: None, status: None, ..Default::default() };
+            let unsorted_events: Vec<MpEvent> = vec!(event_3.clone(), event_2.clone(), event_4.clone(), event_1.clone());
+            let exp_sorted_events: Vec<MpEvent> = vec!(event_1, event_2, event_3, event_4);
+            let sorted_events = sort_mpevents_chronologically_by_start(unsorted_events);
+            assert_eq!(exp_sorted_events, sorted_events);
+        }
+
+        #[test]
+        fn test_expand_mpevents_in_window() {
+            use std::str::FromStr;
+            use crate::mp_calendar::cal_recur::RecurrenceRule;
+            let standup_start = DateTime::parse_from_rfc3339("2024-01-01T09:00:00+00:00").unwrap();
+            let standup = MpEvent {
+                name: Some(String::from("Standup")),
+                start_time: Some(standup_start),
+                end_time: Some(standup_start + chrono::Duration::minutes(15)),
+                rrule: Some(RecurrenceRule::from_str("FREQ=WEEKLY;BYDAY=MO,WE").unwrap()),
+                ..Default::default()
+            };
+            let review_start = DateTime::parse_from_rfc3339("2024-01-10T08:00:00+00:00").unwrap();
+            let review = MpEvent {
+                name: Some(String::from("Review")),
+                start_time: Some(review_start),
+                end_time: Some(review_start + chrono::Duration::hours(2)),
+                ..Default::default()
+            };
+            let outside_start = DateTime::parse_from_rfc3339("2024-03-01T08:00:00+00:00").unwrap();
+            let outside = MpEvent { name: Some(String::from("Later")), start_time: Some(outside_start), ..Default::default() };
+            let window_start = DateTime::parse_from_rfc3339("2024-01-08T00:00:00+00:00").unwrap();
+            let window_end = DateTime::parse_from_rfc3339("2024-01-15T00:00:00+00:00").unwrap();
+            let expanded = expand_mpevents_in_window(vec!(outside, review, standup), window_start, window_end);
+            let summary: Vec<(String, String)> = expanded.iter()
+                .map(|event| (event.name.clone().unwrap(), event.start_time.unwrap().to_rfc3339()))
+                .collect();
+            let expected: Vec<(String, String)> = vec!(
+                (String::from("Standup"), String::from("2024-01-08T09:00:00+00:00")),
+                (String::from("Review"), String::from("2024-01-10T08:00:00+00:00")),
+                (String::from("Standup"), String::from("2024-01-10T09:00:00+00:00")),
+            );
+            assert_eq!(expected, summary);
+            assert!(expanded.iter().all(|event| event.rrule.is_none()));
+            assert_eq!(Some(chrono::Duration::minutes(15)), expanded[0].end_time.map(|end| end - expanded[0].start_time.unwrap()));
+        }
+
+        #[test]
+        fn test_sort_mptasks_and_find_subtasks() {
+            use crate::mp_calendar::cal_io::{parse_file_to_ical_calendar, extract_tasks_from_ical};
+            use crate::mp_calendar::cal_tz::MpTimeZone;
+            let cal = parse_file_to_ical_calendar(String::from("src/test/test_tasks.ics")).unwrap();
+            let tasks = sort_mptasks_by_due_and_priority(extract_tasks_from_ical(cal, &MpTimeZone::utc()).unwrap());
+            let names: Vec<&str> = tasks.iter().map(|task| task.name().unwrap()).collect();
+            assert_eq!(vec!["Book the review room", "Write the announcement, blog and email", "Ship the launch", "Tidy the wiki"], names);
+            let subtasks: Vec<&str> = subtasks_of(&tasks, &tasks[2]).iter().map(|task| task.name().unwrap()).collect();
+            assert_eq!(vec!["Book the review room", "Write the announcement, blog and email"], subtasks);
+        }
+
+        #[test]
+        fn test_busy_periods_in_window() {
+            use crate::mp_calendar::cal_io::{parse_file_to_ical_calendar, extract_events_from_ical};
+            use crate::mp_calendar::cal_tz::MpTimeZone;
+            let cal = parse_file_to_ical_calendar(String::from("src/test/test_freebusy.ics")).unwrap();
+            let events = extract_events_from_ical(cal, &MpTimeZone::utc()).unwrap();
+            let window_start = DateTime::parse_from_rfc3339("2024-01-08T00:00:00+00:00").unwrap();
+            let window_end = DateTime::parse_from_rfc3339("2024-01-09T18:00:00+00:00").unwrap();
+            let periods: Vec<(String, String, FreeBusyType)> = busy_periods_in_window(&events, window_start, window_end).iter()
+                .map(|period| (period.start.to_rfc3339(), period.end.to_rfc3339(), period.fb_type))
+                .collect();
+            let period = |start: &str, end: &str, fb_type: FreeBusyType| {
+                (format!("2024-01-{}:00+00:00", start), format!("2024-01-{}:00+00:00", end), fb_type)
+            };
+            // Touching and overlapping events merge, the tentative talk only counts once the vendor
+            // call ends, the cancelled and transparent events don't count at all, and planning ends
+            // after its DURATION
+            let expected = vec![
+                period("08T09:00", "08T11:00", FreeBusyType::Busy),
+                period("08T11:00", "08T13:00", FreeBusyType::BusyTentative),
+                period("09T09:00", "09T09:15", FreeBusyType::Busy),
+                period("09T12:00", "09T13:30", FreeBusyType::Busy),
+                period("09T15:00", "09T18:00", FreeBusyType::Busy)
+            ];
+            assert_eq!(expected, periods);
+        }
+
+        #[test]
+        fn test_find_meeting_slots() {
+            use crate::mp_calendar::cal_io::read_events_from_ics_file;
+            use crate::mp_calendar::cal_tz::MpTimeZone;
+            let london = MpTimeZone::from_iana_name("Europe/London").unwrap();
+            let calendars = vec![
+                read_events_from_ics_file(String::from("src/test/test_slots_alice.ics"), &london).unwrap(),
+                read_events_from_ics_file(String::from("src/test/test_slots_bob.ics"), &london).unwrap()
+            ];
+            let window_start = DateTime::parse_from_rfc3339("2024-06-10T00:00:00+01:00").unwrap();
+            let window_end = DateTime::parse_from_rfc3339("2024-06-11T00:00:00+01:00").unwrap();
+            let mut request = SlotRequest::new(Duration::minutes(30), window_start, window_end);
+            request.zone = london;
+            request.step = Duration::minutes(30);
+            let summarise = |slots: Vec<CandidateSlot>| -> Vec<(String, Vec<usize>)> {
+                slots.into_iter().map(|slot| (slot.start.to_rfc3339(), slot.tentative_conflicts)).collect()
+            };
+            // Free slots first, then those clashing with Alice's tentative gym session. The cancelled
+            // sync and transparent focus time don't block anything; back-to-back meetings are fine.
+            let expected = vec![
+                (String::from("2024-06-10T12:00:00+01:00"), vec![]),
+                (String::from("2024-06-10T14:00:00+01:00"), vec![]),
+                (String::from("2024-06-10T13:00:00+01:00"), vec![0]),
+                (String::from("2024-06-10T13:30:00+01:00"), vec![0])
+            ];
+            assert_eq!(expected, summarise(find_meeting_slots(&calendars, &request)));
+            request.duration = Duration::hours(1);
+            request.buffer_before = Duration::minutes(15);
+            assert_eq!(vec![(String::from("2024-06-10T13:30:00+01:00"), vec![0])], summarise(find_meeting_slots(&calendars, &request)));
+            request.working_days = vec![Weekday::Sat, Weekday::Sun];
+            assert!(find_meeting_slots(&calendars, &request).is_empty());
+        }
+
+        #[test]
+        fn test_find_conflicts_in_window() {
+            use crate::mp_calendar::cal_io::read_events_from_ics_file;
+            use crate::mp_calendar::cal_tz::MpTimeZone;
+            let events = read_events_from_ics_file(String::from("src/test/test_conflicts.ics"), &MpTimeZone::utc()).unwrap();
+            let window_start = DateTime::parse_from_rfc3339("2024-01-08T00:00:00+00:00").unwrap();
+            let window_end = DateTime::parse_from_rfc3339("2024-01-11T00:00:00+00:00").unwrap();
+            let conflicts = find_conflicts_in_window(&events, window_start, window_end);
+            let names: Vec<Vec<String>> = conflicts.iter()
+                .map(|conflict| conflict.events.iter().map(|event| event.name.clone().unwrap()).collect())
+                .collect();
+            let overlaps: Vec<Vec<(usize, usize, i64)>> = conflicts.iter()
+                .map(|conflict| conflict.overlaps.iter().map(|(i, j, overlap)| (*i, *j, overlap.num_minutes())).collect())
+                .collect();
+            // The chain on the 8th is one conflict even though standup and the vendor call don't touch.
+            // Lunch and the walk only meet, and the cancelled, transparent and moved events don't clash.
+            assert_eq!(vec![vec!["Standup", "Design review", "Vendor call"], vec!["Standup", "Interview"]], names);
+            assert_eq!(vec![vec![(0, 1, 15), (1, 2, 15)], vec![(0, 1, 30)]], overlaps);
+        }
+
+        fn read_fixture(path: &str) -> Vec<MpEvent> {
+            return crate::mp_calendar::cal_io::read_events_from_ics_file(String::from(path), &MpTimeZone::utc()).unwrap();
+        }
+
+        #[test]
+        fn test_diff_calendars() {
+            let before = read_fixture("src/test/test_diff_before.ics");
+            let after = read_fixture("src/test/test_diff_after.ics");
+            let changes = diff_calendars(&before, &after);
+            let summary: Vec<(&str, &str)> = changes.iter()
+                .map(|change| (change.kind(), change.event().name.as_deref().unwrap()))
+                .collect();
+            // The gym session has no UID but is matched by its name and start; the moved standup is
+            // compared with where the series had it
+            let expected = vec![("rescheduled", "Design review"), ("relocated", "Design review"), ("removed", "Lunch"),
+                                ("added", "Dentist"), ("rescheduled", "Standup"), ("status_changed", "Vendor call")];
+            assert_eq!(expected, summary);
+            assert!(diff_calendars(&after, &after).is_empty());
+
+            // Dropping the override moves the standup back
+            let reverted = diff_calendars(&after, &before);
+            let standup = reverted.iter().find(|change| change.event().name.as_deref() == Some("Standup")).unwrap();
+            match standup {
+                EventChange::Rescheduled { before, after } => {
+                    assert_eq!(DateTime::parse_from_rfc3339("2024-03-13T09:30:00+00:00").ok(), before.start_time);
+                    assert_eq!(DateTime::parse_from_rfc3339("2024-03-13T09:00:00+00:00").ok(), after.start_time);
+                },
+                other => panic!("expected the standup to be rescheduled, got {:?}", other)
+            };
+        }
+
+        #[test]
+        fn test_calendar_diff_to_json() {
+            let changes = diff_calendars(&read_fixture("src/test/test_diff_before.ics"), &read_fixture("src/test/test_diff_after.ics"));
+            let json: serde_json::Value = serde_json::from_str(&calendar_diff_to_json(&changes)).unwrap();
+            let entries = json.as_array().unwrap();
+            assert_eq!(changes.len(), entries.len());
+            assert_eq!(serde_json::json!({
+                "change": "rescheduled",
+                "uid": "diff-review@example.com",
+                "recurrence_id": null,
+                "name": "Design review",
+                "before": { "start": "2024-03-11T14:00:00+00:00", "end": "2024-03-11T15:00:00+00:00", "location": "Room 2", "status": "CONFIRMED" },
+                "after": { "start": "2024-03-11T15:00:00+00:00", "end": "2024-03-11T16:00:00+00:00", "location": "Room 4", "status": "CONFIRMED" }
+            }), entries[0]);
+            assert_eq!("removed", entries[2]["change"]);
+            assert!(entries[2]["after"].is_null());
+            assert!(entries[3]["before"].is_null());
+            assert_eq!("2024-03-13T09:00:00+00:00", entries[4]["recurrence_id"]);
+            assert_eq!("[]", calendar_diff_to_json(&[]));
+        }
+
+        #[test]
+        fn test_grid_days() {
+            let date = NaiveDate::from_ymd(2024, 2, 14);
+            let week = week_grid_days(date);
+            assert_eq!((NaiveDate::from_ymd(2024, 2, 12), NaiveDate::from_ymd(2024, 2, 18)), (week[0], week[6]));
+            // February 2024 starts on a Thursday and ends on a Thursday
+            let month = month_grid_days(date);
+            assert_eq!(35, month.len());
+            assert_eq!((NaiveDate::from_ymd(2024, 1, 29), NaiveDate::from_ymd(2024, 3, 3)), (month[0], month[34]));
+            let december = month_grid_days(NaiveDate::from_ymd(2024, 12, 25));
+            assert_eq!((42, NaiveDate::from_ymd(2025, 1, 5)), (december.len(), december[41]));
+        }
+
+        #[test]
+        fn test_render_week_view() {
+            let calendars = vec![
+                GridCalendar { events: read_fixture("src/test/test_conflicts.ics"), colour: Some(String::from("#1e90ff")) },
+                GridCalendar {
+                    events: vec![MpEvent {
+                        name: Some(String::from("Offsite")),
+                        start_time: Some(DateTime::parse_from_rfc3339("2024-01-11T00:00:00+00:00").unwrap()),
+                        end_time: Some(DateTime::parse_from_rfc3339("2024-01-13T00:00:00+00:00").unwrap()),
+                        all_day: true,
+                        ..Default::default()
+                    }],
+                    colour: None
+                }
+            ];
+            let view = render_week_view(&calendars, NaiveDate::from_ymd(2024, 1, 10), &MpTimeZone::utc(), false);
+            let lines: Vec<&str> = view.lines().collect();
+            assert_eq!("Week of Mon  8 Jan 2024", lines[0]);
+            assert_eq!("       |Mon  8 Jan    |Tue  9 Jan    |Wed 10 Jan    |Thu 11 Jan    |Fri 12 Jan    |Sat 13 Jan    |Sun 14 Jan    ", lines[1]);
+            assert_eq!("All day|              |              |              |Offsite       |Offsite       |              |              ", lines[3]);
+            assert_eq!("08:00  |              |              |              |              |              |              |              ", lines[5]);
+            // Standup and Design review overlap so share Monday's column, and Vendor call takes the
+            // lane Standup has finished with; the cancelled 1:1 isn't drawn
+            assert_eq!("09:00  |!Stan… !Desi… |Stand… Focus… |Planning      |              |              |              |              ", lines[7]);
+            assert_eq!("09:30  |       │      |       │      |              |              |              |              |              ", lines[8]);
+            assert_eq!("10:00  |!Vend… │      |       │      |              |              |              |              |              ", lines[9]);
+            assert_eq!("10:30  |│             |       │      |              |              |              |              |              ", lines[10]);
+            assert_eq!("12:00  |Lunch         |              |              |              |              |              |              ", lines[13]);
+            assert_eq!("12:30  |│             |              |              |              |              |              |              ", lines[14]);
+            assert_eq!("17:30  |              |              |              |              |              |              |              ", lines[24]);
+            assert_eq!(25, lines.len());
+
+            let coloured = render_week_view(&calendars, NaiveDate::from_ymd(2024, 1, 10), &MpTimeZone::utc(), true);
+            assert!(coloured.contains("|\x1b[38;2;30;144;255mLunch         \x1b[0m|"));
+            assert!(coloured.contains("|Offsite       |"));
+        }
+
+        #[test]
+        fn test_grid_marks_untitled_double_bookings() {
+            let at = |start: &str, end: &str| MpEvent {
+                start_time: Some(DateTime::parse_from_rfc3339(start).unwrap()),
+                end_time: Some(DateTime::parse_from_rfc3339(end).unwrap()),
+                ..Default::default()
+            };
+            let events = vec![at("2024-01-08T09:00:00+00:00", "2024-01-08T10:00:00+00:00"),
+                              at("2024-01-08T09:00:00+00:00", "2024-01-08T09:30:00+00:00"),
+                              at("2024-01-08T12:00:00+00:00", "2024-01-08T12:30:00+00:00")];
+            let view = render_week_view(&[GridCalendar { events, colour: None }], NaiveDate::from_ymd(2024, 1, 8), &MpTimeZone::utc(), false);
+            let lines: Vec<&str> = view.lines().collect();
+            assert!(lines[5].starts_with("09:00  |!Unti… !Unti… |"));
+            assert!(lines[11].starts_with("12:00  |Untitled event|"));
+        }
+
+        #[test]
+        fn test_render_month_view() {
+            let calendars = vec![GridCalendar { events: read_fixture("src/test/test_conflicts.ics"), colour: None }];
+            let view = render_month_view(&calendars, NaiveDate::from_ymd(2024, 1, 31), &MpTimeZone::utc(), false);
+            let lines: Vec<&str> = view.lines().collect();
+            assert_eq!("January 2024", lines[0]);
+            assert_eq!("Mon           |Tue           |Wed           |Thu           |Fri           |Sat           |Sun           ", lines[1]);
+            assert_eq!(" 8            | 9            |10            |11            |12            |13            |14            ", lines[5]);
+            assert_eq!("09:00 !Standup|09:00 Standup |09:00 Planning|              |              |              |              ", lines[6]);
+            assert_eq!("09:15 !Design…|09:00 Focus b…|14:00 !Standup|              |              |              |              ", lines[7]);
+            // Five events on Monday, with the cancelled 1:1 left out
+            assert_eq!("+3 more       |              |14:00 !Interv…|              |              |              |              ", lines[8]);
+            assert_eq!("29            |30            |31            |              |              |              |              ", lines[14]);
+            assert_eq!(16, lines.len());
         }
     }
 }
\ No newline at end of file
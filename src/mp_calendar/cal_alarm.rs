@@ -0,0 +1,511 @@
+use std::fmt;
+use std::str::FromStr;
+use chrono::prelude::*;
+use chrono::Duration;
+use serde::{Serialize, Deserialize};
+use super::{MpEvent, MpProperty, MpCalendarError};
+
+/// How far back alarms that came due while moneypenny wasn't running are still delivered
+const MISSED_ALARM_LIMIT_HOURS: i64 = 12;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum AlarmAction {
+    Display,
+    Audio,
+    Email,
+    Other(String)
+}
+
+impl FromStr for AlarmAction {
+    type Err = ();
+    fn from_str(input: &str) -> Result<AlarmAction, Self::Err> {
+        match input {
+            "DISPLAY" => Ok(AlarmAction::Display),
+            "AUDIO"   => Ok(AlarmAction::Audio),
+            "EMAIL"   => Ok(AlarmAction::Email),
+            ""        => Err(()),
+            other     => Ok(AlarmAction::Other(String::from(other))),
+        }
+    }
+}
+
+impl fmt::Display for AlarmAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let action_str = match self {
+            AlarmAction::Display => "DISPLAY",
+            AlarmAction::Audio => "AUDIO",
+            AlarmAction::Email => "EMAIL",
+            AlarmAction::Other(other) => other
+        };
+        return write!(f, "{}", action_str);
+    }
+}
+
+/// When an alarm goes off: an offset from the start (or end) of its event, or a fixed instant
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum AlarmTrigger {
+    Relative { offset: Duration, from_end: bool },
+    Absolute(DateTime<FixedOffset>)
+}
+
+/// A VALARM belonging to an event
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct MpAlarm {
+    pub(super) action: AlarmAction,
+    pub(super) trigger: AlarmTrigger,
+    pub(super) description: Option<String>,
+    // The alarm goes off 1 + repeat times, repeat_interval apart
+    pub(super) repeat: u32,
+    pub(super) repeat_interval: Option<Duration>,
+    pub(super) extra_properties: Vec<MpProperty>
+}
+
+impl MpAlarm {
+    pub fn new(action: AlarmAction, trigger: AlarmTrigger) -> MpAlarm {
+        return MpAlarm { action, trigger, description: None, repeat: 0, repeat_interval: None, extra_properties: vec![] };
+    }
+
+    /// Every instant this alarm goes off for an event instance starting and ending at the given times,
+    /// including repeats. An end-relative trigger on an event with no end is taken from its start.
+    pub fn fire_times(&self, start: DateTime<FixedOffset>, end: Option<DateTime<FixedOffset>>) -> Vec<DateTime<FixedOffset>> {
+        let first = match &self.trigger {
+            AlarmTrigger::Relative { offset, from_end: true } => end.unwrap_or(start) + *offset,
+            AlarmTrigger::Relative { offset, from_end: false } => start + *offset,
+            AlarmTrigger::Absolute(time) => *time
+        };
+        let mut times = vec![first];
+        match self.repeat_interval {
+            Some(interval) => {
+                for repetition in 1..=self.repeat {
+                    times.push(first + interval * repetition as i32);
+                }
+            }
+            None => ()
+        };
+        return times;
+    }
+
+    /// The widest span between an instance's start and any time this alarm fires for it, as
+    /// (how long before the start, how long after the start not counting the event's own length)
+    fn reach(&self) -> (Duration, Duration) {
+        let repeats = match self.repeat_interval {
+            Some(interval) => interval * self.repeat as i32,
+            None => Duration::zero()
+        };
+        match &self.trigger {
+            AlarmTrigger::Relative { offset, .. } => {
+                let before = if *offset < Duration::zero() { -*offset } else { Duration::zero() };
+                let after = if *offset + repeats > Duration::zero() { *offset + repeats } else { Duration::zero() };
+                return (before, after);
+            }
+            AlarmTrigger::Absolute(_) => return (Duration::zero(), Duration::zero())
+        }
+    }
+}
+
+/// Parses an RFC 5545 DURATION value such as "-PT15M", "P1DT12H" or "P2W"
+pub fn parse_ical_duration(input: &str) -> Result<Duration, String> {
+    let (negative, rest) = match input.trim() {
+        value if value.starts_with('-') => (true, &value[1..]),
+        value if value.starts_with('+') => (false, &value[1..]),
+        value => (false, value)
+    };
+    if !rest.starts_with('P') || rest.len() < 3 {
+        return Err(format!("'{}' is not a duration", input));
+    }
+    let too_long = || format!("duration '{}' is too long", input);
+    let mut seconds: i64 = 0;
+    let mut number = String::new();
+    let mut in_time = false;
+    for c in rest[1..].chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        if c == 'T' {
+            in_time = true;
+            continue;
+        }
+        let amount = number.parse::<i64>().map_err(|_| format!("'{}' is not a duration", input))?;
+        number.clear();
+        let unit_seconds = match (c, in_time) {
+            ('W', false) => 7 * 86400,
+            ('D', false) => 86400,
+            ('H', true) => 3600,
+            ('M', true) => 60,
+            ('S', true) => 1,
+            _ => return Err(format!("unexpected '{}' in duration '{}'", c, input))
+        };
+        seconds = amount.checked_mul(unit_seconds)
+            .and_then(|part| seconds.checked_add(part))
+            .ok_or_else(too_long)?;
+    }
+    if !number.is_empty() {
+        return Err(format!("duration '{}' ends in a number with no unit", input));
+    }
+    // Duration::seconds panics beyond i64::MAX milliseconds
+    if seconds > i64::MAX / 1000 {
+        return Err(too_long());
+    }
+    let duration = Duration::seconds(seconds);
+    if negative {
+        return Ok(-duration);
+    }
+    return Ok(duration);
+}
+
+pub fn format_ical_duration(duration: Duration) -> String {
+    let sign = if duration < Duration::zero() { "-" } else { "" };
+    let mut seconds = duration.num_seconds().abs();
+    if seconds == 0 {
+        return String::from("PT0S");
+    }
+    if seconds % (7 * 86400) == 0 {
+        return format!("{}P{}W", sign, seconds / (7 * 86400));
+    }
+    let mut formatted = format!("{}P", sign);
+    let days = seconds / 86400;
+    seconds %= 86400;
+    if days > 0 {
+        formatted.push_str(&format!("{}D", days));
+    }
+    if seconds > 0 {
+        formatted.push('T');
+        let (hours, minutes, secs) = (seconds / 3600, seconds % 3600 / 60, seconds % 60);
+        if hours > 0 {
+            formatted.push_str(&format!("{}H", hours));
+        }
+        if minutes > 0 {
+            formatted.push_str(&format!("{}M", minutes));
+        }
+        if secs > 0 {
+            formatted.push_str(&format!("{}S", secs));
+        }
+    }
+    return formatted;
+}
+
+/// An alarm that has gone off. The key identifies the alarm, event instance and repetition.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct FiredAlarm {
+    pub key: String,
+    pub body: String,
+    pub due: DateTime<FixedOffset>
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+struct SnoozedAlarm {
+    key: String,
+    body: String,
+    until: String
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct AlarmState {
+    last_checked: Option<String>,
+    #[serde(default)]
+    snoozed: Vec<SnoozedAlarm>
+}
+
+/// Delivers alarms as Calendar messages as they come due. Everything up to last_checked has been
+/// delivered, and that is persisted to state_path so a restart neither repeats nor loses alarms.
+pub struct AlarmScheduler {
+    state_path: String,
+    last_checked: Option<DateTime<FixedOffset>>,
+    snoozed: Vec<SnoozedAlarm>
+}
+
+impl AlarmScheduler {
+    /// Loads the state left by a previous run, if there is any. A state file that can't be read
+    /// is reported and the scheduler starts afresh.
+    pub fn new(state_path: String) -> AlarmScheduler {
+        let mut scheduler = AlarmScheduler { state_path, last_checked: None, snoozed: vec![] };
+        let state_str = match std::fs::read_to_string(&scheduler.state_path) {
+            Ok(state_str) => state_str,
+            Err(_) => return scheduler
+        };
+        match toml::from_str::<AlarmState>(&state_str) {
+            Ok(state) => {
+                scheduler.last_checked = state.last_checked.and_then(|time| DateTime::parse_from_rfc3339(&time).ok());
+                scheduler.snoozed = state.snoozed;
+            }
+            Err(e) => super::output_mp_calendar_message(format!("Ignoring unreadable alarm state {}: {}", scheduler.state_path, e))
+        };
+        return scheduler;
+    }
+
+    /// Alarms that came due in (last_checked, now], oldest first, without marking them delivered.
+    /// On the first ever run nothing before now is due; after a restart, alarms missed while
+    /// stopped are due as long as they're no older than MISSED_ALARM_LIMIT_HOURS.
+    pub fn due_alarms(&self, events: &[MpEvent], now: DateTime<FixedOffset>) -> Vec<FiredAlarm> {
+        let oldest = now - Duration::hours(MISSED_ALARM_LIMIT_HOURS);
+        let since = match self.last_checked {
+            Some(last_checked) if last_checked > oldest => last_checked,
+            Some(_) => oldest,
+            None => now
+        };
+        let mut due: Vec<FiredAlarm> = vec![];
+        for event in events {
+            due.extend(event_alarms_due(event, since, now));
+        }
+        for snoozed in &self.snoozed {
+            match DateTime::parse_from_rfc3339(&snoozed.until) {
+                Ok(until) if until <= now => {
+                    due.push(FiredAlarm { key: snoozed.key.clone(), body: snoozed.body.clone(), due: until });
+                }
+                _ => ()
+            };
+        }
+        due.sort_by(|lhs, rhs| lhs.due.cmp(&rhs.due).then_with(|| lhs.key.cmp(&rhs.key)));
+        return due;
+    }
+
+    /// Outputs a Calendar message for every alarm due by now, records them as delivered and
+    /// saves the state. Returns the alarms fired so the caller can offer to snooze them.
+    pub fn tick(&mut self, events: &[MpEvent], now: DateTime<FixedOffset>) -> Vec<FiredAlarm> {
+        let fired = self.due_alarms(events, now);
+        for alarm in &fired {
+            super::output_mp_calendar_message(alarm.body.clone());
+        }
+        self.snoozed.retain(|snoozed| match DateTime::parse_from_rfc3339(&snoozed.until) {
+            Ok(until) => until > now,
+            Err(_) => false
+        });
+        self.last_checked = Some(now);
+        self.save_or_report();
+        return fired;
+    }
+
+    /// Fires the alarm again at until, which is normally a few minutes after it went off
+    pub fn snooze(&mut self, alarm: &FiredAlarm, until: DateTime<FixedOffset>) {
+        self.snoozed.retain(|snoozed| snoozed.key != alarm.key);
+        self.snoozed.push(SnoozedAlarm { key: alarm.key.clone(), body: alarm.body.clone(), until: until.to_rfc3339() });
+        self.save_or_report();
+    }
+
+    pub fn save(&self) -> Result<(), MpCalendarError> {
+        let state = AlarmState { last_checked: self.last_checked.map(|time| time.to_rfc3339()), snoozed: self.snoozed.clone() };
+        // Serialising a struct of strings can't fail
+        let state_str = toml::to_string(&state).unwrap();
+        return std::fs::write(&self.state_path, state_str)
+            .map_err(|e| MpCalendarError::Io { path: self.state_path.clone(), source: e });
+    }
+
+    fn save_or_report(&self) {
+        match self.save() {
+            Ok(()) => (),
+            Err(e) => super::output_mp_calendar_message(format!("Could not save alarm state: {}", e))
+        };
+    }
+}
+
+/// The alarms of events that go off in (from, until], soonest first. Unlike AlarmScheduler this keeps
+/// no state, so it's for showing what's coming up rather than delivering it.
+pub fn upcoming_alarms(events: &[MpEvent], from: DateTime<FixedOffset>, until: DateTime<FixedOffset>) -> Vec<FiredAlarm> {
+    let mut upcoming: Vec<FiredAlarm> = events.iter().flat_map(|event| event_alarms_due(event, from, until)).collect();
+    upcoming.sort_by(|lhs, rhs| lhs.due.cmp(&rhs.due).then_with(|| lhs.key.cmp(&rhs.key)));
+    return upcoming;
+}
+
+/// The alarms of every instance of an event that fire in (since, now]
+fn event_alarms_due(event: &MpEvent, since: DateTime<FixedOffset>, now: DateTime<FixedOffset>) -> Vec<FiredAlarm> {
+    let mut due: Vec<FiredAlarm> = vec![];
+    // An absolute trigger goes off once, whatever instances the event has
+    for component in std::iter::once(event).chain(event.overrides.iter()) {
+        let start = match component.start_time {
+            Some(start) => start,
+            None => continue
+        };
+        for (index, alarm) in component.alarms.iter().enumerate() {
+            match alarm.trigger {
+                AlarmTrigger::Absolute(_) => due.extend(alarm_fires_due(component, index, alarm, start, since, now)),
+                AlarmTrigger::Relative { .. } => ()
+            };
+        }
+    }
+    let all_alarms = event.alarms.iter().chain(event.overrides.iter().flat_map(|over| over.alarms.iter()));
+    let (mut before, mut after) = (Duration::zero(), Duration::zero());
+    for alarm in all_alarms {
+        let (alarm_before, alarm_after) = alarm.reach();
+        before = before.max(alarm_before);
+        after = after.max(alarm_after);
+    }
+    // Any instance with a relative alarm in (since, now] intersects this window, however long the event is
+    for instance in event.expand_occurrences(since - after, now + before + Duration::seconds(1)) {
+        let start = match instance.start_time {
+            Some(start) => start,
+            None => continue
+        };
+        for (index, alarm) in instance.alarms.iter().enumerate() {
+            match alarm.trigger {
+                AlarmTrigger::Relative { .. } => due.extend(alarm_fires_due(&instance, index, alarm, start, since, now)),
+                AlarmTrigger::Absolute(_) => ()
+            };
+        }
+    }
+    return due;
+}
+
+fn alarm_fires_due(instance: &MpEvent, index: usize, alarm: &MpAlarm, start: DateTime<FixedOffset>,
+                   since: DateTime<FixedOffset>, now: DateTime<FixedOffset>) -> Vec<FiredAlarm> {
+    let uid = instance.uid.clone().unwrap_or_else(|| instance.name.clone().unwrap_or_default());
+    let mut due: Vec<FiredAlarm> = vec![];
+    for (repetition, fire_time) in alarm.fire_times(start, instance.end_time).into_iter().enumerate() {
+        if fire_time <= since || fire_time > now {
+            continue;
+        }
+        let key = format!("{}/{}/{}/{}", uid, start.to_rfc3339(), index, repetition);
+        due.push(FiredAlarm { key, body: alarm_message(instance, alarm, start), due: fire_time });
+    }
+    return due;
+}
+
+fn alarm_message(instance: &MpEvent, alarm: &MpAlarm, start: DateTime<FixedOffset>) -> String {
+    let local_start = match &instance.timezone {
+        Some(zone) => zone.convert(start),
+        None => start
+    };
+    let when = if instance.all_day {
+        local_start.format("%a %e %b").to_string()
+    } else {
+        local_start.format("%a %e %b %H:%M").to_string()
+    };
+    let name = match (&instance.name, &alarm.description) {
+        (Some(name), _) => name.clone(),
+        (None, Some(description)) => description.clone(),
+        (None, None) => String::from("Untitled event")
+    };
+    match &instance.location {
+        Some(location) => return format!("Reminder: {} at {}, {}", name, when, location),
+        None => return format!("Reminder: {} at {}", name, when)
+    }
+}
+
+#[cfg(test)]
+mod cal_alarm_tests {
+    use super::*;
+
+    fn time(input: &str) -> DateTime<FixedOffset> {
+        return DateTime::parse_from_rfc3339(input).unwrap();
+    }
+
+    #[test]
+    fn test_parse_and_format_ical_duration() {
+        assert_eq!(Duration::minutes(-15), parse_ical_duration("-PT15M").unwrap());
+        assert_eq!(Duration::hours(36), parse_ical_duration("P1DT12H").unwrap());
+        assert_eq!(Duration::weeks(2), parse_ical_duration("+P2W").unwrap());
+        assert_eq!(Duration::seconds(90), parse_ical_duration("PT1M30S").unwrap());
+        assert!(parse_ical_duration("PT15").is_err());
+        assert!(parse_ical_duration("P1H").is_err());
+        assert!(parse_ical_duration("15M").is_err());
+        assert!(parse_ical_duration("-P99999999999W").is_err());
+        assert!(parse_ical_duration("P1DT99999999999999999H").is_err());
+        assert!(parse_ical_duration("P15250284452471D").is_err());
+        for value in &["-PT15M", "P1DT12H", "P2W", "PT1M30S", "PT0S", "-P1D"] {
+            assert_eq!(*value, format_ical_duration(parse_ical_duration(value).unwrap()));
+        }
+    }
+
+    #[test]
+    fn test_alarm_with_overlong_trigger_is_skipped() {
+        use crate::mp_calendar::cal_io::read_events_from_ics_string;
+        use crate::mp_calendar::cal_tz::MpTimeZone;
+        let ics = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nBEGIN:VEVENT\r\nUID:far@example.com\r\nSUMMARY:Dentist\r\n\
+                   DTSTART:20240101T090000Z\r\nBEGIN:VALARM\r\nACTION:DISPLAY\r\nTRIGGER:-P99999999999W\r\nEND:VALARM\r\n\
+                   END:VEVENT\r\nEND:VCALENDAR\r\n";
+        let events = read_events_from_ics_string(ics, String::from("far.ics"), &MpTimeZone::utc()).unwrap();
+        assert_eq!(1, events.len());
+        assert!(events[0].alarms.is_empty());
+    }
+
+    fn alarm_test_events() -> Vec<MpEvent> {
+        use crate::mp_calendar::cal_io::{parse_file_to_ical_calendar, extract_events_from_ical};
+        use crate::mp_calendar::cal_tz::MpTimeZone;
+        let cal = parse_file_to_ical_calendar(String::from("src/test/test_alarms.ics")).unwrap();
+        return extract_events_from_ical(cal, &MpTimeZone::utc()).unwrap();
+    }
+
+    fn fresh_state_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("moneypenny_{}_{}.toml", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        return path.to_string_lossy().into_owned();
+    }
+
+    #[test]
+    fn test_scheduler_fires_repeats_once_across_restarts() {
+        let events = alarm_test_events();
+        let state_path = fresh_state_path("alarm_restarts");
+        let mut scheduler = AlarmScheduler::new(state_path.clone());
+        // Nothing from before the very first check is delivered
+        assert!(scheduler.tick(&events, time("2024-01-08T08:47:00+00:00")).is_empty());
+        let fired = scheduler.tick(&events, time("2024-01-08T08:52:00+00:00"));
+        assert_eq!(1, fired.len());
+        assert_eq!("Reminder: Team standup at Mon  8 Jan 09:00, Room 2", fired[0].body);
+        assert_eq!(time("2024-01-08T08:50:00+00:00"), fired[0].due);
+        let mut restarted = AlarmScheduler::new(state_path.clone());
+        let fired: Vec<DateTime<FixedOffset>> = restarted.tick(&events, time("2024-01-08T09:30:00+00:00"))
+            .iter().map(|alarm| alarm.due).collect();
+        assert_eq!(vec![time("2024-01-08T08:55:00+00:00")], fired);
+        let _ = std::fs::remove_file(&state_path);
+    }
+
+    #[test]
+    fn test_scheduler_snooze_survives_restart() {
+        let events = alarm_test_events();
+        let state_path = fresh_state_path("alarm_snooze");
+        let mut scheduler = AlarmScheduler::new(state_path.clone());
+        scheduler.tick(&events, time("2024-01-09T07:00:00+00:00"));
+        let fired = scheduler.tick(&events, time("2024-01-09T08:01:00+00:00"));
+        assert_eq!(1, fired.len());
+        assert_eq!("Reminder: Report deadline at Tue  9 Jan 17:00", fired[0].body);
+        scheduler.snooze(&fired[0], time("2024-01-09T08:10:00+00:00"));
+        let mut restarted = AlarmScheduler::new(state_path.clone());
+        assert!(restarted.tick(&events, time("2024-01-09T08:05:00+00:00")).is_empty());
+        let fired_again = restarted.tick(&events, time("2024-01-09T08:10:00+00:00"));
+        assert_eq!(vec![fired[0].key.clone()], fired_again.iter().map(|alarm| alarm.key.clone()).collect::<Vec<String>>());
+        assert!(restarted.tick(&events, time("2024-01-09T08:20:00+00:00")).is_empty());
+        let _ = std::fs::remove_file(&state_path);
+    }
+
+    #[test]
+    fn test_scheduler_uses_override_alarms_and_limits_missed_alarms() {
+        let events = alarm_test_events();
+        let state_path = fresh_state_path("alarm_missed");
+        let mut scheduler = AlarmScheduler::new(state_path.clone());
+        scheduler.tick(&events, time("2024-01-08T10:00:00+00:00"));
+        // Two days later only the last MISSED_ALARM_LIMIT_HOURS are caught up on. The moved
+        // instance on the 10th has its own alarm instead of the series' three.
+        let fired: Vec<(String, DateTime<FixedOffset>)> = scheduler.tick(&events, time("2024-01-10T12:00:00+00:00"))
+            .into_iter().map(|alarm| (alarm.body, alarm.due)).collect();
+        let expected = vec![
+            (String::from("Reminder: Team standup (late start) at Wed 10 Jan 11:00"), time("2024-01-10T10:50:00+00:00"))
+        ];
+        assert_eq!(expected, fired);
+        let _ = std::fs::remove_file(&state_path);
+    }
+
+    #[test]
+    fn test_fire_times_with_repeats() {
+        let mut alarm = MpAlarm::new(AlarmAction::Display, AlarmTrigger::Relative { offset: Duration::minutes(-15), from_end: false });
+        alarm.repeat = 2;
+        alarm.repeat_interval = Some(Duration::minutes(5));
+        let start = time("2024-01-08T09:00:00+00:00");
+        let expected = vec![time("2024-01-08T08:45:00+00:00"), time("2024-01-08T08:50:00+00:00"), time("2024-01-08T08:55:00+00:00")];
+        assert_eq!(expected, alarm.fire_times(start, None));
+        let at_end = MpAlarm::new(AlarmAction::Audio, AlarmTrigger::Relative { offset: Duration::zero(), from_end: true });
+        assert_eq!(vec![time("2024-01-08T09:30:00+00:00")], at_end.fire_times(start, Some(time("2024-01-08T09:30:00+00:00"))));
+    }
+
+    #[test]
+    fn test_upcoming_alarms() {
+        let events = alarm_test_events();
+        let upcoming: Vec<DateTime<FixedOffset>> = upcoming_alarms(&events, time("2024-01-08T08:50:00+00:00"), time("2024-01-09T08:50:00+00:00"))
+            .into_iter().map(|alarm| alarm.due).collect();
+        // The deadline's absolute alarm falls between the first standup's repeats and the second's,
+        // and both ends of the window are included
+        let expected = vec![time("2024-01-08T08:55:00+00:00"), time("2024-01-09T08:00:00+00:00"),
+                            time("2024-01-09T08:45:00+00:00"), time("2024-01-09T08:50:00+00:00")];
+        assert_eq!(expected, upcoming);
+        assert!(upcoming_alarms(&events, time("2024-01-20T00:00:00+00:00"), time("2024-01-21T00:00:00+00:00")).is_empty());
+    }
+}
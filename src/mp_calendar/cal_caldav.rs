@@ -0,0 +1,547 @@
+use std::collections::HashMap;
+use chrono::prelude::*;
+use ureq::http;
+use super::{MpEvent, MpCalendarError, cal_io};
+use super::cal_text::escape_markup;
+use super::cal_tz::MpTimeZone;
+
+pub(super) const DAV_NS: &str = "DAV:";
+pub(super) const CALDAV_NS: &str = "urn:ietf:params:xml:ns:caldav";
+pub(super) const CALENDARSERVER_NS: &str = "http://calendarserver.org/ns/";
+
+/// A calendar collection found by CalDavClient::discover_calendars. href is the server's path for it.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CalendarCollection {
+    pub href: String,
+    pub display_name: Option<String>,
+    // Changes whenever anything in the collection does (CalendarServer extension)
+    pub ctag: Option<String>,
+    pub sync_token: Option<String>
+}
+
+/// One calendar object resource: an .ics on the server holding an event and its modified instances
+#[derive(Debug, Clone)]
+pub struct RemoteEvent {
+    pub href: String,
+    pub etag: Option<String>,
+    pub events: Vec<MpEvent>
+}
+
+/// What changed in a collection since the sync token passed to CalDavClient::sync_collection.
+/// Pass sync_token to the next call to get only what changes after this one.
+#[derive(Debug, Clone)]
+pub struct SyncChanges {
+    pub changed: Vec<RemoteEvent>,
+    pub removed: Vec<String>,
+    pub sync_token: String
+}
+
+/// Where a PUT left the event, for updating or deleting it later
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct StoredResource {
+    pub href: String,
+    pub etag: Option<String>
+}
+
+struct DavReply {
+    status: u16,
+    etag: Option<String>,
+    body: String
+}
+
+// A prop from a 200 propstat, cut down to what the client reads: its text, the DAV:href
+// elements inside it and the names of its child elements
+#[derive(Debug, Default)]
+struct DavProp {
+    text: String,
+    hrefs: Vec<String>,
+    children: Vec<(String, String)>
+}
+
+// One <response> of a 207 Multi-Status. status is only set when the whole response has one,
+// as sync-collection uses for removed resources.
+#[derive(Debug, Default)]
+struct DavResponse {
+    href: String,
+    status: Option<u16>,
+    props: HashMap<(String, String), DavProp>
+}
+
+impl DavResponse {
+    fn prop(&self, namespace: &str, name: &str) -> Option<&DavProp> {
+        return self.props.get(&(String::from(namespace), String::from(name)));
+    }
+
+    fn prop_text(&self, namespace: &str, name: &str) -> Option<String> {
+        return self.prop(namespace, name).map(|prop| prop.text.clone()).filter(|text| !text.is_empty());
+    }
+}
+
+/// Pulls events from and pushes them to a CalDAV server (RFC 4791), with sync-collection
+/// (RFC 6578) for incremental updates. Event data is read and written with cal_io, floating
+/// times read in local_zone.
+pub struct CalDavClient {
+    agent: ureq::Agent,
+    base_url: String,
+    authorization: Option<String>,
+    local_zone: MpTimeZone
+}
+
+impl CalDavClient {
+    /// base_url is where discovery starts, typically the server root or a principal URL
+    pub fn new(base_url: &str, local_zone: MpTimeZone) -> CalDavClient {
+        let config = ureq::Agent::config_builder()
+            .http_status_as_error(false)
+            .allow_non_standard_methods(true)
+            .timeout_global(Some(std::time::Duration::from_secs(30)))
+            .build();
+        return CalDavClient {
+            agent: ureq::Agent::new_with_config(config),
+            base_url: String::from(base_url),
+            authorization: None,
+            local_zone
+        };
+    }
+
+    /// Sends HTTP Basic credentials with every request
+    pub fn with_credentials(mut self, user: &str, password: &str) -> CalDavClient {
+        use base64::Engine;
+
+        let encoded = base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, password));
+        self.authorization = Some(format!("Basic {}", encoded));
+        return self;
+    }
+
+    /// Finds the user's calendars: the current principal from base_url, its calendar home, then
+    /// every calendar collection in the home. A server without principals is searched from base_url.
+    pub fn discover_calendars(&self) -> Result<Vec<CalendarCollection>, MpCalendarError> {
+        let principal = self.propfind_href(&self.base_url, DAV_NS, "current-user-principal")?;
+        let principal_url = match &principal {
+            Some(href) => self.resolve(href),
+            None => self.base_url.clone()
+        };
+        let home = self.propfind_href(&principal_url, CALDAV_NS, "calendar-home-set")?;
+        let home_url = match &home {
+            Some(href) => self.resolve(href),
+            None => principal_url
+        };
+        let body = format!("<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+                            <D:propfind xmlns:D=\"{}\" xmlns:C=\"{}\" xmlns:CS=\"{}\"><D:prop>\
+                            <D:resourcetype/><D:displayname/><D:sync-token/><CS:getctag/>\
+                            </D:prop></D:propfind>", DAV_NS, CALDAV_NS, CALENDARSERVER_NS);
+        let reply = self.request("PROPFIND", &home_url, &[("Depth", String::from("1"))], body)?;
+        let (responses, _) = self.multistatus(&home_url, reply)?;
+        let calendars = responses.into_iter()
+            .filter(|response| response.prop(DAV_NS, "resourcetype")
+                .is_some_and(|prop| prop.children.contains(&(String::from(CALDAV_NS), String::from("calendar")))))
+            .map(|response| CalendarCollection {
+                display_name: response.prop_text(DAV_NS, "displayname"),
+                ctag: response.prop_text(CALENDARSERVER_NS, "getctag"),
+                sync_token: response.prop_text(DAV_NS, "sync-token"),
+                href: response.href
+            })
+            .collect();
+        return Ok(calendars);
+    }
+
+    /// Events in the collection with an occurrence in [window_start, window_end), by calendar-query.
+    /// The server does the recurrence expansion, so whole series come back.
+    pub fn events_in_range(&self,
+                           collection_href: &str,
+                           window_start: DateTime<FixedOffset>,
+                           window_end: DateTime<FixedOffset>) -> Result<Vec<RemoteEvent>, MpCalendarError> {
+        let body = format!("<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+                            <C:calendar-query xmlns:D=\"{}\" xmlns:C=\"{}\">\
+                            <D:prop><D:getetag/><C:calendar-data/></D:prop>\
+                            <C:filter><C:comp-filter name=\"VCALENDAR\"><C:comp-filter name=\"VEVENT\">\
+                            <C:time-range start=\"{}\" end=\"{}\"/>\
+                            </C:comp-filter></C:comp-filter></C:filter></C:calendar-query>",
+                           DAV_NS, CALDAV_NS,
+                           cal_io::convert_fixed_offset_to_ical_utc_time(window_start),
+                           cal_io::convert_fixed_offset_to_ical_utc_time(window_end));
+        let url = self.resolve(collection_href);
+        let reply = self.request("REPORT", &url, &[("Depth", String::from("1"))], body)?;
+        let (responses, _) = self.multistatus(&url, reply)?;
+        let mut events: Vec<RemoteEvent> = vec![];
+        for response in responses {
+            match self.remote_event(response)? {
+                Some(event) => events.push(event),
+                None => ()
+            };
+        }
+        return Ok(events);
+    }
+
+    /// Everything that changed in the collection since sync_token, or everything in it if there's
+    /// no token yet. An expired token gives MpCalendarError::PreconditionFailed; sync again from None.
+    pub fn sync_collection(&self, collection_href: &str, sync_token: Option<&str>) -> Result<SyncChanges, MpCalendarError> {
+        let body = format!("<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+                            <D:sync-collection xmlns:D=\"{}\" xmlns:C=\"{}\">\
+                            <D:sync-token>{}</D:sync-token><D:sync-level>1</D:sync-level>\
+                            <D:prop><D:getetag/><C:calendar-data/></D:prop></D:sync-collection>",
+                           DAV_NS, CALDAV_NS, escape_markup(sync_token.unwrap_or("")));
+        let url = self.resolve(collection_href);
+        let reply = self.request("REPORT", &url, &[], body)?;
+        // RFC 6578 says 403 with DAV:valid-sync-token, but some servers use 409 or 410
+        if sync_token.is_some() && matches!(reply.status, 403 | 409 | 410) {
+            return Err(MpCalendarError::PreconditionFailed { url });
+        }
+        let (responses, new_token) = self.multistatus(&url, reply)?;
+        let mut changes = SyncChanges {
+            changed: vec![],
+            removed: vec![],
+            sync_token: new_token.ok_or_else(|| MpCalendarError::Remote { url: url.clone(), message: String::from("no sync-token in response") })?
+        };
+        for response in responses {
+            if response.status == Some(404) {
+                changes.removed.push(response.href);
+                continue;
+            }
+            // The collection itself can appear when its properties change
+            if same_path(&response.href, collection_href) {
+                continue;
+            }
+            match self.remote_event(response)? {
+                Some(event) => changes.changed.push(event),
+                None => ()
+            };
+        }
+        return Ok(changes);
+    }
+
+    /// Fetches a single calendar object resource
+    pub fn get_event(&self, href: &str) -> Result<RemoteEvent, MpCalendarError> {
+        let (etag, body) = self.fetch(href)?;
+        let events = cal_io::read_events_from_ics_string(&body, self.resolve(href), &self.local_zone)?;
+        return Ok(RemoteEvent { href: String::from(href), etag, events });
+    }
+
+    /// Uploads event, with its modified instances, to the collection as a new resource named
+    /// <UID>.ics. Only happens if there's no resource there yet; if there is, the result is
+    /// MpCalendarError::PreconditionFailed. Use update_event for events already on the server.
+    pub fn create_event(&self, collection_href: &str, event: &MpEvent) -> Result<StoredResource, MpCalendarError> {
+        let uid = match &event.uid {
+            Some(uid) => uid.clone(),
+            None => cal_io::generate_uid(event)
+        };
+        let href = format!("{}/{}.ics", collection_href.trim_end_matches('/'), encode_path_segment(&uid));
+        return self.put_event(href, event, ("If-None-Match", String::from("*")));
+    }
+
+    /// Replaces the resource at href, which is wherever the server keeps the event (RemoteEvent::href
+    /// or StoredResource::href) and need not be named after its UID. With if_match set the upload
+    /// only happens if the server's copy still has that ETag, else it's MpCalendarError::PreconditionFailed.
+    pub fn update_event(&self, href: &str, event: &MpEvent, if_match: Option<&str>) -> Result<StoredResource, MpCalendarError> {
+        let condition = match if_match {
+            Some(etag) => ("If-Match", String::from(etag)),
+            // Still refuse to create a resource that's gone from the server
+            None => ("If-Match", String::from("*"))
+        };
+        return self.put_event(String::from(href), event, condition);
+    }
+
+    /// Deletes the resource at href, if it still has ETag if_match when that's given
+    pub fn delete_event(&self, href: &str, if_match: Option<&str>) -> Result<(), MpCalendarError> {
+        let headers: Vec<(&str, String)> = if_match.map(|etag| ("If-Match", String::from(etag))).into_iter().collect();
+        let url = self.resolve(href);
+        let reply = self.request("DELETE", &url, &headers, String::new())?;
+        match reply.status {
+            200 | 202 | 204 => return Ok(()),
+            412 => return Err(MpCalendarError::PreconditionFailed { url }),
+            status => return Err(MpCalendarError::HttpStatus { url, status })
+        }
+    }
+
+    // The href inside property name of url, found with a Depth 0 PROPFIND
+    fn propfind_href(&self, url: &str, namespace: &str, name: &str) -> Result<Option<String>, MpCalendarError> {
+        let prefix = if namespace == DAV_NS { "D" } else { "C" };
+        let body = format!("<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+                            <D:propfind xmlns:D=\"{}\" xmlns:C=\"{}\"><D:prop><{}:{}/></D:prop></D:propfind>",
+                           DAV_NS, CALDAV_NS, prefix, name);
+        let reply = self.request("PROPFIND", url, &[("Depth", String::from("0"))], body)?;
+        let (responses, _) = self.multistatus(url, reply)?;
+        return Ok(responses.iter()
+            .filter_map(|response| response.prop(namespace, name))
+            .find_map(|prop| prop.hrefs.first().cloned()));
+    }
+
+    fn put_event(&self, href: String, event: &MpEvent, condition: (&str, String)) -> Result<StoredResource, MpCalendarError> {
+        let body = cal_io::deserialise_mpevents_to_ics_string(std::slice::from_ref(event));
+        let url = self.resolve(&href);
+        let reply = self.request("PUT", &url, &[condition, ("Content-Type", String::from("text/calendar; charset=utf-8"))], body)?;
+        match reply.status {
+            200 | 201 | 204 => return Ok(StoredResource { href, etag: reply.etag }),
+            412 => return Err(MpCalendarError::PreconditionFailed { url }),
+            status => return Err(MpCalendarError::HttpStatus { url, status })
+        }
+    }
+
+    // The ETag and body of a GET of href
+    fn fetch(&self, href: &str) -> Result<(Option<String>, String), MpCalendarError> {
+        let url = self.resolve(href);
+        let reply = self.request("GET", &url, &[], String::new())?;
+        if reply.status != 200 {
+            return Err(MpCalendarError::HttpStatus { url, status: reply.status });
+        }
+        return Ok((reply.etag, reply.body));
+    }
+
+    // None for a resource with no events. One that can't be read is reported and skipped too, so
+    // a single bad resource doesn't hide the rest of the collection.
+    fn remote_event(&self, response: DavResponse) -> Result<Option<RemoteEvent>, MpCalendarError> {
+        let (etag, data) = match response.prop_text(CALDAV_NS, "calendar-data") {
+            Some(data) => (response.prop_text(DAV_NS, "getetag"), data),
+            // Not every server sends calendar-data in a sync-collection report
+            None => self.fetch(&response.href)?
+        };
+        let source = self.resolve(&response.href);
+        match cal_io::read_events_from_ics_string(&data, source.clone(), &self.local_zone) {
+            Ok(events) if events.is_empty() => return Ok(None),
+            Ok(events) => return Ok(Some(RemoteEvent { href: response.href, etag, events })),
+            Err(e) => {
+                super::output_mp_calendar_message(format!("Skipping unreadable calendar resource {}: {}", source, e));
+                return Ok(None);
+            }
+        }
+    }
+
+    fn request(&self, method: &str, url: &str, headers: &[(&str, String)], body: String) -> Result<DavReply, MpCalendarError> {
+        let remote_error = |message: String| MpCalendarError::Remote { url: String::from(url), message };
+        let method = http::Method::from_bytes(method.as_bytes()).map_err(|e| remote_error(e.to_string()))?;
+        let mut builder = http::Request::builder().method(method).uri(url);
+        if !body.is_empty() && !headers.iter().any(|(name, _)| *name == "Content-Type") {
+            builder = builder.header("Content-Type", "application/xml; charset=utf-8");
+        }
+        for (name, value) in headers {
+            builder = builder.header(*name, value.as_str());
+        }
+        match &self.authorization {
+            Some(authorization) => builder = builder.header("Authorization", authorization.as_str()),
+            None => ()
+        };
+        let request = builder.body(body).map_err(|e| remote_error(e.to_string()))?;
+        let mut response = self.agent.run(request).map_err(|e| remote_error(e.to_string()))?;
+        let etag = response.headers().get("ETag")
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+        let body = response.body_mut().read_to_string().map_err(|e| remote_error(e.to_string()))?;
+        return Ok(DavReply { status: response.status().as_u16(), etag, body });
+    }
+
+    // The responses of a 207 Multi-Status and its top level sync-token, if any
+    fn multistatus(&self, url: &str, reply: DavReply) -> Result<(Vec<DavResponse>, Option<String>), MpCalendarError> {
+        if reply.status != 207 {
+            return Err(MpCalendarError::HttpStatus { url: String::from(url), status: reply.status });
+        }
+        return parse_multistatus(&reply.body).map_err(|message| MpCalendarError::Remote { url: String::from(url), message });
+    }
+
+    // An href from the server as a full URL. Absolute paths are taken from base_url's origin.
+    fn resolve(&self, href: &str) -> String {
+        if href.starts_with("http://") || href.starts_with("https://") {
+            return String::from(href);
+        }
+        let after_scheme = self.base_url.find("://").map(|index| index + 3).unwrap_or(0);
+        let origin_end = self.base_url[after_scheme..].find('/').map(|index| index + after_scheme).unwrap_or(self.base_url.len());
+        if href.starts_with('/') {
+            return format!("{}{}", &self.base_url[..origin_end], href);
+        }
+        let directory_end = self.base_url.rfind('/').filter(|index| *index >= origin_end).map(|index| index + 1);
+        match directory_end {
+            Some(end) => return format!("{}{}", &self.base_url[..end], href),
+            None => return format!("{}/{}", &self.base_url[..origin_end], href)
+        }
+    }
+}
+
+fn parse_multistatus(xml: &str) -> Result<(Vec<DavResponse>, Option<String>), String> {
+    let document = roxmltree::Document::parse(xml).map_err(|e| e.to_string())?;
+    let root = document.root_element();
+    if !is_element(&root, DAV_NS, "multistatus") {
+        return Err(String::from("expected a DAV:multistatus"));
+    }
+    let mut responses: Vec<DavResponse> = vec![];
+    let mut sync_token: Option<String> = None;
+    for child in root.children().filter(|node| node.is_element()) {
+        if is_element(&child, DAV_NS, "sync-token") {
+            sync_token = Some(element_text(&child));
+            continue;
+        }
+        if !is_element(&child, DAV_NS, "response") {
+            continue;
+        }
+        let mut response = DavResponse::default();
+        for part in child.children().filter(|node| node.is_element()) {
+            if is_element(&part, DAV_NS, "href") {
+                response.href = element_text(&part);
+            } else if is_element(&part, DAV_NS, "status") {
+                response.status = parse_status_line(&element_text(&part));
+            } else if is_element(&part, DAV_NS, "propstat") {
+                let status = part.children()
+                    .find(|node| is_element(node, DAV_NS, "status"))
+                    .and_then(|node| parse_status_line(&element_text(&node)));
+                if status != Some(200) {
+                    continue;
+                }
+                let props = part.children().filter(|node| is_element(node, DAV_NS, "prop")).flat_map(|prop| prop.children());
+                for prop in props.filter(|node| node.is_element()) {
+                    let name = (String::from(prop.tag_name().namespace().unwrap_or("")), String::from(prop.tag_name().name()));
+                    let value = DavProp {
+                        text: element_text(&prop),
+                        hrefs: prop.descendants().filter(|node| is_element(node, DAV_NS, "href")).map(|node| element_text(&node)).collect(),
+                        children: prop.children()
+                            .filter(|node| node.is_element())
+                            .map(|node| (String::from(node.tag_name().namespace().unwrap_or("")), String::from(node.tag_name().name())))
+                            .collect()
+                    };
+                    response.props.insert(name, value);
+                }
+            }
+        }
+        responses.push(response);
+    }
+    return Ok((responses, sync_token));
+}
+
+fn is_element(node: &roxmltree::Node, namespace: &str, name: &str) -> bool {
+    return node.is_element() && node.tag_name().name() == name && node.tag_name().namespace() == Some(namespace);
+}
+
+// All the text inside an element, trimmed. calendar-data keeps its inner line breaks.
+fn element_text(node: &roxmltree::Node) -> String {
+    let text: String = node.descendants().filter(|node| node.is_text()).filter_map(|node| node.text()).collect();
+    return String::from(text.trim());
+}
+
+// "HTTP/1.1 404 Not Found" to 404
+fn parse_status_line(line: &str) -> Option<u16> {
+    return line.split_whitespace().nth(1).and_then(|code| code.parse().ok());
+}
+
+fn same_path(lhs: &str, rhs: &str) -> bool {
+    return lhs.trim_end_matches('/') == rhs.trim_end_matches('/');
+}
+
+// UIDs often contain '@' and can contain anything, so everything but unreserved characters is escaped
+pub(super) fn encode_path_segment(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    return encoded;
+}
+
+#[cfg(test)]
+mod caldav_stand_in;
+
+#[cfg(test)]
+mod cal_caldav_tests {
+    use super::*;
+    use super::caldav_stand_in::StandInServer;
+
+    fn client_for(server: &StandInServer) -> CalDavClient {
+        return CalDavClient::new(&server.base_url(), MpTimeZone::utc()).with_credentials("tom", "secret");
+    }
+
+    fn names(remote: &[RemoteEvent]) -> Vec<&str> {
+        let mut names: Vec<&str> = remote.iter().flat_map(|remote| remote.events.iter()).map(|event| event.name().unwrap()).collect();
+        names.sort_unstable();
+        return names;
+    }
+
+    #[test]
+    fn test_discover_calendars() {
+        let server = StandInServer::start("src/test/test_conflicts.ics");
+        let calendars = client_for(&server).discover_calendars().unwrap();
+        assert_eq!(1, calendars.len());
+        assert_eq!("/calendars/tom/work/", calendars[0].href);
+        assert_eq!(Some(String::from("Work")), calendars[0].display_name);
+        assert!(calendars[0].sync_token.is_some());
+        assert_eq!(Some(String::from("Basic dG9tOnNlY3JldA==")), server.last_authorization());
+    }
+
+    #[test]
+    fn test_events_in_range() {
+        let server = StandInServer::start("src/test/test_conflicts.ics");
+        let window_start = FixedOffset::east(0).ymd(2024, 1, 9).and_hms(0, 0, 0);
+        let found = client_for(&server).events_in_range("/calendars/tom/work/", window_start, window_start + chrono::Duration::days(1)).unwrap();
+        // The recurring standup has an occurrence in the window, so the whole series comes back
+        assert_eq!(vec!["Focus block", "Standup"], names(&found));
+        assert!(found.iter().all(|remote| remote.etag.is_some() && remote.href.ends_with(".ics")));
+    }
+
+    #[test]
+    fn test_sync_put_and_delete() {
+        let server = StandInServer::start("src/test/test_conflicts.ics");
+        let client = client_for(&server);
+        let collection = "/calendars/tom/work/";
+        let initial = client.sync_collection(collection, None).unwrap();
+        assert_eq!(9, initial.changed.len());
+        assert!(initial.removed.is_empty());
+
+        let nothing = client.sync_collection(collection, Some(&initial.sync_token)).unwrap();
+        assert!(nothing.changed.is_empty() && nothing.removed.is_empty());
+
+        let lunch = initial.changed.iter().find(|remote| names(std::slice::from_ref(remote)) == ["Lunch"]).unwrap();
+        let mut renamed = lunch.events[0].clone();
+        renamed.name = Some(String::from("Team lunch"));
+        // The server named the resource, not after the UID, and the update goes to that name
+        assert!(!lunch.href.contains(lunch.events[0].uid.as_deref().unwrap()));
+        let stored = client.update_event(&lunch.href, &renamed, lunch.etag.as_deref()).unwrap();
+        assert_eq!(lunch.href, stored.href);
+        assert_ne!(lunch.etag, stored.etag);
+        // The old ETag no longer matches, for updates and deletes alike
+        assert!(matches!(client.update_event(&lunch.href, &renamed, lunch.etag.as_deref()), Err(MpCalendarError::PreconditionFailed { .. })));
+        assert!(matches!(client.delete_event(&lunch.href, lunch.etag.as_deref()), Err(MpCalendarError::PreconditionFailed { .. })));
+        // Creating with a UID that's already there is refused too
+        assert!(matches!(client.create_event(collection, &renamed), Err(MpCalendarError::HttpStatus { status: 403, .. })));
+
+        let walk = initial.changed.iter().find(|remote| names(std::slice::from_ref(remote)) == ["Walk"]).unwrap();
+        client.delete_event(&walk.href, walk.etag.as_deref()).unwrap();
+        let mut new_event = renamed.clone();
+        new_event.uid = Some(String::from("new event@example.com"));
+        new_event.name = Some(String::from("Retro"));
+        let created = client.create_event(collection, &new_event).unwrap();
+        assert_eq!("/calendars/tom/work/new%20event%40example.com.ics", created.href);
+        assert!(matches!(client.create_event(collection, &new_event), Err(MpCalendarError::PreconditionFailed { .. })));
+        // Without an ETag an update still needs something there to replace
+        assert!(matches!(client.update_event(&walk.href, &new_event, None), Err(MpCalendarError::PreconditionFailed { .. })));
+
+        let changes = client.sync_collection(collection, Some(&initial.sync_token)).unwrap();
+        assert_eq!(vec!["Retro", "Team lunch"], names(&changes.changed));
+        assert_eq!(vec![walk.href.clone()], changes.removed);
+        assert_eq!("Team lunch", client.get_event(&stored.href).unwrap().events[0].name().unwrap());
+
+        assert!(matches!(client.sync_collection(collection, Some("http://moneypenny.test/sync/unknown")),
+                         Err(MpCalendarError::PreconditionFailed { .. })));
+    }
+
+    #[test]
+    fn test_unreadable_resources_are_skipped() {
+        let server = StandInServer::start("src/test/test_conflicts.ics");
+        server.add_resource("/calendars/tom/work/broken.ics", "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nBEGIN:VEVENT\r\nDTSTART\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n");
+        let client = client_for(&server);
+        let changes = client.sync_collection("/calendars/tom/work/", None).unwrap();
+        assert_eq!(9, changes.changed.len());
+        assert!(changes.changed.iter().all(|remote| remote.href != "/calendars/tom/work/broken.ics"));
+        let window_start = FixedOffset::east(0).ymd(2024, 1, 9).and_hms(0, 0, 0);
+        let found = client.events_in_range("/calendars/tom/work/", window_start, window_start + chrono::Duration::days(1)).unwrap();
+        assert_eq!(vec!["Focus block", "Standup"], names(&found));
+        // Asked for on its own, it's still an error
+        assert!(client.get_event("/calendars/tom/work/broken.ics").is_err());
+    }
+
+    #[test]
+    fn test_resolve() {
+        let client = CalDavClient::new("https://dav.example.com/dav/principals/", MpTimeZone::utc());
+        assert_eq!("https://dav.example.com/calendars/tom/", client.resolve("/calendars/tom/"));
+        assert_eq!("https://dav.example.com/dav/principals/tom/", client.resolve("tom/"));
+        assert_eq!("https://other.example.com/x", client.resolve("https://other.example.com/x"));
+        let bare = CalDavClient::new("http://localhost:8008", MpTimeZone::utc());
+        assert_eq!("http://localhost:8008/calendars/", bare.resolve("/calendars/"));
+    }
+}
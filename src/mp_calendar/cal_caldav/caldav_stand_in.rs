@@ -0,0 +1,258 @@
+// A small in-process CalDAV server for testing CalDavClient without a real one. It serves one
+// principal (tom) with one calendar (work), and supports just what the client uses: discovery
+// PROPFINDs, calendar-query and sync-collection REPORTs, GET, and conditional PUT and DELETE.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use chrono::prelude::*;
+use crate::mp_calendar::{cal_io, MpEvent};
+use crate::mp_calendar::cal_tz::MpTimeZone;
+use crate::mp_calendar::cal_text::escape_markup;
+
+const PRINCIPAL: &str = "/principals/tom/";
+const HOME: &str = "/calendars/tom/";
+const COLLECTION: &str = "/calendars/tom/work/";
+const SYNC_TOKEN_PREFIX: &str = "http://moneypenny.test/sync/";
+
+struct Resource {
+    ics: String,
+    etag: String
+}
+
+#[derive(Default)]
+struct StandInState {
+    resources: BTreeMap<String, Resource>,
+    // (sync token it happened in, href) for every PUT and DELETE
+    changes: Vec<(u64, String)>,
+    sync_token: u64,
+    next_etag: u64,
+    last_authorization: Option<String>
+}
+
+impl StandInState {
+    fn store(&mut self, href: String, ics: String) -> String {
+        self.next_etag += 1;
+        let etag = format!("\"{}\"", self.next_etag);
+        self.resources.insert(href.clone(), Resource { ics, etag: etag.clone() });
+        self.record_change(href);
+        return etag;
+    }
+
+    fn record_change(&mut self, href: String) {
+        self.sync_token += 1;
+        self.changes.push((self.sync_token, href));
+    }
+
+    // Whether a resource other than href already holds an event with one of the UIDs in ics
+    fn uid_taken(&self, href: &str, ics: &str) -> bool {
+        let uids = |ics: &str| -> Vec<String> {
+            return cal_io::read_events_from_ics_string(ics, String::new(), &MpTimeZone::utc())
+                .map(|events| events.into_iter().filter_map(|event| event.uid).collect())
+                .unwrap_or_default();
+        };
+        let new_uids = uids(ics);
+        return self.resources.iter()
+            .filter(|(other, _)| other.as_str() != href)
+            .any(|(_, resource)| uids(&resource.ics).iter().any(|uid| new_uids.contains(uid)));
+    }
+
+    fn sync_token(&self) -> String {
+        return format!("{}{}", SYNC_TOKEN_PREFIX, self.sync_token);
+    }
+}
+
+pub struct StandInServer {
+    server: Arc<tiny_http::Server>,
+    state: Arc<Mutex<StandInState>>,
+    thread: Option<JoinHandle<()>>
+}
+
+impl StandInServer {
+    /// Serves the events of ics_path, one resource per UID, on a free local port. Like many real
+    /// servers it names the resources itself, so their hrefs have nothing to do with the UIDs.
+    pub fn start(ics_path: &str) -> StandInServer {
+        let mut state = StandInState::default();
+        let events = cal_io::read_events_from_ics_file(String::from(ics_path), &MpTimeZone::utc()).unwrap();
+        for (index, event) in events.into_iter().enumerate() {
+            let href = format!("{}event-{}.ics", COLLECTION, index + 1);
+            state.store(href, cal_io::deserialise_mpevents_to_ics_string(&[event]));
+        }
+        let server = Arc::new(tiny_http::Server::http("127.0.0.1:0").unwrap());
+        let state = Arc::new(Mutex::new(state));
+        let thread = {
+            let server = Arc::clone(&server);
+            let state = Arc::clone(&state);
+            std::thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    handle(request, &state);
+                }
+            })
+        };
+        return StandInServer { server, state, thread: Some(thread) };
+    }
+
+    pub fn base_url(&self) -> String {
+        return format!("http://{}/", self.server.server_addr().to_ip().unwrap());
+    }
+
+    pub fn last_authorization(&self) -> Option<String> {
+        return self.state.lock().unwrap().last_authorization.clone();
+    }
+
+    /// Puts ics in the collection at href as it is, readable or not
+    pub fn add_resource(&self, href: &str, ics: &str) {
+        self.state.lock().unwrap().store(String::from(href), String::from(ics));
+    }
+}
+
+impl Drop for StandInServer {
+    fn drop(&mut self) {
+        self.server.unblock();
+        match self.thread.take() {
+            Some(thread) => { let _ = thread.join(); },
+            None => ()
+        };
+    }
+}
+
+fn header(request: &tiny_http::Request, name: &'static str) -> Option<String> {
+    return request.headers().iter()
+        .find(|header| header.field.equiv(name))
+        .map(|header| header.value.as_str().to_string());
+}
+
+fn handle(mut request: tiny_http::Request, state: &Mutex<StandInState>) {
+    let mut body = String::new();
+    let _ = request.as_reader().read_to_string(&mut body);
+    let method = request.method().as_str().to_string();
+    let url = request.url().to_string();
+    let mut state = state.lock().unwrap();
+    state.last_authorization = header(&request, "Authorization");
+    let (status, etag, reply) = match (method.as_str(), url.as_str()) {
+        ("PROPFIND", "/") => (207, None, multistatus(&[prop_response("/", &format!("<D:current-user-principal><D:href>{}</D:href></D:current-user-principal>", PRINCIPAL))], None)),
+        ("PROPFIND", PRINCIPAL) => (207, None, multistatus(&[prop_response(PRINCIPAL, &format!("<C:calendar-home-set><D:href>{}</D:href></C:calendar-home-set>", HOME))], None)),
+        ("PROPFIND", HOME) => {
+            let collection_props = format!("<D:resourcetype><D:collection/><C:calendar/></D:resourcetype>\
+                                            <D:displayname>Work</D:displayname><D:sync-token>{}</D:sync-token>\
+                                            <CS:getctag>{}</CS:getctag>", state.sync_token(), state.sync_token);
+            let responses = [
+                prop_response(HOME, "<D:resourcetype><D:collection/></D:resourcetype>"),
+                prop_response(COLLECTION, &collection_props)
+            ];
+            (207, None, multistatus(&responses, None))
+        },
+        ("REPORT", COLLECTION) if body.contains("calendar-query") => (207, None, calendar_query(&state, &body)),
+        ("REPORT", COLLECTION) if body.contains("sync-collection") => match sync_collection(&state, &body) {
+            Some(reply) => (207, None, reply),
+            None => (403, None, String::from("<D:error xmlns:D=\"DAV:\"><D:valid-sync-token/></D:error>"))
+        },
+        ("GET", href) => match state.resources.get(href) {
+            Some(resource) => (200, Some(resource.etag.clone()), resource.ics.clone()),
+            None => (404, None, String::new())
+        },
+        ("PUT", href) => {
+            let current = state.resources.get(href).map(|resource| resource.etag.clone());
+            let allowed = match (header(&request, "If-Match"), header(&request, "If-None-Match")) {
+                (Some(if_match), _) if if_match == "*" => current.is_some(),
+                (Some(if_match), _) => current.as_deref() == Some(if_match.as_str()),
+                (None, Some(_)) => current.is_none(),
+                (None, None) => true
+            };
+            if allowed && state.uid_taken(href, &body) {
+                (403, None, String::from("<D:error xmlns:D=\"DAV:\" xmlns:C=\"urn:ietf:params:xml:ns:caldav\"><C:no-uid-conflict/></D:error>"))
+            } else if allowed {
+                let etag = state.store(String::from(href), body);
+                (if current.is_some() { 204 } else { 201 }, Some(etag), String::new())
+            } else {
+                (412, None, String::new())
+            }
+        },
+        ("DELETE", href) => {
+            let current = state.resources.get(href).map(|resource| resource.etag.clone());
+            match (current, header(&request, "If-Match")) {
+                (None, _) => (404, None, String::new()),
+                (Some(etag), Some(if_match)) if etag != if_match => (412, None, String::new()),
+                (Some(_), _) => {
+                    state.resources.remove(href);
+                    state.record_change(String::from(href));
+                    (204, None, String::new())
+                }
+            }
+        },
+        _ => (405, None, String::new())
+    };
+    let mut response = tiny_http::Response::from_string(reply).with_status_code(status);
+    match etag {
+        Some(etag) => response = response.with_header(tiny_http::Header::from_bytes("ETag", etag).unwrap()),
+        None => ()
+    };
+    let _ = request.respond(response);
+}
+
+fn multistatus(responses: &[String], sync_token: Option<String>) -> String {
+    let token = sync_token.map(|token| format!("<D:sync-token>{}</D:sync-token>", token)).unwrap_or_default();
+    return format!("<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+                    <D:multistatus xmlns:D=\"DAV:\" xmlns:C=\"urn:ietf:params:xml:ns:caldav\" xmlns:CS=\"http://calendarserver.org/ns/\">\
+                    {}{}</D:multistatus>", responses.concat(), token);
+}
+
+fn prop_response(href: &str, props: &str) -> String {
+    return format!("<D:response><D:href>{}</D:href><D:propstat><D:prop>{}</D:prop>\
+                    <D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>", href, props);
+}
+
+fn resource_response(href: &str, resource: &Resource) -> String {
+    let props = format!("<D:getetag>{}</D:getetag><C:calendar-data>{}</C:calendar-data>",
+                        escape_markup(&resource.etag), escape_markup(&resource.ics));
+    return prop_response(href, &props);
+}
+
+fn calendar_query(state: &StandInState, body: &str) -> String {
+    let document = roxmltree::Document::parse(body).unwrap();
+    let range = document.descendants().find(|node| node.tag_name().name() == "time-range").unwrap();
+    let parse = |value: &str| Utc.datetime_from_str(value, "%Y%m%dT%H%M%SZ").unwrap().with_timezone(&FixedOffset::east(0));
+    let window_start = parse(range.attribute("start").unwrap());
+    let window_end = parse(range.attribute("end").unwrap());
+    let responses: Vec<String> = state.resources.iter()
+        .filter(|(href, resource)| {
+            // An unreadable resource is sent regardless, like a server that doesn't check what it's given
+            match cal_io::read_events_from_ics_string(&resource.ics, href.to_string(), &MpTimeZone::utc()) {
+                Ok(events) => events.iter().any(|event: &MpEvent| !event.expand_occurrences(window_start, window_end).is_empty()),
+                Err(_) => true
+            }
+        })
+        .map(|(href, resource)| resource_response(href, resource))
+        .collect();
+    return multistatus(&responses, None);
+}
+
+// None if the request's sync token isn't one this server gave out
+fn sync_collection(state: &StandInState, body: &str) -> Option<String> {
+    let document = roxmltree::Document::parse(body).unwrap();
+    let token = document.descendants()
+        .find(|node| node.tag_name().name() == "sync-token")
+        .and_then(|node| node.text())
+        .unwrap_or("");
+    let since: u64 = if token.is_empty() {
+        0
+    } else {
+        let number: u64 = token.strip_prefix(SYNC_TOKEN_PREFIX)?.parse().ok()?;
+        if number > state.sync_token {
+            return None;
+        }
+        number
+    };
+    let mut changed: Vec<&String> = state.changes.iter().filter(|(token, _)| *token > since).map(|(_, href)| href).collect();
+    changed.sort();
+    changed.dedup();
+    let responses: Vec<String> = changed.into_iter()
+        .filter_map(|href| match state.resources.get(href) {
+            Some(resource) => Some(resource_response(href, resource)),
+            // Nothing to report for a resource created and deleted since the client last synced
+            None if since == 0 => None,
+            None => Some(format!("<D:response><D:href>{}</D:href><D:status>HTTP/1.1 404 Not Found</D:status></D:response>", href))
+        })
+        .collect();
+    return Some(multistatus(&responses, Some(state.sync_token())));
+}
@@ -0,0 +1,408 @@
+use std::str::FromStr;
+use chrono::prelude::*;
+use serde::ser::{Serialize, Serializer, SerializeMap};
+use super::{MpEvent, MpCalendarError, cal_io, cal_phrase};
+use super::cal_text::escape_markup;
+use super::cal_tz::MpTimeZone;
+
+/// A column of an export. Which ones are written, and in what order, comes from export_fields in
+/// the config.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ExportField {
+    Uid,
+    Name,
+    Start,
+    End,
+    AllDay,
+    Duration,
+    Location,
+    Description,
+    Status,
+    // The recurrence rule in English, for whole series exported without after: or before:
+    Repeats
+}
+
+impl FromStr for ExportField {
+    type Err = ();
+    fn from_str(input: &str) -> Result<ExportField, Self::Err> {
+        match input {
+            "uid" => Ok(ExportField::Uid),
+            "name" => Ok(ExportField::Name),
+            "start" => Ok(ExportField::Start),
+            "end" => Ok(ExportField::End),
+            "all_day" => Ok(ExportField::AllDay),
+            "duration" => Ok(ExportField::Duration),
+            "location" => Ok(ExportField::Location),
+            "description" => Ok(ExportField::Description),
+            "status" => Ok(ExportField::Status),
+            "repeats" => Ok(ExportField::Repeats),
+            _ => Err(()),
+        }
+    }
+}
+
+impl ExportField {
+    /// The name export_fields and the CSV and JSON exports use
+    pub fn key(self) -> &'static str {
+        match self {
+            ExportField::Uid => return "uid",
+            ExportField::Name => return "name",
+            ExportField::Start => return "start",
+            ExportField::End => return "end",
+            ExportField::AllDay => return "all_day",
+            ExportField::Duration => return "duration",
+            ExportField::Location => return "location",
+            ExportField::Description => return "description",
+            ExportField::Status => return "status",
+            ExportField::Repeats => return "repeats"
+        }
+    }
+
+    /// The column heading in the Markdown and HTML tables
+    fn heading(self) -> &'static str {
+        match self {
+            ExportField::Uid => return "UID",
+            ExportField::Name => return "Event",
+            ExportField::Start => return "Start",
+            ExportField::End => return "End",
+            ExportField::AllDay => return "All day",
+            ExportField::Duration => return "Duration",
+            ExportField::Location => return "Location",
+            ExportField::Description => return "Description",
+            ExportField::Status => return "Status",
+            ExportField::Repeats => return "Repeats"
+        }
+    }
+
+    /// How the field reads in a table cell: local times to the minute, all-day dates without a time
+    /// and all-day ends as the last day covered rather than the exclusive DTEND. Missing values are
+    /// empty.
+    fn text(self, event: &MpEvent, local_zone: &MpTimeZone) -> String {
+        match self {
+            ExportField::Start => return format_export_time(event.start_time, event.all_day, false, local_zone).unwrap_or_default(),
+            ExportField::End => return format_export_time(event.end_time, event.all_day, true, local_zone).unwrap_or_default(),
+            ExportField::AllDay => return String::from(if event.all_day { "yes" } else { "no" }),
+            ExportField::Duration => match (event.start_time, event.end_time) {
+                (Some(start), Some(end)) => return super::format_duration(end - start),
+                _ => return String::new()
+            },
+            _ => return self.optional_text(event).unwrap_or_default()
+        }
+    }
+
+    /// The fields that are just text, or nothing
+    fn optional_text(self, event: &MpEvent) -> Option<String> {
+        match self {
+            ExportField::Uid => return event.uid.clone(),
+            ExportField::Name => return event.name.clone(),
+            ExportField::Location => return event.location.clone(),
+            ExportField::Description => return event.description.clone(),
+            ExportField::Status => return event.status.map(|status| status.to_ical_str().to_lowercase()),
+            ExportField::Repeats => return event.rrule.as_ref().map(cal_phrase::describe_rule),
+            _ => return None
+        }
+    }
+
+    /// As text, but typed: all_day is a boolean, duration a number of minutes, timed starts and
+    /// ends RFC 3339 in the local zone and missing values null
+    fn json(self, event: &MpEvent, local_zone: &MpTimeZone) -> serde_json::Value {
+        use serde_json::Value;
+
+        match self {
+            ExportField::Start => return json_time(event.start_time, event.all_day, false, local_zone),
+            ExportField::End => return json_time(event.end_time, event.all_day, true, local_zone),
+            ExportField::AllDay => return Value::from(event.all_day),
+            ExportField::Duration => match (event.start_time, event.end_time) {
+                (Some(start), Some(end)) => return Value::from((end - start).num_minutes()),
+                _ => return Value::Null
+            },
+            _ => return self.optional_text(event).map_or(Value::Null, Value::from)
+        }
+    }
+}
+
+/// Used when the config doesn't list any export_fields
+pub const DEFAULT_EXPORT_FIELDS: [ExportField; 5] = [ExportField::Start, ExportField::End, ExportField::Name,
+                                                     ExportField::Location, ExportField::Status];
+
+/// The fields named by export_fields, in its order, or the defaults if it's empty
+pub fn parse_export_fields(names: &[String]) -> Result<Vec<ExportField>, MpCalendarError> {
+    if names.is_empty() {
+        return Ok(DEFAULT_EXPORT_FIELDS.to_vec());
+    }
+    return names.iter()
+        .map(|name| ExportField::from_str(name)
+            .map_err(|_| MpCalendarError::InvalidValue { property: String::from("export_fields"), value: name.clone() }))
+        .collect();
+}
+
+fn format_export_time(time: Option<DateTime<FixedOffset>>, all_day: bool, is_end: bool, local_zone: &MpTimeZone) -> Option<String> {
+    let local = local_zone.convert(time?);
+    if all_day {
+        let date = if is_end { local.date().naive_local().pred() } else { local.date().naive_local() };
+        return Some(date.format("%Y-%m-%d").to_string());
+    }
+    return Some(local.format("%Y-%m-%d %H:%M").to_string());
+}
+
+fn json_time(time: Option<DateTime<FixedOffset>>, all_day: bool, is_end: bool, local_zone: &MpTimeZone) -> serde_json::Value {
+    match time {
+        Some(time) if !all_day => return serde_json::Value::from(local_zone.convert(time).to_rfc3339()),
+        _ => return format_export_time(time, all_day, is_end, local_zone).map_or(serde_json::Value::Null, serde_json::Value::from)
+    }
+}
+
+/// Turns the events chosen for an export into a document. Every format is given the same events;
+/// the ones that are tables also get the columns to write, and the local zone to write times in.
+pub trait EventExporter {
+    fn export(&self, events: &[MpEvent], fields: &[ExportField], local_zone: &MpTimeZone) -> String;
+}
+
+/// A VCALENDAR, as written to calendar files. Every property is kept, so fields don't apply.
+pub struct IcsExporter;
+
+impl EventExporter for IcsExporter {
+    fn export(&self, events: &[MpEvent], _fields: &[ExportField], _local_zone: &MpTimeZone) -> String {
+        return cal_io::deserialise_mpevents_to_ics_string(events);
+    }
+}
+
+/// RFC 4180 CSV with a header row of field keys, for spreadsheets
+pub struct CsvExporter;
+
+impl EventExporter for CsvExporter {
+    fn export(&self, events: &[MpEvent], fields: &[ExportField], local_zone: &MpTimeZone) -> String {
+        let mut csv = String::new();
+        let header: Vec<String> = fields.iter().map(|field| csv_cell(field.key())).collect();
+        csv.push_str(&header.join(","));
+        csv.push_str("\r\n");
+        for event in events {
+            let row: Vec<String> = fields.iter().map(|field| csv_cell(&field.text(event, local_zone))).collect();
+            csv.push_str(&row.join(","));
+            csv.push_str("\r\n");
+        }
+        return csv;
+    }
+}
+
+// Quoted only when it has to be, with quotes doubled. A cell a spreadsheet would take for a
+// formula gets a leading ' so it's shown as text instead of run.
+fn csv_cell(text: &str) -> String {
+    let text = if text.starts_with(['=', '+', '-', '@']) { format!("'{}", text) } else { String::from(text) };
+    if text.contains([',', '"', '\r', '\n']) {
+        return format!("\"{}\"", text.replace('"', "\"\""));
+    }
+    return text;
+}
+
+/// A pretty-printed JSON array with an object for each event, its keys in field order
+pub struct JsonExporter;
+
+// Serialised by hand so that the keys come out in the configured order
+struct JsonRow<'a> {
+    event: &'a MpEvent,
+    fields: &'a [ExportField],
+    local_zone: &'a MpTimeZone
+}
+
+impl<'a> Serialize for JsonRow<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.fields.len()))?;
+        for field in self.fields {
+            map.serialize_entry(field.key(), &field.json(self.event, self.local_zone))?;
+        }
+        return map.end();
+    }
+}
+
+impl EventExporter for JsonExporter {
+    fn export(&self, events: &[MpEvent], fields: &[ExportField], local_zone: &MpTimeZone) -> String {
+        let rows: Vec<JsonRow> = events.iter().map(|event| JsonRow { event, fields, local_zone }).collect();
+        // Nothing in a row can fail to serialise: the keys are strings and the values JSON already
+        return serde_json::to_string_pretty(&rows).unwrap_or_default();
+    }
+}
+
+/// A GitHub-flavoured Markdown table, for pasting into wikis
+pub struct MarkdownExporter;
+
+impl EventExporter for MarkdownExporter {
+    fn export(&self, events: &[MpEvent], fields: &[ExportField], local_zone: &MpTimeZone) -> String {
+        let row = |cells: Vec<String>| format!("| {} |\n", cells.join(" | "));
+        let mut markdown = row(fields.iter().map(|field| String::from(field.heading())).collect());
+        markdown.push_str(&row(fields.iter().map(|_| String::from("---")).collect()));
+        for event in events {
+            markdown.push_str(&row(fields.iter().map(|field| markdown_cell(&field.text(event, local_zone))).collect()));
+        }
+        return markdown;
+    }
+}
+
+// A cell can't contain a line break or an unescaped '|', and Markdown renderers pass HTML through,
+// so '<', '>' and '&' are escaped to show as themselves
+fn markdown_cell(text: &str) -> String {
+    return text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+        .replace('|', "\\|").replace("\r\n", "<br>").replace('\n', "<br>");
+}
+
+/// A standalone HTML page with the events in a table under title
+pub struct HtmlExporter {
+    pub title: String
+}
+
+impl EventExporter for HtmlExporter {
+    fn export(&self, events: &[MpEvent], fields: &[ExportField], local_zone: &MpTimeZone) -> String {
+        let title = escape_markup(&self.title);
+        let mut html = format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n\
+                                <style>table {{ border-collapse: collapse; }} th, td {{ border: 1px solid #ccc; padding: 4px 8px; text-align: left; vertical-align: top; }}</style>\n\
+                                </head>\n<body>\n<h1>{}</h1>\n<table>\n<thead>\n<tr>", title, title);
+        for field in fields {
+            html.push_str(&format!("<th>{}</th>", field.heading()));
+        }
+        html.push_str("</tr>\n</thead>\n<tbody>\n");
+        for event in events {
+            html.push_str("<tr>");
+            for field in fields {
+                let cell = escape_markup(&field.text(event, local_zone)).replace("\r\n", "<br>").replace('\n', "<br>");
+                html.push_str(&format!("<td>{}</td>", cell));
+            }
+            html.push_str("</tr>\n");
+        }
+        html.push_str("</tbody>\n</table>\n</body>\n</html>\n");
+        return html;
+    }
+}
+
+/// The formats an export can be written in, chosen by the file's extension
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ExportFormat {
+    Ics,
+    Csv,
+    Json,
+    Markdown,
+    Html
+}
+
+impl ExportFormat {
+    /// .csv, .json, .md or .markdown, and .html or .htm; anything else is written as ICS
+    pub fn from_path(path: &str) -> ExportFormat {
+        let extension = std::path::Path::new(path).extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        match extension.as_str() {
+            "csv" => return ExportFormat::Csv,
+            "json" => return ExportFormat::Json,
+            "md" | "markdown" => return ExportFormat::Markdown,
+            "html" | "htm" => return ExportFormat::Html,
+            _ => return ExportFormat::Ics
+        }
+    }
+
+    /// The exporter for this format. title only appears in HTML.
+    pub fn exporter(self, title: &str) -> Box<dyn EventExporter> {
+        match self {
+            ExportFormat::Ics => return Box::new(IcsExporter),
+            ExportFormat::Csv => return Box::new(CsvExporter),
+            ExportFormat::Json => return Box::new(JsonExporter),
+            ExportFormat::Markdown => return Box::new(MarkdownExporter),
+            ExportFormat::Html => return Box::new(HtmlExporter { title: String::from(title) })
+        }
+    }
+}
+
+#[cfg(test)]
+mod cal_export_tests {
+    use super::*;
+    use super::super::EventStatus;
+
+    fn time(rfc3339: &str) -> DateTime<FixedOffset> {
+        return DateTime::parse_from_rfc3339(rfc3339).unwrap();
+    }
+
+    fn test_events() -> Vec<MpEvent> {
+        let review = MpEvent {
+            name: Some(String::from("Review \"Q1\" plan")),
+            start_time: Some(time("2024-06-03T09:00:00+00:00")),
+            end_time: Some(time("2024-06-03T10:30:00+00:00")),
+            location: Some(String::from("Room 4, Building | B")),
+            description: Some(String::from("Bring <notes>\nand figures")),
+            status: Some(EventStatus::Confirmed),
+            uid: Some(String::from("review@example.com")),
+            rrule: Some("FREQ=WEEKLY;BYDAY=MO".parse().unwrap()),
+            ..Default::default()
+        };
+        let offsite = MpEvent {
+            name: Some(String::from("Offsite")),
+            start_time: Some(time("2024-06-05T00:00:00+01:00")),
+            end_time: Some(time("2024-06-07T00:00:00+01:00")),
+            all_day: true,
+            ..Default::default()
+        };
+        return vec![review, offsite];
+    }
+
+    fn fields(names: &[&str]) -> Vec<ExportField> {
+        return parse_export_fields(&names.iter().map(|name| String::from(*name)).collect::<Vec<String>>()).unwrap();
+    }
+
+    #[test]
+    fn test_parse_export_fields() {
+        assert_eq!(DEFAULT_EXPORT_FIELDS.to_vec(), parse_export_fields(&[]).unwrap());
+        assert_eq!(vec![ExportField::Name, ExportField::AllDay, ExportField::Start], fields(&["name", "all_day", "start"]));
+        assert!(parse_export_fields(&[String::from("start"), String::from("Start")]).is_err());
+        assert_eq!(ExportFormat::Csv, ExportFormat::from_path("agenda.CSV"));
+        assert_eq!(ExportFormat::Markdown, ExportFormat::from_path("wiki/agenda.md"));
+        assert_eq!(ExportFormat::Html, ExportFormat::from_path("agenda.htm"));
+        assert_eq!(ExportFormat::Ics, ExportFormat::from_path("agenda"));
+    }
+
+    #[test]
+    fn test_csv_export() {
+        let london = MpTimeZone::from_iana_name("Europe/London").unwrap();
+        let csv = CsvExporter.export(&test_events(), &fields(&["name", "start", "end", "duration", "location", "repeats"]), &london);
+        assert_eq!("name,start,end,duration,location,repeats\r\n\
+                    \"Review \"\"Q1\"\" plan\",2024-06-03 10:00,2024-06-03 11:30,1h 30m,\"Room 4, Building | B\",every Monday\r\n\
+                    Offsite,2024-06-05,2024-06-06,48h,,\r\n", csv);
+
+        // Cells a spreadsheet would run as formulas are shown as text
+        assert_eq!("'=1+2", csv_cell("=1+2"));
+        assert_eq!("'+44 20 7946 0000", csv_cell("+44 20 7946 0000"));
+        assert_eq!("'-2", csv_cell("-2"));
+        assert_eq!("\"'@SUM(A1,A2)\"", csv_cell("@SUM(A1,A2)"));
+        assert_eq!("Q&A", csv_cell("Q&A"));
+    }
+
+    #[test]
+    fn test_json_export() {
+        let london = MpTimeZone::from_iana_name("Europe/London").unwrap();
+        let json = JsonExporter.export(&test_events(), &fields(&["status", "start", "all_day", "duration", "description"]), &london);
+        // Keys stay in the configured order rather than being sorted
+        assert!(json.find("\"status\"").unwrap() < json.find("\"start\"").unwrap());
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::json!([
+            {"status": "confirmed", "start": "2024-06-03T10:00:00+01:00", "all_day": false, "duration": 90,
+             "description": "Bring <notes>\nand figures"},
+            {"status": null, "start": "2024-06-05", "all_day": true, "duration": 2880, "description": null}
+        ]), value);
+    }
+
+    #[test]
+    fn test_markdown_and_html_export() {
+        let london = MpTimeZone::from_iana_name("Europe/London").unwrap();
+        let columns = fields(&["start", "name", "location", "description"]);
+        let markdown = MarkdownExporter.export(&test_events(), &columns, &london);
+        assert_eq!("| Start | Event | Location | Description |\n\
+                    | --- | --- | --- | --- |\n\
+                    | 2024-06-03 10:00 | Review \"Q1\" plan | Room 4, Building \\| B | Bring &lt;notes&gt;<br>and figures |\n\
+                    | 2024-06-05 | Offsite |  |  |\n", markdown);
+        assert_eq!("Q&amp;A &lt;b&gt;live&lt;/b&gt;", markdown_cell("Q&A <b>live</b>"));
+
+        let html = HtmlExporter { title: String::from("Week of 3 June") }.export(&test_events(), &columns, &london);
+        assert!(html.starts_with("<!DOCTYPE html>\n"));
+        assert!(html.contains("<title>Week of 3 June</title>"));
+        assert!(html.contains("<tr><th>Start</th><th>Event</th><th>Location</th><th>Description</th></tr>"));
+        assert!(html.contains("<tr><td>2024-06-03 10:00</td><td>Review &quot;Q1&quot; plan</td><td>Room 4, Building | B</td>\
+                               <td>Bring &lt;notes&gt;<br>and figures</td></tr>"));
+        assert!(html.ends_with("</html>\n"));
+    }
+}
@@ -0,0 +1,520 @@
+use chrono::prelude::*;
+use crate::mp_core::core_config::Config;
+use super::{MpEvent, MpCalendarError, EventStatus, cal_io, cal_ops};
+use super::cal_caldav::{DAV_NS, CALDAV_NS, CALENDARSERVER_NS, encode_path_segment};
+use super::cal_text::escape_markup;
+
+// The feed of every configured calendar merged together
+const ALL_CALENDARS_SLUG: &str = "all";
+const CALENDAR_HOME: &str = "/calendars/";
+const APPLE_ICAL_NS: &str = "http://apple.com/ns/ical/";
+
+struct FeedResponse {
+    status: u16,
+    content_type: Option<&'static str>,
+    etag: Option<String>,
+    headers: Vec<(&'static str, String)>,
+    body: String
+}
+
+impl FeedResponse {
+    fn new(status: u16, content_type: Option<&'static str>, body: String) -> FeedResponse {
+        return FeedResponse { status, content_type, etag: None, headers: vec![], body };
+    }
+
+    fn empty(status: u16) -> FeedResponse {
+        return FeedResponse::new(status, None, String::new());
+    }
+
+    fn multistatus(responses: Vec<String>) -> FeedResponse {
+        let body = format!("<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+                            <D:multistatus xmlns:D=\"{}\" xmlns:C=\"{}\" xmlns:CS=\"{}\" xmlns:A=\"{}\">{}</D:multistatus>",
+                           DAV_NS, CALDAV_NS, CALENDARSERVER_NS, APPLE_ICAL_NS, responses.concat());
+        return FeedResponse::new(207, Some("application/xml; charset=utf-8"), body);
+    }
+}
+
+// One event, with its modified instances, as a CalDAV resource
+struct FeedResource {
+    name: String,
+    etag: String,
+    ics: String,
+    events: Vec<MpEvent>
+}
+
+/// Serves each configured calendar as a read-only ICS feed at /calendars/<slug>.ics, and all of
+/// them merged at /calendars/all.ics, for webcal subscriptions. With [feed] caldav = true the
+/// same calendars are also served as read-only CalDAV collections under /calendars/<slug>/.
+/// Calendars are read afresh for every request, so feeds follow changes to the files.
+pub struct FeedServer {
+    server: tiny_http::Server,
+    config: Config,
+    // (slug, index into config.calendars) for every calendar that passed validation
+    feeds: Vec<(String, usize)>,
+    caldav: bool,
+    hide_cancelled: bool
+}
+
+impl FeedServer {
+    /// Listens on the [feed] address and port. Fails if the config has no [feed] table.
+    pub fn bind(config: &Config) -> Result<FeedServer, MpCalendarError> {
+        let feed_config = config.feed.clone().ok_or_else(|| MpCalendarError::MissingProperty(String::from("[feed] table")))?;
+        let address = format!("{}:{}", feed_config.address, feed_config.port);
+        let server = tiny_http::Server::http(address.as_str())
+            .map_err(|e| MpCalendarError::Remote { url: address.clone(), message: e.to_string() })?;
+        let mut feeds: Vec<(String, usize)> = vec![];
+        for (index, calendar) in config.calendars.iter().enumerate() {
+            if calendar.validate().is_err() {
+                continue;
+            }
+            let base = slug_for(calendar.display_name());
+            let mut slug = base.clone();
+            let mut suffix = 2;
+            while slug == ALL_CALENDARS_SLUG || feeds.iter().any(|(taken, _)| *taken == slug) {
+                slug = format!("{}-{}", base, suffix);
+                suffix += 1;
+            }
+            feeds.push((slug, index));
+        }
+        return Ok(FeedServer {
+            server,
+            config: config.clone(),
+            feeds,
+            caldav: feed_config.caldav,
+            hide_cancelled: feed_config.hide_cancelled
+        });
+    }
+
+    pub fn url(&self) -> String {
+        match self.server.server_addr().to_ip() {
+            Some(address) => return format!("http://{}/", address),
+            None => return String::from("http://localhost/")
+        }
+    }
+
+    /// Answers requests until stop is called
+    pub fn serve_forever(&self) {
+        for request in self.server.incoming_requests() {
+            self.handle(request);
+        }
+    }
+
+    pub fn stop(&self) {
+        self.server.unblock();
+    }
+
+    fn handle(&self, mut request: tiny_http::Request) {
+        let mut body = String::new();
+        let _ = request.as_reader().read_to_string(&mut body);
+        let header = |name: &'static str| request.headers().iter()
+            .find(|header| header.field.equiv(name))
+            .map(|header| header.value.as_str().to_string());
+        let response = self.respond(request.method().as_str(),
+                                    request.url(),
+                                    header("If-None-Match").as_deref(),
+                                    header("Depth").as_deref(),
+                                    &body);
+        let mut reply = tiny_http::Response::from_string(response.body).with_status_code(response.status);
+        let mut headers = response.headers;
+        match response.content_type {
+            Some(content_type) => headers.push(("Content-Type", String::from(content_type))),
+            None => ()
+        };
+        match response.etag {
+            Some(etag) => headers.push(("ETag", etag)),
+            None => ()
+        };
+        for (name, value) in headers {
+            match tiny_http::Header::from_bytes(name, value) {
+                Ok(header) => reply = reply.with_header(header),
+                Err(_) => ()
+            };
+        }
+        let _ = request.respond(reply);
+    }
+
+    fn respond(&self, method: &str, url: &str, if_none_match: Option<&str>, depth: Option<&str>, body: &str) -> FeedResponse {
+        let path = url.split('?').next().unwrap_or("/");
+        if method == "OPTIONS" {
+            let mut response = FeedResponse::empty(200);
+            response.headers.push(("Allow", String::from(if self.caldav { "OPTIONS, GET, HEAD, PROPFIND, REPORT" } else { "OPTIONS, GET, HEAD" })));
+            if self.caldav {
+                response.headers.push(("DAV", String::from("1, 3, calendar-access")));
+            }
+            return response;
+        }
+        if method == "GET" || method == "HEAD" {
+            let mut response = self.get(path, if_none_match);
+            if method == "HEAD" {
+                response.body = String::new();
+            }
+            return response;
+        }
+        if !self.caldav {
+            return FeedResponse::empty(405);
+        }
+        match method {
+            "PROPFIND" => return self.propfind(path, depth.unwrap_or("0")),
+            "REPORT" => return self.report(path, body),
+            // Everything here is generated from the configured calendars, so nothing can be changed
+            "PUT" | "DELETE" | "PROPPATCH" | "MKCALENDAR" | "MKCOL" | "MOVE" | "COPY" => return FeedResponse::empty(403),
+            _ => return FeedResponse::empty(405)
+        }
+    }
+
+    fn get(&self, path: &str, if_none_match: Option<&str>) -> FeedResponse {
+        if path == "/" {
+            return FeedResponse::new(200, Some("text/plain; charset=utf-8"), self.index());
+        }
+        let relative = match path.strip_prefix(CALENDAR_HOME) {
+            Some(relative) => relative,
+            None => return FeedResponse::empty(404)
+        };
+        let (ics, etag) = match relative.strip_suffix(".ics") {
+            // A whole calendar, /calendars/<slug>.ics
+            Some(slug) if !slug.contains('/') => match self.calendar_events(slug) {
+                Some(Ok(events)) => {
+                    let ics = cal_io::deserialise_mpevents_to_ics_string(&events);
+                    let etag = etag_for(&ics);
+                    (ics, etag)
+                },
+                Some(Err(e)) => return FeedResponse::new(500, Some("text/plain; charset=utf-8"), e.to_string()),
+                None => return FeedResponse::empty(404)
+            },
+            // One event in a CalDAV collection, /calendars/<slug>/<uid>.ics
+            Some(_) if self.caldav => {
+                let (slug, name) = relative.split_at(relative.find('/').unwrap_or(0));
+                match self.resources(slug).map(|resources| resources.into_iter().find(|resource| resource.name == name[1..])) {
+                    Some(Some(resource)) => (resource.ics, resource.etag),
+                    _ => return FeedResponse::empty(404)
+                }
+            },
+            _ => return FeedResponse::empty(404)
+        };
+        if if_none_match.is_some_and(|if_none_match| etag_matches(if_none_match, &etag)) {
+            let mut response = FeedResponse::empty(304);
+            response.etag = Some(etag);
+            return response;
+        }
+        let mut response = FeedResponse::new(200, Some("text/calendar; charset=utf-8"), ics);
+        response.etag = Some(etag);
+        return response;
+    }
+
+    fn propfind(&self, path: &str, depth: &str) -> FeedResponse {
+        // The server root is its own principal
+        if path == "/" {
+            let props = format!("<D:resourcetype><D:collection/></D:resourcetype>\
+                                 <D:current-user-principal><D:href>/</D:href></D:current-user-principal>\
+                                 <C:calendar-home-set><D:href>{}</D:href></C:calendar-home-set>", CALENDAR_HOME);
+            return FeedResponse::multistatus(vec![prop_response("/", &props)]);
+        }
+        if path == CALENDAR_HOME {
+            let mut responses = vec![prop_response(CALENDAR_HOME, "<D:resourcetype><D:collection/></D:resourcetype>")];
+            if depth != "0" {
+                for slug in self.slugs() {
+                    match self.collection_response(&slug) {
+                        Some(response) => responses.push(response),
+                        None => ()
+                    };
+                }
+            }
+            return FeedResponse::multistatus(responses);
+        }
+        let slug = match collection_slug(path) {
+            Some(slug) => slug,
+            None => return FeedResponse::empty(404)
+        };
+        let mut responses = match self.collection_response(slug) {
+            Some(response) => vec![response],
+            None => return FeedResponse::empty(404)
+        };
+        if depth != "0" {
+            for resource in self.resources(slug).unwrap_or_default() {
+                let props = format!("<D:resourcetype/><D:getetag>{}</D:getetag>\
+                                     <D:getcontenttype>text/calendar; charset=utf-8; component=vevent</D:getcontenttype>",
+                                    escape_markup(&resource.etag));
+                responses.push(prop_response(&format!("{}{}/{}", CALENDAR_HOME, slug, resource.name), &props));
+            }
+        }
+        return FeedResponse::multistatus(responses);
+    }
+
+    // calendar-multiget gives the resources listed in the request; calendar-query gives all of
+    // them, or those with an occurrence in its time-range. Other filters are ignored.
+    fn report(&self, path: &str, body: &str) -> FeedResponse {
+        let slug = match collection_slug(path) {
+            Some(slug) => slug,
+            None => return FeedResponse::empty(404)
+        };
+        let resources = match self.resources(slug) {
+            Some(resources) => resources,
+            None => return FeedResponse::empty(404)
+        };
+        let document = match roxmltree::Document::parse(body) {
+            Ok(document) => document,
+            Err(_) => return FeedResponse::empty(400)
+        };
+        let root = document.root_element();
+        let selected: Vec<FeedResource> = if root.tag_name().name() == "calendar-multiget" {
+            let wanted: Vec<String> = root.children()
+                .filter(|node| node.tag_name().name() == "href" && node.tag_name().namespace() == Some(DAV_NS))
+                .filter_map(|node| node.text().map(|href| String::from(href.trim())))
+                .collect();
+            resources.into_iter()
+                .filter(|resource| wanted.contains(&format!("{}{}/{}", CALENDAR_HOME, slug, resource.name)))
+                .collect()
+        } else if root.tag_name().name() == "calendar-query" {
+            let range = root.descendants().find(|node| node.tag_name().name() == "time-range");
+            let parse = |value: Option<&str>| value.and_then(|value| Utc.datetime_from_str(value, "%Y%m%dT%H%M%SZ").ok())
+                .map(|time| time.with_timezone(&FixedOffset::east(0)));
+            match range.map(|range| (parse(range.attribute("start")), parse(range.attribute("end")))) {
+                Some((window_start, window_end)) => {
+                    let window_start = window_start.unwrap_or_else(|| FixedOffset::east(0).ymd(1970, 1, 1).and_hms(0, 0, 0));
+                    let window_end = window_end.unwrap_or_else(|| FixedOffset::east(0).ymd(9999, 1, 1).and_hms(0, 0, 0));
+                    resources.into_iter()
+                        .filter(|resource| resource.events.iter().any(|event| !event.expand_occurrences(window_start, window_end).is_empty()))
+                        .collect()
+                },
+                None => resources
+            }
+        } else {
+            return FeedResponse::empty(403);
+        };
+        let responses = selected.iter()
+            .map(|resource| {
+                let props = format!("<D:getetag>{}</D:getetag><C:calendar-data>{}</C:calendar-data>",
+                                    escape_markup(&resource.etag), escape_markup(&resource.ics));
+                return prop_response(&format!("{}{}/{}", CALENDAR_HOME, slug, resource.name), &props);
+            })
+            .collect();
+        return FeedResponse::multistatus(responses);
+    }
+
+    fn index(&self) -> String {
+        let mut index = String::from("moneypenny calendar feeds");
+        for slug in self.slugs() {
+            index.push_str(&format!("\n  {}: {}{}.ics", self.display_name(&slug), CALENDAR_HOME, slug));
+        }
+        return index;
+    }
+
+    fn slugs(&self) -> Vec<String> {
+        let mut slugs: Vec<String> = self.feeds.iter().map(|(slug, _)| slug.clone()).collect();
+        slugs.push(String::from(ALL_CALENDARS_SLUG));
+        return slugs;
+    }
+
+    fn display_name(&self, slug: &str) -> String {
+        match self.feeds.iter().find(|(taken, _)| taken == slug) {
+            Some((_, index)) => return String::from(self.config.calendars[*index].display_name()),
+            None => return String::from("All calendars")
+        }
+    }
+
+    fn collection_response(&self, slug: &str) -> Option<String> {
+        let events = self.calendar_events(slug)?.unwrap_or_default();
+        let ctag = etag_for(&cal_io::deserialise_mpevents_to_ics_string(&events));
+        let colour = match self.feeds.iter().find(|(taken, _)| taken == slug) {
+            Some((_, index)) => self.config.calendars[*index].colour.clone(),
+            None => None
+        };
+        let colour_prop = colour.map(|colour| format!("<A:calendar-color>{}</A:calendar-color>", escape_markup(&colour))).unwrap_or_default();
+        let props = format!("<D:resourcetype><D:collection/><C:calendar/></D:resourcetype>\
+                             <D:displayname>{}</D:displayname><CS:getctag>{}</CS:getctag>{}\
+                             <C:supported-calendar-component-set><C:comp name=\"VEVENT\"/></C:supported-calendar-component-set>\
+                             <D:current-user-privilege-set><D:privilege><D:read/></D:privilege></D:current-user-privilege-set>",
+                            escape_markup(&self.display_name(slug)), escape_markup(&ctag), colour_prop);
+        return Some(prop_response(&format!("{}{}/", CALENDAR_HOME, slug), &props));
+    }
+
+    // None if there's no calendar called slug
+    fn calendar_events(&self, slug: &str) -> Option<Result<Vec<MpEvent>, MpCalendarError>> {
+        let events = if slug == ALL_CALENDARS_SLUG {
+            // Loaded on every request, so duplicates aren't reported each time
+            Ok(super::load_configured_calendars_quietly(&self.config))
+        } else {
+            let (_, index) = self.feeds.iter().find(|(taken, _)| taken == slug)?;
+            super::load_configured_calendar(&self.config, &self.config.calendars[*index])
+        };
+        return Some(events.map(|events| {
+            let shown = events.into_iter()
+                .filter(|event| !(self.hide_cancelled && event.status == Some(EventStatus::Cancelled)))
+                .collect();
+            return cal_ops::sort_mpevents_chronologically_by_start(shown);
+        }));
+    }
+
+    // A calendar's events as CalDAV resources, one per UID
+    fn resources(&self, slug: &str) -> Option<Vec<FeedResource>> {
+        let events = self.calendar_events(slug)?.unwrap_or_default();
+        let resources = events.into_iter()
+            .map(|event| {
+                let uid = match &event.uid {
+                    Some(uid) => uid.clone(),
+                    None => cal_io::generate_uid(&event)
+                };
+                let ics = cal_io::deserialise_mpevents_to_ics_string(std::slice::from_ref(&event));
+                return FeedResource { name: format!("{}.ics", encode_path_segment(&uid)), etag: etag_for(&ics), ics, events: vec![event] };
+            })
+            .collect();
+        return Some(resources);
+    }
+}
+
+// "Team Work!" to "team-work"
+fn slug_for(name: &str) -> String {
+    let file_name = name.rsplit('/').next().unwrap_or(name);
+    let stem = file_name.strip_suffix(".ics").unwrap_or(file_name);
+    let lowered: String = stem.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '-' })
+        .collect();
+    let slug: Vec<&str> = lowered.split('-').filter(|part| !part.is_empty()).collect();
+    if slug.is_empty() {
+        return String::from("calendar");
+    }
+    return slug.join("-");
+}
+
+// "/calendars/work/" to "work"
+fn collection_slug(path: &str) -> Option<&str> {
+    let slug = path.strip_prefix(CALENDAR_HOME)?.trim_end_matches('/');
+    if slug.is_empty() || slug.contains('/') {
+        return None;
+    }
+    return Some(slug);
+}
+
+// The writer stamps events that have no DTSTAMP with the current time, so DTSTAMP lines are left
+// out or the ETag would change every second and clients would never get a 304
+fn etag_for(ics: &str) -> String {
+    let lines: Vec<Option<String>> = ics.split("\r\n")
+        .filter(|line| !line.starts_with("DTSTAMP:"))
+        .map(|line| Some(String::from(line)))
+        .collect();
+    return format!("\"{}\"", cal_io::stable_hash(&lines));
+}
+
+// If-None-Match is "*" or a list of ETags, any of which may be weak (W/"..."). The comparison is
+// weak, as RFC 9110 has it for If-None-Match.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let etag = etag.strip_prefix("W/").unwrap_or(etag);
+    return if_none_match.trim() == "*" || if_none_match.split(',')
+        .map(|candidate| candidate.trim())
+        .any(|candidate| candidate.strip_prefix("W/").unwrap_or(candidate) == etag);
+}
+
+fn prop_response(href: &str, props: &str) -> String {
+    return format!("<D:response><D:href>{}</D:href><D:propstat><D:prop>{}</D:prop>\
+                    <D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>", escape_markup(href), props);
+}
+
+#[cfg(test)]
+mod cal_feed_tests {
+    use super::*;
+    use std::sync::Arc;
+    use ureq::http;
+    use crate::mp_core::core_config::{CalendarConfig, FeedConfig};
+    use crate::mp_calendar::cal_caldav::CalDavClient;
+    use crate::mp_calendar::cal_tz::MpTimeZone;
+
+    fn test_config(caldav: bool) -> Config {
+        let calendars = vec![
+            CalendarConfig { path: String::from("src/test/test_conflicts.ics"), name: Some(String::from("Team Work!")), colour: Some(String::from("#1e90ff")), ..Default::default() },
+            CalendarConfig { path: String::from("src/test/test_overrides.ics"), ..Default::default() }
+        ];
+        return Config {
+            feed: Some(FeedConfig { port: 0, address: String::from("127.0.0.1"), caldav, hide_cancelled: true }),
+            ..crate::mp_calendar::test_support::test_config(None, calendars)
+        };
+    }
+
+    // Serves config on a free port until the returned server is stopped
+    fn start(config: &Config) -> (Arc<FeedServer>, std::thread::JoinHandle<()>) {
+        let server = Arc::new(FeedServer::bind(config).unwrap());
+        let serving = Arc::clone(&server);
+        let thread = std::thread::spawn(move || serving.serve_forever());
+        return (server, thread);
+    }
+
+    fn agent() -> ureq::Agent {
+        let config = ureq::Agent::config_builder().http_status_as_error(false).allow_non_standard_methods(true).build();
+        return ureq::Agent::new_with_config(config);
+    }
+
+    #[test]
+    fn test_slug_for() {
+        assert_eq!("team-work", slug_for("Team Work!"));
+        assert_eq!("test-overrides", slug_for("src/test/test_overrides.ics"));
+        assert_eq!("calendar", slug_for("???"));
+    }
+
+    #[test]
+    fn test_etag_matches() {
+        assert!(etag_matches("\"abc\"", "\"abc\""));
+        assert!(etag_matches("\"old\", W/\"abc\"", "\"abc\""));
+        assert!(etag_matches("*", "\"abc\""));
+        assert!(!etag_matches("\"abcd\", \"ab\"", "\"abc\""));
+        assert!(!etag_matches("", "\"abc\""));
+    }
+
+    #[test]
+    fn test_ics_feeds() {
+        let (server, thread) = start(&test_config(false));
+        let agent = agent();
+        let index = agent.get(&server.url()).call().unwrap().body_mut().read_to_string().unwrap();
+        assert_eq!("moneypenny calendar feeds\n  Team Work!: /calendars/team-work.ics\n  \
+                    src/test/test_overrides.ics: /calendars/test-overrides.ics\n  All calendars: /calendars/all.ics", index);
+
+        let mut response = agent.get(&format!("{}calendars/team-work.ics", server.url())).call().unwrap();
+        assert_eq!(200, response.status().as_u16());
+        assert!(response.headers().get("Content-Type").unwrap().to_str().unwrap().starts_with("text/calendar"));
+        let etag = response.headers().get("ETag").unwrap().to_str().unwrap().to_string();
+        let feed = response.body_mut().read_to_string().unwrap();
+        let events = cal_io::read_events_from_ics_string(&feed, String::from("feed"), &MpTimeZone::utc()).unwrap();
+        // The cancelled 1:1 is hidden
+        assert_eq!(8, events.len());
+        assert!(events.iter().all(|event| event.status != Some(EventStatus::Cancelled)));
+
+        let unchanged = agent.get(&format!("{}calendars/team-work.ics", server.url())).header("If-None-Match", &etag).call().unwrap();
+        assert_eq!(304, unchanged.status().as_u16());
+        let listed = agent.get(&format!("{}calendars/team-work.ics", server.url()))
+            .header("If-None-Match", &format!("\"stale\", W/{}", etag))
+            .call().unwrap();
+        assert_eq!(304, listed.status().as_u16());
+        let mut all = agent.get(&format!("{}calendars/all.ics", server.url())).call().unwrap();
+        let merged = cal_io::read_events_from_ics_string(&all.body_mut().read_to_string().unwrap(), String::from("feed"), &MpTimeZone::utc()).unwrap();
+        assert_eq!(10, merged.len());
+        assert_eq!(404, agent.get(&format!("{}calendars/nope.ics", server.url())).call().unwrap().status().as_u16());
+        // CalDAV is off
+        let propfind = http::Request::builder().method("PROPFIND").uri(server.url()).body(String::new()).unwrap();
+        assert_eq!(405, agent.run(propfind).unwrap().status().as_u16());
+        server.stop();
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_caldav_endpoint() {
+        let (server, thread) = start(&test_config(true));
+        let client = CalDavClient::new(&server.url(), MpTimeZone::utc());
+        let calendars = client.discover_calendars().unwrap();
+        let names: Vec<Option<&str>> = calendars.iter().map(|calendar| calendar.display_name.as_deref()).collect();
+        assert_eq!(vec![Some("Team Work!"), Some("src/test/test_overrides.ics"), Some("All calendars")], names);
+        assert_eq!("/calendars/team-work/", calendars[0].href);
+
+        let window_start = FixedOffset::east(0).ymd(2024, 1, 9).and_hms(0, 0, 0);
+        let found = client.events_in_range(&calendars[0].href, window_start, window_start + chrono::Duration::days(1)).unwrap();
+        let mut found_names: Vec<&str> = found.iter().map(|remote| remote.events[0].name().unwrap()).collect();
+        found_names.sort_unstable();
+        assert_eq!(vec!["Focus block", "Standup"], found_names);
+        let standup = found.iter().find(|remote| remote.events[0].name() == Some("Standup")).unwrap();
+        assert_eq!(1, client.get_event(&standup.href).unwrap().events[0].overrides.len());
+
+        // Read-only
+        let event = standup.events[0].clone();
+        assert!(matches!(client.update_event(&standup.href, &event, standup.etag.as_deref()), Err(MpCalendarError::HttpStatus { status: 403, .. })));
+        assert!(matches!(client.delete_event(&standup.href, None), Err(MpCalendarError::HttpStatus { status: 403, .. })));
+        server.stop();
+        thread.join().unwrap();
+    }
+}
@@ -0,0 +1,439 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use chrono::prelude::*;
+use super::{MpEvent, MpCalendarError};
+
+/// Handle for an event in an MpEventIndex, used to remove it again
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub struct EventId(u64);
+
+type Link = Option<Box<IndexNode>>;
+type IndexKey = (DateTime<FixedOffset>, DateTime<FixedOffset>, EventId);
+
+// Nodes are ordered by (start, end, id), so ties at identical times keep insertion order and
+// every key is unique. max_end is the latest end anywhere in the node's subtree, which lets
+// range queries skip subtrees that finish before the range starts.
+struct IndexNode {
+    start: DateTime<FixedOffset>,
+    end: DateTime<FixedOffset>,
+    id: EventId,
+    event: MpEvent,
+    max_end: DateTime<FixedOffset>,
+    height: u32,
+    left: Link,
+    right: Link
+}
+
+/// Events held in an AVL interval tree. Insert, remove and the next event after a time are
+/// O(log n); events containing a time or intersecting a range are O(k log n) at worst for k
+/// results, as each result can cost a path down the tree that the max_end pruning doesn't cut short.
+/// Results come back in chronological order by start then end, with events at identical times in
+/// the order they were inserted. Recurring events should be expanded first; each occurrence is
+/// indexed on its own. cal_ops::find_meeting_slots uses one per calendar to check each slot.
+#[derive(Default)]
+pub struct MpEventIndex {
+    root: Link,
+    // Key of every event in the tree, so remove only needs the id
+    keys: HashMap<EventId, (DateTime<FixedOffset>, DateTime<FixedOffset>)>,
+    next_id: u64
+}
+
+impl MpEventIndex {
+    pub fn new() -> MpEventIndex {
+        return MpEventIndex::default();
+    }
+
+    /// Indexes every event with a start time. Events without one are left out.
+    pub fn from_events(events: Vec<MpEvent>) -> MpEventIndex {
+        let mut index = MpEventIndex::new();
+        for event in events {
+            let _ = index.insert(event);
+        }
+        return index;
+    }
+
+    pub fn len(&self) -> usize {
+        return self.keys.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.keys.is_empty();
+    }
+
+    /// Adds event to the index. Events with no end are instants at their start.
+    pub fn insert(&mut self, event: MpEvent) -> Result<EventId, MpCalendarError> {
+        let start = match event.start_time {
+            Some(time) => time,
+            None => return Err(MpCalendarError::MissingProperty(String::from("DTSTART")))
+        };
+        let end = match event.end_time {
+            Some(time) if time > start => time,
+            _ => start
+        };
+        let id = EventId(self.next_id);
+        self.next_id += 1;
+        let node = Box::new(IndexNode { start, end, id, event, max_end: end, height: 1, left: None, right: None });
+        self.root = Some(insert_node(self.root.take(), node));
+        self.keys.insert(id, (start, end));
+        return Ok(id);
+    }
+
+    /// Takes the event out of the index, or None if id isn't in it
+    pub fn remove(&mut self, id: EventId) -> Option<MpEvent> {
+        let (start, end) = self.keys.remove(&id)?;
+        let (root, removed) = remove_node(self.root.take(), (start, end, id));
+        self.root = root;
+        return removed.map(|node| node.event);
+    }
+
+    pub fn get(&self, id: EventId) -> Option<&MpEvent> {
+        let (start, end) = self.keys.get(&id)?;
+        let key = (*start, *end, id);
+        let mut link = &self.root;
+        while let Some(node) = link {
+            match key.cmp(&node.key()) {
+                Ordering::Less => link = &node.left,
+                Ordering::Greater => link = &node.right,
+                Ordering::Equal => return Some(&node.event)
+            }
+        }
+        return None;
+    }
+
+    /// Every event, in chronological order
+    pub fn events(&self) -> Vec<&MpEvent> {
+        let mut found: Vec<&MpEvent> = Vec::with_capacity(self.len());
+        collect_in_order(&self.root, &mut found);
+        return found;
+    }
+
+    /// Events intersecting [window_start, window_end), with the same rules as
+    /// cal_ops::expand_mpevents_in_window: an instant only counts if it's inside the window
+    pub fn intersecting(&self, window_start: DateTime<FixedOffset>, window_end: DateTime<FixedOffset>) -> Vec<&MpEvent> {
+        let mut found: Vec<&MpEvent> = vec![];
+        collect_intersecting(&self.root, window_start, window_end, &mut found);
+        return found;
+    }
+
+    /// Events in progress at time, i.e. start <= time < end, plus instants at exactly time
+    pub fn containing(&self, time: DateTime<FixedOffset>) -> Vec<&MpEvent> {
+        let mut found: Vec<&MpEvent> = vec![];
+        collect_containing(&self.root, time, &mut found);
+        return found;
+    }
+
+    /// The first event to start at or after time
+    pub fn next_after(&self, time: DateTime<FixedOffset>) -> Option<&MpEvent> {
+        let mut next: Option<&MpEvent> = None;
+        let mut link = &self.root;
+        while let Some(node) = link {
+            if node.start >= time {
+                next = Some(&node.event);
+                link = &node.left;
+            } else {
+                link = &node.right;
+            }
+        }
+        return next;
+    }
+}
+
+impl IndexNode {
+    fn key(&self) -> IndexKey {
+        return (self.start, self.end, self.id);
+    }
+
+    fn intersects(&self, window_start: DateTime<FixedOffset>, window_end: DateTime<FixedOffset>) -> bool {
+        if self.start == self.end {
+            return self.start >= window_start && self.start < window_end;
+        }
+        return self.start < window_end && self.end > window_start;
+    }
+
+    fn contains(&self, time: DateTime<FixedOffset>) -> bool {
+        if self.start == self.end {
+            return self.start == time;
+        }
+        return self.start <= time && time < self.end;
+    }
+
+    // Recomputes height and max_end from the children
+    fn update(&mut self) {
+        self.height = 1 + height(&self.left).max(height(&self.right));
+        self.max_end = self.end;
+        for child in [&self.left, &self.right].iter().copied().flatten() {
+            if child.max_end > self.max_end {
+                self.max_end = child.max_end;
+            }
+        }
+    }
+
+    fn balance(&self) -> i64 {
+        return height(&self.left) as i64 - height(&self.right) as i64;
+    }
+}
+
+fn height(link: &Link) -> u32 {
+    match link {
+        Some(node) => return node.height,
+        None => return 0
+    }
+}
+
+fn rotate_right(mut node: Box<IndexNode>) -> Box<IndexNode> {
+    let mut pivot = node.left.take().expect("rotate_right needs a left child");
+    node.left = pivot.right.take();
+    node.update();
+    pivot.right = Some(node);
+    pivot.update();
+    return pivot;
+}
+
+fn rotate_left(mut node: Box<IndexNode>) -> Box<IndexNode> {
+    let mut pivot = node.right.take().expect("rotate_left needs a right child");
+    node.right = pivot.left.take();
+    node.update();
+    pivot.left = Some(node);
+    pivot.update();
+    return pivot;
+}
+
+fn rebalance(mut node: Box<IndexNode>) -> Box<IndexNode> {
+    node.update();
+    let balance = node.balance();
+    if balance > 1 {
+        if node.left.as_ref().is_some_and(|left| left.balance() < 0) {
+            node.left = node.left.take().map(rotate_left);
+        }
+        return rotate_right(node);
+    }
+    if balance < -1 {
+        if node.right.as_ref().is_some_and(|right| right.balance() > 0) {
+            node.right = node.right.take().map(rotate_right);
+        }
+        return rotate_left(node);
+    }
+    return node;
+}
+
+fn insert_node(link: Link, new_node: Box<IndexNode>) -> Box<IndexNode> {
+    let mut node = match link {
+        Some(node) => node,
+        None => return new_node
+    };
+    if new_node.key() < node.key() {
+        node.left = Some(insert_node(node.left.take(), new_node));
+    } else {
+        node.right = Some(insert_node(node.right.take(), new_node));
+    }
+    return rebalance(node);
+}
+
+// Returns the new subtree and the node taken out of it, if key was found
+fn remove_node(link: Link, key: IndexKey) -> (Link, Option<Box<IndexNode>>) {
+    let mut node = match link {
+        Some(node) => node,
+        None => return (None, None)
+    };
+    let removed = match key.cmp(&node.key()) {
+        Ordering::Less => {
+            let (left, removed) = remove_node(node.left.take(), key);
+            node.left = left;
+            removed
+        },
+        Ordering::Greater => {
+            let (right, removed) = remove_node(node.right.take(), key);
+            node.right = right;
+            removed
+        },
+        Ordering::Equal => {
+            let left = node.left.take();
+            let right = node.right.take();
+            match (left, right) {
+                (None, right) => return (right, Some(node)),
+                (left, None) => return (left, Some(node)),
+                (left, Some(right)) => {
+                    // Replace node with its in-order successor
+                    let (right, mut successor) = remove_min(right);
+                    successor.left = left;
+                    successor.right = right;
+                    return (Some(rebalance(successor)), Some(node));
+                }
+            }
+        }
+    };
+    return (Some(rebalance(node)), removed);
+}
+
+fn remove_min(mut node: Box<IndexNode>) -> (Link, Box<IndexNode>) {
+    match node.left.take() {
+        Some(left) => {
+            let (left, min) = remove_min(left);
+            node.left = left;
+            return (Some(rebalance(node)), min);
+        },
+        None => {
+            let right = node.right.take();
+            return (right, node);
+        }
+    }
+}
+
+fn collect_in_order<'a>(link: &'a Link, found: &mut Vec<&'a MpEvent>) {
+    match link {
+        Some(node) => {
+            collect_in_order(&node.left, found);
+            found.push(&node.event);
+            collect_in_order(&node.right, found);
+        },
+        None => ()
+    }
+}
+
+fn collect_intersecting<'a>(link: &'a Link,
+                            window_start: DateTime<FixedOffset>,
+                            window_end: DateTime<FixedOffset>,
+                            found: &mut Vec<&'a MpEvent>) {
+    let node = match link {
+        Some(node) => node,
+        None => return
+    };
+    // Nothing below here ends late enough. An instant at window_start still counts, hence <.
+    if node.max_end < window_start {
+        return;
+    }
+    collect_intersecting(&node.left, window_start, window_end, found);
+    if node.intersects(window_start, window_end) {
+        found.push(&node.event);
+    }
+    // Everything to the right starts no earlier than this node
+    if node.start < window_end {
+        collect_intersecting(&node.right, window_start, window_end, found);
+    }
+}
+
+fn collect_containing<'a>(link: &'a Link, time: DateTime<FixedOffset>, found: &mut Vec<&'a MpEvent>) {
+    let node = match link {
+        Some(node) => node,
+        None => return
+    };
+    if node.max_end < time {
+        return;
+    }
+    collect_containing(&node.left, time, found);
+    if node.contains(time) {
+        found.push(&node.event);
+    }
+    if node.start <= time {
+        collect_containing(&node.right, time, found);
+    }
+}
+
+#[cfg(test)]
+mod cal_index_tests {
+    use super::*;
+    use crate::mp_calendar::cal_ops;
+
+    fn time(hour: u32, min: u32) -> DateTime<FixedOffset> {
+        return FixedOffset::east(0).ymd(2024, 1, 8).and_hms(hour, min, 0);
+    }
+
+    fn event(name: &str, start: DateTime<FixedOffset>, end: Option<DateTime<FixedOffset>>) -> MpEvent {
+        return MpEvent { name: Some(String::from(name)), start_time: Some(start), end_time: end, ..Default::default() };
+    }
+
+    fn names(events: Vec<&MpEvent>) -> Vec<&str> {
+        return events.iter().map(|event| event.name.as_deref().unwrap()).collect();
+    }
+
+    fn test_index() -> (MpEventIndex, Vec<EventId>) {
+        let mut index = MpEventIndex::new();
+        let ids = vec![
+            index.insert(event("Lunch", time(12, 0), Some(time(13, 0)))).unwrap(),
+            index.insert(event("Standup", time(9, 0), Some(time(9, 15)))).unwrap(),
+            index.insert(event("Focus", time(8, 0), Some(time(17, 0)))).unwrap(),
+            index.insert(event("Deadline", time(10, 0), None)).unwrap(),
+            index.insert(event("Review", time(9, 15), Some(time(10, 30)))).unwrap()
+        ];
+        return (index, ids);
+    }
+
+    #[test]
+    fn test_queries() {
+        let (index, _) = test_index();
+        assert_eq!(5, index.len());
+        assert_eq!(vec!["Focus", "Standup", "Review", "Deadline", "Lunch"], names(index.events()));
+        assert_eq!(vec!["Focus", "Review", "Deadline"], names(index.intersecting(time(9, 15), time(11, 0))));
+        // Touching the end of Standup isn't intersecting it, and an instant at window_end is outside
+        assert_eq!(vec!["Focus", "Review"], names(index.intersecting(time(9, 15), time(10, 0))));
+        assert_eq!(vec!["Focus", "Review", "Deadline"], names(index.containing(time(10, 0))));
+        assert_eq!(vec!["Focus", "Review"], names(index.containing(time(9, 15))));
+        assert!(index.containing(time(17, 0)).is_empty());
+        assert_eq!(Some("Review"), index.next_after(time(9, 1)).and_then(|event| event.name.as_deref()));
+        assert_eq!(Some("Standup"), index.next_after(time(9, 0)).and_then(|event| event.name.as_deref()));
+        assert!(index.next_after(time(12, 1)).is_none());
+    }
+
+    #[test]
+    fn test_insert_without_start() {
+        let mut index = MpEventIndex::new();
+        let undated = MpEvent { name: Some(String::from("Someday")), ..Default::default() };
+        assert!(matches!(index.insert(undated), Err(MpCalendarError::MissingProperty(_))));
+        assert!(index.is_empty());
+    }
+
+    #[test]
+    fn test_remove() {
+        let (mut index, ids) = test_index();
+        assert_eq!(Some("Focus"), index.remove(ids[2]).and_then(|event| event.name).as_deref());
+        assert!(index.remove(ids[2]).is_none());
+        assert!(index.get(ids[2]).is_none());
+        assert_eq!(Some("Lunch"), index.get(ids[0]).and_then(|event| event.name.as_deref()));
+        assert_eq!(vec!["Review", "Deadline"], names(index.intersecting(time(9, 15), time(11, 0))));
+        assert_eq!(4, index.len());
+    }
+
+    #[test]
+    fn test_matches_vec_after_many_changes() {
+        // Pseudo-random intervals, some inserted then removed, checked against a plain scan
+        let mut index = MpEventIndex::new();
+        let mut kept: Vec<MpEvent> = vec![];
+        let mut seed: u64 = 7;
+        let mut next = || { seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407); (seed >> 33) as i64 };
+        for n in 0..2000 {
+            let start = time(0, 0) + chrono::Duration::minutes(next() % 10000);
+            let end = start + chrono::Duration::minutes(next() % 300);
+            let new_event = event(&n.to_string(), start, Some(end));
+            let id = index.insert(new_event.clone()).unwrap();
+            if n % 3 == 0 {
+                index.remove(id);
+            } else {
+                kept.push(new_event);
+            }
+        }
+        assert_eq!(kept.len(), index.len());
+        let window_start = time(0, 0) + chrono::Duration::minutes(4000);
+        let window_end = window_start + chrono::Duration::minutes(600);
+        let expected = cal_ops::expand_mpevents_in_window(kept.clone(), window_start, window_end);
+        // Ties at identical times are in insertion order here but by name in the Vec, so re-sort
+        let found: Vec<MpEvent> = index.intersecting(window_start, window_end).into_iter().cloned().collect();
+        assert_eq!(expected, cal_ops::sort_mpevents_chronologically_by_start(found));
+        let indexed: Vec<MpEvent> = index.events().into_iter().cloned().collect();
+        assert!(indexed.windows(2).all(|pair| pair[0].start_time <= pair[1].start_time));
+        for minutes in (0..10000).step_by(700) {
+            let at = time(0, 0) + chrono::Duration::minutes(minutes);
+            let next_start = kept.iter().filter_map(|event| event.start_time).filter(|start| *start >= at).min();
+            assert_eq!(next_start, index.next_after(at).and_then(|event| event.start_time));
+            let in_progress = kept.iter()
+                .filter(|event| event.start_time.unwrap() <= at && at < event.end_time.unwrap())
+                .count();
+            let instants = kept.iter()
+                .filter(|event| event.start_time == event.end_time && event.start_time == Some(at))
+                .count();
+            assert_eq!(in_progress + instants, index.containing(at).len());
+        }
+        // AVL trees are never more than about 1.44 log2(n) high
+        assert!(height(&index.root) <= 16);
+        assert_eq!(cal_ops::sort_mpevents_chronologically_by_start(kept), cal_ops::sort_mpevents_chronologically_by_start(indexed));
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use chrono::prelude::*;
+use super::MpEvent;
+
+/// How an incoming event was recognised as a copy of one already merged
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MergeMatch {
+    // Same UID and RECURRENCE-ID
+    Uid,
+    // Different or missing UIDs, but the same name (ignoring case, punctuation and spacing) starting
+    // in the same minute
+    NameAndStart
+}
+
+/// Two copies of one event, and which of them the merge kept
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct MergedPair {
+    pub kept: MpEvent,
+    pub discarded: MpEvent,
+    pub matched_by: MergeMatch
+}
+
+/// What merging did with each incoming event: added it because nothing matched, let it replace the
+/// older copy it matched, or dropped it because the copy it matched was at least as new
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct MergeReport {
+    pub added: Vec<MpEvent>,
+    pub updated: Vec<MergedPair>,
+    pub dropped: Vec<MergedPair>
+}
+
+impl MergeReport {
+    /// True if any incoming event turned out to be a copy of another
+    pub fn has_duplicates(&self) -> bool {
+        return !self.updated.is_empty() || !self.dropped.is_empty();
+    }
+}
+
+pub(super) type UidKey = (String, Option<DateTime<FixedOffset>>);
+pub(super) type NameAndStartKey = (String, i64);
+
+// Where each merged event can be found by either key. An event that has been replaced stays findable
+// by the old copy's keys too, so a third copy of it still matches.
+#[derive(Default)]
+struct MergeKeys {
+    by_uid: HashMap<UidKey, usize>,
+    by_name_and_start: HashMap<NameAndStartKey, usize>
+}
+
+impl MergeKeys {
+    fn add(&mut self, event: &MpEvent, index: usize) {
+        match uid_key(event) {
+            Some(key) => { self.by_uid.entry(key).or_insert(index); },
+            None => ()
+        };
+        match name_and_start_key(event) {
+            Some(key) => { self.by_name_and_start.entry(key).or_insert(index); },
+            None => ()
+        };
+    }
+
+    fn find(&self, event: &MpEvent) -> Option<(usize, MergeMatch)> {
+        let by_uid = uid_key(event)
+            .and_then(|key| self.by_uid.get(&key))
+            .map(|index| (*index, MergeMatch::Uid));
+        return by_uid.or(name_and_start_key(event)
+            .and_then(|key| self.by_name_and_start.get(&key))
+            .map(|index| (*index, MergeMatch::NameAndStart)));
+    }
+}
+
+pub(super) fn uid_key(event: &MpEvent) -> Option<UidKey> {
+    return event.uid.clone().map(|uid| (uid, event.recurrence_id));
+}
+
+pub(super) fn name_and_start_key(event: &MpEvent) -> Option<NameAndStartKey> {
+    let name = normalise_name(event.name.as_deref()?);
+    if name.is_empty() {
+        return None;
+    }
+    let start = event.start_time?;
+    return Some((name, start.timestamp().div_euclid(60)));
+}
+
+/// Lowercase words of letters and digits separated by single spaces, so "Lunch with  Sam!" and
+/// "lunch with sam" compare equal
+fn normalise_name(name: &str) -> String {
+    let words: Vec<String> = name
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect();
+    return words.join(" ");
+}
+
+/// True if candidate is a later revision than existing: a higher SEQUENCE, or the same SEQUENCE
+/// and a later LAST-MODIFIED. A missing SEQUENCE counts as 0 and a missing LAST-MODIFIED as older
+/// than any. DTSTAMP isn't considered, as exports stamp it with the time they were made.
+pub fn is_newer_revision(candidate: &MpEvent, existing: &MpEvent) -> bool {
+    let candidate_sequence = candidate.sequence.unwrap_or(0);
+    let existing_sequence = existing.sequence.unwrap_or(0);
+    if candidate_sequence != existing_sequence {
+        return candidate_sequence > existing_sequence;
+    }
+    match (candidate.last_modified, existing.last_modified) {
+        (Some(candidate_time), Some(existing_time)) => return candidate_time > existing_time,
+        (Some(_), None) => return true,
+        _ => return false
+    }
+}
+
+/// Merges incoming into base. Each incoming event is matched against the events merged so far,
+/// by UID and RECURRENCE-ID first and then by name and start time; if it matches, whichever copy
+/// is the newer revision is kept in the other's place, otherwise it's added at the end. Ties keep
+/// the copy already merged. The events of base aren't matched against each other.
+pub fn merge_events(base: Vec<MpEvent>, incoming: Vec<MpEvent>) -> (Vec<MpEvent>, MergeReport) {
+    let mut keys = MergeKeys::default();
+    for (index, event) in base.iter().enumerate() {
+        keys.add(event, index);
+    }
+    let mut merged = base;
+    let mut report = MergeReport::default();
+    for event in incoming {
+        match keys.find(&event) {
+            Some((index, matched_by)) if is_newer_revision(&event, &merged[index]) => {
+                keys.add(&event, index);
+                let discarded = std::mem::replace(&mut merged[index], event.clone());
+                report.updated.push(MergedPair { kept: event, discarded, matched_by });
+            },
+            Some((index, matched_by)) => {
+                report.dropped.push(MergedPair { kept: merged[index].clone(), discarded: event, matched_by });
+            },
+            None => {
+                keys.add(&event, merged.len());
+                merged.push(event.clone());
+                report.added.push(event);
+            }
+        };
+    }
+    return (merged, report);
+}
+
+/// Merges several sources in order, as merge_events would one after the other starting from none,
+/// but hands back each source's share of the result: the events of it that the merge kept. Saving
+/// each share under its own source saves the merge without losing track of where events came from.
+pub fn merge_by_source(sources: Vec<Vec<MpEvent>>) -> Vec<Vec<MpEvent>> {
+    let mut keys = MergeKeys::default();
+    let mut merged: Vec<(usize, MpEvent)> = vec![];
+    let source_count = sources.len();
+    for (source, events) in sources.into_iter().enumerate() {
+        for event in events {
+            match keys.find(&event) {
+                Some((index, _)) if is_newer_revision(&event, &merged[index].1) => {
+                    keys.add(&event, index);
+                    merged[index] = (source, event);
+                },
+                Some(_) => (),
+                None => {
+                    keys.add(&event, merged.len());
+                    merged.push((source, event));
+                }
+            };
+        }
+    }
+    let mut shares: Vec<Vec<MpEvent>> = vec![vec![]; source_count];
+    for (source, event) in merged {
+        shares[source].push(event);
+    }
+    return shares;
+}
+
+/// Removes duplicates within one list of events, keeping the newest revision of each in the
+/// position of its first copy. Every first copy is reported as added.
+pub fn dedupe_events(events: Vec<MpEvent>) -> (Vec<MpEvent>, MergeReport) {
+    return merge_events(vec![], events);
+}
+
+#[cfg(test)]
+mod cal_merge_tests {
+    use super::*;
+    use crate::mp_calendar::cal_io;
+    use crate::mp_calendar::cal_tz::MpTimeZone;
+
+    fn read_fixture(path: &str) -> Vec<MpEvent> {
+        return cal_io::read_events_from_ics_file(String::from(path), &MpTimeZone::utc()).unwrap();
+    }
+
+    fn names(events: &[MpEvent]) -> Vec<&str> {
+        return events.iter().map(|event| event.name().unwrap()).collect();
+    }
+
+    #[test]
+    fn test_normalise_name() {
+        assert_eq!("lunch with sam", normalise_name("Lunch with  Sam!"));
+        assert_eq!("q3 planning", normalise_name(" Q3 planning - "));
+        assert_eq!("", normalise_name("--"));
+    }
+
+    #[test]
+    fn test_is_newer_revision() {
+        let revision = |sequence: Option<u32>, modified: Option<&str>| MpEvent {
+            sequence,
+            last_modified: modified.map(|time| DateTime::parse_from_rfc3339(time).unwrap()),
+            ..Default::default()
+        };
+        let march = Some("2024-03-01T09:00:00+00:00");
+        let february = Some("2024-02-01T09:00:00+00:00");
+        assert!(is_newer_revision(&revision(Some(2), None), &revision(Some(1), march)));
+        assert!(!is_newer_revision(&revision(None, march), &revision(Some(1), february)));
+        assert!(is_newer_revision(&revision(Some(0), march), &revision(None, february)));
+        assert!(is_newer_revision(&revision(None, february), &revision(None, None)));
+        assert!(!is_newer_revision(&revision(None, None), &revision(None, None)));
+        assert!(!is_newer_revision(&revision(Some(1), march), &revision(Some(1), march)));
+    }
+
+    #[test]
+    fn test_merge_events() {
+        let work = read_fixture("src/test/test_merge_work.ics");
+        let personal = read_fixture("src/test/test_merge_personal.ics");
+        let (merged, report) = merge_events(work, personal);
+
+        assert_eq!(vec!["Design review", "Standup", "Lunch with Sam", "Dentist"], names(&merged));
+        // The rescheduled review replaced the older one in place
+        assert_eq!(Some(2), merged[0].sequence());
+        assert_eq!(Some(String::from("Room 4")), merged[0].location);
+
+        assert_eq!(vec!["Dentist"], names(&report.added));
+        assert_eq!(1, report.updated.len());
+        assert_eq!(MergeMatch::Uid, report.updated[0].matched_by);
+        assert_eq!(Some(1), report.updated[0].discarded.sequence());
+
+        let dropped: Vec<(&str, MergeMatch)> = report.dropped.iter()
+            .map(|pair| (pair.discarded.name().unwrap(), pair.matched_by))
+            .collect();
+        assert_eq!(vec![("Standup", MergeMatch::Uid), ("lunch with  Sam!", MergeMatch::NameAndStart)], dropped);
+        assert_eq!(Some("merge-lunch-work@example.com"), report.dropped[1].kept.uid());
+        assert!(report.has_duplicates());
+    }
+
+    #[test]
+    fn test_merge_is_idempotent_and_dedupes_within_a_list() {
+        let work = read_fixture("src/test/test_merge_work.ics");
+        let (merged, report) = merge_events(work.clone(), work.clone());
+        assert_eq!(names(&work), names(&merged));
+        assert!(report.added.is_empty() && report.updated.is_empty());
+        assert_eq!(3, report.dropped.len());
+
+        let mut doubled = work.clone();
+        doubled.extend(read_fixture("src/test/test_merge_personal.ics"));
+        let (deduped, report) = dedupe_events(doubled);
+        assert_eq!(vec!["Design review", "Standup", "Lunch with Sam", "Dentist"], names(&deduped));
+        assert_eq!(4, report.added.len());
+        assert_eq!(1, report.updated.len());
+        assert_eq!(2, report.dropped.len());
+
+        // Events with neither a UID nor a name and start time can't be matched, so are all kept
+        let (anonymous, _) = dedupe_events(vec![MpEvent::default(), MpEvent::default()]);
+        assert_eq!(2, anonymous.len());
+    }
+
+    #[test]
+    fn test_merge_by_source() {
+        let work = read_fixture("src/test/test_merge_work.ics");
+        let personal = read_fixture("src/test/test_merge_personal.ics");
+        let shares = merge_by_source(vec![work.clone(), personal.clone()]);
+        assert_eq!(vec!["Standup", "Lunch with Sam"], names(&shares[0]));
+        assert_eq!(vec!["Design review", "Dentist"], names(&shares[1]));
+        assert_eq!(Some(2), shares[1][0].sequence());
+        // The newest revision wins whichever source it's in
+        let reversed = merge_by_source(vec![personal, work]);
+        assert_eq!(vec!["Design review", "lunch with  Sam!", "Dentist"], names(&reversed[0]));
+        assert_eq!(vec!["Standup"], names(&reversed[1]));
+    }
+
+    #[test]
+    fn test_third_copy_matches_the_replaced_event() {
+        let start = FixedOffset::east(0).ymd(2024, 3, 11).and_hms(9, 0, 0);
+        let original = MpEvent {
+            name: Some(String::from("Retro")),
+            uid: Some(String::from("retro@example.com")),
+            start_time: Some(start),
+            ..Default::default()
+        };
+        let moved = MpEvent { start_time: Some(start + chrono::Duration::hours(1)), sequence: Some(1), ..original.clone() };
+        // A copy from before the move, under another UID, still matches by the old start time
+        let stale = MpEvent { uid: Some(String::from("retro-copy@example.com")), ..original.clone() };
+        let (merged, report) = merge_events(vec![original], vec![moved.clone(), stale]);
+        assert_eq!(vec![moved], merged);
+        assert_eq!(MergeMatch::Uid, report.updated[0].matched_by);
+        assert_eq!(MergeMatch::NameAndStart, report.dropped[0].matched_by);
+    }
+}
@@ -0,0 +1,634 @@
+use chrono::prelude::*;
+use chrono::Duration;
+use super::{MpEvent, MpCalendarError, EventStatus, cal_io, cal_ops};
+use super::cal_recur::{Frequency, RecurrenceRule, WeekdayNum};
+use super::cal_tz::MpTimeZone;
+
+/// Length of an event given a time but no duration or end
+const DEFAULT_DURATION_MINUTES: i64 = 60;
+
+// One recognised part of a phrase
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum Element {
+    Date(NaiveDate),
+    Time(NaiveTime),
+    EndTime(NaiveTime),
+    // "in 2 hours": a start relative to now
+    StartIn(Duration),
+    Duration(Duration),
+    Recurrence(RecurrenceRule),
+    // "in 99999999 days": understood, but further away than a date can be
+    TooFar
+}
+
+// Each word of the phrase as typed, and lowercased without surrounding punctuation for matching
+struct Words<'a> {
+    typed: Vec<&'a str>,
+    plain: Vec<String>
+}
+
+impl<'a> Words<'a> {
+    fn new(phrase: &'a str) -> Words<'a> {
+        let typed: Vec<&str> = phrase.split_whitespace().collect();
+        let plain = typed.iter()
+            .map(|word| word.trim_matches([',', '.', ';', '!', '?']).to_lowercase())
+            .collect();
+        return Words { typed, plain };
+    }
+
+    fn len(&self) -> usize {
+        return self.typed.len();
+    }
+
+    fn plain(&self, index: usize) -> Option<&str> {
+        return self.plain.get(index).map(|word| word.as_str());
+    }
+}
+
+/// Turns a phrase such as "lunch with Priya tomorrow 1pm for an hour at the canteen" into an event,
+/// reading dates and times as local to local_zone and relative to now. Understood, anywhere in the
+/// phrase:
+///  - dates: "today", "tomorrow", "monday" or "on monday" (the next one, today included), "next
+///    monday" (the next one after today), "in 3 days", "in 2 weeks", "14 march", "march 14th",
+///    "2024-03-14"
+///  - times: "1pm", "1:30pm", "13:00", "noon", "at 1 pm", "from 2pm", "until 3pm", "1-2pm", "in 2 hours"
+///  - durations: "for an hour", "for 90 minutes", "for half an hour", "for 2h", "for 3 days"
+///  - locations: everything after "at" up to the next thing understood, e.g. "at the canteen"
+///  - recurrence: "daily", "every day", "every weekday", "every friday", "every other friday",
+///    "every 3 weeks", "fortnightly", "monthly", "every year"
+///
+/// Whatever's left is the event's name. With a time but no date the event is today, or tomorrow if
+/// that time has passed; a weekly rule starts on its first weekday on or after the date; with no
+/// time it's an all-day event. Timed events last an hour unless told otherwise. Abbreviated weekdays
+/// such as "sat" are only dates after "on" or before a time, so they can appear in names.
+pub fn parse_event_phrase(phrase: &str, now: DateTime<FixedOffset>, local_zone: &MpTimeZone) -> Result<MpEvent, MpCalendarError> {
+    let local_now = local_zone.convert(now).naive_local();
+    let today = local_now.date();
+    let words = Words::new(phrase);
+    let invalid = |reason: &str| MpCalendarError::InvalidPhrase { phrase: String::from(phrase), reason: String::from(reason) };
+
+    let mut elements: Vec<Element> = vec![];
+    let mut name_words: Vec<&str> = vec![];
+    let mut location: Option<String> = None;
+    let mut index = 0;
+    while index < words.len() {
+        match parse_element(&words, index, today) {
+            Some((parsed, used)) => {
+                elements.extend(parsed);
+                index += used;
+            },
+            None if words.plain(index) == Some("at") && index + 1 < words.len() => {
+                let mut end = index + 1;
+                while end < words.len() && parse_element(&words, end, today).is_none() {
+                    end += 1;
+                }
+                // With nothing before the next element, "at" just leads into it, as in "at tomorrow 3pm"
+                if end > index + 1 {
+                    let place = words.typed[index + 1..end].join(" ");
+                    location = Some(String::from(place.trim_end_matches([',', '.'])));
+                }
+                index = end;
+            },
+            None => {
+                name_words.push(words.typed[index]);
+                index += 1;
+            }
+        };
+    }
+
+    let name = capitalise(name_words.join(" ").trim_matches(|c: char| c == ',' || c.is_whitespace()));
+    if name.is_empty() {
+        return Err(invalid("there's nothing left to use as the event's name"));
+    }
+    let mut date: Option<NaiveDate> = None;
+    let mut time: Option<NaiveTime> = None;
+    let mut end_time: Option<NaiveTime> = None;
+    let mut duration: Option<Duration> = None;
+    let mut rule: Option<RecurrenceRule> = None;
+    for element in elements {
+        match element {
+            Element::Date(day) => date = Some(day),
+            Element::Time(at) => time = Some(at),
+            Element::EndTime(at) => end_time = Some(at),
+            Element::StartIn(wait) => {
+                let start = local_now.checked_add_signed(wait).ok_or_else(|| invalid("the start is too far away"))?;
+                date = Some(start.date());
+                time = Some(start.time());
+            },
+            Element::Duration(length) => duration = Some(length),
+            Element::Recurrence(recurrence) => rule = Some(recurrence),
+            Element::TooFar => return Err(invalid("a date or length is too far away"))
+        };
+    }
+    let weekdays: Vec<Weekday> = rule.iter().flat_map(|recurrence| recurrence.by_day.iter().map(|day| day.weekday)).collect();
+    let date = match (date, time, &rule) {
+        // A weekly rule's first occurrence is on one of its weekdays, so "every friday on 2 november"
+        // starts on the first Friday from then
+        (Some(day), _, _) if !weekdays.is_empty() => on_or_after(day, &weekdays),
+        (Some(day), _, _) => day,
+        (None, _, _) if !weekdays.is_empty() => on_or_after(today, &weekdays),
+        (None, Some(at), _) if today.and_time(at) <= local_now => today.succ(),
+        (None, Some(_), _) => today,
+        (None, None, Some(_)) => today,
+        (None, None, None) => return Err(invalid("no date or time was given"))
+    };
+
+    let mut event = match time {
+        Some(at) => {
+            let start = date.and_time(at);
+            let end = match (end_time, duration) {
+                (Some(until), _) if until > at => date.and_time(until),
+                // "11pm until 1am" ends the next day
+                (Some(until), _) => date.succ().and_time(until),
+                (None, Some(length)) => start.checked_add_signed(length).ok_or_else(|| invalid("the end is too far away"))?,
+                (None, None) => start + Duration::minutes(DEFAULT_DURATION_MINUTES)
+            };
+            cal_ops::create_new_mpevent(Some(name), Some(local_zone.from_local_datetime(start)),
+                                        Some(local_zone.from_local_datetime(end)), location, None,
+                                        Some(EventStatus::Confirmed))
+        },
+        None if end_time.is_some() => return Err(invalid("an end time needs a start time")),
+        None => {
+            let days = duration.map(|length| length.num_days()).unwrap_or(1).max(1);
+            let start = date.and_hms(0, 0, 0);
+            let end = start.checked_add_signed(Duration::days(days)).ok_or_else(|| invalid("the end is too far away"))?;
+            let mut event = cal_ops::create_new_mpevent(Some(name), Some(local_zone.from_local_datetime(start)),
+                                                        Some(local_zone.from_local_datetime(end)),
+                                                        location, None, Some(EventStatus::Confirmed));
+            event.all_day = true;
+            event
+        }
+    };
+    event.timezone = Some(local_zone.clone());
+    event.rrule = rule;
+    event.dtstamp = Some(now);
+    event.uid = Some(cal_io::generate_uid(&event));
+    return Ok(event);
+}
+
+/// How a rule made by parse_event_phrase reads in English, e.g. "every other Friday"
+pub fn describe_rule(rule: &RecurrenceRule) -> String {
+    let weekdays: Vec<Weekday> = rule.by_day.iter().map(|day| day.weekday).collect();
+    if rule.freq == Frequency::Weekly && weekdays == WORKING_DAYS && rule.interval == 1 {
+        return String::from("every weekday");
+    }
+    let unit = match rule.freq {
+        Frequency::Secondly => "second",
+        Frequency::Minutely => "minute",
+        Frequency::Hourly => "hour",
+        Frequency::Daily => "day",
+        Frequency::Weekly => "week",
+        Frequency::Monthly => "month",
+        Frequency::Yearly => "year"
+    };
+    let names: Vec<&str> = weekdays.iter().map(|weekday| weekday_name(*weekday)).collect();
+    match (rule.freq, rule.interval, names.is_empty()) {
+        (Frequency::Weekly, 1, false) => return format!("every {}", names.join(" and ")),
+        (Frequency::Weekly, 2, false) => return format!("every other {}", names.join(" and ")),
+        (Frequency::Weekly, interval, false) => return format!("every {} weeks on {}", interval, names.join(" and ")),
+        (_, 1, _) => return format!("every {}", unit),
+        (_, 2, _) => return format!("every other {}", unit),
+        (_, interval, _) => return format!("every {} {}s", interval, unit)
+    }
+}
+
+const WORKING_DAYS: [Weekday; 5] = [Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri];
+
+fn weekday_name(weekday: Weekday) -> &'static str {
+    match weekday {
+        Weekday::Mon => return "Monday",
+        Weekday::Tue => return "Tuesday",
+        Weekday::Wed => return "Wednesday",
+        Weekday::Thu => return "Thursday",
+        Weekday::Fri => return "Friday",
+        Weekday::Sat => return "Saturday",
+        Weekday::Sun => return "Sunday"
+    }
+}
+
+fn capitalise(text: &str) -> String {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(first) => return first.to_uppercase().chain(chars).collect(),
+        None => return String::new()
+    }
+}
+
+// The elements starting at words[index] and how many words they used, or None if nothing is
+// understood there
+fn parse_element(words: &Words, index: usize, today: NaiveDate) -> Option<(Vec<Element>, usize)> {
+    let word = words.plain(index)?;
+    let next = words.plain(index + 1);
+    match word {
+        "today" => return Some((vec![Element::Date(today)], 1)),
+        "tomorrow" => return Some((vec![Element::Date(today.succ())], 1)),
+        "next" => {
+            let weekday = parse_weekday(next?)?;
+            return Some((vec![Element::Date(on_or_after(today.succ(), &[weekday]))], 2));
+        },
+        "on" | "this" => {
+            let (date, used) = parse_date(words, index + 1, today, true)?;
+            return Some((vec![Element::Date(date)], used + 1));
+        },
+        "in" => {
+            let (amount, used) = parse_amount(words, index + 1)?;
+            let unit = words.plain(index + 1 + used)?;
+            let element = match unit_duration(unit, amount)? {
+                (Some(length), true) => today.checked_add_signed(length).map(Element::Date).unwrap_or(Element::TooFar),
+                (Some(length), false) => Element::StartIn(length),
+                (None, _) => Element::TooFar
+            };
+            return Some((vec![element], used + 2));
+        },
+        "at" | "from" => {
+            let (time, used) = parse_time(words, index + 1)?;
+            return Some((time, used + 1));
+        },
+        "until" | "till" | "to" => {
+            let (time, used) = parse_time(words, index + 1)?;
+            let end = match time.as_slice() {
+                [Element::Time(at)] => *at,
+                _ => return None
+            };
+            return Some((vec![Element::EndTime(end)], used + 1));
+        },
+        "for" => {
+            if next == Some("half") && words.plain(index + 2).is_some_and(|word| word == "an" || word == "a")
+                && words.plain(index + 3).is_some_and(|word| word == "hour") {
+                return Some((vec![Element::Duration(Duration::minutes(30))], 4));
+            }
+            match next.and_then(parse_short_duration) {
+                Some(Some(length)) => return Some((vec![Element::Duration(length)], 2)),
+                Some(None) => return Some((vec![Element::TooFar], 2)),
+                None => ()
+            };
+            let (amount, used) = parse_amount(words, index + 1)?;
+            let element = match unit_duration(words.plain(index + 1 + used)?, amount)? {
+                (Some(length), _) => Element::Duration(length),
+                (None, _) => Element::TooFar
+            };
+            return Some((vec![element], used + 2));
+        },
+        "every" => {
+            let (rule, used) = parse_every(words, index + 1)?;
+            return Some((vec![Element::Recurrence(rule)], used + 1));
+        },
+        "daily" => return Some((vec![Element::Recurrence(RecurrenceRule::new(Frequency::Daily))], 1)),
+        "weekly" => return Some((vec![Element::Recurrence(RecurrenceRule::new(Frequency::Weekly))], 1)),
+        "fortnightly" => {
+            let rule = RecurrenceRule { interval: 2, ..RecurrenceRule::new(Frequency::Weekly) };
+            return Some((vec![Element::Recurrence(rule)], 1));
+        },
+        "monthly" => return Some((vec![Element::Recurrence(RecurrenceRule::new(Frequency::Monthly))], 1)),
+        "yearly" | "annually" => return Some((vec![Element::Recurrence(RecurrenceRule::new(Frequency::Yearly))], 1)),
+        _ => ()
+    };
+    // Without "on" before it, an abbreviated weekday is only a date when a time follows, so
+    // "pick up sat nav" keeps its "sat"
+    let in_date_position = parse_time(words, index + 1).is_some();
+    match parse_date(words, index, today, in_date_position) {
+        Some((date, used)) => return Some((vec![Element::Date(date)], used)),
+        None => return parse_time(words, index)
+    }
+}
+
+// A weekday, day and month, or ISO date at words[index]. Weekdays can be abbreviated ("sat") only
+// if in_date_position.
+fn parse_date(words: &Words, index: usize, today: NaiveDate, in_date_position: bool) -> Option<(NaiveDate, usize)> {
+    let word = words.plain(index)?;
+    match parse_weekday(word) {
+        Some(weekday) if in_date_position || weekday_name(weekday).eq_ignore_ascii_case(word) => {
+            return Some((on_or_after(today, &[weekday]), 1));
+        },
+        _ => ()
+    };
+    match NaiveDate::parse_from_str(word, "%Y-%m-%d") {
+        Ok(date) => return Some((date, 1)),
+        Err(_) => ()
+    };
+    let next = words.plain(index + 1)?;
+    let (day, month) = match (parse_day_of_month(word), parse_month(next), parse_month(word), parse_day_of_month(next)) {
+        (Some(day), Some(month), _, _) => (day, month),
+        (_, _, Some(month), Some(day)) => (day, month),
+        _ => return None
+    };
+    // The next time that date comes round, today included
+    let this_year = NaiveDate::from_ymd_opt(today.year(), month, day)?;
+    if this_year >= today {
+        return Some((this_year, 2));
+    }
+    return Some((NaiveDate::from_ymd_opt(today.year() + 1, month, day)?, 2));
+}
+
+// "14", "14th", "1st", "22nd", "3rd"
+fn parse_day_of_month(word: &str) -> Option<u32> {
+    let digits = word.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let suffix = &word[digits.len()..];
+    if !["", "st", "nd", "rd", "th"].contains(&suffix) {
+        return None;
+    }
+    return digits.parse::<u32>().ok().filter(|day| (1..=31).contains(day));
+}
+
+fn parse_month(word: &str) -> Option<u32> {
+    const MONTHS: [&str; 12] = ["january", "february", "march", "april", "may", "june", "july",
+                                "august", "september", "october", "november", "december"];
+    if word.len() < 3 {
+        return None;
+    }
+    return MONTHS.iter()
+        .position(|month| *month == word || (word.len() <= 4 && month.starts_with(word)))
+        .map(|position| position as u32 + 1);
+}
+
+fn parse_weekday(word: &str) -> Option<Weekday> {
+    let weekday = match word {
+        "monday" | "mon" => Weekday::Mon,
+        "tuesday" | "tue" | "tues" => Weekday::Tue,
+        "wednesday" | "wed" => Weekday::Wed,
+        "thursday" | "thu" | "thur" | "thurs" => Weekday::Thu,
+        "friday" | "fri" => Weekday::Fri,
+        "saturday" | "sat" => Weekday::Sat,
+        "sunday" | "sun" => Weekday::Sun,
+        _ => return None
+    };
+    return Some(weekday);
+}
+
+// The first of weekdays on or after from
+fn on_or_after(from: NaiveDate, weekdays: &[Weekday]) -> NaiveDate {
+    let mut date = from;
+    while !weekdays.contains(&date.weekday()) {
+        date = date.succ();
+    }
+    return date;
+}
+
+// A time at words[index], or a range such as "1-2pm" giving a start and end
+fn parse_time(words: &Words, index: usize) -> Option<(Vec<Element>, usize)> {
+    let word = words.plain(index)?;
+    match word {
+        "noon" | "midday" => return Some((vec![Element::Time(NaiveTime::from_hms(12, 0, 0))], 1)),
+        "midnight" => return Some((vec![Element::Time(NaiveTime::from_hms(0, 0, 0))], 1)),
+        _ => ()
+    };
+    // "1 pm" as two words
+    match words.plain(index + 1) {
+        Some("am") | Some("a.m") => return Some((vec![Element::Time(parse_clock(word, Some("am"))?)], 2)),
+        Some("pm") | Some("p.m") => return Some((vec![Element::Time(parse_clock(word, Some("pm"))?)], 2)),
+        _ => ()
+    };
+    match word.split_once('-') {
+        Some((from, until)) => {
+            let end = parse_clock(until, None)?;
+            // "1-2pm" takes its am or pm from the end
+            let meridiem = if until.ends_with("pm") { Some("pm") } else if until.ends_with("am") { Some("am") } else { None };
+            let start = parse_clock(from, meridiem)?;
+            return Some((vec![Element::Time(start), Element::EndTime(end)], 1));
+        },
+        None => ()
+    };
+    let time = parse_clock(word, None)?;
+    return Some((vec![Element::Time(time)], 1));
+}
+
+// "1pm", "1:30pm", "13:00", or with default_meridiem a bare hour such as "1". A bare number with no
+// am or pm isn't a time, as in "3 days".
+fn parse_clock(word: &str, default_meridiem: Option<&str>) -> Option<NaiveTime> {
+    // Words have had any trailing full stop trimmed, so "a.m." is "a.m"
+    let (digits, meridiem) = match word.strip_suffix("am").or(word.strip_suffix("a.m")) {
+        Some(digits) => (digits, Some("am")),
+        None => match word.strip_suffix("pm").or(word.strip_suffix("p.m")) {
+            Some(digits) => (digits, Some("pm")),
+            None => (word, default_meridiem)
+        }
+    };
+    let (hour, minute) = match digits.split_once(':') {
+        Some((hour, minute)) if minute.len() == 2 => (hour.parse::<u32>().ok()?, minute.parse::<u32>().ok()?),
+        Some(_) => return None,
+        None if meridiem.is_some() => (digits.parse::<u32>().ok()?, 0),
+        None => return None
+    };
+    let hour = match meridiem {
+        Some(_) if hour == 0 || hour > 12 => return None,
+        Some("am") if hour == 12 => 0,
+        Some("pm") if hour < 12 => hour + 12,
+        _ => hour
+    };
+    return NaiveTime::from_hms_opt(hour, minute, 0);
+}
+
+// A count at words[index]: a number, or "a"/"an" for one
+fn parse_amount(words: &Words, index: usize) -> Option<(i64, usize)> {
+    let word = words.plain(index)?;
+    match word {
+        "a" | "an" | "one" => return Some((1, 1)),
+        "two" => return Some((2, 1)),
+        "three" => return Some((3, 1)),
+        _ => return word.parse::<i64>().ok().filter(|amount| *amount > 0).map(|amount| (amount, 1))
+    }
+}
+
+// amount of unit (None inside if that's too long to represent), and whether the unit is whole days
+// or longer
+fn unit_duration(unit: &str, amount: i64) -> Option<(Option<Duration>, bool)> {
+    match unit {
+        "minute" | "minutes" | "min" | "mins" => return Some((checked_seconds(amount, 60), false)),
+        "hour" | "hours" | "hr" | "hrs" => return Some((checked_seconds(amount, 60 * 60), false)),
+        "day" | "days" => return Some((checked_seconds(amount, 24 * 60 * 60), true)),
+        "week" | "weeks" => return Some((checked_seconds(amount, 7 * 24 * 60 * 60), true)),
+        _ => return None
+    }
+}
+
+// amount lots of unit_seconds, or None if that overflows a Duration
+fn checked_seconds(amount: i64, unit_seconds: i64) -> Option<Duration> {
+    match amount.checked_mul(unit_seconds) {
+        Some(seconds) if seconds.abs() <= i64::MAX / 1000 => return Some(Duration::seconds(seconds)),
+        _ => return None
+    }
+}
+
+// "2h", "90m", "1h30m", with None inside if that's too long to represent
+fn parse_short_duration(word: &str) -> Option<Option<Duration>> {
+    let (hours, rest) = match word.split_once('h') {
+        Some((hours, rest)) => (hours.parse::<i64>().ok()?, rest),
+        None => (0, word)
+    };
+    let minutes = match rest {
+        "" => 0,
+        _ => rest.strip_suffix('m')?.parse::<i64>().ok()?
+    };
+    if hours == 0 && minutes == 0 {
+        return None;
+    }
+    return Some(hours.checked_mul(60).and_then(|hour_minutes| hour_minutes.checked_add(minutes))
+        .and_then(|total| checked_seconds(total, 60)));
+}
+
+// What follows "every": "day", "weekday", "other friday", "3 weeks", "monday and thursday"
+fn parse_every(words: &Words, index: usize) -> Option<(RecurrenceRule, usize)> {
+    let (interval, skipped) = match words.plain(index)? {
+        "other" => (2, 1),
+        word => match word.parse::<u32>() {
+            Ok(interval) if interval > 0 => (interval, 1),
+            _ => (1, 0)
+        }
+    };
+    let word = words.plain(index + skipped)?;
+    let freq = match word.trim_end_matches('s') {
+        "day" => Some(Frequency::Daily),
+        "week" => Some(Frequency::Weekly),
+        "month" => Some(Frequency::Monthly),
+        "year" => Some(Frequency::Yearly),
+        _ => None
+    };
+    match freq {
+        Some(freq) => return Some((RecurrenceRule { interval, ..RecurrenceRule::new(freq) }, skipped + 1)),
+        None => ()
+    };
+    if word == "weekday" && interval == 1 {
+        let by_day = WORKING_DAYS.iter().map(|weekday| WeekdayNum { ordinal: None, weekday: *weekday }).collect();
+        return Some((RecurrenceRule { by_day, ..RecurrenceRule::new(Frequency::Weekly) }, skipped + 1));
+    }
+    let mut weekdays = vec![parse_weekday(word.trim_end_matches('s')).or(parse_weekday(word))?];
+    let mut used = skipped + 1;
+    // "every monday and thursday"
+    while words.plain(index + used) == Some("and") {
+        match words.plain(index + used + 1).and_then(parse_weekday) {
+            Some(weekday) => {
+                weekdays.push(weekday);
+                used += 2;
+            },
+            None => break
+        };
+    }
+    let by_day = weekdays.into_iter().map(|weekday| WeekdayNum { ordinal: None, weekday }).collect();
+    return Some((RecurrenceRule { interval, by_day, ..RecurrenceRule::new(Frequency::Weekly) }, used));
+}
+
+#[cfg(test)]
+mod cal_phrase_tests {
+    use super::*;
+
+    // Sat 17 Oct 2026, 10:00 in London (BST)
+    fn now() -> DateTime<FixedOffset> {
+        return DateTime::parse_from_rfc3339("2026-10-17T09:00:00+00:00").unwrap();
+    }
+
+    fn london() -> MpTimeZone {
+        return MpTimeZone::from_iana_name("Europe/London").unwrap();
+    }
+
+    fn parse(phrase: &str) -> MpEvent {
+        return parse_event_phrase(phrase, now(), &london()).unwrap();
+    }
+
+    fn local(event: &MpEvent) -> (String, String) {
+        let format = |time: Option<DateTime<FixedOffset>>| london().convert(time.unwrap()).format("%a %d %b %H:%M").to_string();
+        return (format(event.start_time), format(event.end_time));
+    }
+
+    #[test]
+    fn test_parse_event_phrase() {
+        let lunch = parse("lunch with Priya tomorrow 1pm for an hour at the canteen");
+        assert_eq!(Some("Lunch with Priya"), lunch.name());
+        assert_eq!(Some(String::from("the canteen")), lunch.location);
+        assert_eq!((String::from("Sun 18 Oct 13:00"), String::from("Sun 18 Oct 14:00")), local(&lunch));
+        assert_eq!(Some(EventStatus::Confirmed), lunch.status());
+        assert!(lunch.uid().is_some() && !lunch.all_day && lunch.rrule.is_none());
+
+        let review = parse("Design review next Tuesday at 9:30am for 90 minutes");
+        assert_eq!((String::from("Tue 20 Oct 09:30"), String::from("Tue 20 Oct 11:00")), local(&review));
+        assert_eq!(Some("Design review"), review.name());
+        assert!(review.location.is_none());
+
+        let dentist = parse("dentist in 3 days at 4pm at Smile Clinic, High St");
+        assert_eq!((String::from("Tue 20 Oct 16:00"), String::from("Tue 20 Oct 17:00")), local(&dentist));
+        assert_eq!(Some(String::from("Smile Clinic, High St")), dentist.location);
+
+        let call = parse("call mum in 2 hours for half an hour");
+        assert_eq!((String::from("Sat 17 Oct 12:00"), String::from("Sat 17 Oct 12:30")), local(&call));
+
+        // 9am has already gone today
+        let gym = parse("gym 9am until 10:15am");
+        assert_eq!((String::from("Sun 18 Oct 09:00"), String::from("Sun 18 Oct 10:15")), local(&gym));
+        let walk = parse("walk 1-2pm");
+        assert_eq!((String::from("Sat 17 Oct 13:00"), String::from("Sat 17 Oct 14:00")), local(&walk));
+
+        let holiday = parse("Holiday 2 november for 3 days");
+        assert!(holiday.all_day);
+        assert_eq!((String::from("Mon 02 Nov 00:00"), String::from("Thu 05 Nov 00:00")), local(&holiday));
+        let birthday = parse("Sam's birthday on March 3rd");
+        assert_eq!(String::from("Wed 03 Mar 00:00"), local(&birthday).0);
+    }
+
+    #[test]
+    fn test_parse_recurring_phrases() {
+        let retro = parse("retro every other Friday 3pm");
+        assert_eq!("FREQ=WEEKLY;INTERVAL=2;BYDAY=FR", retro.rrule.as_ref().unwrap().to_string());
+        assert_eq!(String::from("Fri 23 Oct 15:00"), local(&retro).0);
+        assert_eq!("every other Friday", describe_rule(retro.rrule.as_ref().unwrap()));
+
+        let standup = parse("standup every weekday at 9:15 a.m. for 15 minutes");
+        assert_eq!("every weekday", describe_rule(standup.rrule.as_ref().unwrap()));
+        assert_eq!((String::from("Mon 19 Oct 09:15"), String::from("Mon 19 Oct 09:30")), local(&standup));
+
+        let gym = parse("gym every monday and thursday 7pm");
+        assert_eq!("every Monday and Thursday", describe_rule(gym.rrule.as_ref().unwrap()));
+        assert_eq!(String::from("Mon 19 Oct 19:00"), local(&gym).0);
+        let one_to_one = parse("1:1 every 3 weeks on monday 11am");
+        assert_eq!("every 3 weeks", describe_rule(one_to_one.rrule.as_ref().unwrap()));
+        assert_eq!((Some("1:1"), String::from("Mon 19 Oct 11:00")), (one_to_one.name(), local(&one_to_one).0));
+        assert_eq!("every month", describe_rule(parse("rent monthly").rrule.as_ref().unwrap()));
+        assert_eq!("every other day", describe_rule(parse("water plants every other day").rrule.as_ref().unwrap()));
+    }
+
+    #[test]
+    fn test_at_with_no_place() {
+        let dinner = parse("dinner at tomorrow 7pm");
+        assert_eq!(Some("Dinner"), dinner.name());
+        assert!(dinner.location.is_none());
+        assert_eq!(String::from("Sun 18 Oct 19:00"), local(&dinner).0);
+    }
+
+    #[test]
+    fn test_abbreviated_weekdays_in_names() {
+        let errand = parse("pick up sat nav tomorrow");
+        assert_eq!(Some("Pick up sat nav"), errand.name());
+        assert_eq!(String::from("Sun 18 Oct 00:00"), local(&errand).0);
+        assert_eq!(Some("Fix sun lounger"), parse("fix sun lounger on tue").name());
+        assert_eq!(String::from("Tue 20 Oct 00:00"), local(&parse("fix sun lounger on tue")).0);
+        // In date position they're still dates
+        let swim = parse("swim sat 8pm");
+        assert_eq!((Some("Swim"), String::from("Sat 17 Oct 20:00")), (swim.name(), local(&swim).0));
+    }
+
+    #[test]
+    fn test_weekly_rule_from_a_date() {
+        // 2 November 2026 is a Monday, so the first Friday from then is the 6th
+        let retro = parse("retro every friday 3pm on 2 november");
+        assert_eq!(Some("Retro"), retro.name());
+        assert_eq!("FREQ=WEEKLY;BYDAY=FR", retro.rrule.as_ref().unwrap().to_string());
+        assert_eq!(String::from("Fri 06 Nov 15:00"), local(&retro).0);
+        assert_eq!(String::from("Fri 23 Oct 15:00"), local(&parse("retro every friday next tuesday 3pm")).0);
+        assert_eq!(String::from("Thu 22 Oct 00:00"), local(&parse("bins every thursday on 22 october")).0);
+    }
+
+    #[test]
+    fn test_unparseable_phrases() {
+        assert!(matches!(parse_event_phrase("lunch with Priya", now(), &london()), Err(MpCalendarError::InvalidPhrase { .. })));
+        assert!(matches!(parse_event_phrase("tomorrow at 3pm", now(), &london()), Err(MpCalendarError::InvalidPhrase { .. })));
+        assert!(parse_clock("13pm", None).is_none());
+        assert!(parse_clock("3", None).is_none());
+        assert_eq!(Some(NaiveTime::from_hms(0, 30, 0)), parse_clock("12:30am", None));
+        assert_eq!(Some(Some(Duration::minutes(90))), parse_short_duration("1h30m"));
+        for phrase in ["lunch in 99999999 days", "lunch in 9999999999999999 weeks", "lunch tomorrow for 99999999999h",
+                       "trip tomorrow for 999999999 days", "lunch in 99999999999 hours"] {
+            match parse_event_phrase(phrase, now(), &london()) {
+                Err(MpCalendarError::InvalidPhrase { reason, .. }) => assert!(reason.contains("too far away"), "{}: {}", phrase, reason),
+                other => panic!("{} gave {:?}", phrase, other)
+            };
+        }
+    }
+}
@@ -0,0 +1,353 @@
+use std::str::FromStr;
+use chrono::prelude::*;
+use chrono::Duration;
+use super::{MpEvent, MpCalendarError, EventStatus};
+use super::cal_tz::MpTimeZone;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum TextField {
+    Name,
+    Location,
+    Description,
+    Uid
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum Condition {
+    Status(Vec<EventStatus>),
+    // Equal ignoring case, or containing the text if contains
+    Field { field: TextField, text: String, contains: bool },
+    // In the name, location or description
+    Text(String)
+}
+
+/// A filter over events written as space separated terms, all of which must match, e.g.
+/// `status:confirmed location:~"Room 4" after:2024-05-01 before:+7d text:"review"`
+///  - `status:confirmed`, or several as `status:confirmed,tentative`
+///  - `name:`, `location:`, `description:` and `uid:` match the whole field ignoring case, or
+///    with `~` before the value anywhere in it
+///  - `text:review`, or just `review`, matches anywhere in the name, location or description
+///  - `after:` and `before:` keep events that end after and start before a time: a date such as
+///    2024-05-01 (local midnight), a local time such as 2024-05-01T09:30, `now`, `today`,
+///    `tomorrow`, `yesterday`, or an offset from now such as +7d, -2h, +30m or +1w
+///
+/// Values with spaces go in double quotes. A `-` before a term other than after: and before:
+/// inverts it, e.g. `-status:cancelled`. A word with a `:` that doesn't follow one of these fields,
+/// such as `12:30`, is text. An empty query matches everything, and one with after: later than
+/// before: is invalid.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct EventQuery {
+    // Each condition and whether it's inverted
+    conditions: Vec<(bool, Condition)>,
+    after: Option<DateTime<FixedOffset>>,
+    before: Option<DateTime<FixedOffset>>
+}
+
+// What can come before the ':' of a term. Anything else with a ':' in it, such as 12:30, is text.
+const FIELDS: [&str; 9] = ["after", "before", "status", "text", "name", "summary", "location", "description", "uid"];
+
+// One term as written: -key:~"value"
+struct RawTerm {
+    negated: bool,
+    key: Option<String>,
+    contains: bool,
+    value: String
+}
+
+impl EventQuery {
+    /// Parses query, resolving dates in local_zone and offsets from now
+    pub fn parse(query: &str, now: DateTime<FixedOffset>, local_zone: &MpTimeZone) -> Result<EventQuery, MpCalendarError> {
+        let invalid = |reason: String| MpCalendarError::InvalidQuery { query: String::from(query), reason };
+        let mut parsed = EventQuery::default();
+        for term in split_terms(query).map_err(|reason| invalid(String::from(reason)))? {
+            let key = match &term.key {
+                Some(key) => key.to_lowercase(),
+                None => {
+                    parsed.conditions.push((term.negated, Condition::Text(term.value)));
+                    continue;
+                }
+            };
+            if (key == "after" || key == "before") && (term.negated || term.contains) {
+                return Err(invalid(format!("{}: can't be inverted or take ~", key)));
+            }
+            let condition = match key.as_str() {
+                "after" => {
+                    parsed.after = Some(resolve_time(&term.value, now, local_zone)
+                        .ok_or_else(|| invalid(format!("'{}' isn't a date, time or offset", term.value)))?);
+                    continue;
+                },
+                "before" => {
+                    parsed.before = Some(resolve_time(&term.value, now, local_zone)
+                        .ok_or_else(|| invalid(format!("'{}' isn't a date, time or offset", term.value)))?);
+                    continue;
+                },
+                "status" => {
+                    let statuses = term.value.split(',')
+                        .map(|status| EventStatus::from_str(&status.trim().to_uppercase()))
+                        .collect::<Result<Vec<EventStatus>, ()>>()
+                        .map_err(|_| invalid(format!("'{}' isn't tentative, confirmed or cancelled", term.value)))?;
+                    Condition::Status(statuses)
+                },
+                "text" => Condition::Text(term.value),
+                "name" | "summary" => Condition::Field { field: TextField::Name, text: term.value, contains: term.contains },
+                "location" => Condition::Field { field: TextField::Location, text: term.value, contains: term.contains },
+                "description" => Condition::Field { field: TextField::Description, text: term.value, contains: term.contains },
+                "uid" => Condition::Field { field: TextField::Uid, text: term.value, contains: term.contains },
+                other => return Err(invalid(format!("unknown field '{}'", other)))
+            };
+            parsed.conditions.push((term.negated, condition));
+        }
+        match (parsed.after, parsed.before) {
+            (Some(after), Some(before)) if after > before => return Err(invalid(String::from("after: is later than before:"))),
+            _ => ()
+        };
+        return Ok(parsed);
+    }
+
+    pub fn after(&self) -> Option<DateTime<FixedOffset>> {
+        return self.after;
+    }
+
+    pub fn before(&self) -> Option<DateTime<FixedOffset>> {
+        return self.before;
+    }
+
+    /// The span the query covers: after: to before:, with a missing bound filled in from the
+    /// default window, keeping its length if the other bound falls outside it
+    pub fn window(&self, default_start: DateTime<FixedOffset>, default_end: DateTime<FixedOffset>) -> (DateTime<FixedOffset>, DateTime<FixedOffset>) {
+        let length = default_end - default_start;
+        match (self.after, self.before) {
+            (Some(start), Some(end)) => return (start, end),
+            (Some(start), None) if start < default_end => return (start, default_end),
+            (Some(start), None) => return (start, start + length),
+            (None, Some(end)) if end > default_start => return (default_start, end),
+            (None, Some(end)) => return (end - length, end),
+            (None, None) => return (default_start, default_end)
+        }
+    }
+
+    /// True if event meets every term. A recurring event that hasn't been expanded is judged by
+    /// its first occurrence's times.
+    pub fn matches(&self, event: &MpEvent) -> bool {
+        let start = match (self.after.or(self.before), event.start_time) {
+            (Some(_), None) => return false,
+            (_, start) => start
+        };
+        match (self.after, start) {
+            (Some(after), Some(start)) => {
+                let ends_after = match event.end_time {
+                    Some(end) if end != start => end > after,
+                    _ => start >= after
+                };
+                if !ends_after {
+                    return false;
+                }
+            },
+            _ => ()
+        };
+        match (self.before, start) {
+            (Some(before), Some(start)) if start >= before => return false,
+            _ => ()
+        };
+        return self.conditions.iter().all(|(negated, condition)| condition_matches(condition, event) != *negated);
+    }
+}
+
+fn condition_matches(condition: &Condition, event: &MpEvent) -> bool {
+    match condition {
+        Condition::Status(statuses) => return event.status.is_some_and(|status| statuses.contains(&status)),
+        Condition::Field { field, text, contains } => {
+            let value = match field {
+                TextField::Name => &event.name,
+                TextField::Location => &event.location,
+                TextField::Description => &event.description,
+                TextField::Uid => &event.uid
+            };
+            return value.as_deref().is_some_and(|value| text_matches(value, text, *contains));
+        },
+        Condition::Text(text) => {
+            return [&event.name, &event.location, &event.description].iter()
+                .any(|value| value.as_deref().is_some_and(|value| text_matches(value, text, true)));
+        }
+    }
+}
+
+fn text_matches(value: &str, text: &str, contains: bool) -> bool {
+    let value = value.to_lowercase();
+    let text = text.to_lowercase();
+    if contains {
+        return value.contains(&text);
+    }
+    return value == text;
+}
+
+// Splits a query into terms, honouring double quotes
+fn split_terms(query: &str) -> Result<Vec<RawTerm>, &'static str> {
+    let mut terms: Vec<RawTerm> = vec![];
+    let mut chars = query.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            return Ok(terms);
+        }
+        let negated = chars.peek() == Some(&'-');
+        if negated {
+            chars.next();
+        }
+        let mut term = RawTerm { negated, key: None, contains: false, value: String::new() };
+        let mut quoted = false;
+        loop {
+            match chars.next() {
+                Some('"') if quoted => {
+                    quoted = false;
+                    // Nothing may follow a closing quote but the end of the term
+                    if chars.peek().is_some_and(|c| !c.is_whitespace()) {
+                        return Err("a closing quote must end its term");
+                    }
+                },
+                Some('"') => quoted = true,
+                Some(c) if c.is_whitespace() && !quoted => break,
+                Some(':') if !quoted && term.key.is_none() && FIELDS.contains(&term.value.to_lowercase().as_str()) => {
+                    term.key = Some(std::mem::take(&mut term.value));
+                    if chars.peek() == Some(&'~') {
+                        chars.next();
+                        term.contains = true;
+                    }
+                },
+                Some(c) => term.value.push(c),
+                None if quoted => return Err("a quote isn't closed"),
+                None => break
+            };
+        }
+        if term.value.is_empty() {
+            return Err("a term has no value");
+        }
+        terms.push(term);
+    }
+}
+
+/// A time in an after: or before: term, or None if value isn't one
+fn resolve_time(value: &str, now: DateTime<FixedOffset>, local_zone: &MpTimeZone) -> Option<DateTime<FixedOffset>> {
+    let today = local_zone.convert(now).date().naive_local();
+    let midnight = |date: NaiveDate| local_zone.from_local_datetime(date.and_hms(0, 0, 0));
+    match value.to_lowercase().as_str() {
+        "now" => return Some(now),
+        "today" => return Some(midnight(today)),
+        "tomorrow" => return Some(midnight(today.succ())),
+        "yesterday" => return Some(midnight(today.pred())),
+        _ => ()
+    };
+    match value.chars().next() {
+        Some(sign @ ('+' | '-')) => {
+            let unit = value.chars().last()?;
+            let amount: i64 = value.get(1..value.len() - unit.len_utf8())?.parse().ok()?;
+            let unit_seconds = match unit {
+                'm' => 60,
+                'h' => 60 * 60,
+                'd' => 24 * 60 * 60,
+                'w' => 7 * 24 * 60 * 60,
+                _ => return None
+            };
+            // Too far away to represent is no time at all, rather than an overflow
+            let seconds = amount.checked_mul(unit_seconds).filter(|seconds| seconds.abs() <= i64::MAX / 1000)?;
+            let offset = Duration::seconds(seconds);
+            return if sign == '+' { now.checked_add_signed(offset) } else { now.checked_sub_signed(offset) };
+        },
+        _ => ()
+    };
+    match DateTime::parse_from_rfc3339(value) {
+        Ok(time) => return Some(time),
+        Err(_) => ()
+    };
+    match NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M") {
+        Ok(local) => return Some(local_zone.from_local_datetime(local)),
+        Err(_) => ()
+    };
+    return NaiveDate::parse_from_str(value, "%Y-%m-%d").ok().map(midnight);
+}
+
+#[cfg(test)]
+mod cal_query_tests {
+    use super::*;
+    use crate::mp_calendar::cal_io;
+
+    fn now() -> DateTime<FixedOffset> {
+        return DateTime::parse_from_rfc3339("2024-03-11T08:00:00+00:00").unwrap();
+    }
+
+    fn query(text: &str) -> EventQuery {
+        return EventQuery::parse(text, now(), &MpTimeZone::utc()).unwrap();
+    }
+
+    fn matching(text: &str, events: &[MpEvent]) -> Vec<String> {
+        let query = query(text);
+        return events.iter().filter(|event| query.matches(event)).map(|event| event.name.clone().unwrap()).collect();
+    }
+
+    #[test]
+    fn test_parse_and_match_queries() {
+        let events = cal_io::read_events_from_ics_file(String::from("src/test/test_diff_after.ics"), &MpTimeZone::utc()).unwrap();
+        assert_eq!(vec!["Design review", "Standup", "Vendor call", "Gym", "Dentist"], matching("", &events));
+        assert_eq!(vec!["Design review"], matching("status:confirmed location:~\"Room 4\"", &events));
+        assert_eq!(vec!["Design review", "Vendor call"], matching("status:confirmed,cancelled", &events));
+        assert_eq!(vec!["Design review"], matching("status:confirmed -status:cancelled", &events));
+        assert_eq!(vec!["Vendor call"], matching("location:\"room 1\"", &events));
+        assert!(matching("location:room", &events).is_empty());
+        assert_eq!(vec!["Design review"], matching("text:\"REVIEW\"", &events));
+        assert_eq!(vec!["Design review", "Gym", "Dentist"], matching("-call -text:standup", &events));
+        assert_eq!(vec!["Dentist"], matching("uid:~dentist", &events));
+        // Unexpanded, the standup series is judged by its first occurrence
+        assert_eq!(vec!["Vendor call", "Dentist"], matching("after:2024-03-13 before:2024-03-14", &events));
+        assert_eq!(vec!["Design review", "Standup"], matching("before:+1d", &events));
+        assert_eq!(vec!["Gym"], matching("after:2024-03-14T18:30", &events));
+        // Only known fields take a ':', so other words with one are text
+        assert_eq!(vec![(false, Condition::Text(String::from("12:30")))], query("12:30").conditions);
+        assert_eq!(vec![(true, Condition::Text(String::from("room:4")))], query("-room:4").conditions);
+        assert_eq!(vec![(false, Condition::Field { field: TextField::Name, text: String::from("1:1"), contains: false })],
+                   query("NAME:1:1").conditions);
+    }
+
+    #[test]
+    fn test_resolve_time() {
+        let london = MpTimeZone::from_iana_name("Europe/London").unwrap();
+        let resolve = |value: &str| resolve_time(value, now(), &london).map(|time| time.to_rfc3339());
+        assert_eq!(Some(String::from("2024-03-18T08:00:00+00:00")), resolve("+7d"));
+        assert_eq!(Some(String::from("2024-03-11T06:00:00+00:00")), resolve("-2h"));
+        assert_eq!(Some(String::from("2024-03-12T00:00:00+00:00")), resolve("tomorrow"));
+        assert_eq!(Some(String::from("2024-05-01T00:00:00+01:00")), resolve("2024-05-01"));
+        assert_eq!(Some(String::from("2024-05-01T09:30:00+01:00")), resolve("2024-05-01T09:30"));
+        assert_eq!(None, resolve("+7y"));
+        assert_eq!(None, resolve("soon"));
+        assert_eq!(None, resolve("+"));
+        assert_eq!(None, resolve("+99999999999999d"));
+        assert_eq!(None, resolve("+999999999d"));
+        assert_eq!(None, resolve("-9999999999999999999w"));
+    }
+
+    #[test]
+    fn test_window() {
+        let start = now();
+        let end = start + Duration::days(1);
+        let at = |days: i64| start + Duration::days(days);
+        assert_eq!((start, end), query("").window(start, end));
+        assert_eq!((at(-2), at(3)), query("after:-2d before:+3d").window(start, end));
+        assert_eq!((start, at(7)), query("before:+7d").window(start, end));
+        assert_eq!((at(5), at(6)), query("after:+5d").window(start, end));
+    }
+
+    #[test]
+    fn test_invalid_queries() {
+        let reason = |text: &str| match EventQuery::parse(text, now(), &MpTimeZone::utc()) {
+            Err(MpCalendarError::InvalidQuery { reason, .. }) => reason,
+            other => panic!("expected {} to be invalid, got {:?}", text, other)
+        };
+        assert_eq!("after: is later than before:", reason("after:+3d before:+1d"));
+        assert_eq!("'busy' isn't tentative, confirmed or cancelled", reason("status:busy"));
+        assert_eq!("'someday' isn't a date, time or offset", reason("after:someday"));
+        assert_eq!("after: can't be inverted or take ~", reason("-after:today"));
+        assert_eq!("a quote isn't closed", reason("text:\"review"));
+        assert_eq!("a term has no value", reason("location:"));
+    }
+}
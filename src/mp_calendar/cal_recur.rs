@@ -0,0 +1,642 @@
+use std::fmt;
+use std::str::FromStr;
+use chrono::prelude::*;
+use chrono::Duration;
+use super::cal_tz::MpTimeZone;
+
+/// Hard stop on the number of periods walked when expanding a rule, so that a rule
+/// which can never produce a date (e.g. BYMONTH=2;BYMONTHDAY=30) terminates
+const MAX_PERIODS: u32 = 100_000;
+
+/// RFC 5545 rule parts that occurrences can't honour. A rule using one is kept as it was read,
+/// rather than expanded into the wrong dates.
+const UNSUPPORTED_PARTS: [&str; 5] = ["BYHOUR", "BYMINUTE", "BYSECOND", "BYYEARDAY", "BYWEEKNO"];
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Frequency {
+    Secondly,
+    Minutely,
+    Hourly,
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly
+}
+
+impl FromStr for Frequency {
+    type Err = ();
+    fn from_str(input: &str) -> Result<Frequency, Self::Err> {
+        match input {
+            "SECONDLY" => Ok(Frequency::Secondly),
+            "MINUTELY" => Ok(Frequency::Minutely),
+            "HOURLY"   => Ok(Frequency::Hourly),
+            "DAILY"    => Ok(Frequency::Daily),
+            "WEEKLY"   => Ok(Frequency::Weekly),
+            "MONTHLY"  => Ok(Frequency::Monthly),
+            "YEARLY"   => Ok(Frequency::Yearly),
+            _          => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Frequency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let freq_str = match self {
+            Frequency::Secondly => "SECONDLY",
+            Frequency::Minutely => "MINUTELY",
+            Frequency::Hourly => "HOURLY",
+            Frequency::Daily => "DAILY",
+            Frequency::Weekly => "WEEKLY",
+            Frequency::Monthly => "MONTHLY",
+            Frequency::Yearly => "YEARLY"
+        };
+        return write!(f, "{}", freq_str);
+    }
+}
+
+/// A BYDAY entry, e.g. MO (every Monday), 2TU (second Tuesday) or -1FR (last Friday)
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct WeekdayNum {
+    pub ordinal: Option<i32>,
+    pub weekday: Weekday
+}
+
+impl FromStr for WeekdayNum {
+    type Err = ();
+    fn from_str(input: &str) -> Result<WeekdayNum, Self::Err> {
+        // The weekday is the last two characters, which needn't be two bytes in malformed input
+        let day_index = match input.char_indices().rev().nth(1) {
+            Some((index, _)) => index,
+            None => return Err(())
+        };
+        let (ordinal_str, day_str) = input.split_at(day_index);
+        let weekday = parse_ical_weekday(day_str)?;
+        let ordinal = match ordinal_str {
+            "" => None,
+            _ => {
+                let ordinal = ordinal_str.trim_start_matches('+').parse::<i32>().map_err(|_| ())?;
+                if ordinal == 0 || ordinal.abs() > 53 {
+                    return Err(());
+                }
+                Some(ordinal)
+            }
+        };
+        return Ok(WeekdayNum { ordinal, weekday });
+    }
+}
+
+impl fmt::Display for WeekdayNum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.ordinal {
+            Some(ordinal) => write!(f, "{}", ordinal)?,
+            None => ()
+        };
+        return write!(f, "{}", format_ical_weekday(self.weekday));
+    }
+}
+
+/// UNTIL may be a DATE, a floating DATE-TIME or a UTC DATE-TIME
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RecurrenceUntil {
+    Date(NaiveDate),
+    Floating(NaiveDateTime),
+    Utc(NaiveDateTime)
+}
+
+impl RecurrenceUntil {
+    /// The last local wall time still inside the recurrence set for an event in the given zone
+    fn as_local(&self, zone: &MpTimeZone) -> NaiveDateTime {
+        match self {
+            RecurrenceUntil::Date(date) => return date.and_hms(23, 59, 59),
+            RecurrenceUntil::Floating(time) => return *time,
+            RecurrenceUntil::Utc(time) => return *time + zone.offset_at_utc(*time)
+        }
+    }
+}
+
+impl fmt::Display for RecurrenceUntil {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecurrenceUntil::Date(date) => write!(f, "{}", date.format("%Y%m%d")),
+            RecurrenceUntil::Floating(time) => write!(f, "{}", time.format("%Y%m%dT%H%M%S")),
+            RecurrenceUntil::Utc(time) => write!(f, "{}", time.format("%Y%m%dT%H%M%SZ"))
+        }
+    }
+}
+
+impl FromStr for RecurrenceUntil {
+    type Err = ();
+    fn from_str(input: &str) -> Result<RecurrenceUntil, Self::Err> {
+        if input.len() == 8 {
+            let date = NaiveDate::parse_from_str(input, "%Y%m%d").map_err(|_| ())?;
+            return Ok(RecurrenceUntil::Date(date));
+        }
+        match input.strip_suffix('Z') {
+            Some(utc_str) => {
+                let time = NaiveDateTime::parse_from_str(utc_str, "%Y%m%dT%H%M%S").map_err(|_| ())?;
+                return Ok(RecurrenceUntil::Utc(time));
+            }
+            None => {
+                let time = NaiveDateTime::parse_from_str(input, "%Y%m%dT%H%M%S").map_err(|_| ())?;
+                return Ok(RecurrenceUntil::Floating(time));
+            }
+        }
+    }
+}
+
+/// An RFC 5545 RRULE value. BYHOUR, BYMINUTE, BYSECOND, BYYEARDAY and BYWEEKNO are not supported:
+/// see unsupported_part
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct RecurrenceRule {
+    pub freq: Frequency,
+    pub interval: u32,
+    pub count: Option<u32>,
+    pub until: Option<RecurrenceUntil>,
+    pub by_day: Vec<WeekdayNum>,
+    pub by_month_day: Vec<i32>,
+    pub by_month: Vec<u32>,
+    pub by_set_pos: Vec<i32>,
+    pub wkst: Weekday
+}
+
+impl RecurrenceRule {
+    pub fn new(freq: Frequency) -> RecurrenceRule {
+        return RecurrenceRule {
+            freq,
+            interval: 1,
+            count: None,
+            until: None,
+            by_day: vec![],
+            by_month_day: vec![],
+            by_month: vec![],
+            by_set_pos: vec![],
+            wkst: Weekday::Mon
+        };
+    }
+
+    /// All occurrence start times from dtstart (inclusive, always the first instance) up to but
+    /// excluding limit, honouring COUNT and UNTIL. Times are local wall times in the given zone.
+    /// Expansion stops after MAX_PERIODS periods, with a message saying so, which can cut short
+    /// minutely or secondly rules over a long window.
+    pub fn occurrences(&self, dtstart: NaiveDateTime, zone: &MpTimeZone, limit: NaiveDateTime) -> Vec<NaiveDateTime> {
+        let mut occurrences: Vec<NaiveDateTime> = vec![];
+        let until = self.until.map(|until| until.as_local(zone));
+        let in_range = |time: NaiveDateTime| {
+            time < limit && match until {
+                Some(until) => time <= until,
+                None => true
+            }
+        };
+        if !in_range(dtstart) {
+            return occurrences;
+        }
+        occurrences.push(dtstart);
+        if self.count == Some(1) {
+            return occurrences;
+        }
+        for period in 0..MAX_PERIODS {
+            let period_start = match self.period_start(dtstart, period) {
+                Some(start) => start,
+                None => return occurrences
+            };
+            if !in_range(period_start) && period_start > dtstart {
+                return occurrences;
+            }
+            for candidate in self.period_candidates(dtstart, period_start) {
+                if candidate <= dtstart {
+                    continue;
+                }
+                if !in_range(candidate) {
+                    return occurrences;
+                }
+                occurrences.push(candidate);
+                if self.count == Some(occurrences.len() as u32) {
+                    return occurrences;
+                }
+            }
+        }
+        super::output_mp_calendar_message(format!("Stopped expanding RRULE {} after {} periods, so any later occurrences are missing",
+                                                  self, MAX_PERIODS));
+        return occurrences;
+    }
+
+    /// The earliest instant of the nth period of the rule after dtstart
+    fn period_start(&self, dtstart: NaiveDateTime, period: u32) -> Option<NaiveDateTime> {
+        let steps = i64::from(period) * i64::from(self.interval.max(1));
+        let start_date = dtstart.date();
+        match self.freq {
+            Frequency::Secondly => return dtstart.checked_add_signed(Duration::seconds(steps)),
+            Frequency::Minutely => return dtstart.checked_add_signed(Duration::minutes(steps)),
+            Frequency::Hourly => return dtstart.checked_add_signed(Duration::hours(steps)),
+            Frequency::Daily => return dtstart.checked_add_signed(Duration::days(steps)),
+            Frequency::Weekly => {
+                let days_since_wkst = (7 + start_date.weekday().num_days_from_monday()
+                    - self.wkst.num_days_from_monday()) % 7;
+                let week_start = start_date - Duration::days(i64::from(days_since_wkst));
+                return week_start.checked_add_signed(Duration::weeks(steps)).map(|date| date.and_hms(0, 0, 0));
+            }
+            Frequency::Monthly => {
+                let month_index = i64::from(start_date.year()) * 12 + i64::from(start_date.month0()) + steps;
+                let year = (month_index / 12) as i32;
+                let month = (month_index % 12) as u32 + 1;
+                return NaiveDate::from_ymd_opt(year, month, 1).map(|date| date.and_hms(0, 0, 0));
+            }
+            Frequency::Yearly => {
+                let year = i64::from(start_date.year()) + steps;
+                if year > 9999 {
+                    return None;
+                }
+                return NaiveDate::from_ymd_opt(year as i32, 1, 1).map(|date| date.and_hms(0, 0, 0));
+            }
+        }
+    }
+
+    /// Every instant generated by the BYxxx parts within one period, sorted and with BYSETPOS applied
+    fn period_candidates(&self, dtstart: NaiveDateTime, period_start: NaiveDateTime) -> Vec<NaiveDateTime> {
+        let mut candidates: Vec<NaiveDateTime> = match self.freq {
+            Frequency::Secondly | Frequency::Minutely | Frequency::Hourly => {
+                if self.date_matches_limits(period_start.date()) {
+                    vec![period_start]
+                } else {
+                    vec![]
+                }
+            }
+            Frequency::Daily => {
+                if self.date_matches_limits(period_start.date()) {
+                    vec![period_start]
+                } else {
+                    vec![]
+                }
+            }
+            Frequency::Weekly => {
+                let week_start = period_start.date();
+                (0..7).map(|offset| week_start + Duration::days(offset))
+                    .filter(|date| {
+                        if self.by_day.is_empty() {
+                            date.weekday() == dtstart.weekday()
+                        } else {
+                            self.by_day.iter().any(|day| day.weekday == date.weekday())
+                        }
+                    })
+                    .filter(|date| self.by_month.is_empty() || self.by_month.contains(&date.month()))
+                    .map(|date| date.and_time(dtstart.time()))
+                    .collect()
+            }
+            Frequency::Monthly => {
+                let date = period_start.date();
+                if !self.by_month.is_empty() && !self.by_month.contains(&date.month()) {
+                    vec![]
+                } else {
+                    self.month_candidates(date.year(), date.month(), dtstart.date())
+                        .into_iter()
+                        .map(|date| date.and_time(dtstart.time()))
+                        .collect()
+                }
+            }
+            Frequency::Yearly => {
+                self.year_candidates(period_start.year(), dtstart.date())
+                    .into_iter()
+                    .map(|date| date.and_time(dtstart.time()))
+                    .collect()
+            }
+        };
+        candidates.sort();
+        candidates.dedup();
+        if self.by_set_pos.is_empty() {
+            return candidates;
+        }
+        let mut selected: Vec<NaiveDateTime> = self.by_set_pos.iter()
+            .filter_map(|pos| select_by_position(&candidates, *pos))
+            .collect();
+        selected.sort();
+        selected.dedup();
+        return selected;
+    }
+
+    /// BYxxx parts act as filters rather than expansions for DAILY and finer frequencies
+    fn date_matches_limits(&self, date: NaiveDate) -> bool {
+        if !self.by_month.is_empty() && !self.by_month.contains(&date.month()) {
+            return false;
+        }
+        if !self.by_month_day.is_empty() && !self.by_month_day.iter().any(|day| month_day_matches(date, *day)) {
+            return false;
+        }
+        if !self.by_day.is_empty() && !self.by_day.iter().any(|day| day.weekday == date.weekday()) {
+            return false;
+        }
+        return true;
+    }
+
+    fn month_candidates(&self, year: i32, month: u32, dtstart: NaiveDate) -> Vec<NaiveDate> {
+        let days = days_in_month(year, month);
+        if self.by_month_day.is_empty() && self.by_day.is_empty() {
+            return NaiveDate::from_ymd_opt(year, month, dtstart.day()).into_iter().collect();
+        }
+        if !self.by_month_day.is_empty() {
+            // BYDAY only limits the set when BYMONTHDAY is also present
+            return days.into_iter()
+                .filter(|date| self.by_month_day.iter().any(|day| month_day_matches(*date, *day)))
+                .filter(|date| self.by_day.is_empty() || self.by_day.iter().any(|day| day.weekday == date.weekday()))
+                .collect();
+        }
+        return expand_weekday_nums(&days, &self.by_day);
+    }
+
+    fn year_candidates(&self, year: i32, dtstart: NaiveDate) -> Vec<NaiveDate> {
+        if !self.by_month.is_empty() {
+            return self.by_month.iter()
+                .flat_map(|month| self.month_candidates(year, *month, dtstart))
+                .collect();
+        }
+        if !self.by_month_day.is_empty() {
+            return (1..=12)
+                .flat_map(|month| self.month_candidates(year, month, dtstart))
+                .collect();
+        }
+        if !self.by_day.is_empty() {
+            let days: Vec<NaiveDate> = NaiveDate::from_ymd(year, 1, 1).iter_days()
+                .take_while(|date| date.year() == year)
+                .collect();
+            return expand_weekday_nums(&days, &self.by_day);
+        }
+        return NaiveDate::from_ymd_opt(year, dtstart.month(), dtstart.day()).into_iter().collect();
+    }
+}
+
+impl FromStr for RecurrenceRule {
+    type Err = String;
+    fn from_str(input: &str) -> Result<RecurrenceRule, Self::Err> {
+        let mut freq: Option<Frequency> = None;
+        let mut rule = RecurrenceRule::new(Frequency::Daily);
+        for part in input.split(';').filter(|part| !part.is_empty()) {
+            let mut key_value = part.splitn(2, '=');
+            let key = key_value.next().unwrap_or("").to_uppercase();
+            let value = match key_value.next() {
+                Some(value) => value,
+                None => return Err(format!("RRULE part '{}' has no value", part))
+            };
+            let bad_value = || format!("RRULE part {} has invalid value '{}'", key, value);
+            match key.as_str() {
+                "FREQ" => freq = Some(Frequency::from_str(value).map_err(|_| bad_value())?),
+                "INTERVAL" => {
+                    rule.interval = value.parse::<u32>().map_err(|_| bad_value())?;
+                    if rule.interval == 0 {
+                        return Err(bad_value());
+                    }
+                }
+                "COUNT" => rule.count = Some(value.parse::<u32>().map_err(|_| bad_value())?),
+                "UNTIL" => rule.until = Some(RecurrenceUntil::from_str(value).map_err(|_| bad_value())?),
+                "BYDAY" => rule.by_day = parse_list(value, |day| WeekdayNum::from_str(day).ok()).ok_or_else(bad_value)?,
+                "BYMONTHDAY" => {
+                    rule.by_month_day = parse_list(value, |day| {
+                        day.trim_start_matches('+').parse::<i32>().ok().filter(|day| *day != 0 && day.abs() <= 31)
+                    }).ok_or_else(bad_value)?
+                }
+                "BYMONTH" => {
+                    rule.by_month = parse_list(value, |month| {
+                        month.parse::<u32>().ok().filter(|month| (1..=12).contains(month))
+                    }).ok_or_else(bad_value)?
+                }
+                "BYSETPOS" => {
+                    rule.by_set_pos = parse_list(value, |pos| {
+                        pos.trim_start_matches('+').parse::<i32>().ok().filter(|pos| *pos != 0 && pos.abs() <= 366)
+                    }).ok_or_else(bad_value)?
+                }
+                "WKST" => rule.wkst = parse_ical_weekday(value).map_err(|_| bad_value())?,
+                _ => return Err(format!("Unsupported RRULE part {}", key))
+            }
+        }
+        match freq {
+            Some(freq) => rule.freq = freq,
+            None => return Err(String::from("RRULE has no FREQ"))
+        };
+        if rule.count.is_some() && rule.until.is_some() {
+            return Err(String::from("RRULE must not contain both COUNT and UNTIL"));
+        }
+        return Ok(rule);
+    }
+}
+
+impl fmt::Display for RecurrenceRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "FREQ={}", self.freq)?;
+        if self.interval != 1 {
+            write!(f, ";INTERVAL={}", self.interval)?;
+        }
+        match self.count {
+            Some(count) => write!(f, ";COUNT={}", count)?,
+            None => ()
+        };
+        match self.until {
+            Some(until) => write!(f, ";UNTIL={}", until)?,
+            None => ()
+        };
+        if !self.by_day.is_empty() {
+            write!(f, ";BYDAY={}", join_list(&self.by_day))?;
+        }
+        if !self.by_month_day.is_empty() {
+            write!(f, ";BYMONTHDAY={}", join_list(&self.by_month_day))?;
+        }
+        if !self.by_month.is_empty() {
+            write!(f, ";BYMONTH={}", join_list(&self.by_month))?;
+        }
+        if !self.by_set_pos.is_empty() {
+            write!(f, ";BYSETPOS={}", join_list(&self.by_set_pos))?;
+        }
+        if self.wkst != Weekday::Mon {
+            write!(f, ";WKST={}", format_ical_weekday(self.wkst))?;
+        }
+        return Ok(());
+    }
+}
+
+/// The first part of an RRULE value that is valid RFC 5545 but that RecurrenceRule can't represent,
+/// e.g. BYHOUR. Rules with one can't be parsed, but aren't wrong either.
+pub fn unsupported_part(input: &str) -> Option<String> {
+    return input.split(';')
+        .map(|part| part.split('=').next().unwrap_or("").to_uppercase())
+        .find(|key| UNSUPPORTED_PARTS.contains(&key.as_str()));
+}
+
+fn parse_ical_weekday(input: &str) -> Result<Weekday, ()> {
+    match input {
+        "MO" => Ok(Weekday::Mon),
+        "TU" => Ok(Weekday::Tue),
+        "WE" => Ok(Weekday::Wed),
+        "TH" => Ok(Weekday::Thu),
+        "FR" => Ok(Weekday::Fri),
+        "SA" => Ok(Weekday::Sat),
+        "SU" => Ok(Weekday::Sun),
+        _    => Err(()),
+    }
+}
+
+fn format_ical_weekday(weekday: Weekday) -> &'static str {
+    match weekday {
+        Weekday::Mon => "MO",
+        Weekday::Tue => "TU",
+        Weekday::Wed => "WE",
+        Weekday::Thu => "TH",
+        Weekday::Fri => "FR",
+        Weekday::Sat => "SA",
+        Weekday::Sun => "SU"
+    }
+}
+
+fn parse_list<T, F: Fn(&str) -> Option<T>>(input: &str, parse_item: F) -> Option<Vec<T>> {
+    return input.split(',').map(|item| parse_item(item.trim())).collect();
+}
+
+fn join_list<T: fmt::Display>(items: &[T]) -> String {
+    return items.iter().map(|item| item.to_string()).collect::<Vec<String>>().join(",");
+}
+
+fn days_in_month(year: i32, month: u32) -> Vec<NaiveDate> {
+    return NaiveDate::from_ymd(year, month, 1).iter_days()
+        .take_while(|date| date.month() == month)
+        .collect();
+}
+
+/// Negative month days count back from the end of the month, -1 being the last day
+fn month_day_matches(date: NaiveDate, month_day: i32) -> bool {
+    if month_day > 0 {
+        return date.day() as i32 == month_day;
+    }
+    let month_length = days_in_month(date.year(), date.month()).len() as i32;
+    return date.day() as i32 == month_length + month_day + 1;
+}
+
+/// Picks the days matching BYDAY out of a month or year, honouring ordinals relative to that span
+fn expand_weekday_nums(days: &[NaiveDate], by_day: &[WeekdayNum]) -> Vec<NaiveDate> {
+    let mut selected: Vec<NaiveDate> = vec![];
+    for day_num in by_day {
+        let matching: Vec<NaiveDate> = days.iter()
+            .filter(|date| date.weekday() == day_num.weekday)
+            .cloned()
+            .collect();
+        match day_num.ordinal {
+            Some(ordinal) => selected.extend(select_by_position(&matching, ordinal)),
+            None => selected.extend(matching)
+        };
+    }
+    return selected;
+}
+
+/// 1-based position from the start, or negative from the end
+fn select_by_position<T: Copy>(items: &[T], position: i32) -> Option<T> {
+    let len = items.len() as i32;
+    let index = if position > 0 { position - 1 } else { len + position };
+    if index < 0 || index >= len {
+        return None;
+    }
+    return Some(items[index as usize]);
+}
+
+#[cfg(test)]
+mod cal_recur_tests {
+    use super::*;
+
+    fn naive(input: &str) -> NaiveDateTime {
+        return NaiveDateTime::parse_from_str(input, "%Y%m%dT%H%M%S").unwrap();
+    }
+
+    fn expand(rule: &str, dtstart: &str, limit: &str) -> Vec<NaiveDateTime> {
+        let rule = RecurrenceRule::from_str(rule).unwrap();
+        return rule.occurrences(naive(dtstart), &MpTimeZone::utc(), naive(limit));
+    }
+
+    #[test]
+    fn test_parse_and_display_round_trip() {
+        let rule_str = "FREQ=MONTHLY;INTERVAL=2;COUNT=10;BYDAY=MO,TU,-1FR;BYMONTHDAY=1,-1;BYMONTH=1,6;BYSETPOS=-1;WKST=SU";
+        let rule = RecurrenceRule::from_str(rule_str).unwrap();
+        assert_eq!(Frequency::Monthly, rule.freq);
+        assert_eq!(2, rule.interval);
+        assert_eq!(Some(10), rule.count);
+        assert_eq!(WeekdayNum { ordinal: Some(-1), weekday: Weekday::Fri }, rule.by_day[2]);
+        assert_eq!(vec![1, -1], rule.by_month_day);
+        assert_eq!(Weekday::Sun, rule.wkst);
+        assert_eq!(rule_str, rule.to_string());
+        let until_rule = RecurrenceRule::from_str("FREQ=DAILY;UNTIL=20240131T120000Z").unwrap();
+        assert_eq!(Some(RecurrenceUntil::Utc(naive("20240131T120000"))), until_rule.until);
+        assert!(RecurrenceRule::from_str("INTERVAL=2").is_err());
+        assert!(RecurrenceRule::from_str("FREQ=DAILY;COUNT=2;UNTIL=20240101").is_err());
+        assert!(RecurrenceRule::from_str("FREQ=DAILY;BYDAY=XX").is_err());
+        // Multibyte input is rejected, not sliced through the middle of a character
+        assert!(RecurrenceRule::from_str("FREQ=WEEKLY;BYDAY=1Mé").is_err());
+        assert!(RecurrenceRule::from_str("FREQ=WEEKLY;BYDAY=é").is_err());
+        assert!(WeekdayNum::from_str("ñ").is_err());
+    }
+
+    #[test]
+    fn test_unsupported_parts() {
+        assert_eq!(Some(String::from("BYHOUR")), unsupported_part("FREQ=DAILY;BYHOUR=9,17"));
+        assert_eq!(Some(String::from("BYWEEKNO")), unsupported_part("FREQ=YEARLY;byweekno=20"));
+        assert_eq!(None, unsupported_part("FREQ=WEEKLY;BYDAY=MO"));
+        assert!(RecurrenceRule::from_str("FREQ=DAILY;BYHOUR=9,17").is_err());
+    }
+
+    #[test]
+    fn test_weekly_byday_with_count() {
+        let occurrences = expand("FREQ=WEEKLY;BYDAY=TU,TH;COUNT=5", "20240102T093000", "20250101T000000");
+        let expected: Vec<NaiveDateTime> = vec!["20240102T093000", "20240104T093000", "20240109T093000",
+            "20240111T093000", "20240116T093000"].into_iter().map(naive).collect();
+        assert_eq!(expected, occurrences);
+    }
+
+    #[test]
+    fn test_daily_interval_until_and_limit() {
+        let occurrences = expand("FREQ=DAILY;INTERVAL=3;UNTIL=20240110T090000Z", "20240101T090000", "20250101T000000");
+        let expected: Vec<NaiveDateTime> = vec!["20240101T090000", "20240104T090000", "20240107T090000",
+            "20240110T090000"].into_iter().map(naive).collect();
+        assert_eq!(expected, occurrences);
+        let limited = expand("FREQ=DAILY", "20240101T090000", "20240103T090000");
+        assert_eq!(2, limited.len());
+    }
+
+    #[test]
+    fn test_monthly_last_weekday_by_set_pos() {
+        let occurrences = expand("FREQ=MONTHLY;BYDAY=MO,TU,WE,TH,FR;BYSETPOS=-1;COUNT=3", "20240131T170000", "20250101T000000");
+        let expected: Vec<NaiveDateTime> = vec!["20240131T170000", "20240229T170000", "20240329T170000"]
+            .into_iter().map(naive).collect();
+        assert_eq!(expected, occurrences);
+    }
+
+    #[test]
+    fn test_monthly_ordinal_byday_and_negative_monthday() {
+        let second_tuesdays = expand("FREQ=MONTHLY;BYDAY=2TU;COUNT=3", "20240109T100000", "20250101T000000");
+        let expected: Vec<NaiveDateTime> = vec!["20240109T100000", "20240213T100000", "20240312T100000"]
+            .into_iter().map(naive).collect();
+        assert_eq!(expected, second_tuesdays);
+        let last_days = expand("FREQ=MONTHLY;BYMONTHDAY=-1;COUNT=3", "20240131T100000", "20250101T000000");
+        let expected: Vec<NaiveDateTime> = vec!["20240131T100000", "20240229T100000", "20240331T100000"]
+            .into_iter().map(naive).collect();
+        assert_eq!(expected, last_days);
+    }
+
+    #[test]
+    fn test_yearly_bymonth_byday() {
+        // US Thanksgiving: fourth Thursday in November
+        let occurrences = expand("FREQ=YEARLY;BYMONTH=11;BYDAY=4TH;COUNT=3", "20231123T120000", "20300101T000000");
+        let expected: Vec<NaiveDateTime> = vec!["20231123T120000", "20241128T120000", "20251127T120000"]
+            .into_iter().map(naive).collect();
+        assert_eq!(expected, occurrences);
+        let leap_days = expand("FREQ=YEARLY", "20240229T000000", "20330101T000000");
+        let expected: Vec<NaiveDateTime> = vec!["20240229T000000", "20280229T000000", "20320229T000000"]
+            .into_iter().map(naive).collect();
+        assert_eq!(expected, leap_days);
+    }
+
+    #[test]
+    fn test_impossible_rule_terminates() {
+        let occurrences = expand("FREQ=YEARLY;BYMONTH=2;BYMONTHDAY=30", "20240101T000000", "99990101T000000");
+        assert_eq!(vec![naive("20240101T000000")], occurrences);
+    }
+
+    #[test]
+    fn test_sub_daily_rule_stops_at_period_limit() {
+        // A year of minutes is more periods than are walked, so the expansion is cut short
+        let occurrences = expand("FREQ=MINUTELY", "20240101T000000", "20250101T000000");
+        assert_eq!(MAX_PERIODS as usize, occurrences.len());
+        assert_eq!(naive("20240310T103900"), occurrences[occurrences.len() - 1]);
+    }
+}
@@ -0,0 +1,432 @@
+use chrono::prelude::*;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use super::{MpEvent, MpCalendarError, EventStatus, cal_io, cal_ops};
+use super::cal_query::EventQuery;
+use super::cal_tz::MpTimeZone;
+
+// Each entry takes the schema up one version. PRAGMA user_version records how many have been
+// applied, so add new entries to the end and never edit old ones.
+const MIGRATIONS: &[&str] = &[
+    // 1: events, the modified instances of recurring events and the properties moneypenny doesn't
+    // interpret. Times are UTC RFC 3339 so they compare correctly as text. ics is the event as
+    // cal_io writes it and is what events are loaded from; the other columns are for querying.
+    "CREATE TABLE events (
+         id INTEGER PRIMARY KEY,
+         uid TEXT NOT NULL,
+         recurrence_id TEXT NOT NULL DEFAULT '',
+         name TEXT,
+         location TEXT,
+         description TEXT,
+         status TEXT,
+         start_time TEXT,
+         end_time TEXT,
+         all_day INTEGER NOT NULL,
+         rrule TEXT,
+         recurring INTEGER NOT NULL,
+         ics TEXT NOT NULL,
+         UNIQUE (uid, recurrence_id)
+     );
+     CREATE INDEX events_by_start ON events (start_time);
+     CREATE INDEX events_by_status ON events (status);
+     CREATE TABLE overrides (
+         event_id INTEGER NOT NULL REFERENCES events (id) ON DELETE CASCADE,
+         recurrence_id TEXT NOT NULL,
+         name TEXT,
+         location TEXT,
+         description TEXT,
+         status TEXT,
+         start_time TEXT,
+         end_time TEXT
+     );
+     CREATE TABLE properties (
+         event_id INTEGER NOT NULL REFERENCES events (id) ON DELETE CASCADE,
+         name TEXT NOT NULL,
+         params TEXT NOT NULL,
+         value TEXT
+     );",
+    // 2: the calendar file each event was last imported from, so that events deleted from the file
+    // can be deleted from the store too. NULL for events saved into the store directly.
+    "ALTER TABLE events ADD COLUMN source TEXT;"
+];
+
+/// Events kept in an SQLite file so they outlast a single run. Events are keyed by UID (and
+/// RECURRENCE-ID, for an instance whose series isn't stored); saving an event with a UID already in
+/// the store replaces it. Events synced from a calendar file remember it as their source, so those
+/// deleted from the file can be pruned. Floating times are read in local_zone, as with cal_io.
+pub struct MpEventStore {
+    conn: Connection,
+    path: String,
+    local_zone: MpTimeZone
+}
+
+impl MpEventStore {
+    /// Opens the store at path, creating it if needed and bringing its schema up to date
+    pub fn open(path: &str, local_zone: MpTimeZone) -> Result<MpEventStore, MpCalendarError> {
+        let conn = Connection::open(path).map_err(|e| MpCalendarError::Database { path: String::from(path), source: e })?;
+        return MpEventStore::from_connection(conn, String::from(path), local_zone);
+    }
+
+    pub fn open_in_memory(local_zone: MpTimeZone) -> Result<MpEventStore, MpCalendarError> {
+        let conn = Connection::open_in_memory().map_err(|e| MpCalendarError::Database { path: String::from(":memory:"), source: e })?;
+        return MpEventStore::from_connection(conn, String::from(":memory:"), local_zone);
+    }
+
+    fn from_connection(conn: Connection, path: String, local_zone: MpTimeZone) -> Result<MpEventStore, MpCalendarError> {
+        let mut store = MpEventStore { conn, path, local_zone };
+        store.conn.pragma_update(None, "foreign_keys", true).map_err(|e| store.db_error(e))?;
+        store.migrate()?;
+        return Ok(store);
+    }
+
+    fn db_error(&self, source: rusqlite::Error) -> MpCalendarError {
+        return MpCalendarError::Database { path: self.path.clone(), source };
+    }
+
+    fn migrate(&mut self) -> Result<(), MpCalendarError> {
+        let version = self.schema_version()?;
+        if version as usize > MIGRATIONS.len() {
+            return Err(MpCalendarError::UnsupportedSchema { path: self.path.clone(), version });
+        }
+        for (applied, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            let result = self.conn.transaction().and_then(|tx| {
+                tx.execute_batch(migration)?;
+                tx.pragma_update(None, "user_version", applied as u32 + 1)?;
+                return tx.commit();
+            });
+            result.map_err(|e| self.db_error(e))?;
+        }
+        return Ok(());
+    }
+
+    pub fn schema_version(&self) -> Result<u32, MpCalendarError> {
+        return self.conn.query_row("PRAGMA user_version", [], |row| row.get(0)).map_err(|e| self.db_error(e));
+    }
+
+    /// Adds event, with its overrides and unrecognised properties, replacing any stored event
+    /// with the same UID. An event without a UID is given the one cal_io would write for it.
+    pub fn save_event(&mut self, event: &MpEvent) -> Result<String, MpCalendarError> {
+        let result = self.conn.transaction().and_then(|tx| {
+            let uid = insert_event(&tx, event, None)?;
+            tx.commit()?;
+            return Ok(uid);
+        });
+        return result.map_err(|e| self.db_error(e));
+    }
+
+    /// As save_event for many events in one transaction. Returns how many were saved.
+    pub fn save_events(&mut self, events: &[MpEvent]) -> Result<usize, MpCalendarError> {
+        let result = self.conn.transaction().and_then(|tx| {
+            for event in events {
+                insert_event(&tx, event, None)?;
+            }
+            return tx.commit();
+        });
+        result.map_err(|e| self.db_error(e))?;
+        return Ok(events.len());
+    }
+
+    /// Makes the store hold exactly events for source, the calendar file they were read from: each
+    /// is saved as with save_events, and the events saved from source before whose UIDs are no longer
+    /// among them are removed. Returns how many were removed.
+    pub fn sync_source(&mut self, source: &str, events: &[MpEvent]) -> Result<usize, MpCalendarError> {
+        let result = self.conn.transaction().and_then(|tx| {
+            let mut kept: Vec<String> = vec![];
+            for event in events {
+                kept.push(insert_event(&tx, event, Some(source))?);
+            }
+            let stored: Vec<String> = tx.prepare("SELECT DISTINCT uid FROM events WHERE source = ?1")?
+                .query_map([source], |row| row.get(0))?
+                .collect::<Result<Vec<String>, rusqlite::Error>>()?;
+            let mut removed = 0;
+            for uid in stored.iter().filter(|uid| !kept.contains(uid)) {
+                removed += tx.execute("DELETE FROM events WHERE source = ?1 AND uid = ?2", params![source, uid])?;
+            }
+            tx.commit()?;
+            return Ok(removed);
+        });
+        return result.map_err(|e| self.db_error(e));
+    }
+
+    /// Removes every event with uid. Returns false if there weren't any.
+    pub fn remove_event(&mut self, uid: &str) -> Result<bool, MpCalendarError> {
+        let removed = self.conn.execute("DELETE FROM events WHERE uid = ?1", [uid]).map_err(|e| self.db_error(e))?;
+        return Ok(removed > 0);
+    }
+
+    /// The event with uid, preferring the whole series over a lone instance
+    pub fn get_event(&self, uid: &str) -> Result<Option<MpEvent>, MpCalendarError> {
+        let ics: Option<String> = self.conn.query_row(
+            "SELECT ics FROM events WHERE uid = ?1 ORDER BY recurrence_id LIMIT 1",
+            [uid],
+            |row| row.get(0)
+        ).optional().map_err(|e| self.db_error(e))?;
+        match ics {
+            Some(ics) => return Ok(self.events_from_ics(&ics)?.into_iter().next()),
+            None => return Ok(None)
+        }
+    }
+
+    /// Every stored event, unexpanded, in chronological order
+    pub fn all_events(&self) -> Result<Vec<MpEvent>, MpCalendarError> {
+        return self.query_events("SELECT ics FROM events", []);
+    }
+
+    /// Occurrences intersecting [window_start, window_end), recurring events expanded, as
+    /// cal_ops::expand_mpevents_in_window would give for all_events
+    pub fn events_in_range(&self,
+                           window_start: DateTime<FixedOffset>,
+                           window_end: DateTime<FixedOffset>) -> Result<Vec<MpEvent>, MpCalendarError> {
+        // Only a rough cut; expand_mpevents_in_window decides exactly what's in the window
+        let candidates = self.query_events(
+            "SELECT ics FROM events
+             WHERE recurring = 1
+                OR (start_time < ?2 AND (end_time > ?1 OR (end_time = start_time AND start_time >= ?1)))",
+            [store_time(window_start), store_time(window_end)]
+        )?;
+        return Ok(cal_ops::expand_mpevents_in_window(candidates, window_start, window_end));
+    }
+
+    /// Stored occurrences that match query in its window, as cal_ops::query_mpevents would give for
+    /// all_events. Bounds the query doesn't give come from [default_start, default_end).
+    pub fn query(&self,
+                 query: &EventQuery,
+                 default_start: DateTime<FixedOffset>,
+                 default_end: DateTime<FixedOffset>) -> Result<Vec<MpEvent>, MpCalendarError> {
+        let (window_start, window_end) = query.window(default_start, default_end);
+        return Ok(cal_ops::filter_mpevents(&self.events_in_range(window_start, window_end)?, query));
+    }
+
+    /// Events with text in their name, location or description, or in those of one of their
+    /// modified instances. Case insensitive for ASCII.
+    pub fn search_text(&self, text: &str) -> Result<Vec<MpEvent>, MpCalendarError> {
+        let pattern = format!("%{}%", text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+        return self.query_events(
+            "SELECT ics FROM events
+             WHERE name LIKE ?1 ESCAPE '\\' OR location LIKE ?1 ESCAPE '\\' OR description LIKE ?1 ESCAPE '\\'
+                OR id IN (SELECT event_id FROM overrides
+                          WHERE name LIKE ?1 ESCAPE '\\' OR location LIKE ?1 ESCAPE '\\' OR description LIKE ?1 ESCAPE '\\')",
+            [pattern]
+        );
+    }
+
+    /// Events whose STATUS is status. Modified instances with a different STATUS don't count.
+    pub fn events_with_status(&self, status: EventStatus) -> Result<Vec<MpEvent>, MpCalendarError> {
+        return self.query_events("SELECT ics FROM events WHERE status = ?1", [status.to_ical_str()]);
+    }
+
+    /// Reads an .ics file with cal_io and syncs the store with it, so events imported from it before
+    /// that it no longer has are removed. Returns how many events were saved.
+    pub fn import_ics_file(&mut self, path: String) -> Result<usize, MpCalendarError> {
+        let events = cal_io::read_events_from_ics_file(path.clone(), &self.local_zone)?;
+        self.sync_source(&path, &events)?;
+        return Ok(events.len());
+    }
+
+    /// Writes every stored event to an .ics file with cal_io, overwriting it
+    pub fn export_ics_file(&self, path: String) -> Result<(), MpCalendarError> {
+        return cal_io::deserialise_mpevents_to_ics_file(path, self.all_events()?);
+    }
+
+    fn query_events<P: rusqlite::Params>(&self, sql: &str, params: P) -> Result<Vec<MpEvent>, MpCalendarError> {
+        let mut statement = self.conn.prepare(sql).map_err(|e| self.db_error(e))?;
+        let rows = statement.query_map(params, |row| row.get::<_, String>(0)).map_err(|e| self.db_error(e))?;
+        let mut events: Vec<MpEvent> = vec![];
+        for ics in rows {
+            let ics = ics.map_err(|e| self.db_error(e))?;
+            events.extend(self.events_from_ics(&ics)?);
+        }
+        return Ok(cal_ops::sort_mpevents_chronologically_by_start(events));
+    }
+
+    fn events_from_ics(&self, ics: &str) -> Result<Vec<MpEvent>, MpCalendarError> {
+        return cal_io::read_events_from_ics_string(ics, self.path.clone(), &self.local_zone);
+    }
+}
+
+fn store_time(time: DateTime<FixedOffset>) -> String {
+    return time.with_timezone(&Utc).to_rfc3339_opts(SecondsFormat::Secs, true);
+}
+
+// An event with no end is stored as ending when it starts, as the index and window checks treat it
+fn store_end_time(event: &MpEvent) -> Option<String> {
+    return event.end_time.or(event.start_time).map(store_time);
+}
+
+fn insert_event(tx: &Transaction, event: &MpEvent, source: Option<&str>) -> Result<String, rusqlite::Error> {
+    let uid = match &event.uid {
+        Some(uid) => uid.clone(),
+        None => cal_io::generate_uid(event)
+    };
+    let recurrence_id = event.recurrence_id.map(store_time).unwrap_or_default();
+    tx.execute("DELETE FROM events WHERE uid = ?1 AND recurrence_id = ?2", params![uid, recurrence_id])?;
+    tx.execute(
+        "INSERT INTO events (uid, recurrence_id, name, location, description, status, start_time, end_time,
+                             all_day, rrule, recurring, ics, source)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+        params![
+            uid,
+            recurrence_id,
+            event.name,
+            event.location,
+            event.description,
+            event.status.map(EventStatus::to_ical_str),
+            event.start_time.map(store_time),
+            store_end_time(event),
+            event.all_day,
+            event.rrule.as_ref().map(|rule| rule.to_string()),
+            event.is_recurring(),
+            cal_io::deserialise_mpevents_to_ics_string(std::slice::from_ref(event)),
+            source
+        ]
+    )?;
+    let event_id = tx.last_insert_rowid();
+    for over in &event.overrides {
+        tx.execute(
+            "INSERT INTO overrides (event_id, recurrence_id, name, location, description, status, start_time, end_time)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                event_id,
+                over.recurrence_id.map(store_time).unwrap_or_default(),
+                over.name,
+                over.location,
+                over.description,
+                over.status.map(EventStatus::to_ical_str),
+                over.start_time.map(store_time),
+                store_end_time(over)
+            ]
+        )?;
+    }
+    for prop in &event.extra_properties {
+        tx.execute(
+            "INSERT INTO properties (event_id, name, params, value) VALUES (?1, ?2, ?3, ?4)",
+            params![event_id, prop.name, cal_io::format_property_params(&prop.params), prop.value]
+        )?;
+    }
+    return Ok(uid);
+}
+
+#[cfg(test)]
+mod cal_store_tests {
+    use super::*;
+
+    fn test_store(ics_path: &str) -> MpEventStore {
+        let mut store = MpEventStore::open_in_memory(MpTimeZone::utc()).unwrap();
+        store.import_ics_file(String::from(ics_path)).unwrap();
+        return store;
+    }
+
+    fn names(events: &[MpEvent]) -> Vec<&str> {
+        return events.iter().map(|event| event.name().unwrap()).collect();
+    }
+
+    #[test]
+    fn test_round_trip_keeps_overrides_and_properties() {
+        for path in ["src/test/test_overrides.ics", "src/test/test_outlook.ics", "src/test/test_alarms.ics"] {
+            let events = cal_io::read_events_from_ics_file(String::from(path), &MpTimeZone::utc()).unwrap();
+            let store = test_store(path);
+            let stored = store.all_events().unwrap();
+            // Events without a DTSTAMP are stamped as they're written, which can fall either side of a second
+            let unstamped = |ics: String| ics.split("\r\n").filter(|line| !line.starts_with("DTSTAMP:")).collect::<Vec<&str>>().join("\r\n");
+            assert_eq!(unstamped(cal_io::deserialise_mpevents_to_ics_string(&cal_ops::sort_mpevents_chronologically_by_start(events))),
+                       unstamped(cal_io::deserialise_mpevents_to_ics_string(&stored)));
+        }
+        let store = test_store("src/test/test_outlook.ics");
+        let organizers: i64 = store.conn.query_row("SELECT COUNT(*) FROM properties WHERE name = 'ORGANIZER'", [], |row| row.get(0)).unwrap();
+        assert!(organizers > 0);
+    }
+
+    #[test]
+    fn test_events_in_range_expands_recurrences() {
+        let store = test_store("src/test/test_overrides.ics");
+        let window_start = FixedOffset::east(0).ymd(2024, 1, 1).and_hms(0, 0, 0);
+        let in_range = store.events_in_range(window_start, window_start + chrono::Duration::days(9)).unwrap();
+        let expected = cal_ops::expand_mpevents_in_window(store.all_events().unwrap(), window_start, window_start + chrono::Duration::days(9));
+        assert_eq!(expected, in_range);
+        assert!(names(&in_range).contains(&"Team standup (moved)"));
+    }
+
+    #[test]
+    fn test_search_text_and_status() {
+        let store = test_store("src/test/test_conflicts.ics");
+        assert_eq!(vec!["Vendor call"], names(&store.search_text("room 2").unwrap()));
+        assert!(store.search_text("100%").unwrap().is_empty());
+        assert_eq!(vec!["Cancelled 1:1"], names(&store.events_with_status(EventStatus::Cancelled).unwrap()));
+        let store = test_store("src/test/test_overrides.ics");
+        // Only the moved instance says "moved", but the whole series comes back
+        assert_eq!(vec!["Team standup"], names(&store.search_text("moved").unwrap()));
+    }
+
+    #[test]
+    fn test_query() {
+        let store = test_store("src/test/test_conflicts.ics");
+        let day_start = FixedOffset::east(0).ymd(2024, 1, 8).and_hms(0, 0, 0);
+        let day_end = day_start + chrono::Duration::days(1);
+        let query = EventQuery::parse("-status:cancelled -lunch", day_start, &MpTimeZone::utc()).unwrap();
+        assert_eq!(names(&cal_ops::query_mpevents(store.all_events().unwrap(), &query, day_start, day_end)),
+                   names(&store.query(&query, day_start, day_end).unwrap()));
+        // before: widens the default window to take in the standups on the 9th and 10th
+        let standups = EventQuery::parse("name:standup before:+3d", day_start, &MpTimeZone::utc()).unwrap();
+        assert_eq!(3, store.query(&standups, day_start, day_end).unwrap().len());
+    }
+
+    #[test]
+    fn test_save_replaces_and_remove() {
+        let mut store = test_store("src/test/test_overrides.ics");
+        let mut standup = store.get_event("standup-002@example.com").unwrap().unwrap();
+        assert_eq!(1, standup.overrides.len());
+        standup.name = Some(String::from("Daily standup"));
+        standup.overrides.clear();
+        store.save_event(&standup).unwrap();
+        let count_before = store.all_events().unwrap().len();
+        let reloaded = store.get_event("standup-002@example.com").unwrap().unwrap();
+        assert_eq!(Some("Daily standup"), reloaded.name());
+        assert!(reloaded.overrides.is_empty());
+        assert!(store.remove_event("standup-002@example.com").unwrap());
+        assert!(!store.remove_event("standup-002@example.com").unwrap());
+        assert_eq!(count_before - 1, store.all_events().unwrap().len());
+    }
+
+    #[test]
+    fn test_import_removes_events_deleted_from_the_file() {
+        let path = std::env::temp_dir().join(format!("moneypenny_store_sync_{}.ics", std::process::id())).to_string_lossy().into_owned();
+        std::fs::copy("src/test/test_overrides.ics", &path).unwrap();
+        let mut store = MpEventStore::open_in_memory(MpTimeZone::utc()).unwrap();
+        store.import_ics_file(path.clone()).unwrap();
+        let added = MpEvent { uid: Some(String::from("added@example.com")), name: Some(String::from("Added")), ..Default::default() };
+        store.save_event(&added).unwrap();
+        assert!(store.get_event("standup-002@example.com").unwrap().is_some());
+
+        let mut events = cal_io::read_events_from_ics_file(path.clone(), &MpTimeZone::utc()).unwrap();
+        events.retain(|event| event.uid.as_deref() != Some("standup-002@example.com"));
+        cal_io::deserialise_mpevents_to_ics_file(path.clone(), events.clone()).unwrap();
+        assert_eq!(events.len(), store.import_ics_file(path.clone()).unwrap());
+        let _ = std::fs::remove_file(&path);
+        assert!(store.get_event("standup-002@example.com").unwrap().is_none());
+        // Overrides go with their series, and events saved directly aren't the file's to remove
+        let overrides: i64 = store.conn.query_row("SELECT COUNT(*) FROM overrides", [], |row| row.get(0)).unwrap();
+        assert_eq!(0, overrides);
+        assert!(store.get_event("added@example.com").unwrap().is_some());
+        assert_eq!(events.len() + 1, store.all_events().unwrap().len());
+    }
+
+    #[test]
+    fn test_persists_across_opens_and_rejects_newer_schema() {
+        let path = std::env::temp_dir().join(format!("moneypenny_store_test_{}.sqlite", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        let _ = std::fs::remove_file(&path);
+        {
+            let mut store = MpEventStore::open(&path, MpTimeZone::utc()).unwrap();
+            assert_eq!(MIGRATIONS.len() as u32, store.schema_version().unwrap());
+            store.import_ics_file(String::from("src/test/test_conflicts.ics")).unwrap();
+        }
+        {
+            let store = MpEventStore::open(&path, MpTimeZone::utc()).unwrap();
+            assert_eq!(MIGRATIONS.len() as u32, store.schema_version().unwrap());
+            assert!(store.get_event("conflict-interview@example.com").unwrap().is_some());
+            store.conn.pragma_update(None, "user_version", 99).unwrap();
+        }
+        let reopened = MpEventStore::open(&path, MpTimeZone::utc());
+        assert!(matches!(reopened, Err(MpCalendarError::UnsupportedSchema { version: 99, .. })));
+        let _ = std::fs::remove_file(&path);
+    }
+}
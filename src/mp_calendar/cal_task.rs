@@ -0,0 +1,162 @@
+use std::str::FromStr;
+use std::cmp::Ordering;
+use chrono::prelude::*;
+use super::cal_tz::MpTimeZone;
+use super::MpProperty;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TaskStatus {
+    NeedsAction,
+    InProcess,
+    Completed,
+    Cancelled
+}
+
+impl FromStr for TaskStatus {
+    type Err = ();
+    fn from_str(input: &str) -> Result<TaskStatus, Self::Err> {
+        match input {
+            "NEEDS-ACTION" => Ok(TaskStatus::NeedsAction),
+            "IN-PROCESS"   => Ok(TaskStatus::InProcess),
+            "COMPLETED"    => Ok(TaskStatus::Completed),
+            "CANCELLED"    => Ok(TaskStatus::Cancelled),
+            _              => Err(()),
+        }
+    }
+}
+
+impl TaskStatus {
+    pub fn to_ical_str(self) -> &'static str {
+        match self {
+            TaskStatus::NeedsAction => return "NEEDS-ACTION",
+            TaskStatus::InProcess => return "IN-PROCESS",
+            TaskStatus::Completed => return "COMPLETED",
+            TaskStatus::Cancelled => return "CANCELLED"
+        }
+    }
+}
+
+/// A VTODO. Lives in the same calendars as MpEvents and is read and written by cal_io alongside them.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct MpTask {
+    pub(super) uid: Option<String>,
+    pub(super) name: Option<String>,
+    pub(super) description: Option<String>,
+    pub(super) dtstamp: Option<DateTime<FixedOffset>>,
+    pub(super) due: Option<DateTime<FixedOffset>>,
+    // A VALUE=DATE due date, stored as local midnight in timezone
+    pub(super) due_all_day: bool,
+    // Zone DUE was given in. None means UTC.
+    pub(super) timezone: Option<MpTimeZone>,
+    pub(super) completed: Option<DateTime<FixedOffset>>,
+    pub(super) percent_complete: Option<u8>,
+    // 1 is the highest priority and 9 the lowest; 0 or None is undefined
+    pub(super) priority: Option<u8>,
+    pub(super) status: Option<TaskStatus>,
+    // UID of the parent task, from RELATED-TO with no RELTYPE or RELTYPE=PARENT
+    pub(super) related_to: Option<String>,
+    pub(super) extra_properties: Vec<MpProperty>
+}
+
+impl MpTask {
+    pub fn new(name: &str) -> MpTask {
+        return MpTask { name: Some(String::from(name)), status: Some(TaskStatus::NeedsAction), ..Default::default() };
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        return self.name.as_deref();
+    }
+
+    pub fn uid(&self) -> Option<&str> {
+        return self.uid.as_deref();
+    }
+
+    pub fn due(&self) -> Option<DateTime<FixedOffset>> {
+        return self.due;
+    }
+
+    pub fn priority(&self) -> Option<u8> {
+        return self.priority;
+    }
+
+    pub fn status(&self) -> Option<TaskStatus> {
+        return self.status;
+    }
+
+    pub fn is_complete(&self) -> bool {
+        return self.status == Some(TaskStatus::Completed);
+    }
+
+    pub fn is_subtask_of(&self, parent: &MpTask) -> bool {
+        match (&self.related_to, &parent.uid) {
+            (Some(parent_uid), Some(uid)) => return parent_uid == uid,
+            _ => return false
+        }
+    }
+
+    /// Sets STATUS:COMPLETED, PERCENT-COMPLETE:100 and COMPLETED to completed_at
+    pub fn mark_complete(&mut self, completed_at: DateTime<FixedOffset>) {
+        self.status = Some(TaskStatus::Completed);
+        self.percent_complete = Some(100);
+        self.completed = Some(completed_at);
+    }
+
+    /// Earliest due first, tasks with no due date last. Ties go to the higher priority, where
+    /// an undefined priority ranks below 9, then to the name.
+    pub fn cmp_due_then_priority(&self, other: &MpTask) -> Ordering {
+        let due_order = match (self.due, other.due) {
+            (Some(lhs), Some(rhs)) => lhs.cmp(&rhs),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal
+        };
+        return due_order
+            .then_with(|| self.priority_rank().cmp(&other.priority_rank()))
+            .then_with(|| self.name.cmp(&other.name));
+    }
+
+    fn priority_rank(&self) -> u8 {
+        match self.priority {
+            Some(priority) if priority > 0 => return priority,
+            _ => return 10
+        }
+    }
+}
+
+#[cfg(test)]
+mod cal_task_tests {
+    use super::*;
+
+    fn task_due(name: &str, due: Option<&str>, priority: Option<u8>) -> MpTask {
+        let mut task = MpTask::new(name);
+        task.due = due.map(|due| DateTime::parse_from_rfc3339(due).unwrap());
+        task.priority = priority;
+        return task;
+    }
+
+    #[test]
+    fn test_cmp_due_then_priority() {
+        let mut tasks = [
+            task_due("No due date", None, Some(1)),
+            task_due("Undefined priority", Some("2024-03-01T17:00:00+00:00"), Some(0)),
+            task_due("Low priority", Some("2024-03-01T17:00:00+00:00"), Some(9)),
+            task_due("Urgent", Some("2024-03-01T17:00:00+00:00"), Some(1)),
+            task_due("Earlier", Some("2024-02-28T09:00:00+00:00"), None)
+        ];
+        tasks.sort_by(|lhs, rhs| lhs.cmp_due_then_priority(rhs));
+        let names: Vec<&str> = tasks.iter().map(|task| task.name().unwrap()).collect();
+        assert_eq!(vec!["Earlier", "Urgent", "Low priority", "Undefined priority", "No due date"], names);
+    }
+
+    #[test]
+    fn test_mark_complete() {
+        let mut task = MpTask::new("File expenses");
+        task.percent_complete = Some(40);
+        assert!(!task.is_complete());
+        let done_at = DateTime::parse_from_rfc3339("2024-03-01T12:00:00+00:00").unwrap();
+        task.mark_complete(done_at);
+        assert!(task.is_complete());
+        assert_eq!(Some(100), task.percent_complete);
+        assert_eq!(Some(done_at), task.completed);
+    }
+}
@@ -0,0 +1,6 @@
+// Text helpers shared by the modules that write other formats
+
+/// Escapes text for an XML or HTML element or a double-quoted attribute
+pub(super) fn escape_markup(text: &str) -> String {
+    return text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;");
+}
@@ -0,0 +1,986 @@
+use std::io::Write;
+use chrono::prelude::*;
+use chrono::Duration;
+use crossterm::{cursor, event, style, terminal, ExecutableCommand, QueueableCommand};
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crate::mp_core::core_config::Config;
+use super::{MpEvent, MpCalendarError, EventStatus, cal_alarm, cal_io, cal_ops, cal_phrase, cal_tz};
+use super::cal_ops::fit_to_width;
+use super::cal_task::MpTask;
+use super::cal_tz::MpTimeZone;
+
+// Width of the tasks and reminders pane, and the narrowest screen it's shown on
+const SIDE_PANE_WIDTH: usize = 36;
+const SIDE_PANE_MIN_SCREEN_WIDTH: usize = 80;
+// How far ahead the side pane lists reminders
+const REMINDER_HOURS: i64 = 24;
+// With no key pressed the screen is redrawn this often, to keep the clock and reminders current
+const REFRESH_SECONDS: u64 = 30;
+const FORM_FIELDS: [&str; 5] = ["Name", "Start", "End", "Location", "Description"];
+
+/// A key press, as far as the dashboard is concerned
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TuiKey {
+    Char(char),
+    Enter,
+    Esc,
+    Tab,
+    BackTab,
+    Backspace,
+    Up,
+    Down,
+    Left,
+    Right,
+    // Ctrl-C, which quits from anywhere
+    Interrupt
+}
+
+impl TuiKey {
+    /// None for key releases and keys the dashboard doesn't use
+    pub fn from_key_event(key: KeyEvent) -> Option<TuiKey> {
+        if key.kind == KeyEventKind::Release {
+            return None;
+        }
+        match key.code {
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Some(TuiKey::Interrupt),
+            KeyCode::Char(c) => return Some(TuiKey::Char(c)),
+            KeyCode::Enter => return Some(TuiKey::Enter),
+            KeyCode::Esc => return Some(TuiKey::Esc),
+            KeyCode::Tab => return Some(TuiKey::Tab),
+            KeyCode::BackTab => return Some(TuiKey::BackTab),
+            KeyCode::Backspace => return Some(TuiKey::Backspace),
+            KeyCode::Up => return Some(TuiKey::Up),
+            KeyCode::Down => return Some(TuiKey::Down),
+            KeyCode::Left => return Some(TuiKey::Left),
+            KeyCode::Right => return Some(TuiKey::Right),
+            _ => return None
+        }
+    }
+}
+
+/// How much of the calendar the main pane covers
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TuiPeriod {
+    Day,
+    Week,
+    Month
+}
+
+impl TuiPeriod {
+    // First and last day of the period containing date
+    fn days(self, date: NaiveDate) -> (NaiveDate, NaiveDate) {
+        match self {
+            TuiPeriod::Day => return (date, date),
+            TuiPeriod::Week => {
+                let week = cal_ops::week_grid_days(date);
+                return (week[0], week[6]);
+            },
+            TuiPeriod::Month => {
+                let in_month: Vec<NaiveDate> = cal_ops::month_grid_days(date).into_iter()
+                    .filter(|day| day.month() == date.month())
+                    .collect();
+                return (in_month[0], in_month[in_month.len() - 1]);
+            }
+        }
+    }
+
+    // A day in the period before or after the one containing date
+    fn step(self, date: NaiveDate, forward: bool) -> NaiveDate {
+        let (first, last) = self.days(date);
+        match self {
+            TuiPeriod::Day | TuiPeriod::Week => {
+                let length = (last - first).num_days() + 1;
+                return if forward { date + Duration::days(length) } else { date - Duration::days(length) };
+            },
+            TuiPeriod::Month if forward => return last.succ(),
+            TuiPeriod::Month => return self.days(first.pred()).0
+        }
+    }
+
+    fn title(self, date: NaiveDate) -> String {
+        match self {
+            TuiPeriod::Day => return date.format("%A %e %B %Y").to_string(),
+            TuiPeriod::Week => return format!("Week of {}", self.days(date).0.format("%a %e %b %Y")),
+            TuiPeriod::Month => return date.format("%B %Y").to_string()
+        }
+    }
+}
+
+// One configured calendar, as loaded into the dashboard and written back to its file
+struct TuiCalendar {
+    name: String,
+    path: String,
+    read_only: bool,
+    events: Vec<MpEvent>,
+    tasks: Vec<MpTask>
+}
+
+// An occurrence in the period shown, with the calendar and event it's expanded from
+struct Listed {
+    calendar: usize,
+    event: usize,
+    occurrence: MpEvent
+}
+
+// The fields of an event being created or edited, as typed
+struct EventForm {
+    // What the fields are applied to: a new event, or a copy of the one being edited
+    template: MpEvent,
+    calendar: usize,
+    // Index of the event being edited in its calendar, or None for a new one
+    editing: Option<usize>,
+    // Start of the occurrence that was opened, so a repeating event is moved by as much as it is
+    occurrence_start: Option<DateTime<FixedOffset>>,
+    values: Vec<String>,
+    // The values as the form was opened with, to tell what's been changed
+    initial: Vec<String>,
+    focus: usize
+}
+
+impl EventForm {
+    fn new(template: MpEvent, occurrence: Option<&MpEvent>, calendar: usize, editing: Option<usize>, local_zone: &MpTimeZone) -> EventForm {
+        let shown = occurrence.unwrap_or(&template);
+        let start = shown.start_time.map(|start| local_zone.convert(start));
+        let end = shown.end_time.map(|end| local_zone.convert(end));
+        let (start_text, end_text) = match (start, end) {
+            // An all-day end is the day after, so the last day is shown instead
+            (Some(start), Some(end)) if shown.all_day => (start.format("%Y-%m-%d").to_string(), end.date().pred().format("%Y-%m-%d").to_string()),
+            (Some(start), end) => (start.format("%Y-%m-%d %H:%M").to_string(), end.map(|end| end.format("%Y-%m-%d %H:%M").to_string()).unwrap_or_default()),
+            (None, _) => (String::new(), String::new())
+        };
+        let values = vec![
+            shown.name.clone().unwrap_or_default(),
+            start_text,
+            end_text,
+            shown.location.clone().unwrap_or_default(),
+            shown.description.clone().unwrap_or_default()
+        ];
+        let occurrence_start = occurrence.and_then(|occurrence| occurrence.start_time);
+        return EventForm { template, calendar, editing, occurrence_start, initial: values.clone(), values, focus: 0 };
+    }
+}
+
+// What the main pane is showing and what keys do
+enum Mode {
+    Browse,
+    // The week or month as a cal_ops grid
+    Grid,
+    Detail,
+    ConfirmDelete,
+    // A phrase for cal_phrase being typed
+    Phrase(String),
+    Form(Box<EventForm>)
+}
+
+/// The state of the calendar dashboard: the configured calendars and their tasks, the period being
+/// looked at, and whatever is being viewed or edited. Keys go to handle_key and render draws the
+/// screen, so it can be driven without a terminal. Changes are written straight back to the
+/// calendar's file; read-only calendars can be looked at but not changed.
+pub struct TuiState {
+    calendars: Vec<TuiCalendar>,
+    local_zone: MpTimeZone,
+    now: DateTime<FixedOffset>,
+    period: TuiPeriod,
+    // Any day in the period shown
+    date: NaiveDate,
+    listed: Vec<Listed>,
+    selected: usize,
+    mode: Mode,
+    // Shown in place of the key help until the next key press
+    status: String,
+    finished: bool
+}
+
+impl TuiState {
+    /// Loads the events and tasks of every valid calendar in the config and starts on this week.
+    /// Calendars that can't be read are left out and reported on the status line, as are the
+    /// malformed components skipped, since nothing can be written to the terminal once it's in use.
+    pub fn load(config: &Config, now: DateTime<FixedOffset>) -> TuiState {
+        let local_zone = cal_tz::configured_local_zone(config.timezone.as_deref());
+        let mut calendars: Vec<TuiCalendar> = vec![];
+        let mut problems: Vec<String> = vec![];
+        for calendar in &config.calendars {
+            if calendar.validate().is_err() {
+                continue;
+            }
+            let zone = cal_tz::configured_local_zone(calendar.timezone.as_deref().or(config.timezone.as_deref()));
+            let loaded = cal_io::parse_file_to_ical_calendar(calendar.path.clone()).and_then(|cal| {
+                let events = cal_io::extract_events_from_ical_with_warnings(cal.clone(), &zone, &mut problems)?;
+                return Ok((events, cal_io::extract_tasks_from_ical_with_warnings(cal, &zone, &mut problems)?));
+            });
+            match loaded {
+                Ok((events, tasks)) => calendars.push(TuiCalendar {
+                    name: calendar.display_name().to_string(),
+                    path: calendar.path.clone(),
+                    read_only: calendar.read_only,
+                    events,
+                    tasks
+                }),
+                Err(e) => problems.push(format!("Could not load calendar '{}': {}", calendar.display_name(), e))
+            };
+        }
+        let date = local_zone.convert(now).date().naive_local();
+        let mut state = TuiState {
+            calendars,
+            local_zone,
+            now,
+            period: TuiPeriod::Week,
+            date,
+            listed: vec![],
+            selected: 0,
+            mode: Mode::Browse,
+            status: problems.join("; "),
+            finished: false
+        };
+        state.refresh();
+        return state;
+    }
+
+    /// True once the dashboard has been asked to quit
+    pub fn is_finished(&self) -> bool {
+        return self.finished;
+    }
+
+    /// Moves the clock on, for the title bar and the reminders pane
+    pub fn set_now(&mut self, now: DateTime<FixedOffset>) {
+        self.now = now;
+    }
+
+    /// Acts on one key press in the current mode
+    pub fn handle_key(&mut self, key: TuiKey) {
+        self.status.clear();
+        if key == TuiKey::Interrupt {
+            self.finished = true;
+            return;
+        }
+        let mode = std::mem::replace(&mut self.mode, Mode::Browse);
+        self.mode = match mode {
+            Mode::Browse => self.browse_key(key, false),
+            Mode::Grid => self.browse_key(key, true),
+            Mode::Detail => self.detail_key(key),
+            Mode::ConfirmDelete => {
+                if key == TuiKey::Char('y') {
+                    self.delete_selected();
+                }
+                Mode::Browse
+            },
+            Mode::Phrase(text) => self.phrase_key(key, text),
+            Mode::Form(form) => self.form_key(key, form)
+        };
+    }
+
+    /// The screen as width by height lines of text: a title bar, the main pane with the tasks and
+    /// reminders pane beside it when there's room, and a status line. The selected event's line is
+    /// in reverse video.
+    pub fn render(&self, width: usize, height: usize) -> Vec<String> {
+        let body_height = height.saturating_sub(2);
+        let show_side = width >= SIDE_PANE_MIN_SCREEN_WIDTH && !matches!(self.mode, Mode::Grid);
+        let main_width = if show_side { width - SIDE_PANE_WIDTH - 1 } else { width };
+        let (main, highlight) = self.main_pane(main_width);
+        let offset = match highlight {
+            Some(line) if line >= body_height => line + 1 - body_height,
+            _ => 0
+        };
+        let side = if show_side { self.side_pane() } else { vec![] };
+
+        let clock = self.local_zone.convert(self.now).format("%a %e %b %H:%M").to_string();
+        let title = format!(" {}", self.period.title(self.date));
+        let gap = width.saturating_sub(title.chars().count() + clock.chars().count() + 1);
+        let mut lines = vec![fit_to_width(&format!("{}{}{} ", title, " ".repeat(gap), clock), width)];
+        for row in 0..body_height {
+            let mut line = fit_to_width(main.get(offset + row).map(|line| line.as_str()).unwrap_or(""), main_width);
+            if highlight == Some(offset + row) {
+                line = format!("\x1b[7m{}\x1b[0m", line);
+            }
+            if show_side {
+                line.push('│');
+                line.push_str(&fit_to_width(side.get(row).map(|line| line.as_str()).unwrap_or(""), SIDE_PANE_WIDTH));
+            }
+            lines.push(line);
+        }
+        lines.push(fit_to_width(&self.status_line(), width));
+        return lines;
+    }
+
+    // Lists the occurrences in the period shown, keeping the selection in range
+    fn refresh(&mut self) {
+        let (first, last) = self.period.days(self.date);
+        let (window_start, _) = cal_ops::grid_day_bounds(first, &self.local_zone);
+        let (_, window_end) = cal_ops::grid_day_bounds(last, &self.local_zone);
+        let mut listed: Vec<Listed> = vec![];
+        for (calendar_index, calendar) in self.calendars.iter().enumerate() {
+            for (event_index, event) in calendar.events.iter().enumerate() {
+                for occurrence in event.expand_occurrences(window_start, window_end) {
+                    listed.push(Listed { calendar: calendar_index, event: event_index, occurrence });
+                }
+            }
+        }
+        listed.sort_by(|lhs, rhs| lhs.occurrence.cmp(&rhs.occurrence));
+        self.listed = listed;
+        self.selected = self.selected.min(self.listed.len().saturating_sub(1));
+    }
+
+    fn browse_key(&mut self, key: TuiKey, grid: bool) -> Mode {
+        let mut period_changed = true;
+        match key {
+            TuiKey::Char('q') => self.finished = true,
+            TuiKey::Char('h') | TuiKey::Left => self.date = self.period.step(self.date, false),
+            TuiKey::Char('l') | TuiKey::Right => self.date = self.period.step(self.date, true),
+            TuiKey::Char('t') => self.date = self.local_zone.convert(self.now).date().naive_local(),
+            TuiKey::Char('d') => self.period = TuiPeriod::Day,
+            TuiKey::Char('w') => self.period = TuiPeriod::Week,
+            TuiKey::Char('m') => self.period = TuiPeriod::Month,
+            _ => period_changed = false
+        };
+        if period_changed {
+            self.selected = 0;
+            self.refresh();
+            if grid && self.period == TuiPeriod::Day {
+                return Mode::Browse;
+            }
+            return if grid { Mode::Grid } else { Mode::Browse };
+        }
+        match key {
+            TuiKey::Char('j') | TuiKey::Down => self.selected = (self.selected + 1).min(self.listed.len().saturating_sub(1)),
+            TuiKey::Char('k') | TuiKey::Up => self.selected = self.selected.saturating_sub(1),
+            TuiKey::Char('g') if grid => return Mode::Browse,
+            TuiKey::Char('g') if self.period == TuiPeriod::Day => self.status = String::from("The grid shows weeks and months"),
+            TuiKey::Char('g') => return Mode::Grid,
+            TuiKey::Enter if !self.listed.is_empty() => return Mode::Detail,
+            TuiKey::Char('a') => return Mode::Phrase(String::new()),
+            TuiKey::Char('n') => match self.new_event_form() {
+                Some(form) => return Mode::Form(Box::new(form)),
+                None => ()
+            },
+            TuiKey::Char('e') => match self.edit_form() {
+                Some(form) => return Mode::Form(Box::new(form)),
+                None => ()
+            },
+            TuiKey::Char('x') if self.writable_selection().is_some() => return Mode::ConfirmDelete,
+            _ => ()
+        };
+        return if grid { Mode::Grid } else { Mode::Browse };
+    }
+
+    fn detail_key(&mut self, key: TuiKey) -> Mode {
+        match key {
+            TuiKey::Char('e') => match self.edit_form() {
+                Some(form) => return Mode::Form(Box::new(form)),
+                None => return Mode::Detail
+            },
+            TuiKey::Char('x') if self.writable_selection().is_some() => return Mode::ConfirmDelete,
+            TuiKey::Esc | TuiKey::Enter | TuiKey::Char('q') => return Mode::Browse,
+            _ => return Mode::Detail
+        }
+    }
+
+    fn phrase_key(&mut self, key: TuiKey, mut text: String) -> Mode {
+        match key {
+            TuiKey::Char(c) => text.push(c),
+            TuiKey::Backspace => { text.pop(); },
+            TuiKey::Esc => return Mode::Browse,
+            // The form shows what was understood so it can be checked before saving
+            TuiKey::Enter => match cal_phrase::parse_event_phrase(&text, self.now, &self.local_zone) {
+                Ok(event) => match self.writable_calendar() {
+                    Some(calendar) => return Mode::Form(Box::new(EventForm::new(event, None, calendar, None, &self.local_zone))),
+                    None => return Mode::Browse
+                },
+                Err(e) => self.status = e.to_string()
+            },
+            _ => ()
+        };
+        return Mode::Phrase(text);
+    }
+
+    fn form_key(&mut self, key: TuiKey, mut form: Box<EventForm>) -> Mode {
+        match key {
+            TuiKey::Tab | TuiKey::Down => form.focus = (form.focus + 1) % FORM_FIELDS.len(),
+            TuiKey::BackTab | TuiKey::Up => form.focus = (form.focus + FORM_FIELDS.len() - 1) % FORM_FIELDS.len(),
+            TuiKey::Char(c) => form.values[form.focus].push(c),
+            TuiKey::Backspace => { form.values[form.focus].pop(); },
+            TuiKey::Esc => {
+                self.status = String::from("Nothing was saved");
+                return Mode::Browse;
+            },
+            TuiKey::Enter => match self.save_form(&form) {
+                Ok(message) => {
+                    self.status = message;
+                    return Mode::Browse;
+                },
+                Err(message) => self.status = message
+            },
+            _ => ()
+        };
+        return Mode::Form(form);
+    }
+
+    // The first calendar that isn't read-only, or None after saying there isn't one
+    fn writable_calendar(&mut self) -> Option<usize> {
+        let writable = self.calendars.iter().position(|calendar| !calendar.read_only);
+        if writable.is_none() {
+            self.status = MpCalendarError::NoWritableCalendar.to_string();
+        }
+        return writable;
+    }
+
+    // The calendar and event index of the selected occurrence, or None if nothing is selected or
+    // it's in a read-only calendar, after saying so
+    fn writable_selection(&mut self) -> Option<(usize, usize)> {
+        let listed = self.listed.get(self.selected)?;
+        let calendar = &self.calendars[listed.calendar];
+        if calendar.read_only {
+            self.status = format!("{} is read-only", calendar.name);
+            return None;
+        }
+        return Some((listed.calendar, listed.event));
+    }
+
+    // A blank form for an hour at 09:00 today, or on the first day shown if today isn't
+    fn new_event_form(&mut self) -> Option<EventForm> {
+        let calendar = self.writable_calendar()?;
+        let (first, last) = self.period.days(self.date);
+        let today = self.local_zone.convert(self.now).date().naive_local();
+        let day = if today >= first && today <= last { today } else { first };
+        let start = self.local_zone.from_local_datetime(day.and_hms(9, 0, 0));
+        let template = MpEvent {
+            start_time: Some(start),
+            end_time: Some(start + Duration::hours(1)),
+            status: Some(EventStatus::Confirmed),
+            timezone: Some(self.local_zone.clone()),
+            dtstamp: Some(self.now),
+            ..Default::default()
+        };
+        return Some(EventForm::new(template, None, calendar, None, &self.local_zone));
+    }
+
+    fn edit_form(&mut self) -> Option<EventForm> {
+        let (calendar, event) = self.writable_selection()?;
+        let template = self.calendars[calendar].events[event].clone();
+        let occurrence = &self.listed[self.selected].occurrence;
+        return Some(EventForm::new(template, Some(occurrence), calendar, Some(event), &self.local_zone));
+    }
+
+    // The form's event, or what's wrong with the fields. A repeating event is edited as a whole
+    // series, moved by as much as the occurrence that was opened.
+    fn event_from_form(&self, form: &EventForm) -> Result<MpEvent, String> {
+        let name = form.values[0].trim();
+        if name.is_empty() {
+            return Err(String::from("An event needs a name"));
+        }
+        let parse_time = |value: &str| -> Result<Option<(NaiveDateTime, bool)>, String> {
+            let value = value.trim();
+            if value.is_empty() {
+                return Ok(None);
+            }
+            match NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M") {
+                Ok(time) => return Ok(Some((time, false))),
+                Err(_) => ()
+            };
+            match NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+                Ok(day) => return Ok(Some((day.and_hms(0, 0, 0), true))),
+                Err(_) => return Err(format!("'{}' should look like 2024-05-01 09:30, or 2024-05-01 for all day", value))
+            }
+        };
+        let (start, all_day) = parse_time(&form.values[1])?.ok_or_else(|| String::from("An event needs a start"))?;
+        // Moving the start keeps the event's length unless the end is changed too
+        let kept_length = match (parse_time(&form.initial[1]), parse_time(&form.initial[2])) {
+            (Ok(Some((initial_start, initial_all_day))), Ok(Some((initial_end, _)))) if form.values[2] == form.initial[2]
+                && form.values[1] != form.initial[1] && initial_all_day == all_day => Some(initial_end - initial_start),
+            _ => None
+        };
+        let end = match (kept_length, parse_time(&form.values[2])?) {
+            (Some(length), _) => start + length,
+            (None, Some((_, end_all_day))) if end_all_day != all_day => {
+                return Err(format!("Give the end {}, like the start", if all_day { "as just a date" } else { "a time" }));
+            },
+            // The last day of an all-day event is given, but it ends at midnight after it
+            (None, Some((end, true))) => end + Duration::days(1),
+            (None, Some((end, false))) => end,
+            (None, None) if all_day => start + Duration::days(1),
+            (None, None) => start + Duration::hours(1)
+        };
+        if end < start {
+            return Err(String::from("The end is before the start"));
+        }
+        let start = self.local_zone.from_local_datetime(start);
+        let end = self.local_zone.from_local_datetime(end);
+        let optional = |value: &str| if value.trim().is_empty() { None } else { Some(String::from(value.trim())) };
+
+        let mut event = form.template.clone();
+        event.name = Some(String::from(name));
+        event.location = optional(&form.values[3]);
+        event.description = optional(&form.values[4]);
+        event.all_day = all_day;
+        match (form.occurrence_start, event.start_time) {
+            (Some(occurrence_start), Some(series_start)) if event.is_recurring() => {
+                let moved = series_start + (start - occurrence_start);
+                event.start_time = Some(moved);
+                event.end_time = Some(moved + (end - start));
+            },
+            _ => {
+                event.start_time = Some(start);
+                event.end_time = Some(end);
+            }
+        };
+        if form.editing.is_some() {
+            event.sequence = Some(event.sequence.unwrap_or(0) + 1);
+            event.last_modified = Some(self.now);
+        }
+        if event.uid.is_none() {
+            event.uid = Some(cal_io::generate_uid(&event));
+        }
+        return Ok(event);
+    }
+
+    // Puts the form's event into its calendar, writes the calendar and moves to the event
+    fn save_form(&mut self, form: &EventForm) -> Result<String, String> {
+        let event = self.event_from_form(form)?;
+        let name = event.name.clone().unwrap_or_default();
+        let start = event.start_time;
+        let calendar = &mut self.calendars[form.calendar];
+        // An edited event read without a UID can't be found in the file, so it's rewritten whole
+        let in_place = form.editing.is_none_or(|index| calendar.events[index].uid.is_some());
+        let index = match form.editing {
+            Some(index) => {
+                calendar.events[index] = event;
+                index
+            },
+            None => {
+                calendar.events.push(event);
+                calendar.events.len() - 1
+            }
+        };
+        let saved = if in_place {
+            let calendar = &self.calendars[form.calendar];
+            cal_io::update_events_in_ics_file(calendar.path.clone(), &calendar.events[index..=index], &[])
+        } else {
+            self.save_calendar(form.calendar)
+        };
+        match start {
+            Some(start) => self.date = self.local_zone.convert(start).date().naive_local(),
+            None => ()
+        };
+        self.refresh();
+        match self.listed.iter().position(|listed| listed.calendar == form.calendar && listed.event == index) {
+            Some(position) => self.selected = position,
+            None => ()
+        };
+        let calendar_name = &self.calendars[form.calendar].name;
+        return saved.map(|_| format!("Saved {} to {}", name, calendar_name))
+            .map_err(|e| format!("Could not save {}: {}", calendar_name, e));
+    }
+
+    // Deletes the selected event, the whole series if it repeats
+    fn delete_selected(&mut self) {
+        let (calendar, event) = match self.writable_selection() {
+            Some(selection) => selection,
+            None => return
+        };
+        let removed = self.calendars[calendar].events.remove(event);
+        let saved = match &removed.uid {
+            Some(uid) => cal_io::update_events_in_ics_file(self.calendars[calendar].path.clone(), &[], std::slice::from_ref(uid)),
+            None => self.save_calendar(calendar)
+        };
+        let name = super::name_of(&removed);
+        self.status = match saved {
+            Ok(()) => format!("Deleted {}", name),
+            Err(e) => format!("Could not save {}: {}", self.calendars[calendar].name, e)
+        };
+        self.refresh();
+    }
+
+    // Rewrites the whole calendar file, for when the changed event can't be found in it by UID
+    fn save_calendar(&self, index: usize) -> Result<(), MpCalendarError> {
+        let calendar = &self.calendars[index];
+        if calendar.tasks.is_empty() {
+            return cal_io::deserialise_mpevents_to_ics_file(calendar.path.clone(), calendar.events.clone());
+        }
+        // Writing the events alone would lose the file's tasks
+        return cal_io::deserialise_calendar_to_ics_file(calendar.path.clone(), calendar.events.clone(), calendar.tasks.clone());
+    }
+
+    // The main pane's lines and which of them is highlighted
+    fn main_pane(&self, width: usize) -> (Vec<String>, Option<usize>) {
+        match &self.mode {
+            Mode::Browse => return self.list_lines(),
+            Mode::Grid => {
+                let calendars: Vec<cal_ops::GridCalendar> = self.calendars.iter()
+                    .map(|calendar| cal_ops::GridCalendar { events: calendar.events.clone(), colour: None })
+                    .collect();
+                let grid = match self.period {
+                    TuiPeriod::Month => cal_ops::render_month_view(&calendars, self.date, &self.local_zone, false),
+                    _ => cal_ops::render_week_view(&calendars, self.date, &self.local_zone, false)
+                };
+                // The grid's own heading repeats the title bar
+                return (grid.lines().skip(1).map(String::from).collect(), None);
+            },
+            Mode::Detail | Mode::ConfirmDelete => return (self.detail_lines(width), None),
+            Mode::Phrase(text) => return (vec![String::from("New event:"), format!("  {}_", text)], None),
+            Mode::Form(form) => {
+                let heading = match form.editing {
+                    Some(_) if form.template.is_recurring() => format!("Editing {} in {}, which repeats: changes apply to every occurrence",
+                                                                       form.template.name.as_deref().unwrap_or("Untitled event"), self.calendars[form.calendar].name),
+                    Some(_) => format!("Editing {} in {}", form.template.name.as_deref().unwrap_or("Untitled event"), self.calendars[form.calendar].name),
+                    None => format!("New event in {}", self.calendars[form.calendar].name)
+                };
+                let mut lines = vec![heading, String::new()];
+                for (index, field) in FORM_FIELDS.iter().enumerate() {
+                    let (marker, cursor) = if index == form.focus { (">", "_") } else { (" ", "") };
+                    lines.push(format!("{} {:<12} {}{}", marker, field, form.values[index], cursor));
+                }
+                return (lines, None);
+            }
+        }
+    }
+
+    // Occurrences under a heading for each day, with times, location and calendar
+    fn list_lines(&self) -> (Vec<String>, Option<usize>) {
+        if self.listed.is_empty() {
+            return (vec![String::from("Nothing planned")], None);
+        }
+        let mut lines: Vec<String> = vec![];
+        let mut highlight: Option<usize> = None;
+        let mut current_day: Option<NaiveDate> = None;
+        for (index, listed) in self.listed.iter().enumerate() {
+            let event = &listed.occurrence;
+            // Expanded occurrences all have a start
+            let start = self.local_zone.convert(event.start_time.unwrap());
+            let day = start.date().naive_local();
+            if current_day != Some(day) {
+                lines.push(start.format("%a %e %b").to_string());
+                current_day = Some(day);
+            }
+            if index == self.selected {
+                highlight = Some(lines.len());
+            }
+            let mut line = format!("  {:<11}  {}", self.times_of(event), event.name.as_deref().unwrap_or("Untitled event"));
+            match &event.location {
+                Some(location) => line.push_str(&format!(" @ {}", location)),
+                None => ()
+            };
+            if event.status == Some(EventStatus::Cancelled) {
+                line.push_str(" (cancelled)");
+            }
+            if self.calendars.len() > 1 {
+                line.push_str(&format!("  [{}]", self.calendars[listed.calendar].name));
+            }
+            lines.push(line);
+        }
+        return (lines, highlight);
+    }
+
+    fn times_of(&self, event: &MpEvent) -> String {
+        let start = self.local_zone.convert(event.start_time.unwrap());
+        match event.end_time.map(|end| self.local_zone.convert(end)) {
+            _ if event.all_day => return String::from("All day"),
+            Some(end) if end.date() != start.date() => return format!("{}-{}", start.format("%H:%M"), end.format("%a %H:%M")),
+            Some(end) => return format!("{}-{}", start.format("%H:%M"), end.format("%H:%M")),
+            None => return start.format("%H:%M").to_string()
+        }
+    }
+
+    fn detail_lines(&self, width: usize) -> Vec<String> {
+        let listed = match self.listed.get(self.selected) {
+            Some(listed) => listed,
+            None => return vec![]
+        };
+        let event = &listed.occurrence;
+        let series = &self.calendars[listed.calendar].events[listed.event];
+        let start = self.local_zone.convert(event.start_time.unwrap());
+        let mut lines = vec![super::name_of(event), String::new()];
+        lines.push(format!("When:      {} {}", start.format("%a %e %b %Y"), self.times_of(event)));
+        match &event.location {
+            Some(location) => lines.push(format!("Where:     {}", location)),
+            None => ()
+        };
+        match &series.rrule {
+            Some(rule) => lines.push(format!("Repeats:   {}", cal_phrase::describe_rule(rule))),
+            None => ()
+        };
+        match event.status {
+            Some(EventStatus::Tentative) => lines.push(String::from("Status:    Tentative")),
+            Some(EventStatus::Confirmed) => lines.push(String::from("Status:    Confirmed")),
+            Some(EventStatus::Cancelled) => lines.push(String::from("Status:    Cancelled")),
+            None => ()
+        };
+        let calendar = &self.calendars[listed.calendar];
+        lines.push(format!("Calendar:  {}{}", calendar.name, if calendar.read_only { " (read-only)" } else { "" }));
+        match &event.description {
+            Some(description) => {
+                lines.push(String::new());
+                lines.extend(wrap_text(description, width));
+            },
+            None => ()
+        };
+        return lines;
+    }
+
+    // Open tasks by due date and priority, then the reminders due in the next REMINDER_HOURS
+    fn side_pane(&self) -> Vec<String> {
+        let mut lines = vec![String::from(" Tasks")];
+        let open: Vec<MpTask> = self.calendars.iter()
+            .flat_map(|calendar| calendar.tasks.iter().filter(|task| !task.is_complete()).cloned())
+            .collect();
+        if open.is_empty() {
+            lines.push(String::from("  No open tasks"));
+        }
+        for task in cal_ops::sort_mptasks_by_due_and_priority(open) {
+            let due = task.due().map(|due| format!(" (due {})", self.local_zone.convert(due).format("%e %b").to_string().trim_start())).unwrap_or_default();
+            lines.push(format!("  • {}{}", task.name().unwrap_or("Untitled task"), due));
+        }
+        lines.push(String::new());
+        lines.push(String::from(" Reminders"));
+        let events: Vec<MpEvent> = self.calendars.iter().flat_map(|calendar| calendar.events.iter().cloned()).collect();
+        let upcoming = cal_alarm::upcoming_alarms(&events, self.now, self.now + Duration::hours(REMINDER_HOURS));
+        if upcoming.is_empty() {
+            lines.push(String::from("  Nothing in the next day"));
+        }
+        for alarm in upcoming {
+            let body = alarm.body.strip_prefix("Reminder: ").unwrap_or(&alarm.body);
+            let time = self.local_zone.convert(alarm.due).format("%a %H:%M").to_string();
+            // Wrapped under the time, which takes the first twelve columns
+            for (index, part) in wrap_text(body, SIDE_PANE_WIDTH - 12).into_iter().enumerate() {
+                lines.push(format!("  {:<9} {}", if index == 0 { time.as_str() } else { "" }, part));
+            }
+        }
+        return lines;
+    }
+
+    fn status_line(&self) -> String {
+        if !self.status.is_empty() {
+            return format!(" {}", self.status);
+        }
+        let help = match &self.mode {
+            Mode::Browse => "←/→ move  d/w/m day/week/month  t today  g grid  ↑/↓ select  Enter open  a add  n new  e edit  x delete  q quit",
+            Mode::Grid => "←/→ move  w/m week/month  t today  g list  q quit",
+            Mode::Detail => "e edit  x delete  Esc back",
+            Mode::ConfirmDelete => "Delete this event, and every occurrence if it repeats? y deletes, any other key keeps it",
+            Mode::Phrase(_) => "Describe the event, e.g. lunch with Priya tomorrow 1pm at the canteen  Enter check  Esc cancel",
+            Mode::Form(_) => "Tab next field  Enter save  Esc cancel  Dates are 2024-05-01 09:30, or 2024-05-01 for all day"
+        };
+        return format!(" {}", help);
+    }
+}
+
+// text broken into lines of at most width characters at spaces, keeping its own line breaks
+fn wrap_text(text: &str, width: usize) -> Vec<String> {
+    let mut lines: Vec<String> = vec![];
+    for paragraph in text.lines() {
+        let mut line = String::new();
+        for word in paragraph.split_whitespace() {
+            if !line.is_empty() && line.chars().count() + 1 + word.chars().count() > width {
+                lines.push(std::mem::take(&mut line));
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(word);
+        }
+        lines.push(line);
+    }
+    return lines;
+}
+
+/// Runs the dashboard full screen until it's quit, then puts the terminal back as it was
+pub fn run(config: &Config) -> Result<(), MpCalendarError> {
+    let now = || Utc::now().with_timezone(&FixedOffset::east(0));
+    let io_error = |e: std::io::Error| MpCalendarError::Io { path: String::from("the terminal"), source: e };
+    let mut state = TuiState::load(config, now());
+    let mut stdout = std::io::stdout();
+    terminal::enable_raw_mode().map_err(io_error)?;
+    // A panic puts the terminal back before its message is printed, or the message would go with
+    // the alternate screen and leave the shell in raw mode
+    let previous_hook = std::sync::Arc::new(std::panic::take_hook());
+    let hook_after_restoring = previous_hook.clone();
+    std::panic::set_hook(Box::new(move |info| {
+        restore_terminal();
+        (*hook_after_restoring)(info);
+    }));
+    let result = stdout.execute(terminal::EnterAlternateScreen)
+        .and_then(|stdout| stdout.execute(cursor::Hide))
+        .and_then(|stdout| {
+            while !state.is_finished() {
+                let (width, height) = terminal::size()?;
+                for (row, line) in state.render(width as usize, height as usize).iter().enumerate() {
+                    stdout.queue(cursor::MoveTo(0, row as u16))?.queue(style::Print(line))?;
+                }
+                stdout.flush()?;
+                if event::poll(std::time::Duration::from_secs(REFRESH_SECONDS))? {
+                    match event::read()? {
+                        Event::Key(key) => match TuiKey::from_key_event(key) {
+                            Some(key) => state.handle_key(key),
+                            None => ()
+                        },
+                        Event::Resize(_, _) => { stdout.queue(terminal::Clear(terminal::ClearType::All))?; },
+                        _ => ()
+                    };
+                }
+                state.set_now(now());
+            }
+            return Ok(());
+        });
+    // Put the terminal back even if drawing failed
+    restore_terminal();
+    drop(std::panic::take_hook());
+    std::panic::set_hook(Box::new(move |info| (*previous_hook)(info)));
+    return result.map_err(io_error);
+}
+
+// Shows the cursor, leaves the alternate screen and turns raw mode off
+fn restore_terminal() {
+    let _ = std::io::stdout().execute(cursor::Show).and_then(|stdout| stdout.execute(terminal::LeaveAlternateScreen));
+    let _ = terminal::disable_raw_mode();
+}
+
+#[cfg(test)]
+mod cal_tui_tests {
+    use super::*;
+    use crate::mp_core::core_config::CalendarConfig;
+    use crate::mp_calendar::test_support;
+
+    fn test_config(calendars: Vec<CalendarConfig>) -> Config {
+        return test_support::test_config(Some("Europe/London"), calendars);
+    }
+
+    fn press(state: &mut TuiState, keys: &str) {
+        for key in keys.chars() {
+            state.handle_key(TuiKey::Char(key));
+        }
+    }
+
+    #[test]
+    fn test_browse_and_render() {
+        let config = test_config(vec![CalendarConfig { path: String::from("src/test/test_alarms.ics"), read_only: true, ..Default::default() }]);
+        let mut state = TuiState::load(&config, DateTime::parse_from_rfc3339("2024-01-08T08:00:00+00:00").unwrap());
+        let screen = state.render(100, 14);
+        assert_eq!(14, screen.len());
+        assert_eq!(" Week of Mon  8 Jan 2024                                                           Mon  8 Jan 08:00 ", screen[0]);
+        assert_eq!("Mon  8 Jan                                                     │ Tasks                              ", screen[1]);
+        // The selected event is in reverse video
+        assert_eq!(format!("\x1b[7m{:<63}\x1b[0m│{:<36}", "  09:00-09:15  Team standup @ Room 2", "  No open tasks"), screen[2]);
+        // The standup's reminder and its repeats
+        let side: Vec<&str> = screen[4..9].iter().map(|line| line.split('│').nth(1).unwrap().trim_end()).collect();
+        assert_eq!(vec![" Reminders", "  Mon 08:45 Team standup at Mon 8", "            Jan 09:00, Room 2", "  Mon 08:50 Team standup at Mon 8", "            Jan 09:00, Room 2"], side);
+        // The fixture's alarm with a TRIGGER of "soon" is skipped, and said so until a key is pressed
+        assert!(screen[13].starts_with(" Skipping malformed alarm: "), "{}", screen[13]);
+        press(&mut state, "j");
+        assert!(state.render(100, 14)[13].starts_with(" ←/→ move"));
+
+        press(&mut state, "jj");
+        state.handle_key(TuiKey::Enter);
+        let detail = state.render(100, 14);
+        assert_eq!("Team standup (late start)", detail[1].split('│').next().unwrap().trim_end());
+        assert!(detail[3].starts_with("When:      Wed 10 Jan 2024 11:00-11:15 "));
+        assert!(detail[4].starts_with("Repeats:   every day "));
+        assert!(detail[5].starts_with("Calendar:  src/test/test_alarms.ics (read-only) "));
+        press(&mut state, "x");
+        assert_eq!(" src/test/test_alarms.ics is read-only", state.render(100, 14)[13].trim_end());
+
+        state.handle_key(TuiKey::Esc);
+        press(&mut state, "ml");
+        assert_eq!(" February 2024", &state.render(100, 14)[0][..14]);
+        assert!(state.render(100, 14)[1].starts_with("Nothing planned "));
+        press(&mut state, "hwg");
+        // Back to the start of January, in a grid that takes the whole width
+        assert!(state.render(120, 30)[1].starts_with("       |Mon  1 Jan    |Tue  2 Jan    |"));
+        press(&mut state, "q");
+        assert!(state.is_finished());
+    }
+
+    #[test]
+    fn test_create_edit_and_delete_write_back() {
+        let dir = test_support::scratch_dir("tui");
+        let path = dir.join("work.ics").to_string_lossy().to_string();
+        std::fs::copy("src/test/test_tasks.ics", &path).unwrap();
+        let config = test_config(vec![CalendarConfig { path: path.clone(), name: Some(String::from("Work")), ..Default::default() }]);
+        let london = MpTimeZone::from_iana_name("Europe/London").unwrap();
+        let read_back = || {
+            let cal = cal_io::parse_file_to_ical_calendar(path.clone()).unwrap();
+            return (cal_io::extract_events_from_ical(cal.clone(), &london).unwrap(), cal_io::extract_tasks_from_ical(cal, &london).unwrap());
+        };
+        // The file now declares the zone of the events it gains, so the tasks' own text is compared
+        let vtodos = || {
+            let text = std::fs::read_to_string(&path).unwrap();
+            return text.split("BEGIN:VTODO").skip(1).map(|todo| String::from(&todo[..todo.find("END:VTODO").unwrap()])).collect::<Vec<String>>();
+        };
+        let tasks_before = vtodos();
+        // Including the one with a bad priority, which isn't read as a task but is still kept
+        assert_eq!(5, tasks_before.len());
+        let mut state = TuiState::load(&config, DateTime::parse_from_rfc3339("2024-03-04T08:00:00+00:00").unwrap());
+
+        press(&mut state, "a");
+        press(&mut state, "dentist tomorrow 3pm at the surgery");
+        state.handle_key(TuiKey::Enter);
+        assert!(state.render(100, 12)[4].starts_with("  Start        2024-03-05 15:00 "));
+        state.handle_key(TuiKey::Enter);
+        assert_eq!(" Saved Dentist to Work", state.render(100, 12)[11].trim_end());
+        let (events, tasks) = read_back();
+        assert_eq!(4, tasks.len());
+        assert_eq!(vec!["Launch planning", "Dentist"], events.iter().map(|event| event.name().unwrap()).collect::<Vec<&str>>());
+        assert_eq!(Some(String::from("the surgery")), events[1].location);
+        assert_eq!(tasks_before, vtodos());
+
+        // The new event is selected, so edit its location and push it back an hour
+        press(&mut state, "e");
+        state.handle_key(TuiKey::Tab);
+        for _ in 0..5 {
+            state.handle_key(TuiKey::Backspace);
+        }
+        press(&mut state, "16:00");
+        for _ in 0..2 {
+            state.handle_key(TuiKey::Tab);
+        }
+        for _ in 0.."the surgery".len() {
+            state.handle_key(TuiKey::Backspace);
+        }
+        press(&mut state, "Harley Street");
+        state.handle_key(TuiKey::Enter);
+        let (events, _) = read_back();
+        assert_eq!(Some(String::from("Harley Street")), events[1].location);
+        assert_eq!("2024-03-05T16:00:00+00:00", events[1].start_time().unwrap().to_rfc3339());
+        // The end wasn't changed, so it moves with the start
+        assert_eq!("2024-03-05T17:00:00+00:00", events[1].end_time().unwrap().to_rfc3339());
+        assert_eq!(Some(1), events[1].sequence());
+
+        // A bad date keeps the form open and says why
+        press(&mut state, "e");
+        state.handle_key(TuiKey::Tab);
+        press(&mut state, "x");
+        state.handle_key(TuiKey::Enter);
+        assert!(state.render(100, 12)[11].starts_with(" '2024-03-05 16:00x' should look like 2024-05-01 09:30"));
+        state.handle_key(TuiKey::Esc);
+
+        press(&mut state, "xn");
+        assert_eq!(2, read_back().0.len());
+        press(&mut state, "xy");
+        assert_eq!(" Deleted Dentist", state.render(100, 12)[11].trim_end());
+        let (events, tasks) = read_back();
+        assert_eq!(4, tasks.len());
+        assert_eq!(1, events.len());
+        assert_eq!(tasks_before, vtodos());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_edit_keeps_outlook_timezone() {
+        let dir = test_support::scratch_dir("tui_outlook");
+        let path = dir.join("outlook.ics").to_string_lossy().to_string();
+        std::fs::copy("src/test/test_outlook.ics", &path).unwrap();
+        let original = std::fs::read_to_string(&path).unwrap();
+        let vtimezone = &original[original.find("BEGIN:VTIMEZONE").unwrap()..original.find("BEGIN:VEVENT").unwrap()];
+        let config = test_config(vec![CalendarConfig { path: path.clone(), name: Some(String::from("Outlook")), ..Default::default() }]);
+        let mut state = TuiState::load(&config, DateTime::parse_from_rfc3339("2024-03-12T08:00:00+00:00").unwrap());
+
+        press(&mut state, "e");
+        state.handle_key(TuiKey::Enter);
+        assert_eq!(" Saved Quarterly review to Outlook", state.render(100, 12)[11].trim_end());
+        let saved = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(saved.starts_with("BEGIN:VCALENDAR\r\nPRODID:-//Microsoft Corporation//Outlook 16.0 MIMEDIR//EN\r\nVERSION:2.0\r\nMETHOD:PUBLISH\r\n"));
+        // The file's own definition of the Windows zone is kept rather than written out again
+        assert_eq!(1, saved.matches("BEGIN:VTIMEZONE").count());
+        assert!(saved.contains(vtimezone));
+        assert!(saved.contains("DTSTART;TZID=GMT Standard Time:20240312T100000\r\n"));
+        assert!(saved.contains("SEQUENCE:1\r\n"));
+    }
+}
@@ -0,0 +1,560 @@
+use std::str::FromStr;
+use chrono::prelude::*;
+use chrono::{Duration, LocalResult};
+use chrono_tz::{Tz, OffsetComponents, OffsetName};
+use ical::parser::ical::component::{IcalTimeZone, IcalTimeZoneTransition};
+use super::cal_recur::{RecurrenceRule, RecurrenceUntil, Frequency, WeekdayNum};
+
+/// One STANDARD or DAYLIGHT block of a VTIMEZONE. DTSTART and RDATEs are local times in offset_from.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ZoneTransition {
+    pub is_daylight: bool,
+    pub dtstart: NaiveDateTime,
+    pub offset_from: FixedOffset,
+    pub offset_to: FixedOffset,
+    pub name: Option<String>,
+    pub rrule: Option<RecurrenceRule>,
+    pub rdates: Vec<NaiveDateTime>
+}
+
+impl ZoneTransition {
+    /// UTC instants at which this transition takes effect, up to and including limit
+    fn onsets_until(&self, limit: NaiveDateTime) -> Vec<NaiveDateTime> {
+        let local_limit = limit + self.offset_from + Duration::seconds(1);
+        let mut local_onsets: Vec<NaiveDateTime> = match &self.rrule {
+            Some(rule) => rule.occurrences(self.dtstart, &MpTimeZone::fixed(self.offset_from), local_limit),
+            None => vec![self.dtstart]
+        };
+        local_onsets.extend(self.rdates.iter().cloned());
+        return local_onsets.into_iter()
+            .map(|local| local - self.offset_from)
+            .filter(|onset| *onset <= limit)
+            .collect();
+    }
+}
+
+// VTIMEZONE onsets are worked out once up to the start of this year, so that finding the offset
+// at an instant doesn't walk every rule from its DTSTART (often 1601) each time
+const ONSET_TABLE_END_YEAR: i32 = 2200;
+
+#[derive(Clone)]
+enum ZoneRules {
+    Fixed(FixedOffset),
+    Iana(Tz),
+    // The blocks as read, and every onset before ONSET_TABLE_END_YEAR with the offset it brings in,
+    // in order
+    Vtimezone(Vec<ZoneTransition>, Vec<(NaiveDateTime, FixedOffset)>)
+}
+
+// The onset table is derived from the transitions, so it's left out of comparisons and debug output
+impl PartialEq for ZoneRules {
+    fn eq(&self, other: &ZoneRules) -> bool {
+        match (self, other) {
+            (ZoneRules::Fixed(lhs), ZoneRules::Fixed(rhs)) => return lhs == rhs,
+            (ZoneRules::Iana(lhs), ZoneRules::Iana(rhs)) => return lhs == rhs,
+            (ZoneRules::Vtimezone(lhs, _), ZoneRules::Vtimezone(rhs, _)) => return lhs == rhs,
+            _ => return false
+        }
+    }
+}
+
+impl Eq for ZoneRules {}
+
+impl std::fmt::Debug for ZoneRules {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ZoneRules::Fixed(offset) => return f.debug_tuple("Fixed").field(offset).finish(),
+            ZoneRules::Iana(tz) => return f.debug_tuple("Iana").field(tz).finish(),
+            ZoneRules::Vtimezone(transitions, _) => return f.debug_tuple("Vtimezone").field(transitions).finish()
+        }
+    }
+}
+
+impl ZoneRules {
+    fn vtimezone(transitions: Vec<ZoneTransition>) -> ZoneRules {
+        let table_end = NaiveDate::from_ymd(ONSET_TABLE_END_YEAR, 1, 1).and_hms(0, 0, 0);
+        let mut onsets: Vec<(NaiveDateTime, FixedOffset)> = transitions.iter()
+            .flat_map(|transition| {
+                transition.onsets_until(table_end).into_iter().map(move |onset| (onset, transition.offset_to))
+            })
+            .filter(|(onset, _)| *onset < table_end)
+            .collect();
+        onsets.sort_by_key(|(onset, _)| *onset);
+        return ZoneRules::Vtimezone(transitions, onsets);
+    }
+}
+
+/// A named time zone an event's local times are expressed in. Zones are equal when both the TZID
+/// and the rules match, so a calendar's own "Europe/London" VTIMEZONE isn't mistaken for the IANA
+/// zone of that name.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct MpTimeZone {
+    tzid: String,
+    rules: ZoneRules
+}
+
+impl MpTimeZone {
+    pub fn utc() -> MpTimeZone {
+        return MpTimeZone { tzid: String::from("UTC"), rules: ZoneRules::Fixed(FixedOffset::east(0)) };
+    }
+
+    pub fn fixed(offset: FixedOffset) -> MpTimeZone {
+        return MpTimeZone { tzid: offset.to_string(), rules: ZoneRules::Fixed(offset) };
+    }
+
+    /// Looks a TZID up in the embedded IANA database. Prefixed forms such as
+    /// "/mozilla.org/20050126_1/Europe/London" are matched on their trailing Area/Location.
+    pub fn from_iana_name(name: &str) -> Option<MpTimeZone> {
+        let segments: Vec<&str> = name.trim_matches('/').split('/').collect();
+        for first in 0..segments.len() {
+            let candidate = segments[first..].join("/");
+            match Tz::from_str(&candidate) {
+                Ok(tz) => return Some(MpTimeZone { tzid: String::from(name), rules: ZoneRules::Iana(tz) }),
+                Err(_) => ()
+            };
+        }
+        return None;
+    }
+
+    /// Builds a zone from a calendar's own VTIMEZONE block
+    pub fn from_vtimezone(vtimezone: &IcalTimeZone) -> Result<MpTimeZone, String> {
+        let tzid = vtimezone.properties.iter()
+            .find(|prop| prop.name == "TZID")
+            .and_then(|prop| prop.value.clone())
+            .ok_or_else(|| String::from("VTIMEZONE has no TZID"))?;
+        let mut transitions: Vec<ZoneTransition> = vec![];
+        for transition in &vtimezone.transitions {
+            let parsed = parse_zone_transition(transition)
+                .map_err(|e| format!("VTIMEZONE {}: {}", tzid, e))?;
+            transitions.push(parsed);
+        }
+        if transitions.is_empty() {
+            return Err(format!("VTIMEZONE {} has no STANDARD or DAYLIGHT blocks", tzid));
+        }
+        return Ok(MpTimeZone { tzid, rules: ZoneRules::vtimezone(transitions) });
+    }
+
+    pub fn tzid(&self) -> &str {
+        return &self.tzid;
+    }
+
+    pub fn is_utc(&self) -> bool {
+        return self.rules == ZoneRules::Fixed(FixedOffset::east(0));
+    }
+
+    /// A bare UTC offset with no name of its own, which is written out as UTC rather than by TZID
+    pub fn is_fixed(&self) -> bool {
+        match self.rules {
+            ZoneRules::Fixed(_) => return true,
+            _ => return false
+        }
+    }
+
+    /// The STANDARD and DAYLIGHT blocks describing this zone from the start of from_year to the end
+    /// of to_year, for writing out as a VTIMEZONE. IANA zones are scanned for offset changes, which
+    /// are folded into yearly rules where the pattern repeats, so the final rules carry on past to_year.
+    pub fn transitions_between(&self, from_year: i32, to_year: i32) -> Vec<ZoneTransition> {
+        match &self.rules {
+            ZoneRules::Vtimezone(transitions, _) => return transitions.clone(),
+            ZoneRules::Fixed(offset) => return vec![constant_transition(*offset, None)],
+            ZoneRules::Iana(tz) => {
+                let onsets = scan_iana_transitions(*tz, from_year, to_year);
+                if onsets.is_empty() {
+                    let offset = tz.offset_from_utc_datetime(&NaiveDate::from_ymd(from_year, 1, 1).and_hms(0, 0, 0));
+                    return vec![constant_transition(offset.fix(), Some(String::from(offset.abbreviation())))];
+                }
+                return fold_into_yearly_rules(onsets);
+            }
+        }
+    }
+
+    /// The VTIMEZONE transitions this zone was defined with, if it came from a calendar
+    pub fn vtimezone_transitions(&self) -> Option<&Vec<ZoneTransition>> {
+        match &self.rules {
+            ZoneRules::Vtimezone(transitions, _) => return Some(transitions),
+            _ => return None
+        }
+    }
+
+    /// The UTC offset in force at a UTC instant
+    pub fn offset_at_utc(&self, utc: NaiveDateTime) -> FixedOffset {
+        match &self.rules {
+            ZoneRules::Fixed(offset) => return *offset,
+            ZoneRules::Iana(tz) => return tz.offset_from_utc_datetime(&utc).fix(),
+            ZoneRules::Vtimezone(transitions, onsets) => {
+                let table_end = NaiveDate::from_ymd(ONSET_TABLE_END_YEAR, 1, 1).and_hms(0, 0, 0);
+                let latest = if utc < table_end {
+                    let index = onsets.partition_point(|(onset, _)| *onset <= utc);
+                    if index == 0 { None } else { Some(onsets[index - 1]) }
+                } else {
+                    transitions.iter()
+                        .flat_map(|transition| {
+                            transition.onsets_until(utc).into_iter().map(move |onset| (onset, transition.offset_to))
+                        })
+                        .max_by_key(|(onset, _)| *onset)
+                };
+                match latest {
+                    Some((_, offset)) => return offset,
+                    None => {
+                        // Before the first transition the zone is at the earliest block's TZOFFSETFROM
+                        let earliest = transitions.iter().min_by_key(|transition| transition.dtstart);
+                        return earliest.map(|transition| transition.offset_from).unwrap_or_else(|| FixedOffset::east(0));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Resolves a local wall time in this zone. Per RFC 5545, a time repeated when clocks go back
+    /// takes its first occurrence, and a time skipped when clocks go forward uses the offset before the gap.
+    pub fn from_local_datetime(&self, local: NaiveDateTime) -> DateTime<FixedOffset> {
+        let offset = match &self.rules {
+            ZoneRules::Fixed(offset) => *offset,
+            ZoneRules::Iana(tz) => match tz.offset_from_local_datetime(&local) {
+                LocalResult::Single(offset) => offset.fix(),
+                LocalResult::Ambiguous(first, second) => {
+                    if first.fix().local_minus_utc() > second.fix().local_minus_utc() { first.fix() } else { second.fix() }
+                }
+                LocalResult::None => tz.offset_from_utc_datetime(&(local - Duration::days(1))).fix()
+            },
+            ZoneRules::Vtimezone(transitions, _) => {
+                let mut candidates: Vec<FixedOffset> = transitions.iter()
+                    .flat_map(|transition| vec![transition.offset_from, transition.offset_to])
+                    .collect();
+                // Largest offset first gives the earliest instant when a local time is ambiguous
+                candidates.sort_by_key(|offset| -offset.local_minus_utc());
+                candidates.dedup();
+                let valid = candidates.into_iter()
+                    .find(|offset| self.offset_at_utc(local - *offset) == *offset);
+                match valid {
+                    Some(offset) => offset,
+                    None => self.offset_at_utc(local - Duration::days(1))
+                }
+            }
+        };
+        return offset.from_local_datetime(&local).unwrap();
+    }
+
+    /// The same instant expressed with this zone's offset
+    pub fn convert(&self, time: DateTime<FixedOffset>) -> DateTime<FixedOffset> {
+        let offset = self.offset_at_utc(time.naive_utc());
+        return time.with_timezone(&offset);
+    }
+}
+
+/// The zones available while reading one calendar: its VTIMEZONE blocks, the IANA database,
+/// and the configured local zone for floating times
+#[derive(Debug, Clone)]
+pub struct TimeZoneContext {
+    local_zone: MpTimeZone,
+    calendar_zones: Vec<MpTimeZone>
+}
+
+impl TimeZoneContext {
+    pub fn new(local_zone: MpTimeZone) -> TimeZoneContext {
+        return TimeZoneContext { local_zone, calendar_zones: vec![] };
+    }
+
+    /// Invalid VTIMEZONE blocks are reported and left out, so their TZIDs fall back to the IANA database
+    pub fn from_ical_timezones(timezones: &[IcalTimeZone], local_zone: MpTimeZone) -> TimeZoneContext {
+        let mut context = TimeZoneContext::new(local_zone);
+        for vtimezone in timezones {
+            match MpTimeZone::from_vtimezone(vtimezone) {
+                Ok(zone) => context.calendar_zones.push(zone),
+                Err(e) => super::output_mp_calendar_message(format!("Ignoring invalid VTIMEZONE: {}", e))
+            }
+        }
+        return context;
+    }
+
+    pub fn local_zone(&self) -> &MpTimeZone {
+        return &self.local_zone;
+    }
+
+    /// Calendar VTIMEZONE definitions take precedence over the IANA database
+    pub fn resolve_tzid(&self, tzid: &str) -> Option<MpTimeZone> {
+        match self.calendar_zones.iter().find(|zone| zone.tzid == tzid) {
+            Some(zone) => return Some(zone.clone()),
+            None => return MpTimeZone::from_iana_name(tzid)
+        }
+    }
+}
+
+/// The local zone named in the config, falling back to UTC if it's missing or unknown
+pub fn configured_local_zone(tzid: Option<&str>) -> MpTimeZone {
+    let tzid = match tzid {
+        Some(tzid) => tzid,
+        None => return MpTimeZone::utc()
+    };
+    match MpTimeZone::from_iana_name(tzid) {
+        Some(zone) => return zone,
+        None => {
+            super::output_mp_calendar_message(format!("Unknown timezone '{}' in config, using UTC", tzid));
+            return MpTimeZone::utc();
+        }
+    }
+}
+
+fn constant_transition(offset: FixedOffset, name: Option<String>) -> ZoneTransition {
+    return ZoneTransition {
+        is_daylight: false,
+        dtstart: NaiveDate::from_ymd(1970, 1, 1).and_hms(0, 0, 0),
+        offset_from: offset,
+        offset_to: offset,
+        name,
+        rrule: None,
+        rdates: vec![]
+    };
+}
+
+/// Every change of offset in an IANA zone within the years, as single-onset transitions
+fn scan_iana_transitions(tz: Tz, from_year: i32, to_year: i32) -> Vec<ZoneTransition> {
+    let mut transitions: Vec<ZoneTransition> = vec![];
+    let end = NaiveDate::from_ymd(to_year + 1, 1, 1).and_hms(0, 0, 0);
+    let mut previous = NaiveDate::from_ymd(from_year, 1, 1).and_hms(0, 0, 0);
+    let mut previous_offset = tz.offset_from_utc_datetime(&previous);
+    while previous < end {
+        let next = previous + Duration::hours(12);
+        let next_offset = tz.offset_from_utc_datetime(&next);
+        if next_offset != previous_offset {
+            // Bisect down to the second the change happens
+            let (mut before, mut after) = (previous, next);
+            while after - before > Duration::seconds(1) {
+                let middle = before + Duration::seconds((after - before).num_seconds() / 2);
+                if tz.offset_from_utc_datetime(&middle) == previous_offset {
+                    before = middle;
+                } else {
+                    after = middle;
+                }
+            }
+            transitions.push(ZoneTransition {
+                is_daylight: next_offset.dst_offset() > Duration::zero(),
+                dtstart: after + previous_offset.fix(),
+                offset_from: previous_offset.fix(),
+                offset_to: next_offset.fix(),
+                name: Some(String::from(next_offset.abbreviation())),
+                rrule: None,
+                rdates: vec![]
+            });
+        }
+        previous = next;
+        previous_offset = next_offset;
+    }
+    return transitions;
+}
+
+/// Describes an onset as a yearly rule day, e.g. the last Sunday of March
+fn yearly_pattern(onset: NaiveDateTime) -> (u32, WeekdayNum, NaiveTime) {
+    let day = onset.day();
+    let days_in_month = NaiveDate::from_ymd_opt(onset.year(), onset.month(), 1)
+        .and_then(|first| (first + Duration::days(31)).with_day(1))
+        .map(|next_first| (next_first - Duration::days(1)).day())
+        .unwrap_or(31);
+    let ordinal = if day + 7 > days_in_month { -1 } else { ((day - 1) / 7 + 1) as i32 };
+    return (onset.month(), WeekdayNum { ordinal: Some(ordinal), weekday: onset.weekday() }, onset.time());
+}
+
+/// Collapses runs of same-shaped transitions in consecutive years into one block with a yearly RRULE.
+/// Every run but the last of each kind is closed off with an UNTIL so that later rules take over.
+fn fold_into_yearly_rules(onsets: Vec<ZoneTransition>) -> Vec<ZoneTransition> {
+    let mut runs: Vec<Vec<ZoneTransition>> = vec![];
+    for onset in onsets {
+        let continues_run = runs.iter_mut().rev()
+            .find(|run| run[0].is_daylight == onset.is_daylight)
+            .filter(|run| {
+                let last = &run[run.len() - 1];
+                last.offset_from == onset.offset_from && last.offset_to == onset.offset_to && last.name == onset.name
+                    && last.dtstart.year() + 1 == onset.dtstart.year()
+                    && yearly_pattern(last.dtstart) == yearly_pattern(onset.dtstart)
+            });
+        match continues_run {
+            Some(run) => run.push(onset),
+            None => runs.push(vec![onset])
+        };
+    }
+    let mut transitions: Vec<ZoneTransition> = vec![];
+    for (index, run) in runs.iter().enumerate() {
+        let mut first = run[0].clone();
+        let is_last_of_kind = !runs[index + 1..].iter().any(|later| later[0].is_daylight == first.is_daylight);
+        if run.len() > 1 || is_last_of_kind {
+            let (month, by_day, _) = yearly_pattern(first.dtstart);
+            let mut rule = RecurrenceRule::new(Frequency::Yearly);
+            rule.by_month = vec![month];
+            rule.by_day = vec![by_day];
+            if !is_last_of_kind {
+                let last = &run[run.len() - 1];
+                rule.until = Some(RecurrenceUntil::Utc(last.dtstart - last.offset_from));
+            }
+            first.rrule = Some(rule);
+        }
+        transitions.push(first);
+    }
+    return transitions;
+}
+
+/// Formats a UTC offset as +HHMM, or +HHMMSS when it has seconds
+pub fn format_utc_offset(offset: FixedOffset) -> String {
+    let total = offset.local_minus_utc();
+    let sign = if total < 0 { '-' } else { '+' };
+    let total = total.abs();
+    let (hours, minutes, seconds) = (total / 3600, (total % 3600) / 60, total % 60);
+    if seconds != 0 {
+        return format!("{}{:02}{:02}{:02}", sign, hours, minutes, seconds);
+    }
+    return format!("{}{:02}{:02}", sign, hours, minutes);
+}
+
+/// Parses a UTC offset of the form +HHMM or -HHMMSS
+pub fn parse_utc_offset(input: &str) -> Option<FixedOffset> {
+    let input = input.trim();
+    // Checked before slicing, so that short or non-ASCII input can't split a character
+    if (input.len() != 5 && input.len() != 7) || !input.bytes().skip(1).all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let sign = match input.as_bytes()[0] {
+        b'+' => 1,
+        b'-' => -1,
+        _ => return None
+    };
+    let hours = input[1..3].parse::<i32>().ok()?;
+    let minutes = input[3..5].parse::<i32>().ok()?;
+    let seconds = if input.len() == 7 { input[5..7].parse::<i32>().ok()? } else { 0 };
+    return FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60 + seconds));
+}
+
+fn parse_zone_transition(transition: &IcalTimeZoneTransition) -> Result<ZoneTransition, String> {
+    let mut dtstart: Option<NaiveDateTime> = None;
+    let mut offset_from: Option<FixedOffset> = None;
+    let mut offset_to: Option<FixedOffset> = None;
+    let mut name: Option<String> = None;
+    let mut rrule: Option<RecurrenceRule> = None;
+    let mut rdates: Vec<NaiveDateTime> = vec![];
+    let mut is_daylight = false;
+    for prop in &transition.properties {
+        let value = match &prop.value {
+            Some(value) => value,
+            None => continue
+        };
+        match prop.name.as_str() {
+            "DTSTART" => dtstart = Some(parse_local_time(value)?),
+            "TZOFFSETFROM" => offset_from = Some(parse_utc_offset(value).ok_or(format!("bad TZOFFSETFROM {}", value))?),
+            "TZOFFSETTO" => offset_to = Some(parse_utc_offset(value).ok_or(format!("bad TZOFFSETTO {}", value))?),
+            "TZNAME" => name = Some(value.clone()),
+            "RRULE" => rrule = Some(RecurrenceRule::from_str(value)?),
+            "RDATE" => {
+                for rdate in value.split(',') {
+                    rdates.push(parse_local_time(rdate)?);
+                }
+            }
+            _ => ()
+        };
+    }
+    // The ical parser drops the BEGIN line, so STANDARD and DAYLIGHT are told apart by their offsets
+    match (offset_from, offset_to) {
+        (Some(from), Some(to)) => is_daylight = to.local_minus_utc() > from.local_minus_utc(),
+        _ => ()
+    };
+    return Ok(ZoneTransition {
+        is_daylight,
+        dtstart: dtstart.ok_or("transition has no DTSTART")?,
+        offset_from: offset_from.ok_or("transition has no TZOFFSETFROM")?,
+        offset_to: offset_to.ok_or("transition has no TZOFFSETTO")?,
+        name,
+        rrule,
+        rdates
+    });
+}
+
+fn parse_local_time(input: &str) -> Result<NaiveDateTime, String> {
+    return NaiveDateTime::parse_from_str(input.trim_end_matches('Z'), "%Y%m%dT%H%M%S")
+        .map_err(|e| format!("bad local time {}: {}", input, e));
+}
+
+#[cfg(test)]
+mod cal_tz_tests {
+    use super::*;
+    use crate::mp_calendar::cal_io::parse_file_to_ical_calendar;
+
+    fn naive(input: &str) -> NaiveDateTime {
+        return NaiveDateTime::parse_from_str(input, "%Y%m%dT%H%M%S").unwrap();
+    }
+
+    #[test]
+    fn test_parse_utc_offset() {
+        assert_eq!(Some(FixedOffset::east(3600)), parse_utc_offset("+0100"));
+        assert_eq!(Some(FixedOffset::west(5 * 3600 + 30 * 60 + 15)), parse_utc_offset("-053015"));
+        assert_eq!(None, parse_utc_offset("0100"));
+        assert_eq!(None, parse_utc_offset("+1"));
+        assert_eq!(None, parse_utc_offset("+01é"));
+        assert_eq!(None, parse_utc_offset("é0100"));
+        assert_eq!(None, parse_utc_offset("++100"));
+    }
+
+    #[test]
+    fn test_iana_transitions_fold_into_yearly_rules() {
+        let london = MpTimeZone::from_iana_name("Europe/London").unwrap();
+        let transitions = london.transitions_between(2020, 2024);
+        assert_eq!(2, transitions.len());
+        let daylight = transitions.iter().find(|transition| transition.is_daylight).unwrap();
+        assert_eq!(naive("20200329T010000"), daylight.dtstart);
+        assert_eq!("FREQ=YEARLY;BYDAY=-1SU;BYMONTH=3", daylight.rrule.as_ref().unwrap().to_string());
+        assert_eq!(Some(String::from("BST")), daylight.name);
+        assert_eq!("+0100", format_utc_offset(daylight.offset_to));
+        // The folded rules reproduce the zone well beyond the scanned years
+        let rebuilt = MpTimeZone { tzid: String::from("Europe/London"), rules: ZoneRules::vtimezone(transitions) };
+        for local in &["20300715T120000", "20301215T120000", "20300331T013000"] {
+            assert_eq!(london.from_local_datetime(naive(local)), rebuilt.from_local_datetime(naive(local)));
+        }
+        // Past the precomputed onsets the rules are walked instead
+        let far = NaiveDate::from_ymd(2250, 7, 1).and_hms(12, 0, 0);
+        assert_eq!(FixedOffset::east(3600), rebuilt.offset_at_utc(far));
+        assert_ne!(london, rebuilt);
+        assert_eq!(rebuilt.clone(), rebuilt);
+        let tokyo = MpTimeZone::from_iana_name("Asia/Tokyo").unwrap().transitions_between(2020, 2024);
+        assert_eq!(1, tokyo.len());
+        assert_eq!(FixedOffset::east(9 * 3600), tokyo[0].offset_to);
+    }
+
+    #[test]
+    fn test_iana_zone_resolution() {
+        let london = MpTimeZone::from_iana_name("Europe/London").unwrap();
+        assert_eq!(FixedOffset::east(0), london.from_local_datetime(naive("20240115T090000")).offset().fix());
+        assert_eq!(FixedOffset::east(3600), london.from_local_datetime(naive("20240715T090000")).offset().fix());
+        let prefixed = MpTimeZone::from_iana_name("/mozilla.org/20050126_1/Europe/London").unwrap();
+        assert_eq!("/mozilla.org/20050126_1/Europe/London", prefixed.tzid());
+        assert!(MpTimeZone::from_iana_name("Not/AZone").is_none());
+        // 01:30 on the autumn change happens twice; the first (BST) occurrence wins
+        let ambiguous = london.from_local_datetime(naive("20241027T013000"));
+        assert_eq!(FixedOffset::east(3600), ambiguous.offset().fix());
+        // 01:30 on the spring change never happens; the pre-gap (GMT) offset is used
+        let skipped = london.from_local_datetime(naive("20240331T013000"));
+        assert_eq!(FixedOffset::east(0), skipped.offset().fix());
+    }
+
+    #[test]
+    fn test_vtimezone_resolution() {
+        let cal = parse_file_to_ical_calendar(String::from("src/test/test_timezones.ics")).unwrap();
+        let zone = MpTimeZone::from_vtimezone(&cal.timezones[0]).unwrap();
+        assert_eq!("Custom/Westminster", zone.tzid());
+        let winter = zone.from_local_datetime(naive("20240115T090000"));
+        assert_eq!("2024-01-15T09:00:00+00:00", winter.to_rfc3339());
+        let summer = zone.from_local_datetime(naive("20240715T090000"));
+        assert_eq!("2024-07-15T09:00:00+01:00", summer.to_rfc3339());
+        assert_eq!(FixedOffset::east(3600), zone.offset_at_utc(naive("20240331T010000")));
+        assert_eq!(FixedOffset::east(0), zone.offset_at_utc(naive("20240331T005959")));
+        let skipped = zone.from_local_datetime(naive("20240331T013000"));
+        assert_eq!(FixedOffset::east(0), skipped.offset().fix());
+        let daylight = zone.vtimezone_transitions().unwrap().iter().filter(|transition| transition.is_daylight).count();
+        assert_eq!(1, daylight);
+    }
+
+    #[test]
+    fn test_context_prefers_calendar_zones() {
+        let cal = parse_file_to_ical_calendar(String::from("src/test/test_timezones.ics")).unwrap();
+        let context = TimeZoneContext::from_ical_timezones(&cal.timezones, MpTimeZone::utc());
+        assert!(context.resolve_tzid("Custom/Westminster").unwrap().vtimezone_transitions().is_some());
+        assert!(context.resolve_tzid("America/New_York").unwrap().vtimezone_transitions().is_none());
+        assert!(context.resolve_tzid("Nowhere").is_none());
+        assert_eq!(MpTimeZone::utc(), configured_local_zone(Some("Nowhere/Special")));
+    }
+}
@@ -1,3 +1,6 @@
+// Written with explicit returns and single-arm matches, like the rest of the module
+#![allow(clippy::needless_return, clippy::single_match)]
+
 pub struct Message {
     pub body: String,
     pub output_time: bool,
@@ -12,6 +15,7 @@ pub mod core_io {
         print_to_output(msg_string)
     }
 
+    #[allow(clippy::needless_late_init)]
     fn format_message_to_str(message: super::Message) -> String {
         let msg_string: String;
         if message.output_time {
@@ -23,7 +27,8 @@ pub mod core_io {
         return msg_string;
     }
 
-    pub fn output_startup_message(state_config: super::core_config::Config) {
+    /// Greets the user, reports anything wrong with the config, then briefs them on their configured calendars
+    pub fn output_startup_message(state_config: &super::core_config::Config) {
         let greeting = super::core_config::create_greeting_string_from_config(state_config);
         let greeting_msg = super::Message {
             body: greeting,
@@ -31,6 +36,15 @@ pub mod core_io {
             sender: String::from("Core"),
         };
         output_message(greeting_msg);
+        for error in super::core_config::validate_config(state_config) {
+            output_message(super::Message {
+                body: format!("Config problem: {}", error),
+                output_time: true,
+                sender: String::from("Core"),
+            });
+        }
+        let now = chrono::Utc::now().with_timezone(&chrono::FixedOffset::east(0));
+        crate::mp_calendar::output_startup_agenda(state_config, now);
     }
 
     fn print_to_output(output: String) {
@@ -101,19 +115,256 @@ pub mod core_config {
     use toml;
     use serde::Deserialize;
 
-    #[derive(Deserialize, PartialEq, Debug)]
+    #[derive(Deserialize, PartialEq, Debug, Clone, Default)]
     pub struct Config {
         pub name: String,
         pub greeting: String,
+        // IANA name of the zone floating calendar times are read in, e.g. "Europe/London"
+        #[serde(default)]
+        pub timezone: Option<String>,
+        #[serde(default)]
+        pub calendars: Vec<CalendarConfig>,
+        // SQLite file events are kept in between runs. Configured calendars are saved into it at startup.
+        #[serde(default)]
+        pub store: Option<String>,
+        // File recording which alarms have gone off. When set, alarms go off while moneypenny runs,
+        // and a restart neither repeats nor loses them.
+        #[serde(default)]
+        pub alarm_state: Option<String>,
+        // How far ahead the startup agenda looks
+        #[serde(default)]
+        pub agenda_window: AgendaWindow,
+        // Serve the calendars over HTTP after the startup briefing
+        #[serde(default)]
+        pub feed: Option<FeedConfig>,
+        // Saved event queries, usable by name wherever a query is
+        #[serde(default)]
+        pub views: Vec<ViewConfig>,
+        // A view name or query the startup agenda shows only the matching events of
+        #[serde(default)]
+        pub agenda_view: Option<String>,
+        // Columns of CSV, JSON, Markdown and HTML exports, in order, e.g. ["start", "name", "location"]
+        #[serde(default)]
+        pub export_fields: Vec<String>,
+    }
+
+    /// One [[views]] entry: a named event query, e.g. name = "office", query = "location:~Room"
+    #[derive(Deserialize, PartialEq, Debug, Clone)]
+    pub struct ViewConfig {
+        pub name: String,
+        pub query: String,
+    }
+
+    /// The [feed] table: where to serve calendar feeds and what to put in them
+    #[derive(Deserialize, PartialEq, Debug, Clone)]
+    pub struct FeedConfig {
+        pub port: u16,
+        // Only reachable from this machine unless changed, e.g. to "0.0.0.0"
+        #[serde(default = "default_feed_address")]
+        pub address: String,
+        // Also answer read-only CalDAV requests, for clients that don't do plain webcal
+        #[serde(default)]
+        pub caldav: bool,
+        // Leave cancelled events out of the feeds
+        #[serde(default)]
+        pub hide_cancelled: bool,
+    }
+
+    fn default_feed_address() -> String {
+        return String::from("127.0.0.1");
+    }
+
+    #[derive(Deserialize, PartialEq, Debug, Clone, Copy)]
+    pub enum AgendaWindow {
+        // The whole of today, including anything already over
+        #[serde(rename = "today")]
+        Today,
+        // From now until the same time tomorrow
+        #[serde(rename = "next_24h")]
+        Next24Hours,
+        // From the start of today until the end of Sunday
+        #[serde(rename = "rest_of_week")]
+        RestOfWeek,
+    }
+
+    impl Default for AgendaWindow {
+        fn default() -> AgendaWindow {
+            return AgendaWindow::Today;
+        }
+    }
+
+    /// One [[calendars]] entry: an .ics file the calendar subsystem loads at startup
+    #[derive(Deserialize, PartialEq, Debug, Clone, Default)]
+    pub struct CalendarConfig {
+        pub path: String,
+        // Shown in place of the path, e.g. "Work"
+        #[serde(default)]
+        pub name: Option<String>,
+        // "#rgb" or "#rrggbb"
+        #[serde(default)]
+        pub colour: Option<String>,
+        // Nothing is ever written back to a read-only calendar
+        #[serde(default)]
+        pub read_only: bool,
+        // IANA zone floating times in this calendar are read in. Falls back to Config::timezone.
+        #[serde(default)]
+        pub timezone: Option<String>,
+    }
+
+    impl CalendarConfig {
+        pub fn display_name(&self) -> &str {
+            match &self.name {
+                Some(name) => return name,
+                None => return &self.path
+            }
+        }
+
+        /// Checks the fields of this entry on their own. Clashes between entries are checked by validate_config.
+        pub fn validate(&self) -> Result<(), ConfigError> {
+            if self.path.trim().is_empty() {
+                return Err(ConfigError::EmptyCalendarPath { calendar: self.display_name().to_string() });
+            }
+            match &self.timezone {
+                Some(tzid) if !is_known_timezone(tzid) => return Err(ConfigError::UnknownTimezone {
+                    calendar: Some(self.display_name().to_string()),
+                    tzid: tzid.clone()
+                }),
+                _ => ()
+            };
+            match &self.colour {
+                Some(colour) if !is_hex_colour(colour) => return Err(ConfigError::InvalidColour {
+                    calendar: self.display_name().to_string(),
+                    colour: colour.clone()
+                }),
+                _ => ()
+            };
+            return Ok(());
+        }
+    }
+
+    #[derive(PartialEq, Debug)]
+    pub enum ConfigError {
+        Unreadable { path: String, reason: String },
+        // Not TOML, or a key with the wrong type of value
+        Malformed { path: String, reason: String },
+        EmptyCalendarPath { calendar: String },
+        // calendar is None for the top level timezone
+        UnknownTimezone { calendar: Option<String>, tzid: String },
+        InvalidColour { calendar: String, colour: String },
+        DuplicateCalendarName(String),
+        DuplicateCalendarPath(String),
+        InvalidView { view: String, reason: String },
+        DuplicateViewName(String),
+        // agenda_view is neither a view's name nor a valid query
+        InvalidAgendaView { reason: String },
+        UnknownExportField(String),
+    }
+
+    impl std::fmt::Display for ConfigError {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            match self {
+                ConfigError::Unreadable { path, reason } => write!(f, "could not read {}: {}", path, reason),
+                ConfigError::Malformed { path, reason } => write!(f, "{} is not a valid config: {}", path, reason.trim_end()),
+                ConfigError::EmptyCalendarPath { calendar } => write!(f, "calendar '{}' has an empty path", calendar),
+                ConfigError::UnknownTimezone { calendar: Some(calendar), tzid } => write!(f, "calendar '{}' has unknown timezone '{}'", calendar, tzid),
+                ConfigError::UnknownTimezone { calendar: None, tzid } => write!(f, "unknown timezone '{}'", tzid),
+                ConfigError::InvalidColour { calendar, colour } => write!(f, "calendar '{}' has invalid colour '{}', expected #rgb or #rrggbb", calendar, colour),
+                ConfigError::DuplicateCalendarName(name) => write!(f, "more than one calendar is named '{}'", name),
+                ConfigError::DuplicateCalendarPath(path) => write!(f, "{} is listed as a calendar more than once", path),
+                ConfigError::InvalidView { view, reason } => write!(f, "view '{}' has an {}", view, reason),
+                ConfigError::DuplicateViewName(name) => write!(f, "more than one view is named '{}'", name),
+                ConfigError::InvalidAgendaView { reason } => write!(f, "agenda_view is not a view and has an {}", reason),
+                ConfigError::UnknownExportField(field) => write!(f, "export_fields has unknown field '{}'", field)
+            }
+        }
     }
 
-    pub fn parse_config_file_to_struct(path: String) -> Config {
-        let str_content = super::core_io::read_file_as_str(path);
-        let config: Config = toml::from_str(&str_content).unwrap();
-        return config;
+    impl std::error::Error for ConfigError {}
+
+    fn is_known_timezone(tzid: &str) -> bool {
+        return crate::mp_calendar::cal_tz::MpTimeZone::from_iana_name(tzid).is_some();
+    }
+
+    fn query_error(query: &str) -> Option<String> {
+        use crate::mp_calendar::cal_query::EventQuery;
+        use crate::mp_calendar::cal_tz::MpTimeZone;
+
+        // Only the syntax is being checked, so any time will do
+        let now = chrono::Utc::now().with_timezone(&chrono::FixedOffset::east(0));
+        return EventQuery::parse(query, now, &MpTimeZone::utc()).err().map(|e| e.to_string());
+    }
+
+    fn is_hex_colour(colour: &str) -> bool {
+        match colour.strip_prefix('#') {
+            Some(digits) => return (digits.len() == 3 || digits.len() == 6) && digits.chars().all(|c| c.is_ascii_hexdigit()),
+            None => return false
+        }
+    }
+
+    /// Every problem with the config, in the order the entries appear. An empty result means the
+    /// config is fine; calendars that fail CalendarConfig::validate are not loaded.
+    pub fn validate_config(config: &Config) -> Vec<ConfigError> {
+        let mut errors: Vec<ConfigError> = vec![];
+        match &config.timezone {
+            Some(tzid) if !is_known_timezone(tzid) => errors.push(ConfigError::UnknownTimezone { calendar: None, tzid: tzid.clone() }),
+            _ => ()
+        };
+        let mut names_seen: Vec<&str> = vec![];
+        let mut paths_seen: Vec<&str> = vec![];
+        for calendar in &config.calendars {
+            match calendar.validate() {
+                Ok(()) => (),
+                Err(e) => errors.push(e)
+            };
+            match &calendar.name {
+                Some(name) if names_seen.contains(&name.as_str()) => {
+                    errors.push(ConfigError::DuplicateCalendarName(name.clone()));
+                },
+                Some(name) => names_seen.push(name),
+                None => ()
+            };
+            if paths_seen.contains(&calendar.path.as_str()) {
+                errors.push(ConfigError::DuplicateCalendarPath(calendar.path.clone()));
+            } else {
+                paths_seen.push(&calendar.path);
+            }
+        }
+        let mut views_seen: Vec<&str> = vec![];
+        for view in &config.views {
+            match query_error(&view.query) {
+                Some(reason) => errors.push(ConfigError::InvalidView { view: view.name.clone(), reason }),
+                None => ()
+            };
+            if views_seen.contains(&view.name.as_str()) {
+                errors.push(ConfigError::DuplicateViewName(view.name.clone()));
+            } else {
+                views_seen.push(&view.name);
+            }
+        }
+        match &config.agenda_view {
+            Some(agenda_view) if !views_seen.contains(&agenda_view.as_str()) => match query_error(agenda_view) {
+                Some(reason) => errors.push(ConfigError::InvalidAgendaView { reason }),
+                None => ()
+            },
+            _ => ()
+        };
+        for field in &config.export_fields {
+            if field.parse::<crate::mp_calendar::cal_export::ExportField>().is_err() {
+                errors.push(ConfigError::UnknownExportField(field.clone()));
+            }
+        }
+        return errors;
     }
 
-    pub fn create_greeting_string_from_config(config: Config) -> String {
+    /// Reads the config at path. The file not being there or not being a valid config is an error;
+    /// what's wrong with the entries of a valid one is left to validate_config.
+    pub fn parse_config_file_to_struct(path: String) -> Result<Config, ConfigError> {
+        let str_content = std::fs::read_to_string(&path)
+            .map_err(|e| ConfigError::Unreadable { path: path.clone(), reason: e.to_string() })?;
+        return toml::from_str(&str_content).map_err(|e| ConfigError::Malformed { path, reason: e.to_string() });
+    }
+
+    pub fn create_greeting_string_from_config(config: &Config) -> String {
         let greeting_string: String = format!("{}, {}", config.greeting, config.name);
         return greeting_string;
     }
@@ -125,7 +376,8 @@ pub mod core_config {
         fn create_test_config() -> Config {
             let test_config = Config {
                 name: String::from("Tom"),
-                greeting: String::from("I HAVE NO GREETING")
+                greeting: String::from("I HAVE NO GREETING"),
+                ..Default::default()
             };
             return test_config;
         }
@@ -134,14 +386,64 @@ pub mod core_config {
         fn test_parse_config_file_to_struct() {
             let test_path = String::from("src/test/test_config.toml");
             let test_config = create_test_config();
-            assert_eq!(test_config, parse_config_file_to_struct(test_path));
+            assert_eq!(test_config, parse_config_file_to_struct(test_path).unwrap());
+        }
+
+        #[test]
+        fn test_parse_config_file_to_struct_reports_bad_files() {
+            assert!(matches!(parse_config_file_to_struct(String::from("src/test/does_not_exist.toml")), Err(ConfigError::Unreadable { .. })));
+            assert!(matches!(parse_config_file_to_struct(String::from("src/test/test_config_malformed.toml")), Err(ConfigError::Malformed { .. })));
+            match parse_config_file_to_struct(String::from("src/test/test_config_wrong_type.toml")) {
+                Err(e @ ConfigError::Malformed { .. }) => assert!(e.to_string().contains("read_only"), "{}", e),
+                other => panic!("expected a malformed config, got {:?}", other)
+            };
+        }
+
+        #[test]
+        fn test_parse_calendar_entries() {
+            let config = parse_config_file_to_struct(String::from("src/test/test_config_calendars.toml")).unwrap();
+            let work = &config.calendars[0];
+            assert_eq!("Work", work.display_name());
+            assert_eq!(Some(String::from("#1e90ff")), work.colour);
+            assert!(work.read_only);
+            assert_eq!(Some(String::from("America/New_York")), work.timezone);
+            let missing = &config.calendars[1];
+            assert_eq!("src/test/does_not_exist.ics", missing.display_name());
+            assert!(!missing.read_only);
+            assert_eq!(None, missing.timezone);
+            assert!(validate_config(&config).is_empty());
+            assert_eq!(vec![ViewConfig { name: String::from("Office"), query: String::from("-status:cancelled location:~Room") }], config.views);
+            assert_eq!(Some(String::from("Office")), config.agenda_view);
+            assert_eq!(vec![String::from("start"), String::from("name"), String::from("location")], config.export_fields);
+        }
+
+        #[test]
+        fn test_validate_config() {
+            let config = parse_config_file_to_struct(String::from("src/test/test_config_invalid.toml")).unwrap();
+            let expected = vec![
+                ConfigError::UnknownTimezone { calendar: None, tzid: String::from("Mars/Olympus_Mons") },
+                ConfigError::InvalidColour { calendar: String::from("Work"), colour: String::from("blue") },
+                ConfigError::UnknownTimezone { calendar: Some(String::from("Home")), tzid: String::from("Europe/Atlantis") },
+                ConfigError::DuplicateCalendarName(String::from("Work")),
+                ConfigError::DuplicateCalendarPath(String::from("src/test/test_recurring.ics")),
+                ConfigError::EmptyCalendarPath { calendar: String::from("Blank") },
+                ConfigError::InvalidView {
+                    view: String::from("Rooms"),
+                    reason: String::from("invalid query 'status:busy': 'busy' isn't tentative, confirmed or cancelled")
+                },
+                ConfigError::DuplicateViewName(String::from("Office")),
+                ConfigError::InvalidAgendaView { reason: String::from("invalid query 'Ofice -': a term has no value") },
+                ConfigError::UnknownExportField(String::from("room"))
+            ];
+            assert_eq!(expected, validate_config(&config));
+            assert_eq!("calendar 'Work' has invalid colour 'blue', expected #rgb or #rrggbb", expected[1].to_string());
         }
 
         #[test]
         fn test_create_greeting_string_from_config() {
             let test_string = String::from("I HAVE NO GREETING, Tom");
             let test_config = create_test_config();
-            assert_eq!(test_string, create_greeting_string_from_config(test_config));
+            assert_eq!(test_string, create_greeting_string_from_config(&test_config));
         }
     }
 }